@@ -0,0 +1,36 @@
+use std::{
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Sets `BUILD_ID` for `deploy::BUILD`: the short hash of the commit being built, marked when
+/// the tree has changes on top of it, or the build time outside a git checkout.
+fn main() {
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|o| o.status.success())
+            .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+    };
+    let id = match git(&["rev-parse", "--short", "HEAD"]) {
+        Some(hash) => {
+            let dirty = git(&["status", "--porcelain"]).map_or(false, |s| !s.is_empty());
+            if dirty {
+                format!("{}-dirty", hash)
+            } else {
+                hash
+            }
+        }
+        None => {
+            let secs = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            format!("t{}", secs)
+        }
+    };
+    println!("cargo:rustc-env=BUILD_ID={}", id);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+}
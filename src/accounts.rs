@@ -0,0 +1,394 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+};
+
+use log::*;
+use screeps::{prelude::*, Creep, Part, Room, RoomName};
+use serde::{Deserialize, Serialize};
+
+use crate::{intel, invaders, memory, remote::RemoteOperation};
+
+/// Remote books are judged every slice, over the rolling window of the last `WINDOW_SLICES`.
+const SLICE_TICKS: u32 = 1000;
+const WINDOW_SLICES: usize = 10;
+/// How long the window's net has to stay negative before an operation is suspended.
+const NEGATIVE_TICKS: u32 = 10_000;
+/// How long a suspended operation waits to be tried again.
+const SUSPEND_TICKS: u32 = 50_000;
+
+/// Owned rooms' books are closed every this many ticks.
+const ROOM_WINDOW_TICKS: u32 = 500;
+/// Energy one Work part spends on a tick of building.
+const BUILD_POWER: u32 = 5;
+
+/// An owned room's books for the current window, in `RoomMemory.books`. Upgrading is left out
+/// so the net is what the room could put into its controller.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RoomBooks {
+    #[serde(default)]
+    pub window_start: u32,
+    /// Energy harvested at home and brought back from remotes.
+    #[serde(default)]
+    pub income: u32,
+    /// Energy spent on spawning, building, repairs and towers.
+    #[serde(default)]
+    pub spent: u32,
+    /// Net energy per tick over the last closed window.
+    #[serde(default)]
+    pub last_rate: Option<f64>,
+}
+
+thread_local! {
+    /// Income and spending posted for each room since its last room pass.
+    static LEDGER: RefCell<HashMap<RoomName, (u32, u32)>> = RefCell::new(HashMap::new());
+}
+
+fn book(room: RoomName, income: u32, spent: u32) {
+    LEDGER.with(|l| {
+        let mut l = l.borrow_mut();
+        let entry = l.entry(room).or_insert((0, 0));
+        entry.0 += income;
+        entry.1 += spent;
+    })
+}
+
+/// Credits what `creep` harvested this tick to `room`, if that's its home.
+pub fn harvested(creep: &Creep, room: &Room) {
+    if memory::creep_home(creep) == Some(room.name()) {
+        book(room.name(), creep.get_active_bodyparts(Part::Work) * invaders::HARVEST_POWER, 0);
+    }
+}
+
+/// Charges a tick of building to `creep`'s home.
+pub fn built(creep: &Creep) {
+    if let Some(home) = memory::creep_home(creep) {
+        book(home, 0, creep.get_active_bodyparts(Part::Work) * BUILD_POWER);
+    }
+}
+
+/// Charges `energy` spent on spawning, repairs or a tower to `room`.
+pub fn spent(room: RoomName, energy: u32) {
+    book(room, 0, energy);
+}
+
+/// From the room pass: adds what was posted since the last one to `books`, and closes them
+/// once they're `ROOM_WINDOW_TICKS` old. Returns the closed window's net energy per tick.
+pub fn close_room_books(room: RoomName, books: &mut RoomBooks) -> Option<f64> {
+    let (income, spent) = LEDGER.with(|l| l.borrow_mut().remove(&room)).unwrap_or((0, 0));
+    books.income += income;
+    books.spent += spent;
+    let now = screeps::game::time();
+    if books.window_start == 0 {
+        books.window_start = now;
+    }
+    let ticks = now - books.window_start;
+    if ticks < ROOM_WINDOW_TICKS {
+        return None;
+    }
+    let rate = (books.income as f64 - books.spent as f64) / ticks as f64;
+    debug!(
+        "{} window: {} income, {} spent besides upgrading, {:.1}/tick",
+        room, books.income, books.spent, rate
+    );
+    *books = RoomBooks {
+        window_start: now,
+        last_rate: Some(rate),
+        ..RoomBooks::default()
+    };
+    Some(rate)
+}
+
+/// One closed slice of a remote's books.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct BookSlice {
+    #[serde(default)]
+    pub income: i32,
+    #[serde(default)]
+    pub spawn: i32,
+    #[serde(default)]
+    pub repair: i32,
+}
+
+/// A remote operation's books, in `Memory.remotes.<room>.books`: the slice being posted to,
+/// and the closed slices that make up the rolling window behind it.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RemoteBooks {
+    #[serde(default)]
+    pub slice_start: u32,
+    /// Energy its creeps brought home.
+    #[serde(default)]
+    pub income: i32,
+    /// Body cost of every creep spawned for it.
+    #[serde(default)]
+    pub spawn: i32,
+    /// Energy spent repairing in the remote.
+    #[serde(default)]
+    pub repair: i32,
+    /// Closed slices, oldest first, at most a window's worth.
+    #[serde(default)]
+    pub history: VecDeque<BookSlice>,
+    /// Net of the rolling window when it was last judged.
+    #[serde(default)]
+    pub last_net: Option<i32>,
+    /// Tick the rolling window's net went negative, while it stays that way.
+    #[serde(default)]
+    pub negative_since: Option<u32>,
+    #[serde(default)]
+    pub suspended_until: Option<u32>,
+    /// Why it was suspended.
+    #[serde(default)]
+    pub suspended_for: Option<String>,
+}
+
+impl RemoteBooks {
+    /// Income, spawning and repairs over the rolling window and the open slice.
+    pub fn totals(&self) -> BookSlice {
+        self.history.iter().fold(
+            BookSlice {
+                income: self.income,
+                spawn: self.spawn,
+                repair: self.repair,
+            },
+            |t, s| BookSlice {
+                income: t.income + s.income,
+                spawn: t.spawn + s.spawn,
+                repair: t.repair + s.repair,
+            },
+        )
+    }
+
+    pub fn net(&self) -> i32 {
+        let t = self.totals();
+        t.income - t.spawn - t.repair
+    }
+
+    pub fn suspended(&self) -> bool {
+        self.suspended_until.is_some()
+    }
+
+    /// Energy brought home per tick over the closed slices, once there are any.
+    pub fn income_rate(&self) -> Option<f64> {
+        if self.history.is_empty() {
+            return None;
+        }
+        let income: i32 = self.history.iter().map(|s| s.income).sum();
+        Some(income.max(0) as f64 / (self.history.len() as u32 * SLICE_TICKS) as f64)
+    }
+
+    /// Moves the open slice into the history, dropping slices older than the window.
+    fn close_slice(&mut self, now: u32) {
+        self.history.push_back(BookSlice {
+            income: self.income,
+            spawn: self.spawn,
+            repair: self.repair,
+        });
+        while self.history.len() > WINDOW_SLICES {
+            self.history.pop_front();
+        }
+        self.slice_start = now;
+        self.income = 0;
+        self.spawn = 0;
+        self.repair = 0;
+    }
+}
+
+/// Adds `amount` to `entry` of `remote`'s books, if it's a remote operation.
+fn post(remote: RoomName, entry: &str, amount: u32) {
+    let books = memory::remotes()
+        .ok()
+        .and_then(|r| r.dict(&remote.to_string()).ok().flatten())
+        .and_then(|op| op.dict_or_create("books").ok());
+    if let Some(books) = books {
+        let current = books.i32(entry).ok().flatten().unwrap_or(0);
+        books.set(entry, current + amount as i32);
+    }
+}
+
+pub fn spawned(remote: RoomName, cost: u32) {
+    post(remote, "spawn", cost);
+}
+
+/// Charges repairs in `room` to its books if it's a remote, and to `creep`'s home either way.
+pub fn repaired(creep: &Creep, room: RoomName, energy: u32) {
+    post(room, "repair", energy);
+    if let Some(home) = memory::creep_home(creep) {
+        spent(home, energy);
+    }
+}
+
+/// Credits energy `creep` delivered in its owner room to the remote it works, and to the
+/// owner's own books.
+pub fn delivered(creep: &Creep, room: RoomName, energy: u32) {
+    let mem = memory::creep_memory(creep);
+    let owner = mem.string("owner_room").ok().flatten();
+    let target = mem.string("target_room").ok().flatten();
+    if let (Some(owner), Some(target)) = (owner, target) {
+        if owner == room.to_string() {
+            if let Ok(remote) = target.parse() {
+                post(remote, "income", energy);
+                book(room, energy, 0);
+            }
+        }
+    }
+}
+
+/// Stops `remote` for `SUSPEND_TICKS`.
+pub fn suspend(remote: RoomName, books: &mut RemoteBooks, why: String) {
+    warn!("suspending remote {} for {} ticks: {}", remote, SUSPEND_TICKS, why);
+    books.suspended_until = Some(screeps::game::time() + SUSPEND_TICKS);
+    books.suspended_for = Some(why);
+}
+
+/// Closes a slice every `SLICE_TICKS` and judges the rolling window behind it, once there's a
+/// full window of it: an operation whose window has been net negative for `NEGATIVE_TICKS` is
+/// suspended, and resumed after `SUSPEND_TICKS`. One with no haulers planned has no income to
+/// judge and is left running, so a remote isn't suspended for the reserver and guards it
+/// needs before its routes are measured.
+pub fn review(remote: RoomName, op: &mut RemoteOperation) {
+    let now = screeps::game::time();
+    let haulers = op.haulers.count;
+    let books = &mut op.books;
+    if let Some(until) = books.suspended_until {
+        if now >= until {
+            info!("remote {} suspension over, running it again", remote);
+            *books = RemoteBooks {
+                slice_start: now,
+                ..RemoteBooks::default()
+            };
+        }
+        return;
+    }
+    if books.slice_start == 0 {
+        books.slice_start = now;
+    }
+    if now - books.slice_start < SLICE_TICKS {
+        return;
+    }
+    books.close_slice(now);
+    if books.history.len() < WINDOW_SLICES {
+        return;
+    }
+    let t = books.totals();
+    let net = books.net();
+    debug!(
+        "remote {} window: {} income, {} spawning, {} repairs, net {}",
+        remote, t.income, t.spawn, t.repair, net
+    );
+    books.last_net = Some(net);
+    if net >= 0 || haulers == 0 {
+        books.negative_since = None;
+        return;
+    }
+    let since = *books.negative_since.get_or_insert(now);
+    if now - since >= NEGATIVE_TICKS {
+        let why = format!(
+            "net {} over the last {} ticks ({} income, {} spawning, {} repairs), negative for \
+             {} ticks",
+            net,
+            WINDOW_SLICES as u32 * SLICE_TICKS,
+            t.income,
+            t.spawn,
+            t.repair,
+            now - since
+        );
+        suspend(remote, books, why);
+    }
+}
+
+/// Console command: logs every remote operation's books.
+pub fn print_remote_profits() {
+    let remotes = match memory::remotes() {
+        Ok(r) => r,
+        Err(e) => {
+            warn!("couldn't read remotes: {}", e);
+            return;
+        }
+    };
+    let mut out = format!(
+        "{:<8} {:<8} {:>8} {:>8} {:>8} {:>8} {:>9}\n",
+        "remote", "home", "income", "spawn", "repair", "last net", "status"
+    );
+    for key in remotes.keys() {
+        let op = match remotes.get::<RemoteOperation>(&key) {
+            Ok(Some(op)) => op,
+            _ => continue,
+        };
+        let b = &op.books;
+        let t = b.totals();
+        out.push_str(&format!(
+            "{:<8} {:<8} {:>8} {:>8} {:>8} {:>8} {:>9}\n",
+            key,
+            op.home.to_string(),
+            t.income,
+            t.spawn,
+            t.repair,
+            b.last_net.map_or_else(|| "-".to_owned(), |n| n.to_string()),
+            if b.suspended() { "suspended" } else { "running" }
+        ));
+        if let Some(why) = b.suspended_for.as_ref().filter(|_| b.suspended()) {
+            out.push_str(&format!("  suspended: {}\n", why));
+        }
+        let harassment = key
+            .parse()
+            .ok()
+            .and_then(|r| intel::get(r).ok().flatten())
+            .and_then(|i| intel::harassment_summary(&i));
+        if let Some(summary) = harassment {
+            out.push_str(&format!("  harassed: {}\n", summary));
+        }
+    }
+    info!("remote profitability:\n{}", out);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn books(income: i32, spawn: i32) -> RemoteBooks {
+        RemoteBooks {
+            income,
+            spawn,
+            ..RemoteBooks::default()
+        }
+    }
+
+    #[test]
+    fn net_covers_history_and_open_slice() {
+        let mut b = books(500, 100);
+        b.close_slice(1000);
+        b.income = 50;
+        b.repair = 20;
+        assert_eq!(b.net(), 500 - 100 + 50 - 20);
+    }
+
+    #[test]
+    fn window_rolls_off_oldest_slice() {
+        let mut b = books(-1, 0);
+        b.close_slice(1000);
+        for tick in 1..=WINDOW_SLICES as u32 {
+            b.income = 10;
+            b.close_slice(1000 * (tick + 1));
+        }
+        assert_eq!(b.history.len(), WINDOW_SLICES);
+        assert!(b.history.iter().all(|s| s.income == 10));
+        assert_eq!(b.net(), 10 * WINDOW_SLICES as i32);
+    }
+
+    #[test]
+    fn income_rate_needs_closed_slices() {
+        let mut b = books(700, 0);
+        assert_eq!(b.income_rate(), None);
+        b.close_slice(1000);
+        b.income = 9999;
+        assert_eq!(b.income_rate(), Some(0.7));
+    }
+
+    #[test]
+    fn closing_resets_open_slice() {
+        let mut b = books(300, 200);
+        b.repair = 5;
+        b.close_slice(2000);
+        assert_eq!((b.income, b.spawn, b.repair, b.slice_start), (0, 0, 0, 2000));
+    }
+}
@@ -0,0 +1,74 @@
+use std::{cell::RefCell, collections::HashSet};
+
+use log::*;
+use screeps::{prelude::*, Creep, Room};
+
+use crate::{intel, room_cache};
+
+thread_local! {
+    /// `Memory.allies` and our own name, read once per tick.
+    static FRIENDS: RefCell<(u32, HashSet<String>)> = RefCell::new((u32::MAX, HashSet::new()));
+}
+
+fn load() -> Vec<String> {
+    match screeps::memory::root().get::<Vec<String>>("allies") {
+        Ok(allies) => allies.unwrap_or_default(),
+        Err(e) => {
+            warn!("Memory.allies is not a list of names: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+fn is_friend(owner: &str) -> bool {
+    let now = screeps::game::time();
+    FRIENDS.with(|f| {
+        let mut f = f.borrow_mut();
+        if f.0 != now {
+            let mut friends: HashSet<String> = load().into_iter().collect();
+            friends.insert(intel::my_username());
+            *f = (now, friends);
+        }
+        f.1.contains(owner)
+    })
+}
+
+/// Whether `owner` is on `Memory.allies`.
+pub fn is_ally(owner: &str) -> bool {
+    owner != intel::my_username() && is_friend(owner)
+}
+
+/// Neither us nor an ally. Every combat decision goes through this.
+pub fn is_hostile(owner: &str) -> bool {
+    !is_friend(owner)
+}
+
+/// `find::HOSTILE_CREEPS` without the allies.
+pub fn hostile_creeps(room: &Room) -> Vec<Creep> {
+    room_cache::hostile_creeps(room)
+        .iter()
+        .filter(|c| is_hostile(&c.owner_name()))
+        .cloned()
+        .collect()
+}
+
+fn store(allies: &[String]) {
+    screeps::memory::root().set("allies", allies.to_vec());
+    FRIENDS.with(|f| f.borrow_mut().0 = u32::MAX);
+}
+
+pub fn add_ally(name: String) {
+    let mut allies = load();
+    if !allies.contains(&name) {
+        allies.push(name.clone());
+        store(&allies);
+    }
+    info!("allies: {}", allies.join(", "));
+}
+
+pub fn remove_ally(name: String) {
+    let mut allies = load();
+    allies.retain(|a| *a != name);
+    store(&allies);
+    info!("allies: {}", allies.join(", "));
+}
@@ -0,0 +1,171 @@
+use std::cell::{Cell, RefCell};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+use stdweb::{js_deserializable, js_serializable};
+
+use crate::phase;
+
+/// Weight of each new tick in the running mean and variance.
+const ALPHA: f64 = 0.02;
+/// Ticks measured before anything counts as a spike.
+const WARMUP_TICKS: u32 = 50;
+/// Standard deviations above the mean that make a tick a spike.
+const SIGMAS: f64 = 3.0;
+/// Reports kept in `Memory.cpu_anomalies`.
+const KEPT_REPORTS: usize = 5;
+/// How often the baseline is saved, so a global reset doesn't start it over.
+const SAVE_TICKS: u32 = 100;
+
+/// Running per-tick CPU, as exponentially weighted mean and variance. Saved to
+/// `Memory.cpu_baseline` now and then.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+struct Baseline {
+    #[serde(default)]
+    ticks: u32,
+    #[serde(default)]
+    mean: f64,
+    #[serde(default)]
+    variance: f64,
+}
+
+js_serializable!(Baseline);
+js_deserializable!(Baseline);
+
+/// One spike, for looking at after the fact with `print_cpu_anomalies()`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AnomalyReport {
+    #[serde(default)]
+    pub tick: u32,
+    #[serde(default)]
+    pub cpu: f64,
+    #[serde(default)]
+    pub mean: f64,
+    #[serde(default)]
+    pub stddev: f64,
+    /// CPU per phase, in the order they ran.
+    #[serde(default)]
+    pub phases: Vec<(String, f64)>,
+    #[serde(default)]
+    pub creeps: u32,
+    #[serde(default)]
+    pub rooms: u32,
+    /// First tick after a global reset.
+    #[serde(default)]
+    pub reset: bool,
+    /// Occasional work that ran this tick, from `note`.
+    #[serde(default)]
+    pub events: Vec<String>,
+}
+
+js_serializable!(AnomalyReport);
+js_deserializable!(AnomalyReport);
+
+thread_local! {
+    static BASELINE: Cell<Baseline> = Cell::new(Baseline::default());
+    static EVENTS: RefCell<Vec<&'static str>> = RefCell::new(Vec::new());
+    static FRESH: Cell<bool> = Cell::new(true);
+}
+
+/// Records that occasional, possibly expensive work (a planner step, memory cleanup) ran this
+/// tick, so a spike can be put down to it.
+pub fn note(event: &'static str) {
+    EVENTS.with(|e| e.borrow_mut().push(event));
+}
+
+fn load() -> Vec<AnomalyReport> {
+    screeps::memory::root()
+        .get::<Vec<AnomalyReport>>("cpu_anomalies")
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+}
+
+/// Checks the tick's CPU against the running baseline, then folds it in. A spike past
+/// `SIGMAS` standard deviations is logged with the phase breakdown and kept in Memory.
+pub fn end_tick(rooms: u32) {
+    let cpu = screeps::game::cpu::get_used();
+    let reset = FRESH.with(|f| f.replace(false));
+    let events = EVENTS.with(|e| std::mem::take(&mut *e.borrow_mut()));
+    let mut b = if reset {
+        let saved = screeps::memory::root().get::<Baseline>("cpu_baseline");
+        saved.ok().flatten().unwrap_or_default()
+    } else {
+        BASELINE.with(|b| b.get())
+    };
+    let stddev = b.variance.sqrt();
+    let mean = b.mean;
+    let spike = b.ticks >= WARMUP_TICKS && cpu > mean + SIGMAS * stddev;
+
+    if b.ticks == 0 {
+        b.mean = cpu;
+    }
+    let delta = cpu - b.mean;
+    b.mean += ALPHA * delta;
+    b.variance = (1.0 - ALPHA) * (b.variance + ALPHA * delta * delta);
+    b.ticks = b.ticks.saturating_add(1);
+    BASELINE.with(|c| c.set(b));
+    if screeps::game::time() % SAVE_TICKS == 0 {
+        screeps::memory::root().set("cpu_baseline", &b);
+    }
+    if !spike {
+        return;
+    }
+
+    let report = AnomalyReport {
+        tick: screeps::game::time(),
+        cpu,
+        mean,
+        stddev,
+        phases: phase::breakdown()
+            .into_iter()
+            .map(|(p, used)| (format!("{:?}", p), used))
+            .collect(),
+        creeps: screeps::game::creeps::keys().len() as u32,
+        rooms,
+        reset,
+        events: events.iter().map(|e| e.to_string()).collect(),
+    };
+    warn!("CPU spike: {}", describe(&report));
+    let mut reports = load();
+    reports.push(report);
+    let excess = reports.len().saturating_sub(KEPT_REPORTS);
+    reports.drain(..excess);
+    screeps::memory::root().set("cpu_anomalies", &reports);
+}
+
+fn describe(r: &AnomalyReport) -> String {
+    let phases: Vec<String> = r
+        .phases
+        .iter()
+        .map(|(p, used)| format!("{} {:.1}", p, used))
+        .collect();
+    let mut line = format!(
+        "tick {} used {:.1} CPU (mean {:.1}, sd {:.1}): {}; {} creeps, {} rooms",
+        r.tick,
+        r.cpu,
+        r.mean,
+        r.stddev,
+        phases.join(", "),
+        r.creeps,
+        r.rooms
+    );
+    if r.reset {
+        line.push_str(", first tick after a global reset");
+    }
+    if !r.events.is_empty() {
+        line.push_str(&format!(", ran {}", r.events.join(", ")));
+    }
+    line
+}
+
+/// Console command: `print_cpu_anomalies()` logs the last few CPU spikes.
+pub fn print_anomalies() {
+    let reports = load();
+    if reports.is_empty() {
+        info!("no CPU spikes recorded");
+    }
+    for r in &reports {
+        info!("{}", describe(r));
+    }
+}
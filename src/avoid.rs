@@ -0,0 +1,165 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use log::*;
+use screeps::{memory::MemoryReference, prelude::*, Creep, RoomName};
+use serde::{Deserialize, Serialize};
+use stdweb::{js_deserializable, js_serializable};
+
+use crate::{memory, settings};
+
+/// Default for `Memory.settings.avoid_deaths`: creeps lost in a room within `DEATH_WINDOW_TICKS`
+/// before it's avoided.
+const AVOID_DEATHS: u32 = 3;
+const DEATH_WINDOW_TICKS: u32 = 5000;
+/// Rooms avoided for their deaths are tried again after this long; manual entries stay.
+const AVOID_EXPIRY_TICKS: u32 = 200_000;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Avoided {
+    /// Tick the room was added.
+    pub since: u32,
+    /// Added with `avoid_room`, so only `unavoid_room` takes it off.
+    #[serde(default)]
+    pub manual: bool,
+}
+
+impl Avoided {
+    fn active(&self, now: u32) -> bool {
+        self.manual || now.saturating_sub(self.since) < AVOID_EXPIRY_TICKS
+    }
+}
+
+/// `Memory.avoid`: rooms every route keeps out of, and recent deaths by room towards adding
+/// more. Unlike intel, nothing here expires when a room is seen quiet.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AvoidList {
+    #[serde(default)]
+    pub rooms: HashMap<String, Avoided>,
+    /// Ticks creeps were found dead, by the room each was last in.
+    #[serde(default)]
+    pub deaths: HashMap<String, Vec<u32>>,
+}
+
+js_serializable!(AvoidList);
+js_deserializable!(AvoidList);
+
+thread_local! {
+    static CACHE: RefCell<(u32, AvoidList)> = RefCell::new((u32::MAX, AvoidList::default()));
+}
+
+fn load() -> AvoidList {
+    match screeps::memory::root().get::<AvoidList>("avoid") {
+        Ok(a) => a.unwrap_or_default(),
+        Err(e) => {
+            warn!("Memory.avoid is unreadable: {}", e);
+            AvoidList::default()
+        }
+    }
+}
+
+fn save(list: &AvoidList) {
+    screeps::memory::root().set("avoid", list);
+    CACHE.with(|c| c.borrow_mut().0 = u32::MAX);
+}
+
+/// Whether routes, scouts and the expansion scorer have to keep out of `room`.
+pub fn avoided(room: RoomName) -> bool {
+    let now = screeps::game::time();
+    let key = room.to_string();
+    CACHE.with(|c| {
+        let mut c = c.borrow_mut();
+        if c.0 != now {
+            *c = (now, load());
+        }
+        c.1.rooms.get(&key).map_or(false, |a| a.active(now))
+    })
+}
+
+/// Notes the room `creep` is in and when it would die of old age, each time it changes rooms,
+/// so memory cleanup can tell where it was killed.
+pub fn track(creep: &Creep) {
+    let room = creep.pos().room_name().to_string();
+    let mem = memory::creep_memory(creep);
+    if mem.string("last_room").ok().flatten().as_deref() == Some(room.as_str()) {
+        return;
+    }
+    mem.set("last_room", room.as_str());
+    mem.set("dies_at", screeps::game::time() + creep.ticks_to_live());
+}
+
+/// From memory cleanup: counts a dead creep against the room it was last in, unless it died
+/// of old age or at home, and avoids the room once it has cost enough creeps.
+pub fn record_death(mem: &MemoryReference, now: u32) {
+    let room = match mem.string("last_room").ok().flatten() {
+        Some(r) => r,
+        None => return,
+    };
+    let dies_at = mem.i32("dies_at").ok().flatten().unwrap_or(0) as u32;
+    if now >= dies_at {
+        return;
+    }
+    let ours = room
+        .parse::<RoomName>()
+        .ok()
+        .and_then(screeps::game::rooms::get)
+        .and_then(|r| r.controller())
+        .map_or(false, |c| c.my());
+    if ours {
+        return;
+    }
+    let mut list = load();
+    let deaths = list.deaths.entry(room.clone()).or_default();
+    deaths.push(now);
+    deaths.retain(|t| now.saturating_sub(*t) < DEATH_WINDOW_TICKS);
+    let count = deaths.len() as u32;
+    if count >= settings::u32_or("avoid_deaths", AVOID_DEATHS)
+        && !list.rooms.contains_key(&room)
+    {
+        warn!("avoiding {} after losing {} creeps there", room, count);
+        list.deaths.remove(&room);
+        list.rooms.insert(
+            room,
+            Avoided {
+                since: now,
+                manual: false,
+            },
+        );
+    }
+    list.deaths.retain(|_, d| d.iter().any(|t| now.saturating_sub(*t) < DEATH_WINDOW_TICKS));
+    list.rooms.retain(|_, a| a.active(now));
+    save(&list);
+}
+
+/// Console command: `avoid_room("W7N3")` keeps every route out of the room until
+/// `unavoid_room`.
+pub fn avoid_room(room: String) -> String {
+    if room.parse::<RoomName>().is_err() {
+        return format!("{} isn't a room name", room);
+    }
+    let mut list = load();
+    let key = room.to_uppercase();
+    list.rooms.insert(
+        key.clone(),
+        Avoided {
+            since: screeps::game::time(),
+            manual: true,
+        },
+    );
+    save(&list);
+    format!("avoiding {}", key)
+}
+
+/// Console command: `unavoid_room("W7N3")` lets routes back into the room and forgets its
+/// deaths.
+pub fn unavoid_room(room: String) -> String {
+    let mut list = load();
+    let key = room.to_uppercase();
+    list.deaths.remove(&key);
+    let was = list.rooms.remove(&key).is_some();
+    save(&list);
+    if was {
+        format!("no longer avoiding {}", key)
+    } else {
+        format!("{} isn't avoided", key)
+    }
+}
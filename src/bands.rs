@@ -0,0 +1,229 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use screeps::{
+    find, prelude::*, ObjectId, Position, ResourceType, Room, RoomName, Structure, StructureType,
+};
+
+use crate::{
+    labs,
+    logistics::{LogisticsRequest, Recipient, RequestKind},
+    memory::LabRole,
+    room_cache, settings,
+};
+
+/// Defaults for `Memory.settings.terminal_energy_min` and `terminal_energy_max`.
+const TERMINAL_ENERGY: (u32, u32) = (20_000, 50_000);
+/// Defaults for `Memory.settings.terminal_mineral_min` and `terminal_mineral_max`, per
+/// mineral or compound.
+const TERMINAL_MINERAL: (u32, u32) = (2_000, 3_000);
+/// Defaults for `Memory.settings.factory_input_min` and `factory_input_max`, for energy and
+/// the room's own mineral.
+const FACTORY_INPUT: (u32, u32) = (500, 2_000);
+/// Default for `Memory.settings.nuker_surplus_energy`: storage energy the nuker never draws
+/// the room below.
+const NUKER_SURPLUS_ENERGY: u32 = 150_000;
+/// Defaults for `Memory.settings.lab_input_min` and `lab_input_max`, for an input lab's
+/// reagent.
+const LAB_INPUT: (u32, u32) = (1_000, 2_000);
+/// Moves smaller than this wait until they're worth a trip.
+const MIN_MOVE: u32 = 100;
+
+/// How much of a resource a structure should hold. Nothing moves while it's inside the band;
+/// outside it the rebalancer brings it back to the middle, so a store sitting at an edge
+/// doesn't flap between filling and draining.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Band {
+    pub low: u32,
+    pub high: u32,
+}
+
+impl Band {
+    fn setting(name: &str, (low, high): (u32, u32)) -> Band {
+        Band {
+            low: settings::u32_or(&format!("{}_min", name), low),
+            high: settings::u32_or(&format!("{}_max", name), high),
+        }
+    }
+
+    pub fn target(self) -> u32 {
+        (self.low + self.high) / 2
+    }
+}
+
+/// Resource held in a structure that should go elsewhere, for a worker to withdraw.
+#[derive(Clone, Debug)]
+pub struct Withdrawal {
+    pub from: ObjectId<Structure>,
+    pub pos: Position,
+    pub resource: ResourceType,
+    pub amount: u32,
+}
+
+thread_local! {
+    static WITHDRAWALS: RefCell<HashMap<RoomName, Vec<Withdrawal>>> =
+        RefCell::new(HashMap::new());
+}
+
+/// The band `structure` keeps `resource` in, if it has one. The terminal bands every tradable
+/// resource, and a factory drains whatever isn't one of its inputs. A reaction lab keeps its
+/// reagent and drains every other mineral, its product included; boost labs are left alone.
+/// Storage has none: it holds whatever the others don't.
+pub fn band(room: &Room, structure: &Structure, resource: ResourceType) -> Option<Band> {
+    match structure.structure_type() {
+        StructureType::Terminal if resource == ResourceType::Energy => {
+            Some(Band::setting("terminal_energy", TERMINAL_ENERGY))
+        }
+        StructureType::Terminal if resource != ResourceType::Power => {
+            Some(Band::setting("terminal_mineral", TERMINAL_MINERAL))
+        }
+        StructureType::Factory => {
+            let mineral = room.find(find::MINERALS).first().map(|m| m.mineral_type());
+            if resource == ResourceType::Energy || Some(resource) == mineral {
+                Some(Band::setting("factory_input", FACTORY_INPUT))
+            } else {
+                Some(Band { low: 0, high: 0 })
+            }
+        }
+        StructureType::Nuker if resource == ResourceType::Energy => Some(Band {
+            low: 300_000,
+            high: 300_000,
+        }),
+        StructureType::Nuker if resource == ResourceType::Ghodium => Some(Band {
+            low: 5_000,
+            high: 5_000,
+        }),
+        StructureType::Lab if resource != ResourceType::Energy => {
+            match labs::lab_role(structure.untyped_id()) {
+                Some((LabRole::Input, Some(reagent))) if reagent == resource => {
+                    Some(Band::setting("lab_input", LAB_INPUT))
+                }
+                Some((LabRole::Input, _)) | Some((LabRole::Output, _)) => {
+                    Some(Band { low: 0, high: 0 })
+                }
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Whether `structure` is a lab holding a mineral other than `resource`. A lab only holds one
+/// at a time, so it's drained before it's filled.
+fn holds_other(structure: &Structure, resource: ResourceType) -> bool {
+    match structure {
+        Structure::Lab(lab) => lab
+            .store_types()
+            .into_iter()
+            .any(|r| r != resource && r != ResourceType::Energy),
+        _ => false,
+    }
+}
+
+/// Room left in `structure` for `resource` before it goes over its band; unlimited for
+/// structures without one. Anything that fills a banded structure stays under this.
+pub fn headroom(room: &Room, structure: &Structure, resource: ResourceType) -> u32 {
+    let held = structure.as_has_store().map_or(0, |s| s.store_of(resource));
+    band(room, structure, resource).map_or(u32::MAX, |b| b.high.saturating_sub(held))
+}
+
+/// What storage can give towards `to` without eating into what it keeps back.
+fn spare(to: &Structure, resource: ResourceType, stored: u32) -> u32 {
+    if to.structure_type() == StructureType::Nuker && resource == ResourceType::Energy {
+        let floor = settings::u32_or("nuker_surplus_energy", NUKER_SURPLUS_ENERGY);
+        return stored.saturating_sub(floor);
+    }
+    stored
+}
+
+/// Compares the room's terminal, factory, nuker and labs against their bands. A structure short of
+/// its band gets a `Rebalance` request, matched by a withdrawal from storage; one over it gets
+/// a withdrawal, matched by a request to storage. Nothing moves while `active` is unset, as
+/// when the room is starved or under attack and a besieged terminal's energy is for the
+/// towers.
+pub fn rebalance(room: &Room, active: bool) -> Vec<LogisticsRequest> {
+    let mut requests = Vec::new();
+    let mut withdrawals = Vec::new();
+    let storage = match room.storage() {
+        Some(s) if active => Structure::Storage(s),
+        _ => {
+            WITHDRAWALS.with(|w| w.borrow_mut().remove(&room.name()));
+            return requests;
+        }
+    };
+    let banded: Vec<Structure> = room_cache::structures(room)
+        .iter()
+        .filter(|s| match s.structure_type() {
+            StructureType::Terminal
+            | StructureType::Factory
+            | StructureType::Nuker
+            | StructureType::Lab => true,
+            _ => false,
+        })
+        .filter(|s| s.as_owned().map_or(false, |o| o.my()))
+        .cloned()
+        .collect();
+    let held = |s: &Structure, r: ResourceType| s.as_has_store().map_or(0, |st| st.store_of(r));
+    let kinds = |s: &Structure| s.as_has_store().map_or(Vec::new(), |st| st.store_types());
+
+    for structure in &banded {
+        let mut resources = kinds(structure);
+        for r in kinds(&storage) {
+            if !resources.contains(&r) {
+                resources.push(r);
+            }
+        }
+        for resource in resources {
+            let band = match band(room, structure, resource) {
+                Some(b) => b,
+                None => continue,
+            };
+            let have = held(structure, resource);
+            if have > band.high {
+                let amount = have - band.target();
+                if amount >= MIN_MOVE {
+                    withdrawals.push(Withdrawal {
+                        from: structure.id(),
+                        pos: structure.pos(),
+                        resource,
+                        amount,
+                    });
+                    requests.push(request(&storage, resource, amount));
+                }
+            } else if have < band.low && !holds_other(structure, resource) {
+                let stored = held(&storage, resource);
+                let amount = (band.target() - have).min(spare(structure, resource, stored));
+                if amount >= MIN_MOVE {
+                    withdrawals.push(Withdrawal {
+                        from: storage.id(),
+                        pos: storage.pos(),
+                        resource,
+                        amount,
+                    });
+                    requests.push(request(structure, resource, amount));
+                }
+            }
+        }
+    }
+    WITHDRAWALS.with(|w| w.borrow_mut().insert(room.name(), withdrawals));
+    requests
+}
+
+fn request(to: &Structure, resource: ResourceType, amount: u32) -> LogisticsRequest {
+    let kind = RequestKind::Rebalance;
+    LogisticsRequest {
+        kind,
+        target: Recipient::Structure(to.id()),
+        pos: to.pos(),
+        resource,
+        amount,
+        base_priority: kind.base_priority(),
+        priority: kind.base_priority(),
+    }
+}
+
+/// This tick's withdrawals for `room`, largest first.
+pub fn withdrawals(room: RoomName) -> Vec<Withdrawal> {
+    let mut list = WITHDRAWALS.with(|w| w.borrow().get(&room).cloned().unwrap_or_default());
+    list.sort_by_key(|w| std::cmp::Reverse(w.amount));
+    list
+}
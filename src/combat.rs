@@ -0,0 +1,192 @@
+/// An in-room `(x, y)` coordinate.
+pub type Tile = (u32, u32);
+
+/// Ticks ahead the hostile's recent movement is carried on to guess where it's heading.
+const APPROACH_TICKS: i32 = 3;
+/// Range a ranged defender keeps from a melee hostile.
+pub const KITE_RANGE: u32 = 3;
+
+/// What a defender knows going into a tick of fighting one hostile.
+#[derive(Clone, Debug, Default)]
+pub struct CombatSnapshot {
+    pub me: Tile,
+    pub melee: bool,
+    pub ranged: bool,
+    pub hostile: Tile,
+    pub hostile_melee: bool,
+    pub hostile_ranged: bool,
+    /// Where the hostiles have been over the last few ticks, oldest first.
+    pub trail: Vec<Tile>,
+    /// Our ramparts nobody else is standing on.
+    pub ramparts: Vec<Tile>,
+    /// Free choke tiles on the side the hostiles came in by.
+    pub chokes: Vec<Tile>,
+    /// The critical structure the chokes cover.
+    pub core: Option<Tile>,
+}
+
+/// Where a defender should be this tick; it attacks whatever it can reach from there.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Maneuver {
+    /// Close in on the hostile.
+    Engage,
+    /// Go to, or stay on, this tile.
+    Hold(Tile),
+    /// Step away from the hostile until it's `KITE_RANGE` off.
+    Kite,
+}
+
+pub fn range(a: Tile, b: Tile) -> u32 {
+    let dx = (a.0 as i32 - b.0 as i32).abs();
+    let dy = (a.1 as i32 - b.1 as i32).abs();
+    dx.max(dy) as u32
+}
+
+/// The latest trail position carried on by the trail's average step for `APPROACH_TICKS`.
+pub fn likely_approach(trail: &[Tile], fallback: Tile) -> Tile {
+    let (first, last) = match (trail.first(), trail.last()) {
+        (Some(f), Some(l)) => (*f, *l),
+        _ => return fallback,
+    };
+    let steps = (trail.len() as i32 - 1).max(1);
+    let project = |from: u32, to: u32| {
+        let trend = (to as i32 - from as i32) * APPROACH_TICKS / steps;
+        (to as i32 + trend).max(1).min(48) as u32
+    };
+    (project(first.0, last.0), project(first.1, last.1))
+}
+
+fn nearest(tiles: &[Tile], to: Tile) -> Option<Tile> {
+    tiles.iter().copied().min_by_key(|t| range(*t, to))
+}
+
+/// The choke tile to hold, rampart ones first, while the hostile is still no nearer the core
+/// than the chokes are.
+fn held_choke(s: &CombatSnapshot) -> Option<Tile> {
+    let core = s.core?;
+    let line = s.chokes.iter().map(|t| range(*t, core)).min()?;
+    if range(s.hostile, core) < line {
+        return None;
+    }
+    let covered: Vec<Tile> = s
+        .chokes
+        .iter()
+        .copied()
+        .filter(|t| s.ramparts.contains(t))
+        .collect();
+    nearest(&covered, s.me).or_else(|| nearest(&s.chokes, s.me))
+}
+
+pub fn decide(s: &CombatSnapshot) -> Maneuver {
+    // let the hostile come to the way in rather than chasing it, unless it's through
+    if let Some(choke) = held_choke(s) {
+        return Maneuver::Hold(choke);
+    }
+    // a melee defender can't catch ranged attackers; it waits for them where they're headed
+    if s.melee && !s.ranged && s.hostile_ranged {
+        let approach = likely_approach(&s.trail, s.hostile);
+        if let Some(rampart) = nearest(&s.ramparts, approach) {
+            return Maneuver::Hold(rampart);
+        }
+    }
+    if s.ranged && !s.melee && s.hostile_melee {
+        return if range(s.me, s.hostile) < KITE_RANGE {
+            Maneuver::Kite
+        } else {
+            Maneuver::Engage
+        };
+    }
+    // up close either way, so take the hits on a rampart if there's one next to the hostile
+    let reach = if s.melee { 1 } else { KITE_RANGE };
+    let beside: Vec<Tile> = s
+        .ramparts
+        .iter()
+        .copied()
+        .filter(|t| range(*t, s.hostile) <= reach && range(*t, s.hostile) > 0)
+        .collect();
+    match nearest(&beside, s.me) {
+        Some(rampart) => Maneuver::Hold(rampart),
+        None => Maneuver::Engage,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn melee_vs_ranged() -> CombatSnapshot {
+        CombatSnapshot {
+            me: (25, 25),
+            melee: true,
+            hostile: (25, 10),
+            hostile_ranged: true,
+            ..CombatSnapshot::default()
+        }
+    }
+
+    #[test]
+    fn melee_holds_rampart_on_ranged_approach() {
+        let s = CombatSnapshot {
+            trail: vec![(25, 4), (25, 7), (25, 10)],
+            ramparts: vec![(10, 10), (25, 20), (40, 40)],
+            ..melee_vs_ranged()
+        };
+        assert_eq!(likely_approach(&s.trail, s.hostile), (25, 19));
+        assert_eq!(decide(&s), Maneuver::Hold((25, 20)));
+    }
+
+    #[test]
+    fn melee_without_ramparts_engages() {
+        assert_eq!(decide(&melee_vs_ranged()), Maneuver::Engage);
+    }
+
+    #[test]
+    fn ranged_kites_melee_inside_range() {
+        let mut s = CombatSnapshot {
+            me: (25, 25),
+            ranged: true,
+            hostile: (25, 23),
+            hostile_melee: true,
+            ..CombatSnapshot::default()
+        };
+        assert_eq!(decide(&s), Maneuver::Kite);
+        s.hostile = (25, 22);
+        assert_eq!(decide(&s), Maneuver::Engage);
+    }
+
+    #[test]
+    fn fights_from_rampart_beside_hostile() {
+        let s = CombatSnapshot {
+            me: (20, 20),
+            melee: true,
+            hostile: (22, 22),
+            hostile_melee: true,
+            ramparts: vec![(22, 22), (21, 21), (30, 30)],
+            ..CombatSnapshot::default()
+        };
+        assert_eq!(decide(&s), Maneuver::Hold((21, 21)));
+    }
+
+    #[test]
+    fn holds_choke_until_hostile_passes_it() {
+        let mut s = CombatSnapshot {
+            me: (25, 30),
+            melee: true,
+            hostile: (25, 5),
+            hostile_melee: true,
+            chokes: vec![(24, 15), (25, 15), (26, 15)],
+            ramparts: vec![(26, 15)],
+            core: Some((25, 25)),
+            ..CombatSnapshot::default()
+        };
+        assert_eq!(decide(&s), Maneuver::Hold((26, 15)));
+        s.hostile = (25, 20);
+        assert_eq!(decide(&s), Maneuver::Engage);
+    }
+
+    #[test]
+    fn approach_stays_inside_room() {
+        assert_eq!(likely_approach(&[(3, 25), (1, 25)], (1, 25)), (1, 25));
+        assert_eq!(likely_approach(&[], (7, 8)), (7, 8));
+    }
+}
@@ -0,0 +1,206 @@
+use std::collections::VecDeque;
+
+use log::*;
+use serde::{Deserialize, Serialize};
+use stdweb::{js, js_deserializable, js_serializable};
+
+use crate::{
+    accounts, allies, anomaly, avoid, deploy, diplomacy, expansion, group, history, inspect,
+    inventory, logging, presets, reconcile, shard,
+};
+
+/// Results of this many applied commands are kept for `command_result`.
+const KEPT_RESULTS: usize = 20;
+
+/// A console command waiting for the start of the next tick.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct QueuedCommand {
+    pub id: u32,
+    pub name: String,
+    pub args: Vec<String>,
+    pub queued: u32,
+}
+
+/// `Memory.commands`: commands that change memory or heap state, queued from the console and
+/// applied in order at the start of the loop. Kept in memory, so a command queued just before
+/// a global reset still runs after it.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CommandQueue {
+    #[serde(default)]
+    pub next_id: u32,
+    #[serde(default)]
+    pub pending: Vec<QueuedCommand>,
+    /// What each recently applied command returned, by id, oldest first.
+    #[serde(default)]
+    pub results: VecDeque<(u32, String)>,
+}
+
+js_serializable!(CommandQueue);
+js_deserializable!(CommandQueue);
+
+fn load() -> CommandQueue {
+    match screeps::memory::root().get::<CommandQueue>("commands") {
+        Ok(q) => q.unwrap_or_default(),
+        Err(e) => {
+            warn!("Memory.commands is unreadable: {}", e);
+            CommandQueue::default()
+        }
+    }
+}
+
+/// Called by the queued console commands: adds `name(args)` to `Memory.commands`.
+fn enqueue(name: String, args: Vec<String>) -> String {
+    let mut queue = load();
+    let id = queue.next_id;
+    queue.next_id += 1;
+    queue.pending.push(QueuedCommand {
+        id,
+        name: name.clone(),
+        args,
+        queued: screeps::game::time(),
+    });
+    screeps::memory::root().set("commands", &queue);
+    format!("{} queued as #{}; see command_result({}) next tick", name, id, id)
+}
+
+fn arg(args: &[String], i: usize) -> Result<String, String> {
+    args.get(i)
+        .cloned()
+        .ok_or_else(|| format!("missing argument {}", i + 1))
+}
+
+fn number(args: &[String], i: usize) -> Result<u32, String> {
+    let raw = arg(args, i)?;
+    raw.parse()
+        .map_err(|_| format!("argument {} isn't a number: {}", i + 1, raw))
+}
+
+/// Runs one queued command. Commands that only log report `done`.
+fn apply(command: &QueuedCommand) -> Result<String, String> {
+    let a = &command.args;
+    let done = || Ok("done".to_owned());
+    match command.name.as_str() {
+        "group_join" => {
+            group::join(arg(a, 0)?, arg(a, 1)?);
+            done()
+        }
+        "group_leave" => {
+            group::leave(arg(a, 0)?);
+            done()
+        }
+        "group_disband" => {
+            group::disband(arg(a, 0)?);
+            done()
+        }
+        "group_move" => {
+            let (x, y, range) = (number(a, 2)?, number(a, 3)?, number(a, 4)?);
+            group::move_group(arg(a, 0)?, arg(a, 1)?, x, y, range);
+            done()
+        }
+        "group_squad" => {
+            group::set_squad(arg(a, 0)?, arg(a, 1).unwrap_or_default());
+            done()
+        }
+        "ally_add" => {
+            allies::add_ally(arg(a, 0)?);
+            done()
+        }
+        "ally_remove" => {
+            allies::remove_ally(arg(a, 0)?);
+            done()
+        }
+        "set_body" => {
+            presets::set_body(arg(a, 0)?, arg(a, 1)?, arg(a, 2)?);
+            done()
+        }
+        "set_stance" => {
+            diplomacy::set_stance(arg(a, 0)?, arg(a, 1)?);
+            done()
+        }
+        "confirm_demolish" => Ok(reconcile::confirm_demolish(arg(a, 0)?)),
+        "avoid_room" => Ok(avoid::avoid_room(arg(a, 0)?)),
+        "unavoid_room" => Ok(avoid::unavoid_room(arg(a, 0)?)),
+        other => Err(format!("unknown command {}", other)),
+    }
+}
+
+/// Applies every queued command, oldest first. Runs first thing in the loop, before anything
+/// has read memory or built a cache this tick.
+pub fn apply_queued() {
+    let mut queue = load();
+    if queue.pending.is_empty() {
+        return;
+    }
+    for command in std::mem::take(&mut queue.pending) {
+        let result = match apply(&command) {
+            Ok(r) => r,
+            Err(e) => format!("error: {}", e),
+        };
+        info!(
+            "command #{} {}({}): {}",
+            command.id,
+            command.name,
+            command.args.join(", "),
+            result
+        );
+        queue.results.push_back((command.id, result));
+        while queue.results.len() > KEPT_RESULTS {
+            queue.results.pop_front();
+        }
+    }
+    screeps::memory::root().set("commands", &queue);
+}
+
+/// Console command: `command_result(id)` shows what a queued command returned.
+pub fn command_result(id: u32) -> String {
+    let queue = load();
+    if queue.pending.iter().any(|c| c.id == id) {
+        return format!("#{} hasn't been applied yet", id);
+    }
+    match queue.results.iter().find(|(i, _)| *i == id) {
+        Some((_, result)) => result.clone(),
+        None => format!("no result kept for #{}", id),
+    }
+}
+
+/// Exposes console commands as globals so they can be called from the game console. The ones
+/// that only read run straight away; the ones that change anything are queued for the start of
+/// the next tick, so they never race the loop.
+pub fn register() {
+    js! {
+        var enqueue = @{enqueue};
+        var queued = function(name) {
+            return function() {
+                var args = [];
+                for (var i = 0; i < arguments.length; i++) {
+                    args.push(String(arguments[i]));
+                }
+                return enqueue(name, args);
+            };
+        };
+
+        global.print_expansion_candidates = @{expansion::print_candidates};
+        global.group_join = queued("group_join");
+        global.group_leave = queued("group_leave");
+        global.group_disband = queued("group_disband");
+        global.group_move = queued("group_move");
+        global.group_squad = queued("group_squad");
+        global.print_surplus = @{inventory::print_surplus};
+        global.print_stats_history = @{history::print_history};
+        global.ally_add = queued("ally_add");
+        global.ally_remove = queued("ally_remove");
+        global.print_remote_profits = @{accounts::print_remote_profits};
+        global.set_body = queued("set_body");
+        global.set_stance = queued("set_stance");
+        global.print_cpu_anomalies = @{anomaly::print_anomalies};
+        global.roles = @{inspect::roles};
+        global.inspect = @{inspect::inspect};
+        global.recent_errors = @{logging::recent_errors};
+        global.confirm_demolish = queued("confirm_demolish");
+        global.avoid_room = queued("avoid_room");
+        global.unavoid_room = queued("unavoid_room");
+        global.version = @{deploy::version};
+        global.print_shard = @{shard::print_shard};
+        global.command_result = @{command_result};
+    }
+}
@@ -0,0 +1,402 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use log::*;
+use screeps::{
+    find, prelude::*, ConstructionSite, Creep, Part, Position, ResourceType, ReturnCode, Room,
+    RoomName, StructureType,
+};
+use serde::{Deserialize, Serialize};
+use stdweb::js_serializable;
+
+use crate::{
+    intents, memory, planner,
+    room::RoomMemory,
+    room_cache, stats,
+    terrain::{self, ROOM_SIZE},
+};
+
+thread_local! {
+    /// Progress already on its way to each site this tick, by site id, per room.
+    static COMMITTED: RefCell<(u32, HashMap<RoomName, HashMap<String, u32>>)> =
+        RefCell::new((0, HashMap::new()));
+}
+
+const CHECK_TICKS: u32 = 100;
+/// Sites with no progress for this long and nobody building them get removed.
+const STUCK_AFTER_TICKS: u32 = 10_000;
+const BUILD_RANGE: u32 = 3;
+/// Blacklisted tiles kept per room; the oldest go first, and the planner may try them again.
+const MAX_BLACKLIST: usize = 200;
+/// Site origins kept per room waiting for their site to show up. A room has at most 100 sites,
+/// so anything past this is for sites that never went up.
+const MAX_SITE_ORIGINS: usize = 100;
+
+/// Last seen state of a construction site, in room memory keyed by site id.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SiteTrack {
+    pub progress: u32,
+    pub remaining: u32,
+    /// Tick progress last changed, or the site was first seen.
+    pub changed: u32,
+    /// The planner that placed the site, when we know.
+    #[serde(default)]
+    pub origin: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ConstructionStats {
+    pending: u32,
+    committed: u32,
+    build_rate: f64,
+    eta: Option<u32>,
+}
+
+js_serializable!(ConstructionStats);
+
+/// Why a site can't go where a planner wants it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SiteProblem {
+    Blacklisted,
+    Wall,
+    /// Nothing is built on the room border, and walls and ramparts stay off the tiles next to
+    /// an exit.
+    NearExit,
+    Occupied(StructureType),
+    SiteThere,
+    /// The controller level allows this many of the type and there are already as many built
+    /// or going up.
+    OverLimit(u32),
+}
+
+impl SiteProblem {
+    /// Whether the tile itself is the problem, so no planner should try it again.
+    pub fn blacklists(self) -> bool {
+        match self {
+            SiteProblem::Wall | SiteProblem::NearExit | SiteProblem::Occupied(_) => true,
+            SiteProblem::Blacklisted | SiteProblem::SiteThere | SiteProblem::OverLimit(_) => {
+                false
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for SiteProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SiteProblem::Blacklisted => write!(f, "the tile is blacklisted"),
+            SiteProblem::Wall => write!(f, "the tile is a wall"),
+            SiteProblem::NearExit => write!(f, "the tile is too close to an exit"),
+            SiteProblem::Occupied(ty) => write!(f, "a {:?} is already there", ty),
+            SiteProblem::SiteThere => write!(f, "there's already a site there"),
+            SiteProblem::OverLimit(n) => write!(f, "the controller level only allows {}", n),
+        }
+    }
+}
+
+/// `CONTROLLER_STRUCTURES`: how many of each type the controller allows, by level 0 to 8.
+fn controller_structures(ty: StructureType) -> [u32; 9] {
+    match ty {
+        StructureType::Spawn => [0, 1, 1, 1, 1, 1, 1, 2, 3],
+        StructureType::Extension => [0, 0, 5, 10, 20, 30, 40, 50, 60],
+        StructureType::Link => [0, 0, 0, 0, 0, 2, 3, 4, 6],
+        StructureType::Storage => [0, 0, 0, 0, 1, 1, 1, 1, 1],
+        StructureType::Tower => [0, 0, 0, 1, 1, 2, 2, 3, 6],
+        StructureType::Observer | StructureType::PowerSpawn | StructureType::Nuker => {
+            [0, 0, 0, 0, 0, 0, 0, 0, 1]
+        }
+        StructureType::Extractor | StructureType::Terminal => [0, 0, 0, 0, 0, 0, 1, 1, 1],
+        StructureType::Factory => [0, 0, 0, 0, 0, 0, 0, 1, 1],
+        StructureType::Lab => [0, 0, 0, 0, 0, 0, 3, 6, 10],
+        StructureType::Container => [5; 9],
+        StructureType::Road => [2500; 9],
+        StructureType::Wall | StructureType::Rampart => {
+            [0, 0, 2500, 2500, 2500, 2500, 2500, 2500, 2500]
+        }
+        _ => [0; 9],
+    }
+}
+
+/// Whether a structure of type `a` can stand on the same tile as one of type `b`: ramparts go
+/// over anything else, and roads and containers share.
+fn can_share(a: StructureType, b: StructureType) -> bool {
+    let walkable = |t| t == StructureType::Road || t == StructureType::Container;
+    (a == StructureType::Rampart) != (b == StructureType::Rampart)
+        || (a != b && walkable(a) && walkable(b))
+}
+
+/// Checks a site of type `ty` at `pos` against what `create_construction_site` would refuse:
+/// the blacklist, terrain, the room border, the structures and sites already there, and the
+/// controller's count limits.
+pub fn validate(
+    room: &Room,
+    mem: &RoomMemory,
+    pos: Position,
+    ty: StructureType,
+) -> Result<(), SiteProblem> {
+    let packed = pos.packed_repr();
+    if mem.site_blacklist.contains(&packed) {
+        return Err(SiteProblem::Blacklisted);
+    }
+    let walls = terrain::walls(room.name());
+    let (x, y) = (pos.x() as usize, pos.y() as usize);
+    let on_wall = walls[terrain::index(x, y)];
+    // roads can be laid through walls, and an extractor sits on its mineral
+    if on_wall && ty != StructureType::Road && ty != StructureType::Extractor {
+        return Err(SiteProblem::Wall);
+    }
+    let edge = |x: usize, y: usize| x == 0 || y == 0 || x == ROOM_SIZE - 1 || y == ROOM_SIZE - 1;
+    if edge(x, y) {
+        return Err(SiteProblem::NearExit);
+    }
+    if ty == StructureType::Wall || ty == StructureType::Rampart {
+        let near_exit = (x - 1..=x + 1)
+            .flat_map(|ex| (y - 1..=y + 1).map(move |ey| (ex, ey)))
+            .any(|(ex, ey)| edge(ex, ey) && !walls[terrain::index(ex, ey)]);
+        if near_exit {
+            return Err(SiteProblem::NearExit);
+        }
+    }
+    let structures = room_cache::structures(room);
+    let there = structures
+        .iter()
+        .find(|s| s.pos() == pos && !can_share(ty, s.structure_type()));
+    if let Some(s) = there {
+        return Err(SiteProblem::Occupied(s.structure_type()));
+    }
+    let sites = room_cache::construction_sites(room);
+    if sites.iter().any(|s| s.pos() == pos) {
+        return Err(SiteProblem::SiteThere);
+    }
+    let level = room.controller().map_or(0, |c| c.level()).min(8) as usize;
+    let allowed = controller_structures(ty)[level];
+    let built = structures
+        .iter()
+        .filter(|s| s.structure_type() == ty)
+        .filter(|s| s.as_owned().map_or(true, |o| o.my()))
+        .count();
+    let going_up = sites.iter().filter(|s| s.structure_type() == ty).count();
+    if (built + going_up) as u32 >= allowed {
+        return Err(SiteProblem::OverLimit(allowed));
+    }
+    Ok(())
+}
+
+/// Remembers which planner placed the site going up at `pos`, until the site shows up.
+pub fn record_origin(mem: &mut RoomMemory, pos: Position, origin: &str) {
+    let packed = pos.packed_repr();
+    mem.site_origins.retain(|(p, _)| *p != packed);
+    mem.site_origins.push((packed, origin.to_owned()));
+    let over = mem.site_origins.len().saturating_sub(MAX_SITE_ORIGINS);
+    mem.site_origins.drain(..over);
+}
+
+/// Keeps the planner off the tile at `packed`, dropping the oldest entries past
+/// `MAX_BLACKLIST`.
+pub fn blacklist(mem: &mut RoomMemory, packed: u32) {
+    if mem.site_blacklist.contains(&packed) {
+        return;
+    }
+    mem.site_blacklist.push(packed);
+    let over = mem.site_blacklist.len().saturating_sub(MAX_BLACKLIST);
+    mem.site_blacklist.drain(..over);
+}
+
+/// Whether a creep standing on a site of type `ty` holds up its completion: everything but the
+/// structures creeps walk over.
+pub fn blocks_creeps(ty: StructureType) -> bool {
+    match ty {
+        StructureType::Road | StructureType::Container | StructureType::Rampart => false,
+        _ => true,
+    }
+}
+
+/// Tiles of the room's sites that creeps shouldn't stand on.
+pub fn blocking_sites(room: &Room) -> Vec<Position> {
+    room_cache::construction_sites(room)
+        .iter()
+        .filter(|s| blocks_creeps(s.structure_type()))
+        .map(|s| s.pos())
+        .collect()
+}
+
+/// Whether a creep that can build is in range of `pos`.
+pub fn being_built(pos: Position) -> bool {
+    pos.find_in_range(find::MY_CREEPS, BUILD_RANGE)
+        .iter()
+        .any(|c| c.get_active_bodyparts(Part::Work) > 0)
+}
+
+/// Updates site progress, removes sites that have been stuck for `STUCK_AFTER_TICKS`, and
+/// publishes pending work to `Memory.stats.construction`.
+pub fn run_construction(room: &Room, mem: &mut RoomMemory) {
+    let now = screeps::game::time();
+    if now % CHECK_TICKS != 0 {
+        return;
+    }
+
+    let mut sites = HashMap::new();
+    let mut built = 0;
+    for site in room.find(find::MY_CONSTRUCTION_SITES) {
+        let id = site.id().to_string();
+        let packed = site.pos().packed_repr();
+        let mut track = match mem.sites.remove(&id) {
+            Some(t) => t,
+            None => SiteTrack {
+                progress: site.progress(),
+                changed: now,
+                origin: mem
+                    .site_origins
+                    .iter()
+                    .position(|(p, _)| *p == packed)
+                    .map(|i| mem.site_origins.remove(i).1),
+                ..SiteTrack::default()
+            },
+        };
+        if site.progress() != track.progress {
+            built += site.progress().saturating_sub(track.progress);
+            track.progress = site.progress();
+            track.changed = now;
+        }
+        track.remaining = site.progress_total() - site.progress();
+
+        if now - track.changed >= STUCK_AFTER_TICKS && !being_built(site.pos()) {
+            warn!(
+                "{} removing stuck {:?} site at {} placed by {}",
+                room.name(),
+                site.structure_type(),
+                site.pos(),
+                track.origin.as_deref().unwrap_or("unknown")
+            );
+            let r = intents::issue("construction", "remove", &id, None, || site.remove());
+            if r == ReturnCode::Ok {
+                blacklist(mem, packed);
+                continue;
+            }
+        }
+        sites.insert(id, track);
+    }
+    // whatever was left untracked finished since the last check
+    built += mem.sites.values().map(|t| t.remaining).sum::<u32>();
+    mem.sites = sites;
+
+    let committed: u32 = mem.sites.values().map(|t| t.remaining).sum();
+    let build_rate = built as f64 / CHECK_TICKS as f64;
+    if let Some(section) = stats::section("construction") {
+        section.set(
+            &room.name().to_string(),
+            &ConstructionStats {
+                pending: mem.sites.len() as u32,
+                committed,
+                build_rate,
+                eta: if build_rate > 0.0 {
+                    Some((committed as f64 / build_rate).ceil() as u32)
+                } else {
+                    None
+                },
+            },
+        );
+    }
+}
+
+/// Sites of the earlier types go up first.
+fn site_priority(ty: StructureType) -> u32 {
+    match ty {
+        StructureType::Spawn => 0,
+        StructureType::Extension => 1,
+        StructureType::Tower => 2,
+        StructureType::Container => 3,
+        StructureType::Storage | StructureType::Link => 4,
+        StructureType::Road => 6,
+        StructureType::Wall | StructureType::Rampart => 7,
+        _ => 5,
+    }
+}
+
+/// Progress each site in `room` already has coming from its assigned builders. Every energy a
+/// builder carries is one point of progress, so that's its commitment. Worked out from creep
+/// memory once per tick, so a builder that died or moved on is no longer counted.
+fn committed_in(room: &Room) -> HashMap<String, u32> {
+    let mut committed = HashMap::new();
+    for creep in room.find(find::MY_CREEPS) {
+        if let Some(site) = memory::creep_memory(&creep).string("build_site").ok().flatten() {
+            *committed.entry(site).or_insert(0) += creep.store_of(ResourceType::Energy);
+        }
+    }
+    committed
+}
+
+fn with_committed<R>(room: &Room, f: impl FnOnce(&mut HashMap<String, u32>) -> R) -> R {
+    let now = screeps::game::time();
+    COMMITTED.with(|c| {
+        let mut c = c.borrow_mut();
+        if c.0 != now {
+            *c = (now, HashMap::new());
+        }
+        let committed = c.1.entry(room.name()).or_insert_with(|| committed_in(room));
+        f(committed)
+    })
+}
+
+/// The site `creep` should build: the one it's assigned to while that still stands, else the
+/// first site by type priority and range whose remaining progress its other builders don't
+/// already cover. `None` once every site has enough builders.
+pub fn assign_site(room: &Room, creep: &Creep) -> Option<ConstructionSite> {
+    let mut sites = room.find(find::MY_CONSTRUCTION_SITES);
+    let current = memory::creep_memory(creep).string("build_site").ok().flatten();
+    if let Some(current) = current {
+        if let Some(site) = sites.iter().find(|s| s.id().to_string() == current) {
+            return Some(site.clone());
+        }
+        memory::creep_memory(creep).del("build_site");
+    }
+    // the first tower goes ahead of everything else
+    let rush = planner::tower_rush(room.name());
+    sites.sort_by_key(|s| {
+        let ty = s.structure_type();
+        let rushed = rush && ty == StructureType::Tower;
+        (!rushed, site_priority(ty), creep.pos().get_range_to(s))
+    });
+    let load = creep.store_of(ResourceType::Energy);
+    with_committed(room, |committed| {
+        let site = sites.into_iter().find(|s| {
+            let remaining = s.progress_total() - s.progress();
+            committed.get(&s.id().to_string()).cloned().unwrap_or(0) < remaining
+        })?;
+        let id = site.id().to_string();
+        *committed.entry(id.clone()).or_insert(0) += load;
+        memory::creep_memory(creep).set("build_site", id.as_str());
+        Some(site)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blacklist_skips_repeats_and_drops_the_oldest() {
+        let mut mem = RoomMemory::default();
+        blacklist(&mut mem, 7);
+        blacklist(&mut mem, 7);
+        assert_eq!(mem.site_blacklist, vec![7]);
+        for packed in 100..100 + MAX_BLACKLIST as u32 {
+            blacklist(&mut mem, packed);
+        }
+        assert_eq!(mem.site_blacklist.len(), MAX_BLACKLIST);
+        assert!(!mem.site_blacklist.contains(&7));
+    }
+
+    #[test]
+    fn origins_are_capped_and_replaced_per_tile() {
+        let mut mem = RoomMemory::default();
+        let room = "W1N1".parse().unwrap();
+        record_origin(&mut mem, Position::new(10, 10, room), "base");
+        record_origin(&mut mem, Position::new(10, 10, room), "roads");
+        assert_eq!(mem.site_origins.len(), 1);
+        assert_eq!(mem.site_origins[0].1, "roads");
+        for i in 0..MAX_SITE_ORIGINS as u32 + 5 {
+            record_origin(&mut mem, Position::new(1 + i % 48, 1 + i / 48, room), "base");
+        }
+        assert_eq!(mem.site_origins.len(), MAX_SITE_ORIGINS);
+    }
+}
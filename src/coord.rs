@@ -0,0 +1,385 @@
+use screeps::{Direction, Position, RoomName};
+
+/// Rooms per sector side; highways run along every tenth row and column.
+const SECTOR_SIZE: i32 = 10;
+/// Tiles per room side.
+const ROOM_TILES: i32 = 50;
+
+/// World position of a room in room units. East and south are non-negative; west and north are
+/// negative, with `W0` at -1 and `N0` at -1 so the axes have no gap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct RoomCoordinate {
+    pub x: i32,
+    pub y: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoomKind {
+    Normal,
+    Highway,
+    /// Where two highways cross.
+    Crossroads,
+    /// The ring of source keeper rooms around a sector centre.
+    SourceKeeper,
+    /// The sector centre, with sources and a mineral but no keepers.
+    Center,
+}
+
+impl RoomCoordinate {
+    /// Parses names like `W12N3`. The simulation room sits at the origin.
+    pub fn parse(name: &str) -> Option<RoomCoordinate> {
+        if name == "sim" {
+            return Some(RoomCoordinate { x: 0, y: 0 });
+        }
+        let name = name.as_bytes();
+        let (x, rest) = axis(name, b'W', b'E')?;
+        let (y, rest) = axis(rest, b'N', b'S')?;
+        if !rest.is_empty() {
+            return None;
+        }
+        Some(RoomCoordinate { x, y })
+    }
+
+    pub fn of(room: RoomName) -> Option<RoomCoordinate> {
+        RoomCoordinate::parse(&room.to_string())
+    }
+
+    pub fn room_name(self) -> Option<RoomName> {
+        let (h, x) = if self.x < 0 { ('W', -self.x - 1) } else { ('E', self.x) };
+        let (v, y) = if self.y < 0 { ('N', -self.y - 1) } else { ('S', self.y) };
+        format!("{}{}{}{}", h, x, v, y).parse().ok()
+    }
+
+    /// Coordinates within the sector, 0 to 9 on both axes, for either side of the origin.
+    fn in_sector(self) -> (i32, i32) {
+        let wrap = |c: i32| (if c < 0 { -c - 1 } else { c }) % SECTOR_SIZE;
+        (wrap(self.x), wrap(self.y))
+    }
+
+    pub fn kind(self) -> RoomKind {
+        let (x, y) = self.in_sector();
+        match (x == 0, y == 0) {
+            (true, true) => return RoomKind::Crossroads,
+            (true, false) | (false, true) => return RoomKind::Highway,
+            _ => {}
+        }
+        let ring = |c: i32| (4..=6).contains(&c);
+        if x == 5 && y == 5 {
+            RoomKind::Center
+        } else if ring(x) && ring(y) {
+            RoomKind::SourceKeeper
+        } else {
+            RoomKind::Normal
+        }
+    }
+
+    /// Rooms apart counting diagonal steps as one, the same measure as
+    /// `Game.map.getRoomLinearDistance` without wrapping.
+    pub fn linear_distance(self, other: RoomCoordinate) -> u32 {
+        (self.x - other.x).abs().max((self.y - other.y).abs()) as u32
+    }
+}
+
+/// Reads a direction letter and a number off the front of `name`; `neg` counts down from -1.
+fn axis(name: &[u8], neg: u8, pos: u8) -> Option<(i32, &[u8])> {
+    let (&dir, rest) = name.split_first()?;
+    let digits = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+    if digits == 0 {
+        return None;
+    }
+    let n: i32 = std::str::from_utf8(&rest[..digits]).ok()?.parse().ok()?;
+    let rest = &rest[digits..];
+    match dir {
+        d if d == neg => Some((-n - 1, rest)),
+        d if d == pos => Some((n, rest)),
+        _ => None,
+    }
+}
+
+/// What kind of room `room` is. The simulation room is always `Normal`.
+pub fn kind(room: RoomName) -> RoomKind {
+    let name = room.to_string();
+    if name == "sim" {
+        return RoomKind::Normal;
+    }
+    RoomCoordinate::parse(&name).map_or(RoomKind::Normal, RoomCoordinate::kind)
+}
+
+pub fn is_highway(room: RoomName) -> bool {
+    match kind(room) {
+        RoomKind::Highway | RoomKind::Crossroads => true,
+        _ => false,
+    }
+}
+
+/// Source keeper and centre rooms, where nobody can claim the controller.
+pub fn is_keeper_sector(room: RoomName) -> bool {
+    match kind(room) {
+        RoomKind::SourceKeeper | RoomKind::Center => true,
+        _ => false,
+    }
+}
+
+/// Linear distance between two rooms, or `None` if either name doesn't parse.
+pub fn distance(a: RoomName, b: RoomName) -> Option<u32> {
+    Some(RoomCoordinate::of(a)?.linear_distance(RoomCoordinate::of(b)?))
+}
+
+/// Every room within `range` of `center`, nearest rings first, not including `center`.
+pub fn neighbors_within(center: RoomName, range: u32) -> Vec<RoomName> {
+    let c = match RoomCoordinate::of(center) {
+        Some(c) => c,
+        None => return Vec::new(),
+    };
+    let mut rooms = Vec::new();
+    for ring in 1..=range as i32 {
+        for dy in -ring..=ring {
+            for dx in -ring..=ring {
+                if dx.abs() != ring && dy.abs() != ring {
+                    continue;
+                }
+                let coord = RoomCoordinate {
+                    x: c.x + dx,
+                    y: c.y + dy,
+                };
+                if let Some(name) = coord.room_name() {
+                    rooms.push(name);
+                }
+            }
+        }
+    }
+    rooms
+}
+
+/// The rooms across the top, right, bottom and left borders of `room`, whether or not the
+/// terrain leaves an exit that way.
+pub fn adjacent(room: RoomName) -> Vec<(Direction, RoomName)> {
+    let c = match RoomCoordinate::of(room) {
+        Some(c) => c,
+        None => return Vec::new(),
+    };
+    [
+        (Direction::Top, 0, -1),
+        (Direction::Right, 1, 0),
+        (Direction::Bottom, 0, 1),
+        (Direction::Left, -1, 0),
+    ]
+    .iter()
+    .filter_map(|&(side, dx, dy)| {
+        let next = RoomCoordinate {
+            x: c.x + dx,
+            y: c.y + dy,
+        };
+        next.room_name().map(|n| (side, n))
+    })
+    .collect()
+}
+
+/// A tile in world coordinates: the room's coordinate times the room size plus the tile's
+/// place in the room. Tiles either side of a room border are one apart, which `Position`'s own
+/// range and direction methods don't see.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct WorldTile {
+    pub x: i32,
+    pub y: i32,
+}
+
+impl WorldTile {
+    pub fn of(pos: Position) -> Option<WorldTile> {
+        let room = RoomCoordinate::of(pos.room_name())?;
+        Some(WorldTile {
+            x: room.x * ROOM_TILES + pos.x() as i32,
+            y: room.y * ROOM_TILES + pos.y() as i32,
+        })
+    }
+
+    pub fn position(self) -> Option<Position> {
+        let room = RoomCoordinate {
+            x: self.x.div_euclid(ROOM_TILES),
+            y: self.y.div_euclid(ROOM_TILES),
+        };
+        let (x, y) = (self.x.rem_euclid(ROOM_TILES), self.y.rem_euclid(ROOM_TILES));
+        Some(Position::new(x as u32, y as u32, room.room_name()?))
+    }
+
+    pub fn range(self, other: WorldTile) -> u32 {
+        (self.x - other.x).abs().max((self.y - other.y).abs()) as u32
+    }
+
+    /// The direction of the first step on a straight line to `other`; `None` once there.
+    pub fn direction(self, other: WorldTile) -> Option<Direction> {
+        match ((other.x - self.x).signum(), (other.y - self.y).signum()) {
+            (0, -1) => Some(Direction::Top),
+            (1, -1) => Some(Direction::TopRight),
+            (1, 0) => Some(Direction::Right),
+            (1, 1) => Some(Direction::BottomRight),
+            (0, 1) => Some(Direction::Bottom),
+            (-1, 1) => Some(Direction::BottomLeft),
+            (-1, 0) => Some(Direction::Left),
+            (-1, -1) => Some(Direction::TopLeft),
+            _ => None,
+        }
+    }
+
+    /// The next tile on a straight line to `other`, or `self` once there.
+    pub fn step_toward(self, other: WorldTile) -> WorldTile {
+        WorldTile {
+            x: self.x + (other.x - self.x).signum(),
+            y: self.y + (other.y - self.y).signum(),
+        }
+    }
+}
+
+/// Range between two positions in any rooms. Positions in rooms whose names don't parse are
+/// only comparable within the same room, and are out of range otherwise.
+pub fn range(a: Position, b: Position) -> u32 {
+    match (WorldTile::of(a), WorldTile::of(b)) {
+        (Some(a), Some(b)) => a.range(b),
+        _ if a.room_name() == b.room_name() => {
+            let dx = (a.x() as i32 - b.x() as i32).abs();
+            let dy = (a.y() as i32 - b.y() as i32).abs();
+            dx.max(dy) as u32
+        }
+        _ => u32::MAX,
+    }
+}
+
+pub fn in_range(a: Position, b: Position, range: u32) -> bool {
+    self::range(a, b) <= range
+}
+
+/// Direction of the first step on a straight line from `a` to `b`, across room borders.
+pub fn direction(a: Position, b: Position) -> Option<Direction> {
+    WorldTile::of(a)?.direction(WorldTile::of(b)?)
+}
+
+/// The next tile on a straight line from `a` to `b`, which may be in the next room over.
+pub fn step_toward(a: Position, b: Position) -> Option<Position> {
+    WorldTile::of(a)?.step_toward(WorldTile::of(b)?).position()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room(name: &str) -> RoomName {
+        name.parse().unwrap()
+    }
+
+    fn coord(x: i32, y: i32) -> RoomCoordinate {
+        RoomCoordinate { x, y }
+    }
+
+    #[test]
+    fn parses_both_sides_of_the_origin() {
+        assert_eq!(RoomCoordinate::parse("W0N0"), Some(coord(-1, -1)));
+        assert_eq!(RoomCoordinate::parse("E0S0"), Some(coord(0, 0)));
+        assert_eq!(RoomCoordinate::parse("W12N3"), Some(coord(-13, -4)));
+        assert_eq!(RoomCoordinate::parse("E7S21"), Some(coord(7, 21)));
+        assert_eq!(RoomCoordinate::parse("sim"), Some(coord(0, 0)));
+    }
+
+    #[test]
+    fn rejects_malformed_names() {
+        for name in &["", "W", "W1", "N1W1", "X1N1", "W1N", "W1N1x", "WN1"] {
+            assert_eq!(RoomCoordinate::parse(name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for name in &["W0N0", "E0S0", "W12N3", "E7S21", "W0S0", "E0N0"] {
+            let c = RoomCoordinate::parse(name).unwrap();
+            assert_eq!(c.room_name(), Some(room(name)));
+        }
+    }
+
+    #[test]
+    fn classifies_rooms() {
+        let kind = |name| RoomCoordinate::parse(name).unwrap().kind();
+        assert_eq!(kind("W10N5"), RoomKind::Highway);
+        assert_eq!(kind("E3S0"), RoomKind::Highway);
+        assert_eq!(kind("W10N10"), RoomKind::Crossroads);
+        assert_eq!(kind("E0S0"), RoomKind::Crossroads);
+        assert_eq!(kind("W5N5"), RoomKind::Center);
+        assert_eq!(kind("E15S15"), RoomKind::Center);
+        assert_eq!(kind("W4N6"), RoomKind::SourceKeeper);
+        assert_eq!(kind("E6S4"), RoomKind::SourceKeeper);
+        assert_eq!(kind("W3N3"), RoomKind::Normal);
+        assert_eq!(kind("E7S5"), RoomKind::Normal);
+    }
+
+    #[test]
+    fn distance_crosses_the_origin() {
+        assert_eq!(distance(room("W1N1"), room("E1S1")), Some(3));
+        assert_eq!(distance(room("W0N0"), room("E0S0")), Some(1));
+        assert_eq!(distance(room("W5N8"), room("W5N8")), Some(0));
+    }
+
+    #[test]
+    fn neighbors_come_in_rings() {
+        let near = neighbors_within(room("W0N0"), 2);
+        assert_eq!(near.len(), 24);
+        assert!(near[..8].iter().all(|r| distance(room("W0N0"), *r) == Some(1)));
+        assert!(near.contains(&room("E0S0")));
+        assert!(!near.contains(&room("W0N0")));
+    }
+
+    #[test]
+    fn adjacent_rooms_by_side() {
+        assert_eq!(
+            adjacent(room("W0N0")),
+            vec![
+                (Direction::Top, room("W0N1")),
+                (Direction::Right, room("E0N0")),
+                (Direction::Bottom, room("W0S0")),
+                (Direction::Left, room("W1N0")),
+            ]
+        );
+    }
+
+    #[test]
+    fn range_and_direction_across_west_east_border() {
+        let a = Position::new(49, 20, room("W5N8"));
+        let b = Position::new(0, 20, room("W4N8"));
+        assert_eq!(range(a, b), 1);
+        assert_eq!(direction(a, b), Some(Direction::Right));
+        assert_eq!(direction(b, a), Some(Direction::Left));
+        let w = Position::new(49, 10, room("W0N3"));
+        let e = Position::new(0, 11, room("E0N3"));
+        assert_eq!(range(w, e), 1);
+        assert_eq!(direction(w, e), Some(Direction::BottomRight));
+    }
+
+    #[test]
+    fn range_and_direction_across_north_south_border() {
+        let n = Position::new(30, 49, room("E2N0"));
+        let s = Position::new(30, 0, room("E2S0"));
+        assert_eq!(range(n, s), 1);
+        assert_eq!(direction(n, s), Some(Direction::Bottom));
+        assert_eq!(direction(s, n), Some(Direction::Top));
+        let up = Position::new(10, 0, room("W3N4"));
+        let down = Position::new(12, 48, room("W3N5"));
+        assert_eq!(range(up, down), 2);
+        assert_eq!(direction(up, down), Some(Direction::TopRight));
+    }
+
+    #[test]
+    fn step_toward_enters_next_room() {
+        let a = Position::new(49, 20, room("W5N8"));
+        let b = Position::new(3, 20, room("W4N8"));
+        assert_eq!(step_toward(a, b), Some(Position::new(0, 20, room("W4N8"))));
+        assert_eq!(step_toward(a, a), Some(a));
+    }
+
+    #[test]
+    fn world_tiles_round_trip() {
+        for pos in &[
+            Position::new(0, 0, room("W0N0")),
+            Position::new(49, 49, room("E0S0")),
+            Position::new(17, 33, room("W12N3")),
+        ] {
+            assert_eq!(WorldTile::of(*pos).and_then(WorldTile::position), Some(*pos));
+        }
+    }
+}
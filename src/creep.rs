@@ -0,0 +1,1600 @@
+use log::*;
+use screeps::{
+    find, look, prelude::*, Creep, ObjectId, Part, Position, ResourceType, ReturnCode, Room,
+    RoomName, Source, Structure, StructureType,
+};
+use serde::{Deserialize, Serialize};
+use stdweb::{js_deserializable, js_serializable};
+
+use crate::{
+    accounts, allies, avoid, bands,
+    combat::{self, CombatSnapshot, Maneuver},
+    construction, coord,
+    deposits::{self, DepositOperation},
+    error::{self, BotError},
+    group, intents, invaders, labs,
+    logistics::{self, Recipient, RequestKind},
+    memory, mining, objects, perimeter, planner, population, reconcile, remote, renewal, repair,
+    role::{self, BodyVerdict, Role},
+    room, room_cache, route, scout, settings, stats, tasklog, threat, traffic,
+};
+
+/// Hostile attackers this close send creeps running.
+const FLEE_RANGE: u32 = 3;
+/// How often a drop miner checks its container site is still there.
+const SITE_RETRY_TICKS: u32 = 20;
+/// Idle defenders wait this close to the middle of their room.
+const DEFENDER_POST_RANGE: u32 = 5;
+/// Creeps waiting to be healed stand this close to a tower.
+const RETREAT_RANGE: u32 = 2;
+/// Energy a tower spends on one heal.
+const TOWER_HEAL_ENERGY: u32 = 10;
+/// Default for `Memory.settings.builder_spawn_fill`: percent full spawn and extensions must be
+/// before a builder takes energy meant for them.
+const BUILDER_SPAWN_FILL: u32 = 80;
+/// Creeps sent home with fewer ticks to live than this are recycled rather than put to work.
+const RETIRE_TTL: u32 = 200;
+/// Delivery targets that asked for more but now have less room than this were topped up by
+/// someone else and aren't worth walking to; once there, any room will do.
+const DELIVERY_MIN_FREE: u32 = 10;
+
+/// What a creep spent its tick on; recorded in the task log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Task {
+    Idle,
+    Harvest,
+    Withdraw,
+    Transfer,
+    Upgrade,
+    Build,
+    Group,
+    Flee,
+    Defend,
+    Pickup,
+    Recycle,
+    Retreat,
+    UsePortal,
+    Repair,
+    Reserve,
+    MoveToLab,
+    Unboost,
+    ReturnHome,
+    Dismantle,
+    Renew,
+    Stage,
+}
+
+impl Task {
+    /// Every task, in code order.
+    const ALL: [Task; 21] = [
+        Task::Idle,
+        Task::Harvest,
+        Task::Withdraw,
+        Task::Transfer,
+        Task::Upgrade,
+        Task::Build,
+        Task::Group,
+        Task::Flee,
+        Task::Defend,
+        Task::Pickup,
+        Task::Recycle,
+        Task::Retreat,
+        Task::UsePortal,
+        Task::Repair,
+        Task::Reserve,
+        Task::MoveToLab,
+        Task::Unboost,
+        Task::ReturnHome,
+        Task::Dismantle,
+        Task::Renew,
+        Task::Stage,
+    ];
+
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+
+    /// The task `code` was recorded for, reading a task log back.
+    pub fn from_code(code: u8) -> Option<Task> {
+        Task::ALL.get(code as usize).copied()
+    }
+}
+
+/// Issues a creep intent on `target` through the intent tracker.
+fn issue<T: HasId>(
+    creep: &Creep,
+    api: &'static str,
+    target: &T,
+    call: impl FnOnce() -> ReturnCode,
+) -> ReturnCode {
+    intents::issue(
+        &intents::creep_actor(creep),
+        api,
+        &target.untyped_id().to_string(),
+        None,
+        call,
+    )
+}
+
+fn move_to<T: HasPosition>(creep: &Creep, target: &T) {
+    let pos = target.pos();
+    intents::issue(
+        &intents::creep_actor(creep),
+        "move_to",
+        &pos.to_string(),
+        None,
+        || route::move_to(creep, pos),
+    );
+    traffic::shove(creep, pos);
+}
+
+/// Moves into range if the intent failed with `NotInRange`, otherwise checks the return code.
+fn act<T: HasPosition>(
+    creep: &Creep,
+    api: &'static str,
+    r: ReturnCode,
+    target: &T,
+    task: Task,
+) -> Result<Task, BotError> {
+    if r == ReturnCode::NotInRange {
+        move_to(creep, target);
+        return Ok(task);
+    }
+    error::check(api, r)?;
+    Ok(task)
+}
+
+pub fn run_creep(creep: &Creep) -> Result<(), BotError> {
+    debug!("running creep {}", creep.name());
+    if creep.spawning() {
+        return Ok(());
+    }
+    traffic::record(creep);
+    avoid::track(creep);
+    // before the role runs, so a creep that moves on anyway takes its own way
+    if let Some(room) = creep.room() {
+        traffic::clear_site(creep, &room);
+    }
+    let task = if group::in_transit(&creep.name()) {
+        Ok(Task::Group)
+    } else {
+        run_role(creep)
+    };
+    tasklog::record(creep, *task.as_ref().unwrap_or(&Task::Idle));
+    match task {
+        Ok(Task::Idle) => clear_renew_spot(creep)?,
+        // anything else frees the staging tile, so the creep doesn't get shoved mid-job
+        Ok(Task::Stage) => {}
+        _ if traffic::shovable(creep) => memory::creep_memory(creep).del("stage"),
+        _ => {}
+    }
+    task.map(|_| ())
+}
+
+/// Steps an idle creep off a renewal tile it wasn't called to, so the spot stays free.
+fn clear_renew_spot(creep: &Creep) -> Result<(), BotError> {
+    let room = match creep.room() {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+    let pos = creep.pos();
+    let mem = memory::get_room_memory(room.name())?;
+    if !renewal::is_spot(&mem, pos) || renewal::assigned_spot(creep, &room)? == Some(pos) {
+        return Ok(());
+    }
+    if let Some(dir) = route::flee_step(pos, &[pos], 2).and_then(|s| coord::direction(pos, s)) {
+        intents::issue(
+            &intents::creep_actor(creep),
+            "move_direction",
+            &pos.to_string(),
+            None,
+            || creep.move_direction(dir),
+        );
+    }
+    Ok(())
+}
+
+/// Steps away from hostiles that can hurt us, if any are close.
+fn flee_hostiles(creep: &Creep) -> Option<Task> {
+    let threats: Vec<Position> = creep
+        .pos()
+        .find_in_range(find::HOSTILE_CREEPS, FLEE_RANGE)
+        .iter()
+        .filter(|h| allies::is_hostile(&h.owner_name()))
+        .filter(|h| {
+            h.get_active_bodyparts(Part::Attack) > 0
+                || h.get_active_bodyparts(Part::RangedAttack) > 0
+        })
+        .map(|h| h.pos())
+        .collect();
+    if threats.is_empty() {
+        return None;
+    }
+    let step = route::flee_step(creep.pos(), &threats, FLEE_RANGE + 2)?;
+    let dir = coord::direction(creep.pos(), step)?;
+    intents::issue(
+        &intents::creep_actor(creep),
+        "move_direction",
+        &step.to_string(),
+        None,
+        || creep.move_direction(dir),
+    );
+    Some(Task::Flee)
+}
+
+/// The room's spawn, or the spawn of the room that owns the creep if this one has none.
+fn recycle_room(creep: &Creep, room: &Room) -> Option<Room> {
+    if !room_cache::my_spawns(room).is_empty() {
+        return Some(room.clone());
+    }
+    let owner = memory::creep_memory(creep).string("owner_room").ok().flatten()?;
+    screeps::game::rooms::get(owner.parse().ok()?)
+}
+
+/// Sends a creep that lost a part its role needs to a tower to be healed, or to be recycled
+/// when nothing here can heal it; one that can't move either waits or suicides. Only looks at
+/// the body once the creep is hurt.
+fn check_body(creep: &Creep, room: &Room, role: Role) -> Result<Option<Task>, BotError> {
+    if creep.hits() >= creep.hits_max() {
+        return Ok(None);
+    }
+    let tower = room_cache::towers(room)
+        .iter()
+        .filter(|t| !room::is_inactive(room.name(), t.untyped_id()))
+        .filter(|t| t.store_of(ResourceType::Energy) >= TOWER_HEAL_ENERGY)
+        .min_by_key(|t| creep.pos().get_range_to(*t))
+        .cloned();
+    match (role::body_verdict(role, &creep.body(), tower.is_some()), tower) {
+        (BodyVerdict::Retreat, Some(tower)) => {
+            if !coord::in_range(creep.pos(), tower.pos(), RETREAT_RANGE) {
+                move_to(creep, &tower);
+            }
+            Ok(Some(Task::Retreat))
+        }
+        (BodyVerdict::Recycle, _) => match recycle_room(creep, room) {
+            Some(home) => {
+                debug!("{} lost a {:?} part for good, recycling", creep.name(), role);
+                recycle(creep, &home).map(Some)
+            }
+            None => Ok(None),
+        },
+        // towers heal anywhere in the room
+        (BodyVerdict::StayPut, _) => Ok(Some(Task::Retreat)),
+        (BodyVerdict::Suicide, _) => {
+            info!("{} can't move or be healed, suiciding", creep.name());
+            let r = intents::issue(
+                &intents::creep_actor(creep),
+                "suicide",
+                &creep.name(),
+                None,
+                || creep.suicide(),
+            );
+            error::check("suicide", r).map(|_| Some(Task::Recycle))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Takes a boosted creep near the end of its life to a lab to be unboosted, then recycles it
+/// so the body energy comes back too. Left alone while the room is under critical threat.
+fn unboost(creep: &Creep, room: &Room) -> Result<Option<Task>, BotError> {
+    let waiting = memory::creep_memory(creep).string("unboost_lab").ok().flatten().is_some();
+    if labs::boosted_parts(creep) == 0 {
+        if !waiting {
+            return Ok(None);
+        }
+        return match recycle_room(creep, room) {
+            Some(home) => recycle(creep, &home).map(Some),
+            None => Ok(None),
+        };
+    }
+    if creep.ticks_to_live() >= labs::UNBOOST_TTL || threat::critical(room.name()) {
+        return Ok(None);
+    }
+    let lab = match labs::unboost_lab(room, creep)? {
+        Some(l) => l,
+        None => return Ok(None),
+    };
+    memory::creep_memory(creep).set("unboost_lab", lab.id().to_string());
+    if creep.pos().is_near_to(&lab) {
+        return Ok(Some(Task::Unboost));
+    }
+    move_to(creep, &lab);
+    Ok(Some(Task::MoveToLab))
+}
+
+/// Walks onto the portal under the flag `portal_<creep name>`. Everything else paths around
+/// portals, so this is the only way a creep takes one. Memory cleanup holds the memory of a
+/// creep that left this way for a while, since it may only be on another shard.
+fn use_portal(creep: &Creep) -> Option<Task> {
+    let flag = screeps::game::flags::get(&format!("portal_{}", creep.name()))?;
+    memory::creep_memory(creep).set("portal_at", screeps::game::time());
+    move_to(creep, &flag);
+    Some(Task::UsePortal)
+}
+
+fn run_role(creep: &Creep) -> Result<Task, BotError> {
+    if let Some(task) = use_portal(creep) {
+        return Ok(task);
+    }
+    let role = population::role_of(creep);
+    // scouts do their own fleeing
+    if role != Role::Defender && role != Role::Scout {
+        if let Some(task) = flee_hostiles(creep) {
+            return Ok(task);
+        }
+    }
+
+    if memory::creep_memory(creep).bool("harvesting") {
+        if creep.store_free_capacity(Some(ResourceType::Energy)) == 0 {
+            memory::creep_memory(creep).set("harvesting", false);
+            memory::creep_memory(creep).del("pickup");
+        }
+    } else {
+        if creep.store_used_capacity(None) == 0 {
+            memory::creep_memory(creep).set("harvesting", true);
+            memory::creep_memory(creep).del("deliveries");
+            memory::creep_memory(creep).del("build_site");
+            memory::creep_memory(creep).del("tower_refill");
+        }
+    }
+
+    let room = creep.room().ok_or(BotError::MissingRoomObject {
+        what: "creep room",
+    })?;
+    if let Some(task) = check_body(creep, &room, role)? {
+        return Ok(task);
+    }
+    if let Some(task) = unboost(creep, &room)? {
+        return Ok(task);
+    }
+    if let Some(task) = return_home(creep, &room)? {
+        return Ok(task);
+    }
+    if let Some(spot) = renewal::assigned_spot(creep, &room)? {
+        if creep.pos() != spot {
+            move_to(creep, &spot);
+        }
+        return Ok(Task::Renew);
+    }
+
+    // a room that lost its spawns rebuilds one before doing anything else, funded by storage
+    let spawnless = room_cache::my_spawns(&room).is_empty();
+    let collecting = memory::creep_memory(creep).bool("harvesting");
+
+    match role {
+        Role::Harvester => return run_harvester(creep, &room),
+        Role::Defender => return run_defender(creep, &room),
+        Role::DepositHarvester => return run_deposit_harvester(creep, &room),
+        Role::DepositHauler => return run_deposit_hauler(creep, &room),
+        Role::Reserver => return run_reserver(creep, &room),
+        Role::Scout => return scout::run_scout(creep, &room),
+        Role::RemoteMiner => return run_remote_miner(creep, &room),
+        Role::RemoteHauler => return run_remote_hauler(creep, &room, collecting),
+        Role::Pioneer => {
+            if let Some(task) = run_pioneer(creep, &room)? {
+                return Ok(task);
+            }
+        }
+        Role::Upgrader if !spawnless => {
+            if let Some(buffer) = logistics::upgrade_buffer(&room) {
+                return run_upgrader(creep, &room, &buffer, collecting);
+            }
+        }
+        _ => {}
+    }
+
+    // without a buffer to draw from, upgraders fall back to working like anyone else
+    if collecting {
+        collect_energy(creep, &room, spawnless)
+    } else {
+        deliver_energy(creep, &room, spawnless)
+    }
+}
+
+fn upgrade(creep: &Creep, room: &Room) -> Result<Task, BotError> {
+    let c = room.controller().ok_or(BotError::MissingRoomObject {
+        what: "controller",
+    })?;
+    act(
+        creep,
+        "upgrade_controller",
+        issue(creep, "upgrade_controller", &c, || creep.upgrade_controller(&c)),
+        &c,
+        Task::Upgrade,
+    )
+}
+
+fn run_upgrader(
+    creep: &Creep,
+    room: &Room,
+    buffer: &Structure,
+    collecting: bool,
+) -> Result<Task, BotError> {
+    if collecting {
+        return match intents::withdraw(creep, buffer, ResourceType::Energy, None)? {
+            // wait by the buffer for the next refill
+            ReturnCode::NotEnough => Ok(Task::Idle),
+            r => act(creep, "withdraw", r, buffer, Task::Withdraw),
+        };
+    }
+    upgrade(creep, room)
+}
+
+/// Walks to the assigned standing tile (range 0) and harvests from there for the rest of its
+/// life. Once in place it's marked `anchored` so it isn't shoved off the tile.
+///
+/// If the container has decayed it asks for a new one on the same tile and drop-mines
+/// meanwhile; haulers get a pickup request for the pile. A harvester with Carry parts builds
+/// the site itself whenever its store is full. Without Carry everything it mines drops.
+fn run_harvester(creep: &Creep, room: &Room) -> Result<Task, BotError> {
+    let assigned = match mining::assignment(creep)? {
+        Some(a) => a,
+        None => match mining::assign(creep, room)? {
+            Some(a) => a,
+            None => return Ok(Task::Idle),
+        },
+    };
+    let stand = Position::from_packed(assigned.stand);
+    if creep.pos() != stand {
+        memory::creep_memory(creep).del("anchored");
+        move_to(creep, &stand);
+        return Ok(Task::Harvest);
+    }
+    memory::creep_memory(creep).set("anchored", true);
+    let source = objects::get_cached(assigned.source).ok_or_else(|| BotError::StaleId {
+        id: assigned.source.to_string(),
+        kind: "harvest source",
+    })?;
+    if mining::source_container(&source).is_none() {
+        if let Some(task) = rebuild_container(creep, room, stand)? {
+            return Ok(task);
+        }
+    }
+    hand_off(creep, room)?;
+    match issue(creep, "harvest", &source, || creep.harvest(&source)) {
+        // the source regenerates soon enough; stay put
+        ReturnCode::NotEnough => Ok(Task::Idle),
+        r => {
+            error::check("harvest", r)?;
+            invaders::record_harvest(creep);
+            accounts::harvested(creep, room);
+            Ok(Task::Harvest)
+        }
+    }
+}
+
+/// Which of the collecting workers next to a harvester holding `held` energy takes it, given
+/// each one's `free` room, and how much: the one with the most room, ahead of the load
+/// spilling into the container.
+fn handoff_taker(held: u32, free: &[u32]) -> Option<(usize, u32)> {
+    if held == 0 {
+        return None;
+    }
+    free.iter()
+        .enumerate()
+        .filter(|&(_, f)| *f > 0)
+        .max_by_key(|&(_, f)| *f)
+        .map(|(i, f)| (i, held.min(*f)))
+}
+
+/// Passes a carrying harvester's energy straight to a worker collecting next to it, ahead of
+/// it spilling into the container, so the worker doesn't wait on a withdraw and nothing sits
+/// decaying. The load is credited to the worker's collection this tick.
+fn hand_off(creep: &Creep, room: &Room) -> Result<(), BotError> {
+    let held = creep.store_of(ResourceType::Energy);
+    if held == 0 {
+        return Ok(());
+    }
+    let takers: Vec<Creep> = room_cache::my_creeps(room)
+        .iter()
+        .filter(|c| !c.spawning() && population::role_of(c) == Role::Worker)
+        .filter(|c| memory::creep_memory(c).bool("harvesting") && creep.pos().is_near_to(*c))
+        .cloned()
+        .collect();
+    let free: Vec<u32> = takers
+        .iter()
+        .map(|c| {
+            let free = c.store_free_capacity(Some(ResourceType::Energy));
+            free.saturating_sub(logistics::handed_to(c))
+        })
+        .collect();
+    let (taker, amount) = match handoff_taker(held, &free) {
+        Some((i, amount)) => (&takers[i], amount),
+        None => return Ok(()),
+    };
+    let r = issue(creep, "transfer", taker, || {
+        creep.transfer_amount(taker, ResourceType::Energy, amount)
+    });
+    error::check("transfer", r)?;
+    logistics::hand_off(taker, amount);
+    stats::increment("logistics", "handoff_energy", amount as i32);
+    Ok(())
+}
+
+/// Requests the container site and, with a full store, builds it. `None` means keep harvesting.
+fn rebuild_container(
+    creep: &Creep,
+    room: &Room,
+    stand: Position,
+) -> Result<Option<Task>, BotError> {
+    if screeps::game::time() % SITE_RETRY_TICKS == 0 {
+        planner::ensure_source_container(room, stand)?;
+    }
+    if creep.get_active_bodyparts(Part::Carry) == 0
+        || creep.store_free_capacity(Some(ResourceType::Energy)) > 0
+    {
+        return Ok(None);
+    }
+    let site = room
+        .look_for_at(look::CONSTRUCTION_SITES, &stand)
+        .into_iter()
+        .find(|s| s.structure_type() == StructureType::Container);
+    match site {
+        Some(site) => {
+            let r = issue(creep, "build", &site, || creep.build(&site));
+            if r == ReturnCode::Ok {
+                accounts::built(creep);
+            }
+            error::check("build", r).map(|_| Some(Task::Build))
+        }
+        // placed this tick, shows up on the next one
+        None => Ok(None),
+    }
+}
+
+/// Sends a creep whose operation went away back to its home room. Its `target_room` is
+/// cleared so the operation stops counting it.
+pub fn send_home(creep: &Creep, why: &str) {
+    let mem = memory::creep_memory(creep);
+    if mem.bool("returning") {
+        return;
+    }
+    info!("{} returning home: {}", creep.name(), why);
+    mem.set("returning", true);
+    mem.del("target_room");
+}
+
+/// Walks a creep sent home back to its room. There it joins the workers if it has the parts
+/// for it and time left, and is recycled otherwise.
+fn return_home(creep: &Creep, room: &Room) -> Result<Option<Task>, BotError> {
+    if !memory::creep_memory(creep).bool("returning") {
+        return Ok(None);
+    }
+    let home = match memory::creep_home(creep) {
+        Some(h) => h,
+        None => {
+            memory::creep_memory(creep).del("returning");
+            return Ok(None);
+        }
+    };
+    if room.name() != home {
+        move_to(creep, &Position::new(25, 25, home));
+        return Ok(Some(Task::ReturnHome));
+    }
+    let useful = [Part::Work, Part::Carry, Part::Move]
+        .iter()
+        .all(|p| creep.get_active_bodyparts(*p) > 0);
+    if !useful || creep.ticks_to_live() < RETIRE_TTL {
+        return recycle(creep, room).map(Some);
+    }
+    let mem = memory::creep_memory(creep);
+    mem.del("returning");
+    mem.del("owner_room");
+    if population::role_of(creep) != Role::Worker {
+        info!("{} is home, working as a worker", creep.name());
+        memory::set_creep_role(&creep.name(), Role::Worker)?;
+    }
+    Ok(None)
+}
+
+/// Whether the operation the creep was sent to is gone: its remote or deposit is no longer in
+/// Memory, or the room it was pioneering is no longer ours.
+fn orphaned(creep: &Creep, remotes: &[String], deposits: &[String]) -> bool {
+    let target = match memory::creep_memory(creep).string("target_room").ok().flatten() {
+        Some(t) => t,
+        None => return false,
+    };
+    match population::role_of(creep) {
+        Role::DepositHarvester | Role::DepositHauler => !deposits.contains(&target),
+        // a pioneer building a remote's roads works a room that isn't ours
+        Role::Pioneer if remotes.contains(&target) => false,
+        Role::Pioneer => target
+            .parse()
+            .ok()
+            .and_then(screeps::game::rooms::get)
+            .and_then(|r| r.controller())
+            .map_or(false, |c| !c.my()),
+        _ => !remotes.contains(&target),
+    }
+}
+
+/// Sends home every creep whose operation no longer exists.
+pub fn send_home_orphans() -> Result<(), BotError> {
+    let remotes = memory::remotes()?.keys();
+    let deposits = memory::deposits()?.keys();
+    for creep in screeps::game::creeps::values() {
+        if orphaned(&creep, &remotes, &deposits) {
+            send_home(&creep, "its operation is gone");
+        }
+    }
+    Ok(())
+}
+
+/// The room under `target_room` in the creep's memory, else `fallback`.
+fn target_room(creep: &Creep, fallback: RoomName) -> RoomName {
+    memory::creep_memory(creep)
+        .string("target_room")
+        .ok()
+        .flatten()
+        .and_then(|r| r.parse::<RoomName>().ok())
+        .unwrap_or(fallback)
+}
+
+fn recycle(creep: &Creep, home: &Room) -> Result<Task, BotError> {
+    let spawn = room_cache::my_spawns(home)
+        .first()
+        .cloned()
+        .ok_or(BotError::MissingRoomObject {
+            what: "spawn to recycle at",
+        })?;
+    let r = issue(creep, "recycle_creep", &spawn, || spawn.recycle_creep(creep));
+    act(creep, "recycle_creep", r, &spawn, Task::Recycle)
+}
+
+/// Takes deposit output to the home storage, then recycles once the operation is over.
+fn return_deposit_load(creep: &Creep, op: &DepositOperation) -> Result<Task, BotError> {
+    let home = screeps::game::rooms::get(op.home).ok_or(BotError::MissingRoomObject {
+        what: "deposit home room",
+    })?;
+    if creep.store_of(op.kind) > 0 {
+        let storage = home.storage().ok_or(BotError::MissingRoomObject {
+            what: "deposit home storage",
+        })?;
+        let r = issue(creep, "transfer", &storage, || creep.transfer_all(&storage, op.kind));
+        return act(creep, "transfer", r, &storage, Task::Transfer);
+    }
+    if op.stopped {
+        return recycle(creep, &home);
+    }
+    let pos = Position::from_packed(op.pos);
+    move_to(creep, &pos);
+    Ok(Task::Idle)
+}
+
+/// Harvests the deposit whenever it's off cooldown and hands full loads to an adjacent hauler.
+fn run_deposit_harvester(creep: &Creep, room: &Room) -> Result<Task, BotError> {
+    let op = match deposits::operation(target_room(creep, room.name()))? {
+        Some(op) => op,
+        None => return Ok(Task::Idle),
+    };
+    if op.stopped {
+        return return_deposit_load(creep, &op);
+    }
+    if creep.store_free_capacity(None) == 0 {
+        let hauler = creep
+            .pos()
+            .find_in_range(find::MY_CREEPS, 1)
+            .into_iter()
+            .find(|c| {
+                population::role_of(c) == Role::DepositHauler && c.store_free_capacity(None) > 0
+            });
+        return match hauler {
+            Some(h) => {
+                let r = issue(creep, "transfer", &h, || creep.transfer_all(&h, op.kind));
+                error::check("transfer", r).map(|_| Task::Transfer)
+            }
+            None => Ok(Task::Idle),
+        };
+    }
+    match objects::get_cached(op.deposit) {
+        Some(deposit) if coord::in_range(creep.pos(), deposit.pos(), 1) => {
+            if deposits::check_cooldown(target_room(creep, room.name()), &deposit)? {
+                return return_deposit_load(creep, &op);
+            }
+            if deposit.cooldown() > 0 {
+                return Ok(Task::Idle);
+            }
+            let r = issue(creep, "harvest", &deposit, || creep.harvest(&deposit));
+            error::check("harvest", r).map(|_| Task::Harvest)
+        }
+        _ => {
+            move_to(creep, &Position::from_packed(op.pos));
+            Ok(Task::Harvest)
+        }
+    }
+}
+
+/// Waits next to the deposit harvester and goes home when full, when it has to leave before
+/// dying on the way, or when the operation stops.
+fn run_deposit_hauler(creep: &Creep, room: &Room) -> Result<Task, BotError> {
+    let op = match deposits::operation(target_room(creep, room.name()))? {
+        Some(op) => op,
+        None => return Ok(Task::Idle),
+    };
+    let carrying = creep.store_of(op.kind) > 0;
+    if op.stopped
+        || creep.store_free_capacity(None) == 0
+        || (carrying && creep.ticks_to_live() <= op.round_trip)
+    {
+        return return_deposit_load(creep, &op);
+    }
+    let pos = Position::from_packed(op.pos);
+    let harvester = pos
+        .find_in_range(find::MY_CREEPS, 1)
+        .into_iter()
+        .find(|c| population::role_of(c) == Role::DepositHarvester);
+    match harvester {
+        Some(h) if coord::in_range(creep.pos(), h.pos(), 1) => Ok(Task::Idle),
+        Some(h) => {
+            move_to(creep, &h);
+            Ok(Task::Withdraw)
+        }
+        None => {
+            if !coord::in_range(creep.pos(), pos, 2) {
+                move_to(creep, &pos);
+            }
+            Ok(Task::Withdraw)
+        }
+    }
+}
+
+/// Travels to the claimed room and, once its spawn stands, becomes a worker there. In between
+/// it falls through to the spawnless worker logic: harvest locally and build the spawn site
+/// the room planner places. Sent to a remote instead, it builds the remote's roads the same
+/// way and goes home once they're done.
+fn run_pioneer(creep: &Creep, room: &Room) -> Result<Option<Task>, BotError> {
+    let target = target_room(creep, room.name());
+    if room.name() != target {
+        move_to(creep, &Position::new(25, 25, target));
+        return Ok(Some(Task::Idle));
+    }
+    if room.controller().map_or(true, |c| !c.my()) {
+        if room_cache::construction_sites(room).is_empty() {
+            send_home(creep, "its remote's roads are built");
+        }
+        return Ok(None);
+    }
+    if !room_cache::my_spawns(room).is_empty() {
+        info!("{} finished the spawn in {}, staying on as a worker", creep.name(), target);
+        memory::set_creep_role(&creep.name(), Role::Worker)?;
+        memory::creep_memory(creep).del("target_room");
+        memory::creep_memory(creep).del("owner_room");
+    }
+    Ok(None)
+}
+
+/// Goes to its target room and keeps the controller reserved.
+fn run_reserver(creep: &Creep, room: &Room) -> Result<Task, BotError> {
+    let target = target_room(creep, room.name());
+    if room.name() != target {
+        move_to(creep, &Position::new(25, 25, target));
+        return Ok(Task::Idle);
+    }
+    let controller = room.controller().ok_or(BotError::MissingRoomObject {
+        what: "controller to reserve",
+    })?;
+    let r = issue(creep, "reserve_controller", &controller, || {
+        creep.reserve_controller(&controller)
+    });
+    act(creep, "reserve_controller", r, &controller, Task::Reserve)
+}
+
+/// Walks to its remote source's post and drop-mines there for the rest of its life: into the
+/// container standing on the post, or onto the ground next to the source until one is built.
+fn run_remote_miner(creep: &Creep, room: &Room) -> Result<Task, BotError> {
+    let target = target_room(creep, room.name());
+    let op = match remote::operation(target)? {
+        Some(op) => op,
+        None => return Ok(Task::Idle),
+    };
+    let post = match remote::post_of(creep, target, &op) {
+        Some(s) => Position::from_packed(s.pos),
+        None => return Ok(Task::Idle),
+    };
+    if room.name() != target {
+        move_to(creep, &post);
+        return Ok(Task::Harvest);
+    }
+    let source = match post.find_in_range(find::SOURCES, 1).into_iter().next() {
+        Some(s) => s,
+        None => return Ok(Task::Idle),
+    };
+    // the post is the source itself until its container is found
+    let parked = if source.pos() == post {
+        creep.pos().is_near_to(&source)
+    } else {
+        creep.pos() == post
+    };
+    if !parked {
+        memory::creep_memory(creep).del("anchored");
+        move_to(creep, &post);
+        return Ok(Task::Harvest);
+    }
+    memory::creep_memory(creep).set("anchored", true);
+    match issue(creep, "harvest", &source, || creep.harvest(&source)) {
+        ReturnCode::NotEnough => Ok(Task::Idle),
+        r => {
+            error::check("harvest", r)?;
+            invaders::record_harvest(creep);
+            Ok(Task::Harvest)
+        }
+    }
+}
+
+/// Fills up at its remote source's post, from the container or what the miner dropped, and
+/// carries the load home: to whatever the home's logistics requests want, then to storage.
+/// A load is taken home early when waiting for the rest would outlive the trip back, and a
+/// hauler too old for another round trip goes home for good.
+fn run_remote_hauler(creep: &Creep, room: &Room, collecting: bool) -> Result<Task, BotError> {
+    let target = target_room(creep, room.name());
+    let op = match remote::operation(target)? {
+        Some(op) => op,
+        None => return Ok(Task::Idle),
+    };
+    let source = match remote::post_of(creep, target, &op) {
+        Some(s) => s,
+        None => return Ok(Task::Idle),
+    };
+    let round_trip = source.round_trip.unwrap_or(0);
+    let carrying = creep.store_of(ResourceType::Energy) > 0;
+    if collecting && creep.ticks_to_live() <= round_trip {
+        if !carrying {
+            send_home(creep, "too old for another round trip");
+            return Ok(Task::ReturnHome);
+        }
+        memory::creep_memory(creep).set("harvesting", false);
+        return Ok(Task::ReturnHome);
+    }
+    if collecting {
+        return collect_remote(creep, room, Position::from_packed(source.pos));
+    }
+    if room.name() != op.home {
+        move_to(creep, &Position::new(25, 25, op.home));
+        return Ok(Task::ReturnHome);
+    }
+    if let Some(task) = run_deliveries(creep, room)? {
+        return Ok(task);
+    }
+    let storage = match room.storage() {
+        Some(s) => s,
+        None => return Ok(Task::Idle),
+    };
+    let amount = creep
+        .store_of(ResourceType::Energy)
+        .min(storage.store_free_capacity(Some(ResourceType::Energy)));
+    let r = issue(creep, "transfer", &storage, || {
+        creep.transfer_amount(&storage, ResourceType::Energy, amount)
+    });
+    if r == ReturnCode::Ok {
+        accounts::delivered(creep, room.name(), amount);
+    }
+    act(creep, "transfer", r, &storage, Task::Transfer)
+}
+
+/// Takes energy at a remote post: the biggest pile within a tile of it first, since dropped
+/// energy decays, then the container on it. Waits a couple of tiles off when there's neither,
+/// clear of the miner.
+fn collect_remote(creep: &Creep, room: &Room, post: Position) -> Result<Task, BotError> {
+    if room.name() != post.room_name() {
+        move_to(creep, &post);
+        return Ok(Task::Withdraw);
+    }
+    let pile = post
+        .find_in_range(find::DROPPED_RESOURCES, 1)
+        .into_iter()
+        .filter(|r| r.resource_type() == ResourceType::Energy)
+        .max_by_key(|r| r.amount());
+    if let Some(pile) = pile {
+        let r = issue(creep, "pickup", &pile, || creep.pickup(&pile));
+        return act(creep, "pickup", r, &pile, Task::Pickup);
+    }
+    let container = room
+        .look_for_at(look::STRUCTURES, &post)
+        .into_iter()
+        .find(|s| s.structure_type() == StructureType::Container);
+    if let Some(container) = container {
+        if container.as_has_store().map_or(0, |s| s.store_of(ResourceType::Energy)) > 0 {
+            let r = intents::withdraw(creep, &container, ResourceType::Energy, None)?;
+            return act(creep, "withdraw", r, &container, Task::Withdraw);
+        }
+    }
+    if !coord::in_range(creep.pos(), post, 2) {
+        move_to(creep, &post);
+    }
+    Ok(Task::Idle)
+}
+
+/// Choke tiles on the side the room's last attack came in by that no other creep of ours is
+/// standing on and that aren't renewal spots, and the core they cover.
+fn free_chokes(
+    creep: &Creep,
+    room: &Room,
+    mem: &room::RoomMemory,
+) -> (Vec<Position>, Option<Position>) {
+    let set = mem
+        .hostile_entry
+        .and_then(|side| mem.chokes.iter().find(|c| c.side == side));
+    let set = match set {
+        Some(s) => s,
+        None => return (Vec::new(), None),
+    };
+    let creeps = room_cache::my_creeps(room);
+    let taken = |pos: &Position| {
+        creeps
+            .iter()
+            .any(|c| c.name() != creep.name() && c.pos() == *pos)
+    };
+    let tiles = set
+        .tiles
+        .iter()
+        .map(|i| perimeter::tile_position(room.name(), *i))
+        .filter(|p| !taken(p) && !renewal::is_spot(mem, *p))
+        .collect();
+    (tiles, set.core.map(|i| perimeter::tile_position(room.name(), i)))
+}
+
+/// What the defender fighting `hostile` knows: both bodies, the hostiles' recent trail and
+/// our free ramparts.
+fn combat_snapshot(
+    creep: &Creep,
+    room: &Room,
+    hostile: &Creep,
+) -> Result<CombatSnapshot, BotError> {
+    let tile = |pos: Position| (pos.x(), pos.y());
+    let mem = memory::get_room_memory(room.name())?;
+    let (chokes, core) = free_chokes(creep, room, &mem);
+    let trail = mem
+        .hostile_trail
+        .iter()
+        .map(|p| tile(Position::from_packed(*p)))
+        .collect();
+    let occupied: Vec<Position> = room_cache::my_creeps(room)
+        .iter()
+        .filter(|c| c.name() != creep.name())
+        .map(|c| c.pos())
+        .collect();
+    let ramparts = room_cache::structures(room)
+        .iter()
+        .filter_map(|s| match s {
+            Structure::Rampart(r) if r.my() && !occupied.contains(&r.pos()) => Some(tile(r.pos())),
+            _ => None,
+        })
+        .collect();
+    Ok(CombatSnapshot {
+        me: tile(creep.pos()),
+        melee: creep.get_active_bodyparts(Part::Attack) > 0,
+        ranged: creep.get_active_bodyparts(Part::RangedAttack) > 0,
+        hostile: tile(hostile.pos()),
+        hostile_melee: hostile.get_active_bodyparts(Part::Attack) > 0,
+        hostile_ranged: hostile.get_active_bodyparts(Part::RangedAttack) > 0,
+        trail,
+        ramparts,
+        chokes: chokes.into_iter().map(tile).collect(),
+        core: core.map(tile),
+    })
+}
+
+/// Attacks `hostile` with whichever parts reach it from where the defender stands.
+fn strike(creep: &Creep, hostile: &Creep) -> Result<(), BotError> {
+    let range = coord::range(creep.pos(), hostile.pos());
+    if range <= 1 && creep.get_active_bodyparts(Part::Attack) > 0 {
+        let r = issue(creep, "attack", hostile, || creep.attack(hostile));
+        error::check("attack", r)?;
+    }
+    if range <= 3 && creep.get_active_bodyparts(Part::RangedAttack) > 0 {
+        let r = issue(creep, "ranged_attack", hostile, || creep.ranged_attack(hostile));
+        error::check("ranged_attack", r)?;
+    }
+    Ok(())
+}
+
+/// Goes to its target room and fights the closest hostile there, waiting near the middle of
+/// the room when there's nothing to fight. Where it stands comes from `combat::decide`.
+fn run_defender(creep: &Creep, room: &Room) -> Result<Task, BotError> {
+    let target_room = target_room(creep, room.name());
+    let post = Position::new(25, 25, target_room);
+    if room.name() != target_room {
+        move_to(creep, &post);
+        return Ok(Task::Defend);
+    }
+    let hostile = allies::hostile_creeps(room)
+        .into_iter()
+        .min_by_key(|h| creep.pos().get_range_to(h));
+    let hostile = match hostile {
+        Some(h) => h,
+        None => {
+            // wait where the last attack came in, if we know the way in
+            let mem = memory::get_room_memory(room.name())?;
+            let (chokes, _) = free_chokes(creep, room, &mem);
+            match chokes.iter().min_by_key(|c| creep.pos().get_range_to(*c)) {
+                Some(choke) if creep.pos() != *choke => move_to(creep, choke),
+                Some(_) => {}
+                None if !coord::in_range(creep.pos(), post, DEFENDER_POST_RANGE) => {
+                    move_to(creep, &post)
+                }
+                None => {}
+            }
+            return Ok(Task::Idle);
+        }
+    };
+    match combat::decide(&combat_snapshot(creep, room, &hostile)?) {
+        Maneuver::Engage => {
+            // head it off where it's going rather than chase where it was
+            if !coord::in_range(creep.pos(), hostile.pos(), 1) {
+                move_to(creep, &threat::predicted_pos(&hostile));
+            }
+        }
+        Maneuver::Hold((x, y)) => {
+            let tile = Position::new(x, y, room.name());
+            if creep.pos() != tile {
+                move_to(creep, &tile);
+            }
+        }
+        Maneuver::Kite => {
+            let step = route::flee_step(creep.pos(), &[hostile.pos()], combat::KITE_RANGE);
+            let dir = step.and_then(|s| coord::direction(creep.pos(), s).map(|d| (s, d)));
+            if let Some((step, dir)) = dir {
+                intents::issue(
+                    &intents::creep_actor(creep),
+                    "move_direction",
+                    &step.to_string(),
+                    None,
+                    || creep.move_direction(dir),
+                );
+            }
+        }
+    }
+    strike(creep, &hostile)?;
+    Ok(Task::Defend)
+}
+
+/// Takes siege energy from the terminal as a tower-refill assignment: the energy is for the
+/// towers, so only workers that aren't building take one, and only for what the towers are
+/// short beyond what creeps already assigned carry. The assignment holds until the load is
+/// gone, and its deliveries go to towers alone.
+fn refill_towers(creep: &Creep, room: &Room, wanted: u32) -> Result<Option<Task>, BotError> {
+    let terminal = match logistics::siege_terminal(room) {
+        Some(t) => t,
+        None => return Ok(None),
+    };
+    let mem = memory::creep_memory(creep);
+    if population::role_of(creep) != Role::Worker || mem.bool("building") {
+        return Ok(None);
+    }
+    let short: u32 = logistics::requests(room.name())
+        .iter()
+        .filter(|r| r.kind == RequestKind::FillTower)
+        .map(|r| r.amount)
+        .sum();
+    let assigned: u32 = room_cache::my_creeps(room)
+        .iter()
+        .filter(|c| c.name() != creep.name() && memory::creep_memory(c).bool("tower_refill"))
+        .map(|c| c.store_of(ResourceType::Energy))
+        .sum();
+    let amount = short.saturating_sub(assigned).min(wanted);
+    if amount == 0 {
+        return Ok(None);
+    }
+    mem.set("tower_refill", true);
+    let terminal = Structure::Terminal(terminal);
+    let r = intents::withdraw(creep, &terminal, ResourceType::Energy, Some(amount))?;
+    act(creep, "withdraw", r, &terminal, Task::Withdraw).map(Some)
+}
+
+/// Where a builder in a spawnless room takes energy for the spawn site.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RebuildSource {
+    Storage,
+    /// The container at this index.
+    Container(usize),
+}
+
+/// Storage while it has any energy, else the fullest container holding a load of `wanted`;
+/// `None` leaves the builder to harvest.
+fn rebuild_energy(stored: u32, containers: &[u32], wanted: u32) -> Option<RebuildSource> {
+    if stored > 0 {
+        return Some(RebuildSource::Storage);
+    }
+    containers
+        .iter()
+        .enumerate()
+        .filter(|&(_, held)| *held >= wanted)
+        .max_by_key(|&(_, held)| *held)
+        .map(|(i, _)| RebuildSource::Container(i))
+}
+
+fn collect_energy(creep: &Creep, room: &Room, spawnless: bool) -> Result<Task, BotError> {
+    // a harvester handed over a full load; it lands next tick
+    let wanted = creep
+        .store_free_capacity(Some(ResourceType::Energy))
+        .saturating_sub(logistics::handed_to(creep));
+    if wanted == 0 {
+        return Ok(Task::Withdraw);
+    }
+
+    if spawnless {
+        let stored = room.storage().map_or(0, |s| s.store_of(ResourceType::Energy));
+        let containers = room_cache::containers(room);
+        let held: Vec<u32> = containers.iter().map(|c| c.store_of(ResourceType::Energy)).collect();
+        let from = match rebuild_energy(stored, &held, wanted) {
+            Some(RebuildSource::Storage) => room.storage().map(Structure::Storage),
+            Some(RebuildSource::Container(i)) => Some(Structure::Container(containers[i].clone())),
+            None => None,
+        };
+        if let Some(from) = from {
+            let r = intents::withdraw(creep, &from, ResourceType::Energy, None)?;
+            return act(creep, "withdraw", r, &from, Task::Withdraw);
+        }
+    }
+
+    if let Some(task) = refill_towers(creep, room, wanted)? {
+        return Ok(task);
+    }
+
+    // builders leave the spawn's energy alone until it's mostly full, and harvest instead;
+    // while the first tower is rushed the logistics layer feeds them at the site
+    let fill = settings::u32_or("builder_spawn_fill", BUILDER_SPAWN_FILL);
+    let spawn_first =
+        memory::creep_memory(creep).bool("building") && !logistics::spawn_energy_at(room, fill);
+
+    // drop-mined energy decays, so it goes first when it's worth the trip
+    let pile = if spawn_first {
+        None
+    } else {
+        logistics::assigned_pickup(creep, room.name())
+    };
+    if let Some(pile) = pile {
+        let r = issue(creep, "pickup", &pile, || creep.pickup(&pile));
+        return act(creep, "pickup", r, &pile, Task::Pickup);
+    }
+
+    // structures in the plan's way pay for their own removal
+    if creep.get_active_bodyparts(Part::Work) > 0 {
+        if let Some(target) = reconcile::dismantle_target(room, creep.pos()) {
+            let r = issue(creep, "dismantle", &target, || creep.dismantle(&target));
+            return act(creep, "dismantle", r, &target, Task::Dismantle);
+        }
+    }
+
+    if !spawn_first {
+        if let Some(task) = take_withdrawal(creep, room)? {
+            return Ok(task);
+        }
+    }
+
+    // harvesters fill source containers; take a full load from one rather than mining
+    let container = mining::mined_sources(room)
+        .iter()
+        .filter_map(mining::source_container)
+        .filter(|c| !spawn_first || !logistics::feeds_spawn(room, c))
+        .find(|c| c.store_of(ResourceType::Energy) >= wanted);
+    if let Some(container) = container {
+        let container = Structure::Container(container);
+        let r = intents::withdraw(creep, &container, ResourceType::Energy, None)?;
+        return act(creep, "withdraw", r, &container, Task::Withdraw);
+    }
+
+    if let Some(task) = stage(creep, room)? {
+        return Ok(task);
+    }
+
+    let source = harvest_source(creep, room).ok_or(BotError::MissingRoomObject { what: "source" })?;
+    let r = issue(creep, "harvest", &source, || creep.harvest(&source));
+    if r == ReturnCode::Ok {
+        invaders::record_harvest(creep);
+        accounts::harvested(creep, room);
+    }
+    act(creep, "harvest", r, &source, Task::Harvest)
+}
+
+/// Takes an empty worker to the rebalancer's withdrawal closest to it, when no other empty
+/// collecting worker is nearer to it. The load then goes out as a normal delivery.
+fn take_withdrawal(creep: &Creep, room: &Room) -> Result<Option<Task>, BotError> {
+    if population::role_of(creep) != Role::Worker || creep.store_used_capacity(None) > 0 {
+        return Ok(None);
+    }
+    let pos = creep.pos();
+    let withdrawal = match bands::withdrawals(room.name())
+        .into_iter()
+        .min_by_key(|w| pos.get_range_to(&w.pos))
+    {
+        Some(w) => w,
+        None => return Ok(None),
+    };
+    let range = pos.get_range_to(&withdrawal.pos);
+    let nearer = room_cache::my_creeps(room).iter().any(|c| {
+        c.name() != creep.name()
+            && population::role_of(c) == Role::Worker
+            && memory::creep_memory(c).bool("harvesting")
+            && c.store_used_capacity(None) == 0
+            && c.pos().get_range_to(&withdrawal.pos) < range
+    });
+    let from = match objects::get_cached(withdrawal.from) {
+        Some(f) if !nearer => f,
+        _ => return Ok(None),
+    };
+    let r = match intents::withdraw(creep, &from, withdrawal.resource, Some(withdrawal.amount)) {
+        Ok(r) => r,
+        // `withdraw` has logged the refusal; the worker collects somewhere else this tick
+        Err(BotError::RejectedIntent { .. }) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if r == ReturnCode::Ok {
+        memory::creep_memory(creep).set("harvesting", false);
+    }
+    act(creep, "withdraw", r, &from, Task::Withdraw).map(Some)
+}
+
+/// Sends a worker with nothing to collect to a staging tile no other worker holds, once
+/// harvesters work every source and one more creep there would only get in their way. It
+/// leaves as soon as anything turns up to pick up or withdraw.
+fn stage(creep: &Creep, room: &Room) -> Result<Option<Task>, BotError> {
+    if population::role_of(creep) != Role::Worker
+        || mining::mined_sources(room).len() < room_cache::sources(room).len()
+    {
+        return Ok(None);
+    }
+    let staging = memory::get_room_memory(room.name())?.staging;
+    let tile = |c: &Creep| memory::creep_memory(c).f64("stage").ok().flatten().map(|p| p as u32);
+    let claimed: Vec<u32> = room_cache::my_creeps(room)
+        .iter()
+        .filter(|c| c.name() != creep.name())
+        .filter_map(tile)
+        .collect();
+    let sites: Vec<u32> = construction::blocking_sites(room)
+        .iter()
+        .map(|p| p.packed_repr())
+        .collect();
+    let free = |p: &u32| staging.contains(p) && !claimed.contains(p) && !sites.contains(p);
+    let pos = creep.pos();
+    let spot = match tile(creep).filter(free).or_else(|| {
+        staging
+            .iter()
+            .cloned()
+            .filter(free)
+            .min_by_key(|p| pos.get_range_to(&Position::from_packed(*p)))
+    }) {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+    memory::creep_memory(creep).set("stage", spot);
+    let at = Position::from_packed(spot);
+    if pos != at {
+        move_to(creep, &at);
+    }
+    Ok(Some(Task::Stage))
+}
+
+/// The closest source that has energy, or will have by the time the creep walks there; else
+/// the one that regenerates first. Chosen again every tick, so a source that runs dry on the
+/// way sends the creep to another.
+fn harvest_source(creep: &Creep, room: &Room) -> Option<Source> {
+    let mut sources: Vec<(u32, Source)> = room_cache::sources(room)
+        .iter()
+        .map(|s| (creep.pos().get_range_to(s), s.clone()))
+        .collect();
+    sources.sort_by_key(|(range, _)| *range);
+    let ready = sources
+        .iter()
+        .find(|(range, s)| s.energy() > 0 || s.ticks_to_regeneration() <= *range)
+        .map(|(_, s)| s.clone());
+    ready.or_else(|| {
+        sources
+            .into_iter()
+            .map(|(_, s)| s)
+            .min_by_key(|s| s.ticks_to_regeneration())
+    })
+}
+
+fn deliver_energy(creep: &Creep, room: &Room, spawnless: bool) -> Result<Task, BotError> {
+    if spawnless {
+        if let Some(task) = build_spawn(creep, room)? {
+            return Ok(task);
+        }
+    }
+
+    if let Some(task) = run_deliveries(creep, room)? {
+        return Ok(task);
+    }
+    if let Some(task) = store_cargo(creep, room)? {
+        return Ok(task);
+    }
+    if let Some(task) = build_sites(creep, room)? {
+        return Ok(task);
+    }
+    if let Some(target) = repair::creep_target(room, creep.pos()) {
+        let r = issue(creep, "repair", &target, || creep.repair(&target));
+        if r == ReturnCode::Ok {
+            // one energy per Work part
+            accounts::repaired(creep, room.name(), creep.get_active_bodyparts(Part::Work));
+        }
+        return act(creep, "repair", r, &target, Task::Repair);
+    }
+
+    upgrade(creep, room)
+}
+
+/// A hauler's planned fill targets for the current load, in visiting order. Kept in creep
+/// memory under `deliveries` until the load is gone.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct DeliveryRun {
+    targets: Vec<Recipient>,
+    /// How much each target asked for when the run was planned; targets past the end of
+    /// this are filled.
+    #[serde(default)]
+    amounts: Vec<u32>,
+    /// What this run delivers; unset for runs planned before haulers carried anything else.
+    #[serde(default)]
+    resource: Option<ResourceType>,
+}
+
+impl DeliveryRun {
+    fn resource(&self) -> ResourceType {
+        self.resource.unwrap_or(ResourceType::Energy)
+    }
+
+    fn pop(&mut self) {
+        self.targets.remove(0);
+        if !self.amounts.is_empty() {
+            self.amounts.remove(0);
+        }
+    }
+}
+
+js_serializable!(DeliveryRun);
+js_deserializable!(DeliveryRun);
+
+/// Room `target` has for `resource`, kept inside its band if it has one.
+fn free_for(target: &Structure, resource: ResourceType) -> u32 {
+    let free = target
+        .as_has_store()
+        .map_or(0, |s| s.store_free_capacity(Some(resource)));
+    target
+        .room()
+        .map_or(free, |r| free.min(bands::headroom(&r, target, resource)))
+}
+
+/// Whether `target` still has room enough for the trip: `DELIVERY_MIN_FREE`, or all it
+/// `asked` for if that was less, and anything at all once adjacent.
+fn worth_delivering(
+    creep: &Creep,
+    target: &Structure,
+    resource: ResourceType,
+    asked: Option<u32>,
+) -> bool {
+    let free = free_for(target, resource);
+    let enough = DELIVERY_MIN_FREE.min(asked.unwrap_or(free));
+    free > 0 && (free >= enough || creep.pos().is_near_to(target))
+}
+
+/// A delivery run for the highest priority request the creep's cargo can serve.
+fn plan_run(creep: &Creep, room: &Room) -> DeliveryRun {
+    let requests = if memory::creep_memory(creep).bool("tower_refill") {
+        // siege energy goes to the nearest tower short of it, and nowhere else
+        let mut towers: Vec<_> = logistics::requests(room.name())
+            .into_iter()
+            .filter(|r| r.kind == RequestKind::FillTower && r.resource == ResourceType::Energy)
+            .collect();
+        towers.sort_by_key(|r| creep.pos().get_range_to(&r.pos));
+        towers.truncate(1);
+        towers
+    } else {
+        let building = memory::creep_memory(creep).bool("building");
+        logistics::batch(room.name(), creep.pos(), &logistics::cargo(creep), building)
+    };
+    DeliveryRun {
+        resource: requests.first().map(|r| r.resource),
+        amounts: requests.iter().map(|r| r.amount).collect(),
+        targets: requests.into_iter().map(|r| r.target).collect(),
+    }
+}
+
+/// What to hand a target that `asked` for an amount and has `free` room, out of `carried`,
+/// and whether the load runs out before the target is topped up.
+fn handover(asked: u32, free: u32, carried: u32) -> (u32, bool) {
+    let wanted = asked.min(free);
+    (wanted.min(carried), carried < wanted)
+}
+
+/// Works down the current delivery run, planning a new one from the logistics requests when
+/// there is none. Returns `None` when nothing in the room wants what the creep carries.
+fn run_deliveries(creep: &Creep, room: &Room) -> Result<Option<Task>, BotError> {
+    let mem = memory::creep_memory(creep);
+    let mut run = match mem.get::<DeliveryRun>("deliveries") {
+        Ok(Some(run)) if !run.targets.is_empty() && creep.store_of(run.resource()) > 0 => run,
+        _ => plan_run(creep, room),
+    };
+    let resource = run.resource();
+
+    // another hauler or the spawn itself may have filled targets since the run was planned;
+    // checked every tick on the way, not just on arrival
+    let mut target = None;
+    while !run.targets.is_empty() {
+        let asked = run.amounts.first().cloned();
+        match run.targets[0] {
+            Recipient::Structure(id) => match objects::get_cached(id) {
+                Some(t) if worth_delivering(creep, &t, resource, asked) => {
+                    target = Some(t);
+                    break;
+                }
+                _ => run.pop(),
+            },
+            Recipient::Creep(id) => match objects::get_cached(id) {
+                Some(builder) if builder.store_free_capacity(Some(resource)) > 0 => {
+                    return feed_builder(creep, room, &mut run, &builder);
+                }
+                _ => run.pop(),
+            },
+        }
+    }
+    let target = match target {
+        Some(t) => t,
+        None => {
+            mem.del("deliveries");
+            mem.del("tower_refill");
+            return Ok(None);
+        }
+    };
+
+    let transferable = target.as_transferable().ok_or(BotError::MissingRoomObject {
+        what: "transferable logistics target",
+    })?;
+    // hand over what the target asked for, so the rest of the load reaches the next one
+    let free = free_for(&target, resource);
+    let asked = run.amounts.first().cloned().unwrap_or(free);
+    let (amount, short) = handover(asked, free, creep.store_of(resource));
+    let r = issue(creep, "transfer", &target, || {
+        creep.transfer_amount(transferable, resource, amount)
+    });
+    if r == ReturnCode::Ok || r == ReturnCode::Full {
+        run.pop();
+    }
+    if r == ReturnCode::Ok {
+        if short {
+            // the load ran out; the target's own request offers the rest again next tick
+            debug!("{} delivered {} of {} to {}", creep.name(), amount, asked, target.id());
+        }
+        if resource == ResourceType::Energy {
+            accounts::delivered(creep, room.name(), amount);
+        }
+    }
+    if run.targets.is_empty() {
+        mem.del("deliveries");
+    } else {
+        mem.set("deliveries", &run);
+    }
+    act(creep, "transfer", r, &target, Task::Transfer).map(Some)
+}
+
+/// Hands the run's load to `builder`, a `FeedBuilder` recipient, wherever it has got to.
+fn feed_builder(
+    creep: &Creep,
+    room: &Room,
+    run: &mut DeliveryRun,
+    builder: &Creep,
+) -> Result<Option<Task>, BotError> {
+    let resource = run.resource();
+    let free = builder.store_free_capacity(Some(resource));
+    let asked = run.amounts.first().cloned().unwrap_or(free);
+    let (amount, _) = handover(asked, free, creep.store_of(resource));
+    let r = issue(creep, "transfer", builder, || {
+        creep.transfer_amount(builder, resource, amount)
+    });
+    if r == ReturnCode::Ok || r == ReturnCode::Full {
+        run.pop();
+    }
+    if r == ReturnCode::Ok && resource == ResourceType::Energy {
+        accounts::delivered(creep, room.name(), amount);
+    }
+    let mem = memory::creep_memory(creep);
+    if run.targets.is_empty() {
+        mem.del("deliveries");
+    } else {
+        mem.set("deliveries", &*run);
+    }
+    act(creep, "transfer", r, builder, Task::Transfer).map(Some)
+}
+
+/// Puts anything but energy that no request wants into storage, first resource in unload
+/// order first. Energy stays for repairs and upgrading.
+fn store_cargo(creep: &Creep, room: &Room) -> Result<Option<Task>, BotError> {
+    let cargo = logistics::cargo(creep);
+    let requests = logistics::requests(room.name());
+    let resource = match logistics::unload_order(&cargo, &requests)
+        .into_iter()
+        .find(|r| *r != ResourceType::Energy)
+    {
+        Some(r) => r,
+        None => return Ok(None),
+    };
+    let storage = match room.storage() {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+    let r = issue(creep, "transfer", &storage, || creep.transfer_all(&storage, resource));
+    act(creep, "transfer", r, &storage, Task::Transfer).map(Some)
+}
+
+/// Works on the site construction assigns this load to. While it has one the creep counts as a
+/// builder, which changes where it may take energy from.
+fn build_sites(creep: &Creep, room: &Room) -> Result<Option<Task>, BotError> {
+    let site = match construction::assign_site(room, creep) {
+        Some(s) => s,
+        None => {
+            memory::creep_memory(creep).del("building");
+            return Ok(None);
+        }
+    };
+    memory::creep_memory(creep).set("building", true);
+    let r = issue(creep, "build", &site, || creep.build(&site));
+    if r == ReturnCode::Ok {
+        accounts::built(creep);
+    }
+    act(creep, "build", r, &site, Task::Build).map(Some)
+}
+
+/// Works on the room's spawn construction site, if there is one.
+fn build_spawn(creep: &Creep, room: &Room) -> Result<Option<Task>, BotError> {
+    let site = match room_cache::construction_sites(room)
+        .iter()
+        .find(|s| s.structure_type() == StructureType::Spawn)
+        .cloned()
+    {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+    let r = issue(creep, "build", &site, || creep.build(&site));
+    if r == ReturnCode::Ok {
+        accounts::built(creep);
+    }
+    act(creep, "build", r, &site, Task::Build).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn handover_gives_what_was_asked() {
+        assert_eq!(handover(100, 800, 300), (100, false));
+    }
+
+    #[test]
+    fn handover_to_a_partly_filled_target() {
+        assert_eq!(handover(50, 20, 300), (20, false));
+        assert_eq!(handover(50, 0, 300), (0, false));
+    }
+
+    #[test]
+    fn handover_with_a_short_load() {
+        assert_eq!(handover(100, 100, 40), (40, true));
+        assert_eq!(handover(100, 30, 40), (30, false));
+    }
+    #[test]
+    fn rebuild_energy_prefers_storage() {
+        assert_eq!(rebuild_energy(500, &[2000], 300), Some(RebuildSource::Storage));
+    }
+
+    #[test]
+    fn rebuild_energy_falls_back_to_the_fullest_container() {
+        assert_eq!(
+            rebuild_energy(0, &[100, 1200, 400], 300),
+            Some(RebuildSource::Container(1))
+        );
+        assert_eq!(rebuild_energy(0, &[100, 200], 300), None);
+        assert_eq!(rebuild_energy(0, &[], 300), None);
+    }
+    #[test]
+    fn handoff_goes_to_the_worker_with_most_room() {
+        assert_eq!(handoff_taker(50, &[20, 100, 0]), Some((1, 50)));
+        assert_eq!(handoff_taker(50, &[20, 0]), Some((0, 20)));
+    }
+
+    #[test]
+    fn no_handoff_without_a_load_or_room() {
+        assert_eq!(handoff_taker(0, &[100]), None);
+        assert_eq!(handoff_taker(50, &[0, 0]), None);
+        assert_eq!(handoff_taker(50, &[]), None);
+    }
+}
@@ -0,0 +1,53 @@
+use std::cell::Cell;
+
+use log::*;
+
+use crate::{error, memory, stats};
+
+/// This build: the crate version and the commit it was built from, or the build time outside
+/// a git checkout. See `build.rs`.
+pub const BUILD: &str = concat!(env!("CARGO_PKG_VERSION"), "+", env!("BUILD_ID"));
+
+thread_local! {
+    static CHECKED: Cell<bool> = Cell::new(false);
+}
+
+/// Run at the start of each tick; only does anything on the first tick after a global reset.
+/// Compares this build with the one that last ran, kept in `Memory.build`. A new one is
+/// logged, gets creep memory migrated straight away rather than at the next cleanup, and is
+/// recorded in `Memory.stats.deploys` so changes in the stats can be lined up with deploys.
+pub fn check() {
+    if CHECKED.with(|c| c.replace(true)) {
+        return;
+    }
+    let root = screeps::memory::root();
+    let last = root.string("build").ok().flatten();
+    if last.as_deref() == Some(BUILD) {
+        info!("global reset, still running {}", BUILD);
+        return;
+    }
+    let now = screeps::game::time();
+    info!(
+        "==== deployed {} -> {} at tick {} ====",
+        last.as_deref().unwrap_or("unknown build"),
+        BUILD,
+        now
+    );
+    root.set("build", BUILD);
+    if let Err(e) = memory::migrate_creeps() {
+        error::report("deploy migration", "Memory.creeps", "-", &e);
+    }
+    if let Some(deploys) = stats::section("deploys") {
+        deploys.set("last_tick", now);
+        deploys.set("last_build", BUILD);
+    }
+    stats::increment("deploys", "count", 1);
+}
+
+/// Console command: `version()` shows the running build and the tick it was deployed.
+pub fn version() -> String {
+    let since = stats::section("deploys")
+        .and_then(|d| d.i32("last_tick").ok().flatten())
+        .map_or("an unknown tick".to_string(), |t| format!("tick {}", t));
+    format!("running {}, deployed at {}", BUILD, since)
+}
@@ -0,0 +1,261 @@
+use log::*;
+use screeps::{
+    memory::MemoryReference, prelude::*, Deposit, ObjectId, Position, ResourceType, RoomName,
+};
+use serde::{Deserialize, Serialize};
+use stdweb::{js_deserializable, js_serializable};
+
+use crate::{
+    coord,
+    error::{self, BotError},
+    intel::{self, DepositIntel},
+    memory, objects, population,
+    role::Role,
+    route, settings,
+    spawn::SpawnRequest,
+};
+
+/// Deposits further than this many rooms from a storage aren't considered.
+const MAX_ROOMS_AWAY: u32 = 4;
+/// Default for `Memory.settings.deposit_max_cooldown`: past this last cooldown each harvest
+/// waits too long to pay for the creeps.
+const MAX_COOLDOWN: u32 = 40;
+/// An operation needs the deposit to outlast this many round trips.
+const DECAY_ROUND_TRIPS: u32 = 5;
+const DEPOSIT_PRIORITY: u32 = 20;
+
+/// A deposit being worked from `home`, in `Memory.deposits` keyed by the deposit's room. Its
+/// creeps have that room as their `target_room`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DepositOperation {
+    pub home: RoomName,
+    pub deposit: ObjectId<Deposit>,
+    pub pos: u32,
+    pub kind: ResourceType,
+    #[serde(default)]
+    pub last_cooldown: u32,
+    #[serde(default)]
+    pub decays_at: u32,
+    #[serde(default)]
+    pub round_trip: u32,
+    /// Set when the deposit stops paying; creeps bring home what they carry and recycle.
+    #[serde(default)]
+    pub stopped: bool,
+}
+
+js_serializable!(DepositOperation);
+js_deserializable!(DepositOperation);
+
+fn max_cooldown() -> u32 {
+    settings::u32_or("deposit_max_cooldown", MAX_COOLDOWN)
+}
+
+pub fn operation(room: RoomName) -> Result<Option<DepositOperation>, BotError> {
+    memory::deposits()?
+        .get::<DepositOperation>(&room.to_string())
+        .map_err(|e| BotError::Deserialize {
+            target: "DepositOperation",
+            source: e.to_string(),
+        })
+}
+
+/// Checked by the harvester every tick it's at the deposit, so an operation stops on the
+/// harvest that pushes the cooldown past profitable rather than at the next `run_deposits`.
+/// Returns whether the operation is stopped.
+pub fn check_cooldown(room: RoomName, deposit: &Deposit) -> Result<bool, BotError> {
+    if deposit.last_cooldown() < max_cooldown() {
+        return Ok(false);
+    }
+    let mut op = match operation(room)? {
+        Some(op) => op,
+        None => return Ok(true),
+    };
+    if !op.stopped {
+        info!("stopping deposit in {}: cooldown {}", room, deposit.last_cooldown());
+        op.last_cooldown = deposit.last_cooldown();
+        op.stopped = true;
+        memory::deposits()?.set(&room.to_string(), &op);
+    }
+    Ok(true)
+}
+
+/// Whether an operation on `deposit` already ran its course; intel can be old enough to still
+/// look worth launching.
+fn finished(deposit: ObjectId<Deposit>) -> Result<bool, BotError> {
+    Ok(memory::finished_deposits()?
+        .i32(&deposit.to_string())
+        .ok()
+        .flatten()
+        .is_some())
+}
+
+/// Marks `op`'s deposit as done until it would have decayed, so `launch` leaves it be.
+fn mark_finished(op: &DepositOperation) -> Result<(), BotError> {
+    memory::finished_deposits()?.set(&op.deposit.to_string(), op.decays_at);
+    Ok(())
+}
+
+/// Drops the markers of deposits that have decayed by now.
+fn prune_finished() -> Result<(), BotError> {
+    let done = memory::finished_deposits()?;
+    let now = screeps::game::time() as i32;
+    for key in done.keys() {
+        if done.i32(&key).ok().flatten().map_or(true, |t| t < now) {
+            done.del(&key);
+        }
+    }
+    Ok(())
+}
+
+/// The closest owned room with a storage to bring deposit resources back to.
+fn home_for(room: RoomName) -> Option<(RoomName, Position)> {
+    screeps::game::rooms::values()
+        .into_iter()
+        .filter(|r| r.controller().map_or(false, |c| c.my()))
+        .filter_map(|r| r.storage().map(|s| (r.name(), s.pos())))
+        .filter_map(|(name, pos)| coord::distance(name, room).map(|d| (d, name, pos)))
+        .filter(|(d, _, _)| *d <= MAX_ROOMS_AWAY)
+        .min_by_key(|(d, _, _)| *d)
+        .map(|(_, name, pos)| (name, pos))
+}
+
+/// An operation for `deposit` if it's fresh enough and close enough to beat its decay.
+fn plan(room: RoomName, deposit: &DepositIntel) -> Option<DepositOperation> {
+    if deposit.last_cooldown >= max_cooldown() {
+        return None;
+    }
+    let (home, storage) = home_for(room)?;
+    let round_trip = route::measure(storage, Position::from_packed(deposit.pos), 1)?
+        .round_trip_ticks();
+    let left = deposit.decays_at.saturating_sub(screeps::game::time());
+    if left < round_trip * DECAY_ROUND_TRIPS {
+        return None;
+    }
+    Some(DepositOperation {
+        home,
+        deposit: deposit.id,
+        pos: deposit.pos,
+        kind: deposit.kind,
+        last_cooldown: deposit.last_cooldown,
+        decays_at: deposit.decays_at,
+        round_trip,
+        stopped: false,
+    })
+}
+
+/// Starts operations on worthwhile deposits in highway rooms from intel, skipping any already
+/// worked to the end.
+fn launch(deposits: &MemoryReference) -> Result<(), BotError> {
+    for (room, info) in intel::all()? {
+        if !coord::is_highway(room) || operation(room)?.is_some() {
+            continue;
+        }
+        let mut fresh = Vec::new();
+        for deposit in &info.deposits {
+            if !finished(deposit.id)? {
+                fresh.push(deposit);
+            }
+        }
+        if let Some(op) = fresh.into_iter().find_map(|d| plan(room, d)) {
+            info!(
+                "starting {:?} deposit in {} from {}, round trip {} ticks",
+                op.kind, room, op.home, op.round_trip
+            );
+            deposits.set(&room.to_string(), &op);
+        }
+    }
+    Ok(())
+}
+
+fn is_deposit_role(role: Role) -> bool {
+    role == Role::DepositHarvester || role == Role::DepositHauler
+}
+
+/// Roles of the living creeps working the deposit in `room`.
+fn creeps_for(room: RoomName) -> Vec<Role> {
+    let target = room.to_string();
+    screeps::game::creeps::values()
+        .iter()
+        .filter(|c| memory::creep_target(c).as_deref() == Some(&target))
+        .map(population::role_of)
+        .filter(|role| is_deposit_role(*role))
+        .collect()
+}
+
+/// Refreshes the operation from vision, stops it once the cooldown is past profitable, and
+/// keeps one harvester and one hauler queued while it runs. Returns `false` once it's over.
+fn run_operation(room: RoomName, op: &mut DepositOperation) -> Result<bool, BotError> {
+    if screeps::game::rooms::get(room).is_some() {
+        match objects::get_cached(op.deposit) {
+            Some(deposit) => {
+                let seen = DepositIntel::of(&deposit);
+                op.last_cooldown = seen.last_cooldown;
+                op.decays_at = seen.decays_at;
+            }
+            None => op.stopped = true,
+        }
+    }
+    let now = screeps::game::time();
+    if !op.stopped
+        && (op.last_cooldown >= max_cooldown()
+            || op.decays_at.saturating_sub(now) < op.round_trip)
+    {
+        info!(
+            "stopping deposit in {}: cooldown {}, {} ticks left",
+            room,
+            op.last_cooldown,
+            op.decays_at.saturating_sub(now)
+        );
+        op.stopped = true;
+    }
+    let creeps = creeps_for(room);
+    let mut home = memory::get_room_memory(op.home)?;
+    if op.stopped {
+        let target = room.to_string();
+        home.spawn_queue.retain(|r| {
+            !(is_deposit_role(r.role) && r.target_room.as_deref() == Some(target.as_str()))
+        });
+        memory::set_room_memory(op.home, &home)?;
+        return Ok(!creeps.is_empty());
+    }
+
+    for role in &[Role::DepositHarvester, Role::DepositHauler] {
+        if creeps.contains(role) {
+            continue;
+        }
+        home.enqueue(SpawnRequest {
+            enqueued: now,
+            dedupe: Some(format!("{:?}-{}", role, room)),
+            target_room: Some(room.to_string()),
+            ..SpawnRequest::new(*role, DEPOSIT_PRIORITY)
+        });
+    }
+    memory::set_room_memory(op.home, &home)?;
+    Ok(true)
+}
+
+pub fn run_deposits() -> Result<(), BotError> {
+    let deposits = memory::deposits()?;
+    prune_finished()?;
+    launch(&deposits)?;
+    for key in deposits.keys() {
+        let room: RoomName = match key.parse() {
+            Ok(r) => r,
+            Err(_) => continue,
+        };
+        let mut op = match operation(room)? {
+            Some(op) => op,
+            None => continue,
+        };
+        match run_operation(room, &mut op) {
+            Ok(true) => deposits.set(&key, &op),
+            Ok(false) => {
+                info!("deposit operation in {} is over", room);
+                mark_finished(&op)?;
+                deposits.del(&key);
+            }
+            Err(e) => error::report("deposit", &key, &op.home.to_string(), &e),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,98 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+};
+
+use log::*;
+use screeps::{find, prelude::*, Position, RawObjectId, Room, RoomName, StructureType};
+
+use crate::{memory, planner, room::RoomMemory};
+
+thread_local! {
+    /// Structures seen in each room last tick, with their type and packed position.
+    static KNOWN: RefCell<HashMap<RoomName, HashMap<RawObjectId, (StructureType, u32)>>> =
+        RefCell::new(HashMap::new());
+    /// Rooms where `detect` found a structure that wasn't there last tick.
+    static BUILT: RefCell<HashSet<RoomName>> = RefCell::new(HashSet::new());
+}
+
+/// A structure that was in the room last tick and isn't now, however it went.
+#[derive(Clone, Copy, Debug)]
+pub struct StructureDestroyed {
+    pub id: RawObjectId,
+    pub kind: StructureType,
+    pub pos: Position,
+}
+
+/// Compares the room's structures with last tick's and returns the ones that are gone, noting
+/// for `built` whether any are new. The first look at a room after a global reset only
+/// records what's there.
+pub fn detect(room: &Room) -> Vec<StructureDestroyed> {
+    let now: HashMap<RawObjectId, (StructureType, u32)> = room
+        .find(find::STRUCTURES)
+        .into_iter()
+        .filter(|s| s.structure_type() != StructureType::Controller)
+        .map(|s| (s.untyped_id(), (s.structure_type(), s.pos().packed_repr())))
+        .collect();
+    let before = KNOWN.with(|k| k.borrow_mut().insert(room.name(), now.clone()));
+    let before = match before {
+        Some(b) => b,
+        None => return Vec::new(),
+    };
+    let built = now.keys().any(|id| !before.contains_key(id));
+    BUILT.with(|b| {
+        let mut b = b.borrow_mut();
+        if built {
+            b.insert(room.name());
+        } else {
+            b.remove(&room.name());
+        }
+    });
+    before
+        .into_iter()
+        .filter(|(id, _)| !now.contains_key(id))
+        .map(|(id, (kind, pos))| StructureDestroyed {
+            id,
+            kind,
+            pos: Position::from_packed(pos),
+        })
+        .collect()
+}
+
+/// Whether the last `detect` of `room` found a structure that wasn't there the tick before.
+pub fn built(room: RoomName) -> bool {
+    BUILT.with(|b| b.borrow().contains(&room))
+}
+
+/// Whether the planner put `event`'s structure where it was, so it should go back there.
+fn planned(event: &StructureDestroyed, mem: &RoomMemory) -> bool {
+    let at = Some(event.pos.packed_repr());
+    match event.kind {
+        StructureType::Spawn | StructureType::Tower => true,
+        StructureType::Container => mem.controller_container == at,
+        StructureType::Link => mem.controller_link == at,
+        _ => false,
+    }
+}
+
+/// Drops everything that depended on each destroyed structure: its structure memory, its
+/// place in the inactive list, and the link layout when a link or the storage went, so the
+/// links are classified again. Structures the planner placed get their site back.
+///
+/// Logistics requests and cost matrices are rebuilt from the room every tick, and delivery
+/// runs skip targets that no longer resolve, so those need nothing here.
+pub fn handle(room: &Room, mem: &mut RoomMemory, events: &[StructureDestroyed]) {
+    for event in events {
+        debug!("{} lost {:?} {} at {}", room.name(), event.kind, event.id, event.pos);
+        memory::forget_structure(event.id);
+        mem.inactive.retain(|id| *id != event.id);
+        if event.kind == StructureType::Link || event.kind == StructureType::Storage {
+            mem.link_layout = None;
+        }
+        if planned(event, mem) {
+            if let Err(e) = planner::replace_site(room, mem, event.pos, event.kind) {
+                warn!("{} couldn't re-place {:?}: {}", room.name(), event.kind, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use log::*;
+use screeps::RoomName;
+use serde::{Deserialize, Serialize};
+use stdweb::{js_deserializable, js_serializable};
+
+use crate::{
+    allies,
+    intel::{self, RoomIntel},
+};
+
+/// How we treat another player. Anyone without one is `Neutral`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stance {
+    Ally,
+    Neutral,
+    /// Their rooms are pathed around where possible and their claims aren't respected.
+    Avoid,
+    Hostile,
+}
+
+impl Stance {
+    fn parse(name: &str) -> Option<Stance> {
+        match name.to_lowercase().as_str() {
+            "ally" => Some(Stance::Ally),
+            "neutral" => Some(Stance::Neutral),
+            "avoid" => Some(Stance::Avoid),
+            "hostile" => Some(Stance::Hostile),
+            _ => None,
+        }
+    }
+
+    /// Ally or neutral: their reservations and signs keep our remotes and claims out.
+    pub fn respects_claims(self) -> bool {
+        self == Stance::Ally || self == Stance::Neutral
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Relation {
+    pub stance: Stance,
+    /// Tick the stance was last set.
+    pub changed: u32,
+}
+
+/// `Memory.diplomacy`: our stance towards each player we've set one for. Allies are also kept
+/// on `Memory.allies`, which is what combat checks.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Diplomacy {
+    #[serde(default)]
+    pub players: HashMap<String, Relation>,
+}
+
+js_serializable!(Diplomacy);
+js_deserializable!(Diplomacy);
+
+thread_local! {
+    static CACHE: RefCell<(u32, Diplomacy)> = RefCell::new((u32::MAX, Diplomacy::default()));
+}
+
+fn load() -> Diplomacy {
+    match screeps::memory::root().get::<Diplomacy>("diplomacy") {
+        Ok(d) => d.unwrap_or_default(),
+        Err(e) => {
+            warn!("Memory.diplomacy is unreadable: {}", e);
+            Diplomacy::default()
+        }
+    }
+}
+
+fn relation(player: &str) -> Option<Relation> {
+    let now = screeps::game::time();
+    CACHE.with(|c| {
+        let mut c = c.borrow_mut();
+        if c.0 != now {
+            *c = (now, load());
+        }
+        c.1.players.get(player).cloned()
+    })
+}
+
+pub fn stance_of(player: &str) -> Stance {
+    if allies::is_ally(player) {
+        return Stance::Ally;
+    }
+    relation(player).map_or(Stance::Neutral, |r| r.stance)
+}
+
+/// NPC usernames; invader cores reserve rooms too, but that's no one's claim.
+const NPCS: [&str; 2] = ["Invader", "Source Keeper"];
+
+/// Other players with a claim on the room in intel: its owner, reserver and signer.
+fn claimants(info: &RoomIntel) -> Vec<String> {
+    let me = intel::my_username();
+    let mut players = Vec::new();
+    let seen = [&info.owner, &info.reserved_by, &info.sign_by];
+    for player in seen.iter().copied().flatten() {
+        if *player != me && !NPCS.contains(&player.as_str()) && !players.contains(player) {
+            players.push(player.clone());
+        }
+    }
+    players
+}
+
+/// The first player with a claim on the room we've agreed to respect.
+pub fn respected_claim(info: &RoomIntel) -> Option<String> {
+    claimants(info)
+        .into_iter()
+        .find(|p| stance_of(p).respects_claims())
+}
+
+/// Whether the room belongs to, or is claimed by, a player we avoid.
+pub fn avoided(room: RoomName) -> bool {
+    match intel::get(room) {
+        Ok(Some(info)) => claimants(&info)
+            .iter()
+            .any(|p| stance_of(p) == Stance::Avoid),
+        _ => false,
+    }
+}
+
+/// `player`'s stance and when it was set, for notifications.
+pub fn describe(player: &str) -> String {
+    match relation(player) {
+        Some(r) => format!("{:?} since tick {}", r.stance, r.changed).to_lowercase(),
+        None => format!("{:?}", stance_of(player)).to_lowercase(),
+    }
+}
+
+/// Console command: `set_stance("someone", "avoid")`. Setting or leaving `ally` also updates
+/// `Memory.allies`.
+pub fn set_stance(player: String, stance: String) {
+    let stance = match Stance::parse(&stance) {
+        Some(s) => s,
+        None => {
+            warn!("set_stance: {} isn't ally, neutral, avoid or hostile", stance);
+            return;
+        }
+    };
+    let before = stance_of(&player);
+    let mut diplomacy = load();
+    diplomacy.players.insert(
+        player.clone(),
+        Relation {
+            stance,
+            changed: screeps::game::time(),
+        },
+    );
+    screeps::memory::root().set("diplomacy", &diplomacy);
+    CACHE.with(|c| c.borrow_mut().0 = u32::MAX);
+    if stance == Stance::Ally {
+        allies::add_ally(player.clone());
+    } else if allies::is_ally(&player) {
+        allies::remove_ally(player.clone());
+    }
+    info!("stance towards {} changed from {:?} to {:?}", player, before, stance);
+}
@@ -0,0 +1,78 @@
+use std::fmt;
+
+use log::*;
+use screeps::ReturnCode;
+
+use crate::stats;
+
+#[derive(Debug)]
+pub enum BotError {
+    StaleId { id: String, kind: &'static str },
+    Deserialize { target: &'static str, source: String },
+    UnexpectedReturnCode { api: &'static str, code: ReturnCode },
+    MissingRoomObject { what: &'static str },
+    RejectedIntent { api: &'static str, target: String },
+    /// A planner asked for a construction site `construction::validate` turned down.
+    InvalidSite { planner: String, site: String, reason: String },
+}
+
+impl BotError {
+    /// Short, stable name used as the key for the per-variant error counters in `Memory.stats`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BotError::StaleId { .. } => "stale_id",
+            BotError::Deserialize { .. } => "deserialize",
+            BotError::UnexpectedReturnCode { .. } => "unexpected_return_code",
+            BotError::MissingRoomObject { .. } => "missing_room_object",
+            BotError::RejectedIntent { .. } => "rejected_intent",
+            BotError::InvalidSite { .. } => "invalid_site",
+        }
+    }
+}
+
+impl fmt::Display for BotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BotError::StaleId { id, kind } => write!(f, "{} {} no longer resolves", kind, id),
+            BotError::Deserialize { target, source } => {
+                write!(f, "couldn't deserialize {}: {}", target, source)
+            }
+            BotError::UnexpectedReturnCode { api, code } => {
+                write!(f, "{} returned {:?}", api, code)
+            }
+            BotError::MissingRoomObject { what } => write!(f, "missing {}", what),
+            BotError::RejectedIntent { api, target } => {
+                write!(f, "refused {} on sink-only {}", api, target)
+            }
+            BotError::InvalidSite {
+                planner,
+                site,
+                reason,
+            } => write!(f, "{} asked for {}, but {}", planner, site, reason),
+        }
+    }
+}
+
+impl std::error::Error for BotError {}
+
+/// Turns a non-`Ok` return code into an `UnexpectedReturnCode` error.
+pub fn check(api: &'static str, code: ReturnCode) -> Result<(), BotError> {
+    if code == ReturnCode::Ok {
+        Ok(())
+    } else {
+        Err(BotError::UnexpectedReturnCode { api, code })
+    }
+}
+
+/// Logs an error raised while running `entity` `name` in `room`, and counts it in the stats.
+pub fn report(entity: &str, name: &str, room: &str, err: &BotError) {
+    warn!(
+        "[{}] {} {} in {}: {}",
+        screeps::game::time(),
+        entity,
+        name,
+        room,
+        err
+    );
+    stats::record_error(err);
+}
@@ -0,0 +1,262 @@
+use std::collections::HashSet;
+
+use log::*;
+use screeps::{find, prelude::*, ResourceType, RoomName};
+use serde::Serialize;
+use stdweb::js_serializable;
+
+use crate::{
+    avoid, coord, diplomacy,
+    error::BotError,
+    intel::{self, RoomIntel},
+    inventory, memory, population,
+    role::Role,
+    settings,
+    spawn::SpawnRequest,
+    stats,
+};
+
+const REPORTED_CANDIDATES: usize = 10;
+/// Default for `Memory.settings.expansion_missing_mineral`: score for a mineral no room of ours
+/// has.
+const MISSING_MINERAL_SCORE: u32 = 15;
+/// Default for `Memory.settings.expansion_catalyst_bonus`: on top of that when it's catalyst,
+/// which goes into every tier 3 boost.
+const CATALYST_BONUS: u32 = 10;
+/// Default for `Memory.settings.expansion_imported_mineral`: score for a mineral that goes into
+/// something we keep buying.
+const IMPORTED_MINERAL_SCORE: u32 = 10;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ExpansionCandidate {
+    pub room: RoomName,
+    pub score: f64,
+    pub sources: u8,
+    pub mineral: Option<ResourceType>,
+    pub mineral_missing: bool,
+    /// Goes into a compound `inventory` says we chronically import.
+    pub mineral_imported: bool,
+    pub distance: u32,
+    pub hostile_neighbors: u32,
+    pub open_area: u32,
+}
+
+#[derive(Serialize)]
+struct ExpansionReport {
+    tick: u32,
+    candidates: Vec<ExpansionCandidate>,
+}
+
+js_serializable!(ExpansionReport);
+
+fn owned_rooms() -> Vec<RoomName> {
+    screeps::game::rooms::values()
+        .into_iter()
+        .filter(|r| r.controller().map(|c| c.my()).unwrap_or(false))
+        .map(|r| r.name())
+        .collect()
+}
+
+fn empire_minerals() -> HashSet<ResourceType> {
+    screeps::game::rooms::values()
+        .into_iter()
+        .filter(|r| r.controller().map(|c| c.my()).unwrap_or(false))
+        .flat_map(|r| r.find(find::MINERALS))
+        .map(|m| m.mineral_type())
+        .collect()
+}
+
+fn is_dangerous(intel: &RoomIntel) -> bool {
+    intel.owner.is_some() || intel.hostile_towers > 0 || intel.keeper_lairs > 0
+}
+
+fn score(
+    room: RoomName,
+    intel: &RoomIntel,
+    all: &[(RoomName, RoomIntel)],
+    owned: &[RoomName],
+    minerals: &HashSet<ResourceType>,
+    imported: &HashSet<ResourceType>,
+) -> Option<ExpansionCandidate> {
+    if !intel.claimable() || intel.sources == 0 {
+        return None;
+    }
+    if diplomacy::respected_claim(intel).is_some() || avoid::avoided(room) {
+        return None;
+    }
+    if coord::is_keeper_sector(room) || coord::is_highway(room) {
+        return None;
+    }
+    let distance = owned.iter().filter_map(|o| coord::distance(*o, room)).min()?;
+    let hostile_neighbors = coord::neighbors_within(room, 1)
+        .iter()
+        .filter(|n| {
+            all.iter()
+                .any(|(name, i)| name == n && is_dangerous(i) && !owned.contains(name))
+        })
+        .count() as u32;
+    let mineral_missing = intel.mineral.map_or(false, |m| !minerals.contains(&m));
+    let mineral_imported = intel.mineral.map_or(false, |m| imported.contains(&m));
+
+    let mut score = 10.0 * intel.sources as f64;
+    if mineral_missing {
+        score += settings::u32_or("expansion_missing_mineral", MISSING_MINERAL_SCORE) as f64;
+        if intel.mineral == Some(ResourceType::Catalyst) {
+            score += settings::u32_or("expansion_catalyst_bonus", CATALYST_BONUS) as f64;
+        }
+    }
+    if mineral_imported {
+        score += settings::u32_or("expansion_imported_mineral", IMPORTED_MINERAL_SCORE) as f64;
+    }
+    // too close fights our own remotes, too far is hard to defend
+    score -= match distance {
+        0 | 1 => 6.0,
+        d => 2.0 * (d as f64 - 2.0),
+    };
+    score -= 5.0 * hostile_neighbors as f64;
+    score += intel.open_area as f64 / 100.0;
+
+    Some(ExpansionCandidate {
+        room,
+        score,
+        sources: intel.sources,
+        mineral: intel.mineral,
+        mineral_missing,
+        mineral_imported,
+        distance,
+        hostile_neighbors,
+        open_area: intel.open_area,
+    })
+}
+
+/// Every claimable room we have intel on, best first. Anything that picks a room to claim should
+/// take the head of this list so it agrees with the published report.
+pub fn rank_candidates() -> Result<Vec<ExpansionCandidate>, BotError> {
+    let all = intel::all()?;
+    let owned = owned_rooms();
+    let minerals = empire_minerals();
+    let imported = inventory::imported_minerals();
+    let mut candidates: Vec<ExpansionCandidate> = all
+        .iter()
+        .filter_map(|(room, i)| score(*room, i, &all, &owned, &minerals, &imported))
+        .collect();
+    candidates.sort_by(|a, b| {
+        b.score
+            .partial_cmp(&a.score)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    Ok(candidates)
+}
+
+pub fn publish_report() -> Result<(), BotError> {
+    let mut candidates = rank_candidates()?;
+    candidates.truncate(REPORTED_CANDIDATES);
+    if let Some(section) = stats::section("expansion") {
+        section.set(
+            "report",
+            &ExpansionReport {
+                tick: screeps::game::time(),
+                candidates,
+            },
+        );
+    }
+    Ok(())
+}
+
+/// `*` after a mineral we don't have, `$` after one we keep buying compounds of.
+fn mineral_marks(c: &ExpansionCandidate) -> String {
+    let mut marks = String::new();
+    if c.mineral_missing {
+        marks.push('*');
+    }
+    if c.mineral_imported {
+        marks.push('$');
+    }
+    marks
+}
+
+pub fn format_table(candidates: &[ExpansionCandidate]) -> String {
+    let mut out = format!(
+        "{:<8} {:>6} {:>4} {:>8} {:>4} {:>7} {:>5}\n",
+        "room", "score", "src", "mineral", "dist", "hostile", "open"
+    );
+    for c in candidates {
+        out.push_str(&format!(
+            "{:<8} {:>6.1} {:>4} {:>8} {:>4} {:>7} {:>5}\n",
+            c.room.to_string(),
+            c.score,
+            c.sources,
+            c.mineral
+                .map(|m| format!("{:?}{}", m, mineral_marks(c)))
+                .unwrap_or_else(|| "-".to_owned()),
+            c.distance,
+            c.hostile_neighbors,
+            c.open_area
+        ));
+    }
+    out
+}
+
+pub fn print_candidates() {
+    match rank_candidates() {
+        Ok(mut candidates) => {
+            candidates.truncate(REPORTED_CANDIDATES);
+            info!("expansion candidates:\n{}", format_table(&candidates));
+        }
+        Err(e) => warn!("couldn't rank expansion candidates: {}", e),
+    }
+}
+
+/// Pioneers kept working on a claimed room until its first spawn stands.
+const PIONEERS: u32 = 2;
+const PIONEER_PRIORITY: u32 = 40;
+
+/// The closest owned room with a spawn, which owns the pioneers for `room`.
+fn parent_of(room: RoomName, owned: &[RoomName]) -> Option<RoomName> {
+    owned
+        .iter()
+        .filter(|o| **o != room)
+        .filter(|o| {
+            screeps::game::rooms::get(**o).map_or(false, |r| !r.find(find::MY_SPAWNS).is_empty())
+        })
+        .filter_map(|o| coord::distance(*o, room).map(|d| (d, *o)))
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, o)| o)
+}
+
+/// Keeps `PIONEERS` pioneers queued in a parent room for every claimed room without a spawn.
+/// They're counted by their `target_room`, not by the room they're in, so the parent doesn't
+/// take them for dead once they leave.
+pub fn run_pioneers() -> Result<(), BotError> {
+    let owned = owned_rooms();
+    for room in &owned {
+        let claimed = match screeps::game::rooms::get(*room) {
+            Some(r) => r,
+            None => continue,
+        };
+        if !claimed.find(find::MY_SPAWNS).is_empty() {
+            continue;
+        }
+        let parent = match parent_of(*room, &owned) {
+            Some(p) => p,
+            None => continue,
+        };
+        let target = room.to_string();
+        let alive = screeps::game::creeps::values()
+            .iter()
+            .filter(|c| population::role_of(c) == Role::Pioneer)
+            .filter(|c| memory::creep_target(c).as_deref() == Some(&target))
+            .count() as u32;
+        let mut mem = memory::get_room_memory(parent)?;
+        for slot in alive..PIONEERS {
+            mem.enqueue(SpawnRequest {
+                enqueued: screeps::game::time(),
+                dedupe: Some(format!("Pioneer-{}-{}", room, slot)),
+                target_room: Some(target.clone()),
+                ..SpawnRequest::new(Role::Pioneer, PIONEER_PRIORITY)
+            });
+        }
+        memory::set_room_memory(parent, &mem)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,559 @@
+use std::{cell::RefCell, collections::HashSet};
+
+use log::*;
+use screeps::{prelude::*, Creep, Position, RoomName};
+use serde::{Deserialize, Serialize};
+use stdweb::{js_deserializable, js_serializable};
+
+use crate::{
+    coord, error::BotError, intents, memory, population, presets, role::Role, route, settings,
+    spawn::SpawnRequest,
+};
+
+/// Followers further than this from the leader make it wait.
+const MAX_SPREAD: u32 = 2;
+/// Ticks the leader waits for stragglers before moving on anyway.
+const REGROUP_TIMEOUT: u32 = 20;
+/// Ticks a missing leader is waited for before another member takes over.
+const LEADER_TIMEOUT: u32 = 5;
+/// Members within this range of the rally point count as there.
+const RALLY_RANGE: u32 = 3;
+/// Default for `Memory.settings.squad_form_timeout`: ticks a re-forming squad waits for its
+/// replacements before going back in with what it has, as long as that's viable.
+const FORM_TIMEOUT: u32 = 1500;
+const REPLACEMENT_PRIORITY: u32 = 60;
+
+/// Where a squad is in its operation. Kept with the group, so a global reset mid-fight carries
+/// on from the same step.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SquadState {
+    /// Waiting at the rally point for the whole roster.
+    Forming,
+    /// Heading for the target, the leader waiting for stragglers.
+    Moving,
+    /// At the target; members fight on their own logic.
+    Engaged,
+    /// No longer viable; heading back to the rally point the way it came.
+    Retreating,
+}
+
+impl Default for SquadState {
+    fn default() -> SquadState {
+        SquadState::Moving
+    }
+}
+
+/// Memory for a set of creeps moving together, stored in `Memory.groups` by group id. Each
+/// member has the id under `group` in its own memory.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Group {
+    pub leader: String,
+    pub members: Vec<String>,
+    /// Packed destination and the range to it that counts as arrived.
+    #[serde(default)]
+    pub target: Option<u32>,
+    #[serde(default)]
+    pub range: u32,
+    /// Where the leader stood last tick; followers step onto it.
+    #[serde(default)]
+    pub leader_prev: Option<u32>,
+    #[serde(default)]
+    pub leader_seen: u32,
+    #[serde(default)]
+    pub waiting_since: Option<u32>,
+    #[serde(default)]
+    pub state: SquadState,
+    #[serde(default)]
+    pub state_since: u32,
+    /// Squad type, which sets the composition it needs to stay viable; see `viable`.
+    #[serde(default)]
+    pub kind: Option<String>,
+    /// Roles the squad set out with, which replacements are queued to restore.
+    #[serde(default)]
+    pub roster: Vec<Role>,
+    /// Packed position the squad set out from and re-forms at.
+    #[serde(default)]
+    pub rally: Option<u32>,
+    /// Room that spawns the replacements.
+    #[serde(default)]
+    pub home: Option<String>,
+    /// Rooms the leader has passed through since leaving the rally room, in order.
+    #[serde(default)]
+    pub inbound: Vec<String>,
+}
+
+js_serializable!(Group);
+js_deserializable!(Group);
+
+thread_local! {
+    static IN_TRANSIT: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+}
+
+/// Whether the group movement phase already moved this creep this tick.
+pub fn in_transit(name: &str) -> bool {
+    IN_TRANSIT.with(|t| t.borrow().contains(name))
+}
+
+fn load(id: &str) -> Result<Option<Group>, BotError> {
+    memory::groups()?
+        .get::<Group>(id)
+        .map_err(|e| BotError::Deserialize {
+            target: "Group",
+            source: e.to_string(),
+        })
+}
+
+fn store(id: &str, group: &Group) -> Result<(), BotError> {
+    memory::groups()?.set(id, group);
+    Ok(())
+}
+
+fn creep_group(creep: &Creep) -> Option<String> {
+    memory::creep_memory(creep).string("group").ok().flatten()
+}
+
+/// Adds a creep to a group, creating the group with the creep as leader if needed.
+pub fn join(id: String, name: String) {
+    let creep = match screeps::game::creeps::get(&name) {
+        Some(c) => c,
+        None => {
+            warn!("can't add {} to group {}: no such creep", name, id);
+            return;
+        }
+    };
+    if let Some(old) = creep_group(&creep) {
+        if old != id {
+            leave(name.clone());
+        }
+    }
+    let res = load(&id).and_then(|group| {
+        let mut group = group.unwrap_or_else(|| Group {
+            leader: name.clone(),
+            leader_seen: screeps::game::time(),
+            ..Group::default()
+        });
+        if !group.members.contains(&name) {
+            group.members.push(name.clone());
+        }
+        store(&id, &group)
+    });
+    match res {
+        Ok(()) => memory::creep_memory(&creep).set("group", id.as_str()),
+        Err(e) => warn!("couldn't add {} to group {}: {}", name, id, e),
+    }
+}
+
+pub fn leave(name: String) {
+    let creep = screeps::game::creeps::get(&name);
+    let id = match creep.as_ref().and_then(creep_group) {
+        Some(id) => id,
+        None => return,
+    };
+    if let Some(creep) = creep {
+        memory::creep_memory(&creep).del("group");
+    }
+    let res = load(&id).and_then(|group| match group {
+        Some(mut group) => {
+            group.members.retain(|m| *m != name);
+            if group.members.is_empty() {
+                memory::groups()?.del(&id);
+                return Ok(());
+            }
+            if group.leader == name {
+                group.leader = group.members[0].clone();
+            }
+            store(&id, &group)
+        }
+        None => Ok(()),
+    });
+    if let Err(e) = res {
+        warn!("couldn't remove {} from group {}: {}", name, id, e);
+    }
+}
+
+pub fn disband(id: String) {
+    let members = match load(&id) {
+        Ok(Some(group)) => group.members,
+        Ok(None) => return,
+        Err(e) => {
+            warn!("couldn't disband group {}: {}", id, e);
+            return;
+        }
+    };
+    for name in members {
+        if let Some(creep) = screeps::game::creeps::get(&name) {
+            memory::creep_memory(&creep).del("group");
+        }
+    }
+    if let Ok(groups) = memory::groups() {
+        groups.del(&id);
+    }
+}
+
+/// Sends a group to `x`,`y` in `room`; members resume their normal logic once within `range`.
+pub fn move_group(id: String, room: String, x: u32, y: u32, range: u32) {
+    let room: RoomName = match room.parse() {
+        Ok(r) => r,
+        Err(_) => {
+            warn!("can't move group {}: bad room name {}", id, room);
+            return;
+        }
+    };
+    let res = load(&id).and_then(|group| match group {
+        Some(mut group) => {
+            group.target = Some(Position::new(x, y, room).packed_repr());
+            group.range = range;
+            group.waiting_since = None;
+            set_out(&mut group);
+            store(&id, &group)
+        }
+        None => Ok(()),
+    });
+    if let Err(e) = res {
+        warn!("couldn't move group {}: {}", id, e);
+    }
+}
+
+/// Sets the squad type of a group, or clears it with an empty `kind`.
+pub fn set_squad(id: String, kind: String) {
+    let res = load(&id).and_then(|group| match group {
+        Some(mut group) => {
+            group.kind = if kind.is_empty() { None } else { Some(kind.clone()) };
+            store(&id, &group)
+        }
+        None => Ok(()),
+    });
+    if let Err(e) = res {
+        warn!("couldn't set the squad type of group {}: {}", id, e);
+    }
+}
+
+/// Adds a creep being spawned to a group; it walks to the rally point once it's out.
+pub fn enlist(id: &str, name: &str) -> Result<(), BotError> {
+    let mut group = match load(id)? {
+        Some(g) => g,
+        None => return Ok(()),
+    };
+    if !group.members.iter().any(|m| m == name) {
+        group.members.push(name.to_owned());
+    }
+    memory::creep_memory_by_name(name)?.set("group", id);
+    store(id, &group)
+}
+
+fn living(group: &Group) -> Vec<Creep> {
+    group
+        .members
+        .iter()
+        .filter_map(|m| screeps::game::creeps::get(m))
+        .collect()
+}
+
+fn enter(group: &mut Group, state: SquadState) {
+    group.state = state;
+    group.state_since = screeps::game::time();
+    group.waiting_since = None;
+}
+
+/// Starts the group off towards its target from where the leader stands, taking the roster
+/// and rally point from the group as it is now.
+fn set_out(group: &mut Group) {
+    let members = living(group);
+    if group.roster.is_empty() {
+        group.roster = members.iter().map(population::role_of).collect();
+    }
+    if let Some(leader) = members.iter().find(|c| c.name() == group.leader) {
+        if group.rally.is_none() {
+            group.rally = Some(leader.pos().packed_repr());
+        }
+        if group.home.is_none() {
+            group.home = memory::creep_home(leader).map(|r| r.to_string());
+        }
+    }
+    group.inbound.clear();
+    enter(group, SquadState::Moving);
+}
+
+fn default_composition(kind: &str) -> Option<&'static str> {
+    match kind {
+        "dismantle" => Some("WH"),
+        "attack" => Some("AH"),
+        "ranged" => Some("RH"),
+        _ => None,
+    }
+}
+
+/// Whether the living members still make up the squad: between them they need the active parts
+/// in `Memory.settings.squad_<kind>`, a body template such as `WH` for a dismantler and its
+/// healer. Groups without a squad type are viable while anyone is left.
+fn viable(group: &Group, members: &[Creep]) -> bool {
+    if members.is_empty() {
+        return false;
+    }
+    let kind = match &group.kind {
+        Some(k) => k,
+        None => return true,
+    };
+    let template = settings::string(&format!("squad_{}", kind))
+        .or_else(|| default_composition(kind).map(str::to_owned));
+    let parts = match template.map(|t| presets::parse(&t)) {
+        Some(Ok(parts)) => parts,
+        Some(Err(e)) => {
+            warn!("bad composition for squad type {}: {}", kind, e);
+            return true;
+        }
+        None => return true,
+    };
+    parts.iter().all(|part| {
+        let need = parts.iter().filter(|p| *p == part).count() as u32;
+        let have: u32 = members.iter().map(|c| c.get_active_bodyparts(*part)).sum();
+        have >= need
+    })
+}
+
+/// Roles in the roster with no living member to fill them.
+fn missing_roles(group: &Group, members: &[Creep]) -> Vec<Role> {
+    let mut have: Vec<Role> = members.iter().map(population::role_of).collect();
+    let mut missing = Vec::new();
+    for role in &group.roster {
+        match have.iter().position(|r| r == role) {
+            Some(i) => {
+                have.remove(i);
+            }
+            None => missing.push(*role),
+        }
+    }
+    missing
+}
+
+/// Queues the squad's missing members at its home room.
+fn queue_replacements(id: &str, group: &Group, members: &[Creep]) -> Result<(), BotError> {
+    let home: RoomName = match group.home.as_ref().and_then(|h| h.parse().ok()) {
+        Some(h) => h,
+        None => return Ok(()),
+    };
+    let missing = missing_roles(group, members);
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let now = screeps::game::time();
+    let mut mem = memory::get_room_memory(home)?;
+    for (i, role) in missing.iter().enumerate() {
+        mem.enqueue(SpawnRequest {
+            enqueued: now,
+            dedupe: Some(format!("squad-{}-{}", id, i)),
+            group: Some(id.to_owned()),
+            ..SpawnRequest::new(*role, REPLACEMENT_PRIORITY)
+        });
+    }
+    info!("group {} queued {} replacements at {}", id, missing.len(), home);
+    memory::set_room_memory(home, &mem)
+}
+
+/// Where a retreating leader heads next: back into the room it entered its current one from,
+/// and once in the rally room, the rally point itself.
+fn retreat_target(group: &Group, leader: &Creep, rally: Position) -> (Position, u32) {
+    let here = leader.pos().room_name().to_string();
+    if leader.pos().room_name() != rally.room_name() {
+        if let Some(i) = group.inbound.iter().position(|r| *r == here) {
+            if let Some(back) = i.checked_sub(1).and_then(|j| group.inbound[j].parse().ok()) {
+                return (Position::new(25, 25, back), 23);
+            }
+        }
+    }
+    (rally, RALLY_RANGE)
+}
+
+fn step_leader(
+    leader: &Creep,
+    group: &mut Group,
+    followers: &[Creep],
+    target: Position,
+    range: u32,
+) {
+    let now = screeps::game::time();
+    let straggling = followers
+        .iter()
+        .any(|f| coord::range(f.pos(), leader.pos()) > MAX_SPREAD || f.fatigue() > 0);
+    if straggling {
+        let since = *group.waiting_since.get_or_insert(now);
+        if now - since < REGROUP_TIMEOUT {
+            return;
+        }
+        debug!("group led by {} gave up waiting for stragglers", leader.name());
+    }
+    group.waiting_since = None;
+
+    if let Some(next) = route::next_step(leader.pos(), target, range) {
+        if let Some(dir) = coord::direction(leader.pos(), next) {
+            group.leader_prev = Some(leader.pos().packed_repr());
+            intents::issue("group", "move_direction", &next.to_string(), None, || {
+                leader.move_direction(dir)
+            });
+        }
+    }
+}
+
+/// Holds the squad at its rally point until the roster is back and everyone is there, then
+/// sends it back in. Members still on their way walk to the rally point.
+fn form(id: &str, group: &mut Group, members: &[Creep]) {
+    let now = screeps::game::time();
+    let rally = match group.rally {
+        Some(r) => Position::from_packed(r),
+        None => {
+            enter(group, SquadState::Moving);
+            return;
+        }
+    };
+    let waited = now.saturating_sub(group.state_since);
+    let full = missing_roles(group, members).is_empty();
+    let timed_out = waited >= settings::u32_or("squad_form_timeout", FORM_TIMEOUT);
+    let gathered = members
+        .iter()
+        .all(|c| !c.spawning() && coord::in_range(c.pos(), rally, RALLY_RANGE));
+    if gathered && (full || timed_out) && viable(group, members) && group.target.is_some() {
+        info!("group {} re-formed after {} ticks, going back in", id, waited);
+        set_out(group);
+        return;
+    }
+    IN_TRANSIT.with(|t| {
+        let mut t = t.borrow_mut();
+        for member in members.iter().filter(|c| !c.spawning()) {
+            t.insert(member.name());
+            if !coord::in_range(member.pos(), rally, RALLY_RANGE) {
+                intents::issue("group", "move_to", &rally.to_string(), None, || {
+                    route::move_to(member, rally)
+                });
+            }
+        }
+    });
+}
+
+fn run_group(id: &str, group: &mut Group) -> bool {
+    let now = screeps::game::time();
+    // the dead never make anyone wait
+    let leader_name = group.leader.clone();
+    group
+        .members
+        .retain(|m| *m == leader_name || screeps::game::creeps::get(m).is_some());
+
+    let members = living(group);
+    let fighting = group.state == SquadState::Moving || group.state == SquadState::Engaged;
+    if fighting && group.target.is_some() && !viable(group, &members) {
+        warn!("group {} is no longer viable, retreating to its rally point", id);
+        enter(group, SquadState::Retreating);
+        if let Err(e) = queue_replacements(id, group, &members) {
+            warn!("couldn't queue replacements for group {}: {}", id, e);
+        }
+    }
+
+    let leader = match screeps::game::creeps::get(&group.leader) {
+        Some(l) => {
+            group.leader_seen = now;
+            l
+        }
+        None => {
+            if now - group.leader_seen >= LEADER_TIMEOUT {
+                let old = group.leader.clone();
+                group.members.retain(|m| *m != old);
+                match group.members.first() {
+                    Some(new) => {
+                        info!("group leader {} is gone, promoting {}", old, new);
+                        group.leader = new.clone();
+                        group.leader_seen = now;
+                        group.leader_prev = None;
+                    }
+                    None => return false,
+                }
+            }
+            return true;
+        }
+    };
+
+    let (target, range) = match group.state {
+        SquadState::Engaged => return true,
+        SquadState::Forming => {
+            form(id, group, &members);
+            return true;
+        }
+        SquadState::Moving => match group.target {
+            Some(t) => (Position::from_packed(t), group.range),
+            None => return true,
+        },
+        SquadState::Retreating => {
+            let rally = match group.rally {
+                Some(r) => Position::from_packed(r),
+                None => leader.pos(),
+            };
+            if coord::in_range(leader.pos(), rally, RALLY_RANGE) {
+                info!("group {} is back at its rally point, re-forming", id);
+                group.inbound.clear();
+                enter(group, SquadState::Forming);
+                return true;
+            }
+            retreat_target(group, &leader, rally)
+        }
+    };
+    if group.state == SquadState::Moving {
+        if coord::in_range(leader.pos(), target, range) {
+            enter(group, SquadState::Engaged);
+            return true;
+        }
+        let here = leader.pos().room_name().to_string();
+        if group.inbound.last() != Some(&here) {
+            group.inbound.push(here);
+        }
+    }
+
+    let followers: Vec<Creep> = group
+        .members
+        .iter()
+        .filter(|m| **m != group.leader)
+        .filter_map(|m| screeps::game::creeps::get(m))
+        .filter(|c| !c.spawning())
+        .collect();
+    let prev = group.leader_prev.map(Position::from_packed);
+    step_leader(&leader, group, &followers, target, range);
+
+    IN_TRANSIT.with(|t| {
+        let mut t = t.borrow_mut();
+        t.insert(leader.name());
+        for follower in &followers {
+            t.insert(follower.name());
+            match prev {
+                Some(p) if follower.pos() != p => {
+                    intents::issue("group", "move_to", &p.to_string(), None, || {
+                        route::move_to(follower, p)
+                    });
+                }
+                Some(_) => {}
+                None => {
+                    intents::issue("group", "move_to", &leader.name(), None, || {
+                        route::move_to(follower, leader.pos())
+                    });
+                }
+            }
+        }
+    });
+    true
+}
+
+/// Moves every group that has a destination, and pulls squads that lost too many members back
+/// to re-form. Groups with no members left are dropped.
+pub fn run_groups() -> Result<(), BotError> {
+    IN_TRANSIT.with(|t| t.borrow_mut().clear());
+    let groups = memory::groups()?;
+    for id in groups.keys() {
+        let mut group = match load(&id)? {
+            Some(g) => g,
+            None => continue,
+        };
+        if run_group(&id, &mut group) {
+            store(&id, &group)?;
+        } else {
+            debug!("group {} has no members left", id);
+            groups.del(&id);
+        }
+    }
+    Ok(())
+}
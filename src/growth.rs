@@ -0,0 +1,204 @@
+use log::*;
+use screeps::{find, prelude::*, Room};
+use serde::{Deserialize, Serialize};
+
+use crate::{phase, presets, room::RoomMemory, stats};
+
+/// Ticks per half of the rolling window; reports cover the last one or two halves.
+const WINDOW_TICKS: u32 = 1500;
+/// Ticks a spawn takes per body part.
+const SPAWN_TICKS_PER_PART: u32 = 3;
+/// Spawns busy at least this share of the time have nothing left to give.
+const SPAWN_SATURATED: f64 = 0.9;
+/// Share of ticks the creep phase had to defer creeps before CPU is the limit.
+const CPU_SATURATED: f64 = 0.1;
+const REPORT_TICKS: u32 = 1000;
+const STATS_TICKS: u32 = 100;
+
+/// Sums over part of the window.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct GrowthTotals {
+    #[serde(default)]
+    pub ticks: u32,
+    /// Spawn-ticks spent spawning, and spawn-ticks there were.
+    #[serde(default)]
+    pub spawn_busy: u32,
+    #[serde(default)]
+    pub spawn_slots: u32,
+    /// Controller progress gained.
+    #[serde(default)]
+    pub progress: u32,
+    /// Ticks the creep phase ran out of CPU.
+    #[serde(default)]
+    pub deferred: u32,
+}
+
+impl GrowthTotals {
+    fn add(&self, other: &GrowthTotals) -> GrowthTotals {
+        GrowthTotals {
+            ticks: self.ticks + other.ticks,
+            spawn_busy: self.spawn_busy + other.spawn_busy,
+            spawn_slots: self.spawn_slots + other.spawn_slots,
+            progress: self.progress + other.progress,
+            deferred: self.deferred + other.deferred,
+        }
+    }
+}
+
+/// Rolling record of how the room is growing, kept in `RoomMemory`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct GrowthWindow {
+    #[serde(default)]
+    pub current: GrowthTotals,
+    #[serde(default)]
+    pub previous: GrowthTotals,
+    /// Controller level and progress as of last tick.
+    #[serde(default)]
+    pub level: u32,
+    #[serde(default)]
+    pub progress: u32,
+}
+
+/// What's holding the room's growth back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bound {
+    Spawn,
+    Energy,
+    Cpu,
+}
+
+impl Bound {
+    fn label(self) -> &'static str {
+        match self {
+            Bound::Spawn => "spawn-bound",
+            Bound::Energy => "energy-bound",
+            Bound::Cpu => "CPU-bound",
+        }
+    }
+}
+
+pub struct GrowthReport {
+    pub level: u32,
+    /// Share of spawn time spent spawning.
+    pub utilization: f64,
+    /// Controller progress per tick.
+    pub rate: f64,
+    /// Spawn time the queue needs, and what the spawns have free over the next window.
+    pub queued: u32,
+    pub spare: u32,
+    /// Ticks to the next level at the current rate; `None` at RCL 8 or with no progress.
+    pub eta: Option<u32>,
+    pub bound: Bound,
+}
+
+/// Adds this tick to the window: how many spawns were busy, the controller progress since last
+/// tick, and whether the last creep phase ran out of CPU.
+pub fn sample(room: &Room, mem: &mut RoomMemory) {
+    let now = screeps::game::time();
+    let growth = &mut mem.growth;
+    if growth.current.ticks >= WINDOW_TICKS {
+        growth.previous = std::mem::take(&mut growth.current);
+    }
+    let totals = &mut growth.current;
+    totals.ticks += 1;
+    let spawns = room.find(find::MY_SPAWNS);
+    totals.spawn_slots += spawns.len() as u32;
+    totals.spawn_busy += spawns.iter().filter(|s| s.spawning().is_some()).count() as u32;
+    if phase::deferred_at() == Some(now.saturating_sub(1)) {
+        totals.deferred += 1;
+    }
+    if let Some(c) = room.controller() {
+        // a level up starts progress over
+        if c.level() == growth.level {
+            totals.progress += c.progress().saturating_sub(growth.progress);
+        }
+        growth.level = c.level();
+        growth.progress = c.progress();
+    }
+}
+
+pub fn report(room: &Room, mem: &RoomMemory) -> GrowthReport {
+    let totals = mem.growth.current.add(&mem.growth.previous);
+    let ticks = totals.ticks.max(1);
+    let utilization = totals.spawn_busy as f64 / totals.spawn_slots.max(1) as f64;
+    let rate = totals.progress as f64 / ticks as f64;
+    let capacity = room.energy_capacity_available();
+    let queued = mem
+        .spawn_queue
+        .iter()
+        .map(|r| {
+            let body = presets::body(room.name(), r.role, r.design_energy(capacity), capacity);
+            body.len() as u32 * SPAWN_TICKS_PER_PART
+        })
+        .sum();
+    let spawns = room.find(find::MY_SPAWNS).len() as f64;
+    let spare = (spawns * WINDOW_TICKS as f64 * (1.0 - utilization)) as u32;
+    let (level, eta) = match room.controller() {
+        Some(c) if c.level() < 8 && rate > 0.0 => {
+            let left = c.progress_total().saturating_sub(c.progress());
+            (c.level(), Some((left as f64 / rate) as u32))
+        }
+        Some(c) => (c.level(), None),
+        None => (0, None),
+    };
+    let bound = if totals.deferred as f64 / ticks as f64 >= CPU_SATURATED {
+        Bound::Cpu
+    } else if utilization >= SPAWN_SATURATED || queued > spare {
+        Bound::Spawn
+    } else {
+        Bound::Energy
+    };
+    GrowthReport {
+        level,
+        utilization,
+        rate,
+        queued,
+        spare,
+        eta,
+        bound,
+    }
+}
+
+/// Short form for the dashboard.
+pub fn summary(report: &GrowthReport) -> String {
+    let eta = match report.eta {
+        Some(t) => format!("RCL {} in {}", report.level + 1, t),
+        None => "no RCL ETA".to_owned(),
+    };
+    format!(
+        "{}  spawns {:.0}%  {}",
+        eta,
+        report.utilization * 100.0,
+        report.bound.label()
+    )
+}
+
+/// Publishes the report to `Memory.stats.growth.<room>` every `STATS_TICKS`, and logs it
+/// every `REPORT_TICKS`.
+pub fn run_growth(room: &Room, mem: &RoomMemory) {
+    let now = screeps::game::time();
+    if now % STATS_TICKS != 61 {
+        return;
+    }
+    let report = report(room, mem);
+    let name = room.name().to_string();
+    let dict = stats::section("growth").and_then(|s| s.dict_or_create(&name).ok());
+    if let Some(dict) = dict {
+        dict.set("spawn_utilization", (report.utilization * 100.0) as i32);
+        dict.set("progress_rate", report.rate);
+        dict.set("queued_body_ticks", report.queued as i32);
+        dict.set("spare_body_ticks", report.spare as i32);
+        dict.set("eta", report.eta.map_or(-1, |t| t as i32));
+        dict.set("bound", report.bound.label());
+    }
+    if now % REPORT_TICKS == 61 {
+        info!(
+            "{} colony: {}, {:.1} progress/tick, {} body-ticks queued against {} spare",
+            room.name(),
+            summary(&report),
+            report.rate,
+            report.queued,
+            report.spare
+        );
+    }
+}
@@ -0,0 +1,250 @@
+use log::*;
+use screeps::raw_memory;
+use stdweb::{js, unstable::TryInto};
+
+use crate::intents;
+
+/// RawMemory segment holding the history; nothing else uses it.
+const SEGMENT: u32 = 90;
+/// Segments hold at most 100KB; oldest records are dropped to stay under it.
+const MAX_SEGMENT_CHARS: usize = 100 * 1024;
+/// The game loads at most this many segments a tick.
+const MAX_ACTIVE_SEGMENTS: usize = 10;
+const SAMPLE_TICKS: u32 = 100;
+
+/// One history record. Encoded little-endian behind a u16 length: tick u32, centi-CPU u16,
+/// bucket u16, creeps u16, room count u8, then per room a length-prefixed name and a u32
+/// energy.
+struct Sample {
+    tick: u32,
+    cpu: f64,
+    bucket: u32,
+    creeps: u32,
+    rooms: Vec<(String, u32)>,
+}
+
+fn take_sample() -> Sample {
+    let rooms = screeps::game::rooms::values()
+        .into_iter()
+        .filter(|r| r.controller().map(|c| c.my()).unwrap_or(false))
+        .map(|r| (r.name().to_string(), r.energy_available()))
+        .collect();
+    Sample {
+        tick: screeps::game::time(),
+        cpu: screeps::game::cpu::get_used(),
+        bucket: screeps::game::cpu::bucket() as u32,
+        creeps: screeps::game::creeps::keys().len() as u32,
+        rooms,
+    }
+}
+
+fn encode(sample: &Sample) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&sample.tick.to_le_bytes());
+    body.extend_from_slice(&((sample.cpu * 100.0).min(65535.0) as u16).to_le_bytes());
+    body.extend_from_slice(&(sample.bucket.min(65535) as u16).to_le_bytes());
+    body.extend_from_slice(&(sample.creeps.min(65535) as u16).to_le_bytes());
+    body.push(sample.rooms.len().min(255) as u8);
+    for (name, energy) in sample.rooms.iter().take(255) {
+        body.push(name.len() as u8);
+        body.extend_from_slice(name.as_bytes());
+        body.extend_from_slice(&energy.to_le_bytes());
+    }
+    let mut record = (body.len() as u16).to_le_bytes().to_vec();
+    record.extend(body);
+    record
+}
+
+/// Reads an `n`-byte little-endian integer at `*at`, advancing it.
+fn read(bytes: &[u8], at: &mut usize, n: usize) -> Option<u32> {
+    let slice = bytes.get(*at..*at + n)?;
+    *at += n;
+    Some(slice.iter().rev().fold(0u32, |acc, b| (acc << 8) | *b as u32))
+}
+
+fn decode(body: &[u8]) -> Option<Sample> {
+    let mut at = 0;
+    let tick = read(body, &mut at, 4)?;
+    let cpu = read(body, &mut at, 2)? as f64 / 100.0;
+    let bucket = read(body, &mut at, 2)?;
+    let creeps = read(body, &mut at, 2)?;
+    let count = read(body, &mut at, 1)?;
+    let mut rooms = Vec::new();
+    for _ in 0..count {
+        let len = read(body, &mut at, 1)? as usize;
+        let name = String::from_utf8(body.get(at..at + len)?.to_vec()).ok()?;
+        at += len;
+        rooms.push((name, read(body, &mut at, 4)?));
+    }
+    Some(Sample {
+        tick,
+        cpu,
+        bucket,
+        creeps,
+        rooms,
+    })
+}
+
+/// Splits segment bytes into record bodies, oldest first.
+fn records(bytes: &[u8]) -> Vec<&[u8]> {
+    let mut out = Vec::new();
+    let mut at = 0;
+    while let Some(len) = read(bytes, &mut at, 2) {
+        match bytes.get(at..at + len as usize) {
+            Some(body) => out.push(body),
+            None => break,
+        }
+        at += len as usize;
+    }
+    out
+}
+
+/// Appends `record` to the ring in `bytes`, dropping the oldest records until it fits in
+/// `max` bytes.
+fn append(bytes: &mut Vec<u8>, record: Vec<u8>, max: usize) {
+    while !bytes.is_empty() && bytes.len() + record.len() > max {
+        let mut at = 0;
+        let len = read(bytes, &mut at, 2).unwrap_or(0) as usize;
+        let drop = (2 + len).min(bytes.len());
+        bytes.drain(..drop);
+    }
+    bytes.extend(record);
+}
+
+/// `loaded` with `SEGMENT` added, keeping within what the game loads at once.
+fn with_segment(mut loaded: Vec<u32>) -> Vec<u32> {
+    if !loaded.contains(&SEGMENT) {
+        loaded.truncate(MAX_ACTIVE_SEGMENTS - 1);
+        loaded.push(SEGMENT);
+    }
+    loaded
+}
+
+/// Asks for `SEGMENT` next tick alongside whatever segments are loaded now, so other users of
+/// `RawMemory` keep theirs.
+fn request_segment() {
+    let raw: String = js! {
+        return JSON.stringify(Object.keys(RawMemory.segments).map(Number));
+    }
+    .try_into()
+    .unwrap_or_default();
+    let loaded: Vec<u32> = serde_json::from_str(&raw).unwrap_or_default();
+    raw_memory::set_active_segments(&with_segment(loaded));
+}
+
+// segments are strings, so each byte is stored as one char in U+0000..U+00FF
+fn to_segment(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| *b as char).collect()
+}
+
+fn from_segment(data: &str) -> Vec<u8> {
+    data.chars().map(|c| c as u32 as u8).collect()
+}
+
+/// Appends a sample every `SAMPLE_TICKS`. The segment is requested the tick before; if it
+/// still isn't loaded the sample is skipped. Dry runs write nothing.
+pub fn run_history() {
+    if intents::dry_run() {
+        return;
+    }
+    let now = screeps::game::time();
+    if now % SAMPLE_TICKS == SAMPLE_TICKS - 1 {
+        request_segment();
+        return;
+    }
+    if now % SAMPLE_TICKS != 0 {
+        return;
+    }
+    let data = match raw_memory::get_segment(SEGMENT) {
+        Some(d) => d,
+        None => return,
+    };
+    let mut bytes = from_segment(&data);
+    append(&mut bytes, encode(&take_sample()), MAX_SEGMENT_CHARS);
+    raw_memory::set_segment(SEGMENT, &to_segment(&bytes));
+}
+
+/// Console command: logs the last `n` samples as a table.
+pub fn print_history(n: u32) {
+    let data = match raw_memory::get_segment(SEGMENT) {
+        Some(d) => d,
+        None => {
+            request_segment();
+            info!("stats history segment isn't loaded this tick, try again next tick");
+            return;
+        }
+    };
+    let bytes = from_segment(&data);
+    let samples: Vec<Sample> = records(&bytes).into_iter().filter_map(decode).collect();
+    let skip = samples.len().saturating_sub(n as usize);
+    let mut out = format!("{:>9} {:>6} {:>6} {:>6}  energy\n", "tick", "cpu", "bucket", "creeps");
+    for s in &samples[skip..] {
+        let rooms: Vec<String> = s
+            .rooms
+            .iter()
+            .map(|(name, energy)| format!("{} {}", name, energy))
+            .collect();
+        out.push_str(&format!(
+            "{:>9} {:>6.2} {:>6} {:>6}  {}\n",
+            s.tick,
+            s.cpu,
+            s.bucket,
+            s.creeps,
+            rooms.join(", ")
+        ));
+    }
+    info!("stats history:\n{}", out);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(tick: u32) -> Sample {
+        Sample {
+            tick,
+            cpu: 12.5,
+            bucket: 9000,
+            creeps: 14,
+            rooms: vec![("W1N1".to_owned(), 550), ("W2N1".to_owned(), 300)],
+        }
+    }
+
+    #[test]
+    fn samples_survive_the_segment_round_trip() {
+        let mut bytes = Vec::new();
+        append(&mut bytes, encode(&sample(100)), MAX_SEGMENT_CHARS);
+        append(&mut bytes, encode(&sample(200)), MAX_SEGMENT_CHARS);
+        let bytes = from_segment(&to_segment(&bytes));
+
+        let samples: Vec<Sample> = records(&bytes).into_iter().filter_map(decode).collect();
+        assert_eq!(samples.len(), 2);
+        let s = &samples[1];
+        assert_eq!((s.tick, s.bucket, s.creeps), (200, 9000, 14));
+        assert!((s.cpu - 12.5).abs() < 0.01);
+        assert_eq!(s.rooms, sample(200).rooms);
+    }
+
+    #[test]
+    fn a_full_ring_drops_its_oldest_records() {
+        let size = encode(&sample(0)).len();
+        let mut bytes = Vec::new();
+        for tick in 0..5 {
+            append(&mut bytes, encode(&sample(tick)), size * 3);
+        }
+        assert_eq!(bytes.len(), size * 3);
+        let ticks: Vec<u32> =
+            records(&bytes).into_iter().filter_map(decode).map(|s| s.tick).collect();
+        assert_eq!(ticks, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn the_history_segment_joins_the_loaded_ones() {
+        assert_eq!(with_segment(vec![3, 7]), vec![3, 7, SEGMENT]);
+        assert_eq!(with_segment(vec![SEGMENT, 3]), vec![SEGMENT, 3]);
+        let full: Vec<u32> = (0..10).collect();
+        let merged = with_segment(full);
+        assert_eq!(merged.len(), MAX_ACTIVE_SEGMENTS);
+        assert_eq!(merged.last(), Some(&SEGMENT));
+    }
+}
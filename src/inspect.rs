@@ -0,0 +1,133 @@
+use screeps::{prelude::*, Creep, RawObjectId, Room};
+use stdweb::{js, unstable::TryInto, Value};
+
+use crate::{memory, population, role::Role, tasklog};
+
+/// Task log entries `inspect` shows.
+const RECENT_TASKS: usize = 5;
+
+fn owned_rooms() -> Vec<Room> {
+    screeps::game::rooms::values()
+        .into_iter()
+        .filter(|r| r.controller().map_or(false, |c| c.my()))
+        .collect()
+}
+
+/// One room's rows of the `roles` table: creeps that call it home, counted as alive and
+/// spawning, what its spawn queue holds, and the population target.
+fn role_rows(room: &Room, creeps: &[Creep]) -> Vec<String> {
+    let mem = match memory::get_room_memory(room.name()) {
+        Ok(m) => m,
+        Err(e) => return vec![format!("{}: {}", room.name(), e)],
+    };
+    let targets = population::targets(room, &mem);
+    let mut rows = vec![
+        format!("{} ({:?})", room.name(), mem.mode),
+        format!("  {:<18}{:>6}{:>9}{:>7}{:>7}", "role", "alive", "spawning", "queued", "target"),
+    ];
+    for role in Role::ALL.iter().copied() {
+        let mine: Vec<&Creep> = creeps
+            .iter()
+            .filter(|c| memory::creep_home(c).unwrap_or_else(|| c.pos().room_name()) == room.name())
+            .filter(|c| population::role_of(c) == role)
+            .collect();
+        let spawning = mine.iter().filter(|c| c.spawning()).count();
+        let alive = mine.len() - spawning;
+        let queued = mem.spawn_queue.iter().filter(|r| r.role == role).count();
+        let target = targets.iter().find(|t| t.0 == role).map_or(0, |t| t.1) as usize;
+        if alive + spawning + queued + target == 0 {
+            continue;
+        }
+        rows.push(format!(
+            "  {:<18}{:>6}{:>9}{:>7}{:>7}",
+            format!("{:?}", role),
+            alive,
+            spawning,
+            queued,
+            target
+        ));
+    }
+    rows
+}
+
+/// Console command: `roles()` tabulates every owned room's creeps by role, `roles("W1N1")`
+/// just that room's.
+pub fn roles(room: Option<String>) -> String {
+    let mut rooms = owned_rooms();
+    if let Some(ref name) = room {
+        rooms.retain(|r| r.name().to_string().eq_ignore_ascii_case(name));
+        if rooms.is_empty() {
+            return format!("{} isn't one of our rooms", name);
+        }
+    }
+    if rooms.is_empty() {
+        return "no rooms of ours".to_owned();
+    }
+    let creeps = screeps::game::creeps::values();
+    rooms
+        .iter()
+        .flat_map(|r| role_rows(r, &creeps))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// "Source at 23,14" for an id that resolves to something we can see.
+fn describe_object(id: RawObjectId) -> Option<String> {
+    let object = screeps::game::get_object_erased(id)?;
+    let pos = object.pos();
+    let kind: String = js! {
+        var o = @{object.as_ref()};
+        return o.structureType || o.constructor.name;
+    }
+    .try_into()
+    .ok()?;
+    Some(format!("{} at {},{} in {}", kind, pos.x(), pos.y(), pos.room_name()))
+}
+
+/// Console command: `inspect("name")` shows a creep's role, home, state and ticks to live, its
+/// memory with object ids resolved, and its last few tasks when it's logging them.
+pub fn inspect(name: String) -> String {
+    let creep = match screeps::game::creeps::get(&name) {
+        Some(c) => c,
+        None => return format!("no creep named {}", name),
+    };
+    let mem = creep.memory();
+    let state = if creep.spawning() {
+        "spawning"
+    } else if mem.bool("returning") {
+        "returning home"
+    } else if mem.bool("harvesting") {
+        "collecting"
+    } else {
+        "delivering"
+    };
+    let home = memory::creep_home(&creep).map_or_else(|| "unknown".to_owned(), |r| r.to_string());
+    let mut lines = vec![
+        format!("{} ({:?}) at {}, home {}", name, population::role_of(&creep), creep.pos(), home),
+        format!("{}, {} ticks to live", state, creep.ticks_to_live()),
+    ];
+    let mut keys = mem.keys();
+    keys.sort();
+    for key in keys.iter().filter(|k| k.as_str() != "tasklog") {
+        let raw = mem.get::<Value>(key).ok().flatten().unwrap_or(Value::Null);
+        let json: String = js!(return JSON.stringify(@{&raw}) || "undefined";)
+            .try_into()
+            .unwrap_or_default();
+        let resolved = mem
+            .string(key)
+            .ok()
+            .flatten()
+            .and_then(|s| s.parse::<RawObjectId>().ok())
+            .and_then(describe_object);
+        match resolved {
+            Some(what) => lines.push(format!("  {}: {} ({})", key, json, what)),
+            None => lines.push(format!("  {}: {}", key, json)),
+        }
+    }
+    let recent = tasklog::recent(&creep, RECENT_TASKS);
+    if !recent.is_empty() {
+        lines.push("  last tasks:".to_owned());
+        lines.extend(recent.into_iter().map(|e| format!("    {}", e)));
+    }
+    lines.join("\n")
+}
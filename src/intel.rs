@@ -0,0 +1,443 @@
+use log::*;
+use screeps::{
+    find, prelude::*, Creep, Deposit, ObjectId, Part, Position, ResourceType, Room, RoomName,
+    StructureType,
+};
+use serde::{Deserialize, Serialize};
+use stdweb::{js, js_deserializable, js_serializable, unstable::TryInto};
+
+use crate::{allies, error::BotError, memory, shard::ShardPortal, terrain};
+
+/// How often a visible room's intel is refreshed.
+const REFRESH_TICKS: u32 = 500;
+/// Tiles with at least this much clearance to the nearest wall count as open building area.
+const OPEN_CLEARANCE: u8 = 3;
+/// Danger zones around hostile towers and keeper lairs.
+const TOWER_DANGER_RANGE: u8 = 10;
+const LAIR_DANGER_RANGE: u8 = 5;
+/// Danger zones from intel older than this are ignored.
+const DANGER_ZONE_MAX_AGE: u32 = 20_000;
+/// A player's creeps seen again within this many ticks are still the same attack.
+const ATTACK_GAP_TICKS: u32 = 300;
+/// Attacks are remembered, and defenders sized by them, for this long.
+pub const ATTACK_MEMORY_TICKS: u32 = 30_000;
+
+/// What we last saw of a room, stored in `Memory.intel` keyed by room name.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RoomIntel {
+    pub updated: u32,
+    #[serde(default)]
+    pub sources: u8,
+    #[serde(default)]
+    pub mineral: Option<ResourceType>,
+    #[serde(default)]
+    pub has_controller: bool,
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub reserved_by: Option<String>,
+    /// Tick the current reservation runs out.
+    #[serde(default)]
+    pub reservation_ends: Option<u32>,
+    /// Player who signed the controller, which some use to mark rooms as theirs.
+    #[serde(default)]
+    pub sign_by: Option<String>,
+    #[serde(default)]
+    pub hostile_towers: u8,
+    #[serde(default)]
+    pub keeper_lairs: u8,
+    /// Tiles with at least `OPEN_CLEARANCE` clearance, from the distance transform.
+    #[serde(default)]
+    pub open_area: u32,
+    /// `(packed position, range)` of hostile towers and keeper lairs, for pathing around them
+    /// without vision.
+    #[serde(default)]
+    pub danger_zones: Vec<(u32, u8)>,
+    #[serde(default)]
+    pub deposits: Vec<DepositIntel>,
+    #[serde(default)]
+    pub portals: Vec<PortalIntel>,
+    /// Last tick a scout ran into hostiles here that could fight.
+    #[serde(default)]
+    pub hostile_seen: Option<u32>,
+    /// Player attacks seen here over the last `ATTACK_MEMORY_TICKS`, oldest first.
+    #[serde(default)]
+    pub attacks: Vec<AttackRecord>,
+}
+
+/// One visit by a hostile player's fighting creeps.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AttackRecord {
+    pub owner: String,
+    pub first_seen: u32,
+    pub last_seen: u32,
+    /// Attack, ranged attack and heal parts on the strongest creep of the visit.
+    #[serde(default)]
+    pub combat_parts: u32,
+    /// Most of their fighting creeps seen at once.
+    #[serde(default)]
+    pub creeps: u32,
+}
+
+/// A portal as last seen. Routes never cross one.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PortalIntel {
+    pub pos: u32,
+    /// Room it leads to, on `shard` when that's set and this shard otherwise.
+    pub destination: String,
+    #[serde(default)]
+    pub shard: Option<String>,
+    /// Unset for portals that never decay.
+    #[serde(default)]
+    pub decays_at: Option<u32>,
+}
+
+/// The raw shape `portals_in` reads from JS.
+#[derive(Deserialize)]
+struct RawPortal {
+    x: u32,
+    y: u32,
+    room: String,
+    #[serde(default)]
+    shard: Option<String>,
+    #[serde(default)]
+    ticks_to_decay: Option<u32>,
+}
+
+/// Portals in a visible room. The API doesn't type inter-shard destinations, so this goes
+/// through JS.
+fn portals_in(room: &Room) -> Vec<PortalIntel> {
+    let name = room.name().to_string();
+    let raw: String = js! {
+        var room = Game.rooms[@{name}];
+        if (!room) {
+            return "[]";
+        }
+        var portals = room.find(FIND_STRUCTURES, {
+            filter: function (s) { return s.structureType === STRUCTURE_PORTAL; }
+        });
+        return JSON.stringify(portals.map(function (p) {
+            var d = p.destination;
+            return {
+                x: p.pos.x,
+                y: p.pos.y,
+                room: d.roomName || d.room,
+                shard: d.shard || null,
+                ticks_to_decay: p.ticksToDecay || null,
+            };
+        }));
+    }
+    .try_into()
+    .unwrap_or_default();
+    let portals: Vec<RawPortal> = serde_json::from_str(&raw).unwrap_or_default();
+    let now = screeps::game::time();
+    portals
+        .into_iter()
+        .map(|p| PortalIntel {
+            pos: Position::new(p.x, p.y, room.name()).packed_repr(),
+            destination: p.room,
+            shard: p.shard,
+            decays_at: p.ticks_to_decay.map(|t| now + t),
+        })
+        .collect()
+}
+
+/// A highway deposit as last seen.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DepositIntel {
+    pub id: ObjectId<Deposit>,
+    pub pos: u32,
+    pub kind: ResourceType,
+    /// Cooldown after the last harvest; grows the more the deposit has been worked.
+    pub last_cooldown: u32,
+    pub decays_at: u32,
+}
+
+impl DepositIntel {
+    pub fn of(deposit: &Deposit) -> DepositIntel {
+        DepositIntel {
+            id: deposit.id(),
+            pos: deposit.pos().packed_repr(),
+            kind: deposit.deposit_type(),
+            last_cooldown: deposit.last_cooldown(),
+            decays_at: screeps::game::time() + deposit.ticks_to_decay(),
+        }
+    }
+}
+
+js_serializable!(RoomIntel);
+js_deserializable!(RoomIntel);
+
+impl RoomIntel {
+    pub fn claimable(&self) -> bool {
+        self.has_controller
+            && self.owner.is_none()
+            && self.keeper_lairs == 0
+            && self.reserved_by.as_deref().map_or(true, |r| r == my_username().as_str())
+    }
+}
+
+pub fn my_username() -> String {
+    screeps::game::spawns::values()
+        .into_iter()
+        .next()
+        .map(|s| s.owner_name())
+        .unwrap_or_default()
+}
+
+fn observe(room: &Room, previous: Option<RoomIntel>) -> RoomIntel {
+    let controller = room.controller();
+    let structures = room.find(find::STRUCTURES);
+    let towers: Vec<u32> = room
+        .find(find::HOSTILE_STRUCTURES)
+        .iter()
+        .filter(|s| s.structure_type() == StructureType::Tower)
+        .filter(|s| s.owner_name().map_or(true, |o| allies::is_hostile(&o)))
+        .map(|s| s.pos().packed_repr())
+        .collect();
+    let lairs: Vec<u32> = structures
+        .iter()
+        .filter(|s| s.structure_type() == StructureType::KeeperLair)
+        .map(|s| s.pos().packed_repr())
+        .collect();
+    let danger_zones = towers
+        .iter()
+        .map(|p| (*p, TOWER_DANGER_RANGE))
+        .chain(lairs.iter().map(|p| (*p, LAIR_DANGER_RANGE)))
+        .collect();
+
+    // terrain never changes, so only pay for the distance transform once per room
+    let open_area = match previous {
+        Some(ref p) if p.updated > 0 => p.open_area,
+        _ => terrain::distance_transform(&terrain::walls(room.name()))
+            .iter()
+            .filter(|d| **d >= OPEN_CLEARANCE)
+            .count() as u32,
+    };
+
+    RoomIntel {
+        updated: screeps::game::time(),
+        sources: room.find(find::SOURCES).len() as u8,
+        mineral: room
+            .find(find::MINERALS)
+            .into_iter()
+            .next()
+            .map(|m| m.mineral_type()),
+        has_controller: controller.is_some(),
+        owner: controller.as_ref().and_then(|c| c.owner_name()),
+        reserved_by: controller
+            .as_ref()
+            .and_then(|c| c.reservation())
+            .map(|r| r.username),
+        reservation_ends: controller
+            .as_ref()
+            .and_then(|c| c.reservation())
+            .map(|r| screeps::game::time() + r.ticks_to_end),
+        sign_by: controller.as_ref().and_then(|c| c.sign()).map(|s| s.username),
+        hostile_towers: towers.len() as u8,
+        keeper_lairs: lairs.len() as u8,
+        open_area,
+        danger_zones,
+        deposits: room.find(find::DEPOSITS).iter().map(DepositIntel::of).collect(),
+        portals: portals_in(room),
+        hostile_seen: previous.as_ref().and_then(|p| p.hostile_seen),
+        attacks: previous.map(|p| p.attacks).unwrap_or_default(),
+    }
+}
+
+pub fn get(room: RoomName) -> Result<Option<RoomIntel>, BotError> {
+    memory::intel()?
+        .get::<RoomIntel>(&room.to_string())
+        .map_err(|e| BotError::Deserialize {
+            target: "RoomIntel",
+            source: e.to_string(),
+        })
+}
+
+/// Portal tiles in `room` from intel, leaving out portals that should have decayed.
+pub fn portals(room: RoomName) -> Vec<Position> {
+    let now = screeps::game::time();
+    match get(room) {
+        Ok(Some(i)) => i
+            .portals
+            .iter()
+            .filter(|p| p.decays_at.map_or(true, |d| d > now))
+            .map(|p| Position::from_packed(p.pos))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Every known portal to another shard, for other shards to read.
+pub fn shard_portals() -> Vec<ShardPortal> {
+    all()
+        .unwrap_or_default()
+        .into_iter()
+        .flat_map(|(room, info)| {
+            info.portals.into_iter().filter_map(move |p| {
+                Some(ShardPortal {
+                    room: room.to_string(),
+                    shard: p.shard?,
+                    destination: p.destination,
+                })
+            })
+        })
+        .collect()
+}
+
+/// Danger zone centres and ranges for `room`, unless its intel is too old to trust.
+pub fn danger_zones(room: RoomName) -> Vec<(Position, u32)> {
+    match get(room) {
+        Ok(Some(i)) if screeps::game::time() - i.updated <= DANGER_ZONE_MAX_AGE => i
+            .danger_zones
+            .iter()
+            .map(|(p, r)| (Position::from_packed(*p), *r as u32))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Refreshes the reservation of a visible room in its intel between full refreshes. Returns
+/// the updated intel, or the stored intel when the room isn't visible.
+pub fn record_reservation(room: RoomName) -> Result<Option<RoomIntel>, BotError> {
+    let mut info = match get(room)? {
+        Some(i) => i,
+        None => return Ok(None),
+    };
+    let reservation = match screeps::game::rooms::get(room).and_then(|r| r.controller()) {
+        Some(c) => c.reservation(),
+        None => return Ok(Some(info)),
+    };
+    info.reserved_by = reservation.as_ref().map(|r| r.username.clone());
+    info.reservation_ends = reservation.map(|r| screeps::game::time() + r.ticks_to_end);
+    memory::intel()?.set(&room.to_string(), &info);
+    Ok(Some(info))
+}
+
+/// Marks `room` as having had hostiles in it at this tick, keeping the rest of its intel.
+pub fn record_hostile_presence(room: RoomName) -> Result<(), BotError> {
+    let mut info = get(room)?.unwrap_or_default();
+    info.hostile_seen = Some(screeps::game::time());
+    memory::intel()?.set(&room.to_string(), &info);
+    Ok(())
+}
+
+fn combat_parts(creep: &Creep) -> u32 {
+    [Part::Attack, Part::RangedAttack, Part::Heal]
+        .iter()
+        .map(|p| creep.get_active_bodyparts(*p))
+        .sum()
+}
+
+/// Adds the hostile players' fighting creeps in `room` to its attack history. NPC invaders
+/// aren't anyone's harassment and are left out.
+pub fn record_attackers(room: &Room) -> Result<(), BotError> {
+    let attackers: Vec<Creep> = allies::hostile_creeps(room)
+        .into_iter()
+        .filter(|c| c.owner_name() != "Invader" && combat_parts(c) > 0)
+        .collect();
+    if attackers.is_empty() {
+        return Ok(());
+    }
+    let now = screeps::game::time();
+    let mut info = get(room.name())?.unwrap_or_default();
+    let mut owners: Vec<String> = attackers.iter().map(|c| c.owner_name()).collect();
+    owners.sort();
+    owners.dedup();
+    for owner in owners {
+        let theirs: Vec<&Creep> = attackers.iter().filter(|c| c.owner_name() == owner).collect();
+        let strongest = theirs.iter().map(|c| combat_parts(c)).max().unwrap_or(0);
+        let ongoing = info
+            .attacks
+            .iter_mut()
+            .rev()
+            .find(|a| a.owner == owner && now - a.last_seen <= ATTACK_GAP_TICKS);
+        match ongoing {
+            Some(a) => {
+                a.last_seen = now;
+                a.combat_parts = a.combat_parts.max(strongest);
+                a.creeps = a.creeps.max(theirs.len() as u32);
+            }
+            None => {
+                info!("{} attacking {} with {} creeps", owner, room.name(), theirs.len());
+                info.attacks.push(AttackRecord {
+                    owner,
+                    first_seen: now,
+                    last_seen: now,
+                    combat_parts: strongest,
+                    creeps: theirs.len() as u32,
+                });
+            }
+        }
+    }
+    info.attacks.retain(|a| now - a.last_seen < ATTACK_MEMORY_TICKS);
+    memory::intel()?.set(&room.name().to_string(), &info);
+    Ok(())
+}
+
+/// The attacks in `info` from the last `ATTACK_MEMORY_TICKS`.
+pub fn recent_attacks(info: &RoomIntel) -> Vec<&AttackRecord> {
+    let now = screeps::game::time();
+    info.attacks
+        .iter()
+        .filter(|a| now.saturating_sub(a.last_seen) < ATTACK_MEMORY_TICKS)
+        .collect()
+}
+
+/// "4 attacks by Foo, Bar, strongest 12 combat parts, last 3000 ticks ago", or `None` with no
+/// recent attacks.
+pub fn harassment_summary(info: &RoomIntel) -> Option<String> {
+    let attacks = recent_attacks(info);
+    let last = attacks.iter().map(|a| a.last_seen).max()?;
+    let mut owners: Vec<&str> = attacks.iter().map(|a| a.owner.as_str()).collect();
+    owners.sort();
+    owners.dedup();
+    let strongest = attacks.iter().map(|a| a.combat_parts).max().unwrap_or(0);
+    Some(format!(
+        "{} attacks by {}, strongest {} combat parts, last {} ticks ago",
+        attacks.len(),
+        owners.join(", "),
+        strongest,
+        screeps::game::time().saturating_sub(last)
+    ))
+}
+
+pub fn all() -> Result<Vec<(RoomName, RoomIntel)>, BotError> {
+    let intel = memory::intel()?;
+    let mut rooms = Vec::new();
+    for key in intel.keys() {
+        let name = match key.parse::<RoomName>() {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+        match intel.get::<RoomIntel>(&key) {
+            Ok(Some(i)) => rooms.push((name, i)),
+            Ok(None) => {}
+            Err(e) => warn!("dropping unreadable intel for {}: {}", key, e),
+        }
+    }
+    Ok(rooms)
+}
+
+/// Refreshes intel for every room we currently have vision of. A room whose intel can't be
+/// read is skipped rather than failing the rest.
+pub fn run_intel() -> Result<(), BotError> {
+    let intel = memory::intel()?;
+    let now = screeps::game::time();
+    for room in screeps::game::rooms::values() {
+        let previous = match get(room.name()) {
+            Ok(p) => p,
+            Err(e) => {
+                warn!("skipping unreadable intel for {}: {}", room.name(), e);
+                continue;
+            }
+        };
+        if let Some(ref p) = previous {
+            if now - p.updated < REFRESH_TICKS {
+                continue;
+            }
+        }
+        intel.set(&room.name().to_string(), &observe(&room, previous));
+    }
+    Ok(())
+}
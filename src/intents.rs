@@ -0,0 +1,304 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+};
+
+use log::*;
+use screeps::{prelude::*, Creep, ResourceType, ReturnCode, Structure, StructureType};
+use stdweb::{js, unstable::TryInto};
+
+use crate::{
+    error::BotError,
+    labs, links,
+    memory::{LabRole, LinkClass},
+    population, settings,
+};
+
+/// An intent recorded instead of issued during a dry run.
+struct Intent {
+    actor: String,
+    api: &'static str,
+    target: String,
+    amount: Option<u32>,
+}
+
+thread_local! {
+    static DRY_RUN: Cell<bool> = Cell::new(false);
+    static INTENDED: RefCell<Vec<Intent>> = RefCell::new(Vec::new());
+    static SNAPSHOT: RefCell<Option<String>> = RefCell::new(None);
+}
+
+pub fn dry_run() -> bool {
+    DRY_RUN.with(|d| d.get())
+}
+
+/// Issues an intent through `call`. During a dry run the call is skipped and the intent is
+/// logged instead, reported as `Ok` so the caller carries on as if it had worked. Range isn't
+/// checked then, so a creep that would have walked first shows up with its work intent.
+pub fn issue<F: FnOnce() -> ReturnCode>(
+    actor: &str,
+    api: &'static str,
+    target: &str,
+    amount: Option<u32>,
+    call: F,
+) -> ReturnCode {
+    if !dry_run() {
+        return call();
+    }
+    INTENDED.with(|i| {
+        i.borrow_mut().push(Intent {
+            actor: actor.to_owned(),
+            api,
+            target: target.to_owned(),
+            amount,
+        })
+    });
+    ReturnCode::Ok
+}
+
+/// Label for intents issued by a creep, so the dry-run summary groups them by role.
+pub fn creep_actor(creep: &Creep) -> String {
+    format!("{:?}", population::role_of(creep))
+}
+
+/// Turns dry-run mode on for this tick while `Memory.dry_run` is set, and snapshots Memory so
+/// the tick's writes can be dropped. The flag clears itself after
+/// `Memory.settings.dry_run_ticks` ticks.
+pub fn begin_tick() {
+    let root = screeps::memory::root();
+    let mut on = root.bool("dry_run");
+    if on {
+        let now = screeps::game::time();
+        let until = match root.i32("dry_run_until").ok().flatten() {
+            Some(u) => u as u32,
+            None => {
+                let until = now + settings::u32_or("dry_run_ticks", 10);
+                info!("dry run until tick {}", until);
+                root.set("dry_run_until", until as i32);
+                until
+            }
+        };
+        if now >= until {
+            info!("dry run over, issuing intents again");
+            root.del("dry_run");
+            root.del("dry_run_until");
+            on = false;
+        }
+    }
+    DRY_RUN.with(|d| d.set(on));
+    if on {
+        let snapshot: String = js!(return JSON.stringify(Memory);)
+            .try_into()
+            .unwrap_or_default();
+        SNAPSHOT.with(|s| *s.borrow_mut() = Some(snapshot));
+    }
+}
+
+/// Logs what a dry-run tick would have done, per actor, and puts Memory back the way it was.
+pub fn end_tick() {
+    if !dry_run() {
+        return;
+    }
+    let intended = INTENDED.with(|i| std::mem::take(&mut *i.borrow_mut()));
+    let mut counts: BTreeMap<(String, &'static str), u32> = BTreeMap::new();
+    for intent in &intended {
+        debug!(
+            "dry run: {} {} {} {:?}",
+            intent.actor, intent.api, intent.target, intent.amount
+        );
+        *counts
+            .entry((intent.actor.clone(), intent.api))
+            .or_insert(0) += 1;
+    }
+    let lines: Vec<String> = counts
+        .iter()
+        .map(|((actor, api), n)| format!("{} {} x{}", actor, api, n))
+        .collect();
+    info!("dry run intents:\n{}", lines.join("\n"));
+
+    if let Some(snapshot) = SNAPSHOT.with(|s| s.borrow_mut().take()) {
+        js! {
+            var snapshot = JSON.parse(@{snapshot});
+            for (var key in Memory) {
+                delete Memory[key];
+            }
+            Object.assign(Memory, snapshot);
+        }
+    }
+}
+
+/// Which way energy may flow through something that holds it, from the logistics layer's
+/// point of view.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flow {
+    /// Only ever filled; withdrawing from it would be circular (spawn energy, tower ammo).
+    Sink,
+    /// Drained by haulers and workers.
+    Source,
+    Both,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Holder {
+    Structure(StructureType),
+    Link(LinkClass),
+    /// A lab by the role it was given, if any.
+    Lab(Option<LabRole>),
+    Tombstone,
+    Ruin,
+    Dropped,
+}
+
+pub fn classify(holder: Holder) -> Flow {
+    match holder {
+        Holder::Structure(StructureType::Spawn)
+        | Holder::Structure(StructureType::Extension)
+        | Holder::Structure(StructureType::Tower)
+        | Holder::Structure(StructureType::Nuker)
+        | Holder::Structure(StructureType::PowerSpawn) => Flow::Sink,
+        Holder::Structure(StructureType::Container)
+        | Holder::Structure(StructureType::Storage)
+        | Holder::Tombstone
+        | Holder::Ruin
+        | Holder::Dropped => Flow::Source,
+        // factories are drained of their products and anything that isn't an input
+        Holder::Structure(StructureType::Terminal) | Holder::Structure(StructureType::Factory) => {
+            Flow::Both
+        }
+        // source links are filled by harvesters and emptied by the link itself; the hub and
+        // controller links are the ones creeps draw from
+        Holder::Link(LinkClass::Source) => Flow::Sink,
+        Holder::Link(LinkClass::Hub) => Flow::Both,
+        Holder::Link(LinkClass::Controller) => Flow::Source,
+        // output labs are drained of their product, input labs of a reagent they no longer
+        // react with; boost labs are only ever filled
+        Holder::Lab(Some(LabRole::Output)) => Flow::Source,
+        Holder::Lab(Some(LabRole::Input)) => Flow::Both,
+        Holder::Lab(_) => Flow::Sink,
+        // links and labs are classified through `Holder::Link` and `Holder::Lab`; anything
+        // else doesn't hold energy
+        Holder::Structure(_) => Flow::Sink,
+    }
+}
+
+fn holder_of(structure: &Structure) -> Result<Holder, BotError> {
+    Ok(match structure {
+        Structure::Link(link) => Holder::Link(links::link_class(link)?),
+        Structure::Lab(lab) => Holder::Lab(labs::lab_role(lab.untyped_id()).map(|(r, _)| r)),
+        s => Holder::Structure(s.structure_type()),
+    })
+}
+
+/// What a withdraw asking for `amount` can move: no more than the target `held` or the creep
+/// has `free` room for. `None` stays `None`, taking all it can.
+fn withdraw_amount(amount: Option<u32>, held: u32, free: u32) -> Option<u32> {
+    amount.map(|a| a.min(held).min(free))
+}
+
+/// Withdraws from `target`, refusing (with an error log) if it's a sink. An `amount` is
+/// clamped to what the target holds and the creep has room for; `None` takes all it can.
+pub fn withdraw(
+    creep: &Creep,
+    target: &Structure,
+    resource: ResourceType,
+    amount: Option<u32>,
+) -> Result<ReturnCode, BotError> {
+    let holder = holder_of(target)?;
+    if classify(holder) == Flow::Sink {
+        error!(
+            "refusing withdraw by {} from sink {:?} {}",
+            creep.name(),
+            holder,
+            target.untyped_id()
+        );
+        return Err(BotError::RejectedIntent {
+            api: "withdraw",
+            target: target.untyped_id().to_string(),
+        });
+    }
+    let withdrawable = target.as_withdrawable().ok_or(BotError::MissingRoomObject {
+        what: "withdrawable structure",
+    })?;
+    let held = target.as_has_store().map_or(0, |s| s.store_of(resource));
+    let amount = withdraw_amount(amount, held, creep.store_free_capacity(Some(resource)));
+    if amount == Some(0) {
+        return Ok(ReturnCode::NotEnough);
+    }
+    Ok(issue(
+        &creep_actor(creep),
+        "withdraw",
+        &target.untyped_id().to_string(),
+        amount,
+        || match amount {
+            Some(a) => creep.withdraw_amount(withdrawable, resource, a),
+            None => creep.withdraw_all(withdrawable, resource),
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn structure(ty: StructureType) -> Flow {
+        classify(Holder::Structure(ty))
+    }
+
+    #[test]
+    fn spawn_energy_and_ammo_are_sinks() {
+        for ty in &[
+            StructureType::Spawn,
+            StructureType::Extension,
+            StructureType::Tower,
+            StructureType::Nuker,
+            StructureType::PowerSpawn,
+        ] {
+            assert_eq!(structure(*ty), Flow::Sink, "{:?}", ty);
+        }
+    }
+
+    #[test]
+    fn stores_and_leftovers_are_sources() {
+        assert_eq!(structure(StructureType::Container), Flow::Source);
+        assert_eq!(structure(StructureType::Storage), Flow::Source);
+        for holder in &[Holder::Tombstone, Holder::Ruin, Holder::Dropped] {
+            assert_eq!(classify(*holder), Flow::Source, "{:?}", holder);
+        }
+    }
+
+    #[test]
+    fn terminal_factory_and_links_by_class() {
+        assert_eq!(structure(StructureType::Terminal), Flow::Both);
+        assert_eq!(structure(StructureType::Factory), Flow::Both);
+        assert_eq!(classify(Holder::Link(LinkClass::Source)), Flow::Sink);
+        assert_eq!(classify(Holder::Link(LinkClass::Hub)), Flow::Both);
+        assert_eq!(classify(Holder::Link(LinkClass::Controller)), Flow::Source);
+    }
+
+    #[test]
+    fn labs_by_role() {
+        assert_eq!(classify(Holder::Lab(Some(LabRole::Output))), Flow::Source);
+        assert_eq!(classify(Holder::Lab(Some(LabRole::Input))), Flow::Both);
+        assert_eq!(classify(Holder::Lab(Some(LabRole::Boost))), Flow::Sink);
+        assert_eq!(classify(Holder::Lab(None)), Flow::Sink);
+    }
+
+    #[test]
+    fn withdraw_takes_what_the_target_holds() {
+        assert_eq!(withdraw_amount(Some(100), 60, 800), Some(60));
+        assert_eq!(withdraw_amount(Some(100), 0, 800), Some(0));
+    }
+
+    #[test]
+    fn withdraw_takes_what_the_creep_has_room_for() {
+        assert_eq!(withdraw_amount(Some(100), 5000, 30), Some(30));
+        assert_eq!(withdraw_amount(Some(100), 5000, 800), Some(100));
+        assert_eq!(withdraw_amount(None, 5000, 30), None);
+    }
+
+    #[test]
+    fn anything_else_is_never_withdrawn_from() {
+        assert_eq!(structure(StructureType::Road), Flow::Sink);
+        assert_eq!(structure(StructureType::Link), Flow::Sink);
+    }
+}
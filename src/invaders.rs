@@ -0,0 +1,134 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use log::*;
+use screeps::{find, prelude::*, Creep, Part, RoomName};
+
+use crate::{error::BotError, intel, memory, population, role::Role, settings, spawn::SpawnRequest};
+
+/// NPC invaders show up after about this much energy has been harvested in a room.
+pub const INVADER_ENERGY: u32 = 100_000;
+/// Remotes past this get a defender before the invader arrives.
+pub const DUE_ENERGY: u32 = 70_000;
+pub const HARVEST_POWER: u32 = 2;
+const DEFENDER_PRIORITY: u32 = 70;
+/// Defenders are kept small; they're meant for lone NPC invaders.
+const DEFENDER_BUDGET: u32 = 520;
+/// Default for `Memory.settings.harassment_margin`: percent more attack parts than the
+/// strongest recent player attacker had combat parts.
+const HARASSMENT_MARGIN: u32 = 50;
+
+thread_local! {
+    static HARVESTED: RefCell<HashMap<RoomName, u32>> = RefCell::new(HashMap::new());
+}
+
+/// Counts a successful harvest by `creep` towards its room's invader clock.
+pub fn record_harvest(creep: &Creep) {
+    let room = match creep.room() {
+        Some(r) => r.name(),
+        None => return,
+    };
+    let amount = creep.get_active_bodyparts(Part::Work) * HARVEST_POWER;
+    HARVESTED.with(|h| *h.borrow_mut().entry(room).or_insert(0) += amount);
+}
+
+/// Energy harvested in `room` since an invader was last seen there, from `Memory.invaders`.
+pub fn harvested(room: RoomName) -> u32 {
+    memory::invaders()
+        .ok()
+        .and_then(|m| m.i32(&room.to_string()).ok().flatten())
+        .map_or(0, |v| v.max(0) as u32)
+}
+
+/// Adds this tick's harvests to `Memory.invaders` and restarts the clock of every visible room
+/// with an invader in it.
+pub fn flush() -> Result<(), BotError> {
+    let counters = memory::invaders()?;
+    let current = |key: &str| counters.i32(key).ok().flatten().unwrap_or(0);
+    let harvested = HARVESTED.with(|h| std::mem::replace(&mut *h.borrow_mut(), HashMap::new()));
+    for (room, amount) in harvested {
+        let key = room.to_string();
+        counters.set(&key, current(&key) + amount as i32);
+    }
+    for room in screeps::game::rooms::values() {
+        let invaded = room
+            .find(find::HOSTILE_CREEPS)
+            .iter()
+            .any(|c| c.owner_name() == "Invader");
+        let key = room.name().to_string();
+        if invaded && current(&key) > 0 {
+            debug!("invader in {}, resetting its harvest counter", key);
+            counters.set(&key, 0);
+        }
+    }
+    Ok(())
+}
+
+/// Attack parts a defender for `remote` needs: enough for an NPC invader, or for the strongest
+/// player attacker seen there lately plus `HARASSMENT_MARGIN`.
+fn needed_attack_parts(remote: RoomName) -> Result<(u32, bool), BotError> {
+    let strongest = intel::get(remote)?.map_or(0, |i| {
+        intel::recent_attacks(&i)
+            .iter()
+            .map(|a| a.combat_parts)
+            .max()
+            .unwrap_or(0)
+    });
+    let unit = Part::Attack.cost() + Part::Move.cost();
+    let invader = DEFENDER_BUDGET / unit;
+    if strongest == 0 {
+        return Ok((invader, false));
+    }
+    let margin = settings::u32_or("harassment_margin", HARASSMENT_MARGIN);
+    let sized = (strongest * (100 + margin) + 99) / 100;
+    Ok((sized.max(invader), true))
+}
+
+/// Queues a defender for `remote` in `home` once the remote is due for an invader, or for as
+/// long as players have attacked it within `intel::ATTACK_MEMORY_TICKS`, unless one is already
+/// alive for it that's big enough for those attackers. The body is sized by
+/// `needed_attack_parts`, so it shrinks back to the invader size once the attacks age out.
+/// Queued requests are deduped per remote.
+pub fn guard_remote(remote: RoomName, home: RoomName) -> Result<(), BotError> {
+    let harvested = harvested(remote);
+    let (parts, harassed) = needed_attack_parts(remote)?;
+    if harvested < DUE_ENERGY && !harassed {
+        return Ok(());
+    }
+    let target = remote.to_string();
+    let guarded = screeps::game::creeps::values().iter().any(|c| {
+        let theirs = memory::creep_memory(c).string("target_room").ok().flatten();
+        population::role_of(c) == Role::Defender
+            && theirs.as_deref() == Some(target.as_str())
+            && (!harassed || c.get_active_bodyparts(Part::Attack) >= parts)
+    });
+    if guarded {
+        return Ok(());
+    }
+    let mut mem = memory::get_room_memory(home)?;
+    let key = format!("Defender-{}", remote);
+    if !mem
+        .spawn_queue
+        .iter()
+        .any(|r| r.dedupe.as_deref() == Some(key.as_str()))
+    {
+        if harassed {
+            info!(
+                "{} has been attacked by players, queueing a {} attack part defender in {}",
+                remote, parts, home
+            );
+        } else {
+            info!(
+                "{} is due for invaders ({} energy harvested), queueing a defender in {}",
+                remote, harvested, home
+            );
+        }
+    }
+    mem.enqueue(SpawnRequest {
+        enqueued: screeps::game::time(),
+        dedupe: Some(key),
+        target_room: Some(target),
+        budget: Some(parts * (Part::Attack.cost() + Part::Move.cost())),
+        ..SpawnRequest::new(Role::Defender, DEFENDER_PRIORITY)
+    });
+    memory::set_room_memory(home, &mem)
+}
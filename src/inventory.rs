@@ -0,0 +1,228 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+};
+
+use log::*;
+use screeps::{find, prelude::*, ResourceType, Room, RoomName, StructureType};
+use serde::{Deserialize, Serialize};
+use stdweb::{js, js_serializable, unstable::TryInto};
+
+use crate::{settings, stats};
+
+pub type Inventory = HashMap<ResourceType, u32>;
+
+/// Default for `Memory.settings.chronic_import_amount`: a compound bought this much over the
+/// market's transaction history is one we keep having to import.
+const CHRONIC_IMPORT_AMOUNT: u32 = 3000;
+
+thread_local! {
+    static ROOMS: RefCell<HashMap<RoomName, Inventory>> = RefCell::new(HashMap::new());
+    /// Amount of each compound bought on the market, by its game name, at the last count.
+    static IMPORTS: RefCell<HashMap<String, u32>> = RefCell::new(HashMap::new());
+}
+
+#[derive(Serialize)]
+struct InventoryReport {
+    tick: u32,
+    rooms: HashMap<String, HashMap<String, u32>>,
+    total: HashMap<String, u32>,
+    imports: HashMap<String, u32>,
+}
+
+/// An incoming transaction as `market_imports` reads it from JS.
+#[derive(Deserialize)]
+struct RawImport {
+    resource: String,
+    amount: u32,
+}
+
+/// What our terminals have received through market deals, over the transactions the game still
+/// lists, by resource name. Sends between our own rooms carry no order and are left out.
+fn market_imports() -> HashMap<String, u32> {
+    let raw: String = js! {
+        var bought = Game.market.incomingTransactions.filter(function (t) { return t.order; });
+        return JSON.stringify(bought.map(function (t) {
+            return { resource: t.resourceType, amount: t.amount };
+        }));
+    }
+    .try_into()
+    .unwrap_or_default();
+    let imports: Vec<RawImport> = serde_json::from_str(&raw).unwrap_or_default();
+    let mut totals = HashMap::new();
+    for import in imports {
+        *totals.entry(import.resource).or_insert(0) += import.amount;
+    }
+    totals
+}
+
+/// The base minerals that go into the compound named `name`; empty for anything that isn't a
+/// mineral or compound.
+fn base_minerals(name: &str) -> Vec<ResourceType> {
+    let mut bases = Vec::new();
+    for c in name.chars() {
+        let found: &[ResourceType] = match c {
+            'H' => &[ResourceType::Hydrogen],
+            'O' => &[ResourceType::Oxygen],
+            'U' => &[ResourceType::Utrium],
+            'L' => &[ResourceType::Lemergium],
+            'K' => &[ResourceType::Keanium],
+            'Z' => &[ResourceType::Zynthium],
+            'X' => &[ResourceType::Catalyst],
+            // ghodium is made from all four of these
+            'G' => &[
+                ResourceType::Zynthium,
+                ResourceType::Keanium,
+                ResourceType::Utrium,
+                ResourceType::Lemergium,
+            ],
+            '2' => &[],
+            _ => return Vec::new(),
+        };
+        for base in found {
+            if !bases.contains(base) {
+                bases.push(*base);
+            }
+        }
+    }
+    bases
+}
+
+js_serializable!(InventoryReport);
+
+fn named(inventory: &Inventory) -> HashMap<String, u32> {
+    inventory
+        .iter()
+        .map(|(r, n)| (format!("{:?}", r), *n))
+        .collect()
+}
+
+/// Everything held in the room's storage, terminal, factory and labs.
+fn room_inventory(room: &Room) -> Inventory {
+    let mut totals = Inventory::new();
+    for structure in room.find(find::MY_STRUCTURES) {
+        match structure.structure_type() {
+            StructureType::Storage
+            | StructureType::Terminal
+            | StructureType::Factory
+            | StructureType::Lab => {}
+            _ => continue,
+        }
+        if let Some(store) = structure.as_has_store() {
+            for resource in store.store_types() {
+                *totals.entry(resource).or_insert(0) += store.store_of(resource);
+            }
+        }
+    }
+    totals
+}
+
+/// Recounts every owned room and publishes the result to `Memory.stats.inventory`. Skipped
+/// while the bucket is low; queries keep answering from the last count.
+pub fn refresh() {
+    if (screeps::game::cpu::bucket() as u32) < settings::u32_or("inventory_min_bucket", 1000) {
+        debug!("bucket low, not refreshing inventory");
+        return;
+    }
+    let rooms: HashMap<RoomName, Inventory> = screeps::game::rooms::values()
+        .into_iter()
+        .filter(|r| r.controller().map(|c| c.my()).unwrap_or(false))
+        .map(|r| (r.name(), room_inventory(&r)))
+        .collect();
+    let imports = market_imports();
+
+    if let Some(section) = stats::section("inventory") {
+        section.set(
+            "report",
+            &InventoryReport {
+                tick: screeps::game::time(),
+                rooms: rooms
+                    .iter()
+                    .map(|(name, inv)| (name.to_string(), named(inv)))
+                    .collect(),
+                total: named(&total_of(&rooms)),
+                imports: imports.clone(),
+            },
+        );
+    }
+    ROOMS.with(|r| *r.borrow_mut() = rooms);
+    IMPORTS.with(|i| *i.borrow_mut() = imports);
+}
+
+/// Base minerals of the compounds we chronically buy on the market, as of the last count;
+/// the ones worth most to a new room right now.
+pub fn imported_minerals() -> HashSet<ResourceType> {
+    let min = settings::u32_or("chronic_import_amount", CHRONIC_IMPORT_AMOUNT);
+    IMPORTS.with(|i| {
+        i.borrow()
+            .iter()
+            .filter(|(_, amount)| **amount >= min)
+            .flat_map(|(name, _)| base_minerals(name))
+            .collect()
+    })
+}
+
+fn total_of(rooms: &HashMap<RoomName, Inventory>) -> Inventory {
+    let mut total = Inventory::new();
+    for inventory in rooms.values() {
+        for (resource, amount) in inventory {
+            *total.entry(*resource).or_insert(0) += amount;
+        }
+    }
+    total
+}
+
+/// How much of `resource` `room` held at the last count.
+pub fn amount(room: RoomName, resource: ResourceType) -> u32 {
+    ROOMS.with(|r| {
+        r.borrow()
+            .get(&room)
+            .and_then(|inv| inv.get(&resource))
+            .cloned()
+            .unwrap_or(0)
+    })
+}
+
+/// The empire-wide total of every resource at the last count.
+pub fn empire_total() -> Inventory {
+    ROOMS.with(|r| total_of(&r.borrow()))
+}
+
+/// Rooms holding more than `threshold` of `resource`, with the amount above it, largest
+/// surplus first.
+pub fn rooms_with_surplus(resource: ResourceType, threshold: u32) -> Vec<(RoomName, u32)> {
+    let mut rooms: Vec<(RoomName, u32)> = ROOMS.with(|r| {
+        r.borrow()
+            .iter()
+            .filter_map(|(name, inv)| {
+                let have = inv.get(&resource).cloned().unwrap_or(0);
+                if have > threshold {
+                    Some((*name, have - threshold))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    });
+    rooms.sort_by_key(|(_, surplus)| std::cmp::Reverse(*surplus));
+    rooms
+}
+
+/// Console command: logs which rooms hold more than `threshold` of the named resource.
+pub fn print_surplus(resource: String, threshold: u32) {
+    let resource = match empire_total()
+        .keys()
+        .find(|r| format!("{:?}", r).eq_ignore_ascii_case(&resource))
+    {
+        Some(r) => *r,
+        None => {
+            info!("no {} anywhere in the empire", resource);
+            return;
+        }
+    };
+    let lines: Vec<String> = rooms_with_surplus(resource, threshold)
+        .into_iter()
+        .map(|(room, surplus)| format!("{} +{} ({} total)", room, surplus, amount(room, resource)))
+        .collect();
+    info!("{:?} above {}:\n{}", resource, threshold, lines.join("\n"));
+}
@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+
+use log::*;
+use screeps::{
+    find, prelude::*, Creep, RawObjectId, ResourceType, ReturnCode, Room, Structure, StructureLab,
+};
+use serde::{Deserialize, Serialize};
+use stdweb::{js, unstable::TryInto};
+
+use crate::{
+    error::{self, BotError},
+    intents, inventory,
+    memory::{self, LabRole, StructureMemory},
+    population,
+    room::{RoomMemory, RoomMode},
+    room_cache, settings, threat,
+};
+
+/// Boosted creeps with fewer ticks to live than this go to a lab to have their boosts
+/// taken back.
+pub const UNBOOST_TTL: u32 = 200;
+/// Minerals an unboost gives back per boosted part, half of what boosting took.
+const MINERALS_PER_PART: u32 = 15;
+/// Default for `Memory.settings.unboost_reaction_min`: a lab in the middle of a reaction is only
+/// held up for an unboost that gives back at least this many minerals.
+const REACTION_INTERRUPT_MIN: u32 = 300;
+/// Default for `Memory.settings.reaction_batch`: most of one step made before the target is
+/// looked at again.
+const REACTION_BATCH: u32 = 3000;
+/// A `Mature` room has energy to spare, so its batches run this many times longer.
+const MATURE_BATCH_FACTOR: u32 = 3;
+/// A reagent the room holds less of than this doesn't count as in stock.
+const MIN_REAGENT: u32 = 100;
+/// What one reaction takes of each reagent.
+const LAB_REACTION_AMOUNT: u32 = 5;
+/// Input labs sit within this range of the labs they feed.
+const REACTION_RANGE: u32 = 2;
+
+/// `RoomMemory.reaction`: the boost the room's labs are working towards, and the step of its
+/// chain they're making now.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Reaction {
+    pub compound: ResourceType,
+    pub step: ResourceType,
+    /// The room's stock of `step` that finishes the batch.
+    pub until: u32,
+}
+
+/// The two compounds a lab reaction makes `compound` from, for the chains of the boosts
+/// anything asks for.
+fn reagents(compound: ResourceType) -> Option<(ResourceType, ResourceType)> {
+    use ResourceType::*;
+    Some(match compound {
+        Hydroxide => (Hydrogen, Oxygen),
+        ZynthiumKeanite => (Zynthium, Keanium),
+        UtriumLemergite => (Utrium, Lemergium),
+        Ghodium => (ZynthiumKeanite, UtriumLemergite),
+        UtriumHydride => (Utrium, Hydrogen),
+        GhodiumHydride => (Ghodium, Hydrogen),
+        UtriumAcid => (UtriumHydride, Hydroxide),
+        GhodiumAcid => (GhodiumHydride, Hydroxide),
+        CatalyzedUtriumAcid => (UtriumAcid, Catalyst),
+        CatalyzedGhodiumAcid => (GhodiumAcid, Catalyst),
+        _ => return None,
+    })
+}
+
+/// Takes `amount` of `compound` out of `stock`, making what's short from its reagents, and
+/// adds the base minerals that run out to `missing`.
+fn draw(
+    compound: ResourceType,
+    amount: u32,
+    stock: &mut HashMap<ResourceType, u32>,
+    missing: &mut Vec<ResourceType>,
+) {
+    let have = stock.entry(compound).or_insert(0);
+    let taken = amount.min(*have);
+    *have -= taken;
+    let short = amount - taken;
+    if short == 0 {
+        return;
+    }
+    match reagents(compound) {
+        Some((a, b)) => {
+            draw(a, short, stock, missing);
+            draw(b, short, stock, missing);
+        }
+        None if !missing.contains(&compound) => missing.push(compound),
+        None => {}
+    }
+}
+
+/// The step of `compound`'s chain the labs can run now: the first one, working down from
+/// `compound`, whose reagents are both in stock.
+fn next_step(compound: ResourceType, stock: &HashMap<ResourceType, u32>) -> Option<ResourceType> {
+    let (a, b) = reagents(compound)?;
+    let held = |r: ResourceType| stock.get(&r).cloned().unwrap_or(0) >= MIN_REAGENT;
+    if held(a) && held(b) {
+        return Some(compound);
+    }
+    [a, b]
+        .iter()
+        .filter(|r| !held(**r))
+        .find_map(|r| next_step(*r, stock))
+}
+
+/// Boosts the room's managers want in stock, summed.
+fn boost_demand(room: &Room, mem: &RoomMemory) -> HashMap<ResourceType, u32> {
+    let mut demand = HashMap::new();
+    for (compound, amount) in threat::boost_demand(mem)
+        .into_iter()
+        .chain(population::boost_demand(room, mem))
+    {
+        *demand.entry(compound).or_insert(0) += amount;
+    }
+    demand
+}
+
+/// The room's stock of everything in the chains of `compounds`.
+fn room_stock(room: &Room, compounds: &[ResourceType]) -> HashMap<ResourceType, u32> {
+    let mut stock = HashMap::new();
+    let mut pending = compounds.to_vec();
+    while let Some(r) = pending.pop() {
+        stock.insert(r, inventory::amount(room.name(), r));
+        if let Some((a, b)) = reagents(r) {
+            pending.push(a);
+            pending.push(b);
+        }
+    }
+    stock
+}
+
+/// Whether `reaction` still has work to do in `room`.
+fn batch_open(room: &Room, reaction: &Reaction, stock: &HashMap<ResourceType, u32>) -> bool {
+    inventory::amount(room.name(), reaction.step) < reaction.until
+        && next_step(reaction.compound, stock) == Some(reaction.step)
+}
+
+/// Points the room's labs at the boost with the largest shortfall whose whole chain the room
+/// has the minerals for, keeping the current target until its batch is done or can't go on.
+/// Only the room's own stock counts towards a chain.
+pub fn select_reaction(room: &Room, mem: &mut RoomMemory) -> Result<(), BotError> {
+    let demand = boost_demand(room, mem);
+    let mut wanted: Vec<ResourceType> = demand.keys().cloned().collect();
+    wanted.extend(mem.reaction.as_ref().map(|r| r.compound));
+    let stock = room_stock(room, &wanted);
+    if let Some(current) = &mem.reaction {
+        if demand.contains_key(&current.compound) && batch_open(room, current, &stock) {
+            return assign_labs(room, mem.reaction.as_ref());
+        }
+    }
+
+    let mut candidates: Vec<(ResourceType, u32, u32)> = demand
+        .into_iter()
+        .filter_map(|(compound, wanted)| {
+            let deficit = wanted.saturating_sub(inventory::amount(room.name(), compound));
+            if deficit > 0 {
+                Some((compound, wanted, deficit))
+            } else {
+                None
+            }
+        })
+        .collect();
+    candidates.sort_by_key(|(_, _, deficit)| std::cmp::Reverse(*deficit));
+
+    let mut batch = settings::u32_or("reaction_batch", REACTION_BATCH);
+    if mem.mode == RoomMode::Mature {
+        batch *= MATURE_BATCH_FACTOR;
+    }
+    let mut chosen = None;
+    let mut reasons = Vec::new();
+    for (compound, demand, deficit) in candidates {
+        let mut missing = Vec::new();
+        draw(compound, deficit, &mut stock.clone(), &mut missing);
+        let step = next_step(compound, &stock);
+        match step {
+            Some(step) if missing.is_empty() => {
+                let have = inventory::amount(room.name(), step);
+                chosen = Some(Reaction {
+                    compound,
+                    step,
+                    until: have + deficit.min(batch),
+                });
+                reasons.push(format!(
+                    "{:?} via {:?} (demand {}, deficit {}, chain in stock)",
+                    compound, step, demand, deficit
+                ));
+                break;
+            }
+            _ => reasons.push(format!(
+                "{:?} skipped (demand {}, deficit {}, short of {:?})",
+                compound, demand, deficit, missing
+            )),
+        }
+    }
+    let changed = chosen.as_ref().map(|r| (r.compound, r.step))
+        != mem.reaction.as_ref().map(|r| (r.compound, r.step));
+    if changed {
+        let target = chosen
+            .as_ref()
+            .map_or_else(|| "none".to_owned(), |r| format!("{:?}", r.compound));
+        info!("{} lab target {}: {}", room.name(), target, reasons.join("; "));
+    }
+    mem.reaction = chosen;
+    assign_labs(room, mem.reaction.as_ref())
+}
+
+/// Gives the room's labs their roles for `reaction`: the two labs in range of the most others
+/// are inputs holding the reagents, and the rest outputs making the step. Boost labs keep
+/// their role.
+fn assign_labs(room: &Room, reaction: Option<&Reaction>) -> Result<(), BotError> {
+    let mut labs = Vec::new();
+    for structure in room_cache::structures(room).iter() {
+        if let Structure::Lab(lab) = structure {
+            if !lab.my() {
+                continue;
+            }
+            match memory::get_structure_memory(lab.untyped_id())? {
+                Some(StructureMemory::Lab {
+                    role: LabRole::Boost,
+                    ..
+                }) => {}
+                _ => labs.push(lab.clone()),
+            }
+        }
+    }
+    if labs.len() < 3 {
+        return Ok(());
+    }
+    let reach = |lab: &StructureLab| {
+        labs.iter()
+            .filter(|o| o.id() != lab.id() && o.pos().get_range_to(lab) <= REACTION_RANGE)
+            .count()
+    };
+    let mut order: Vec<usize> = (0..labs.len()).collect();
+    order.sort_by_key(|i| std::cmp::Reverse(reach(&labs[*i])));
+    let step = reaction.map(|r| r.step);
+    let inputs = step.and_then(reagents);
+    for (rank, i) in order.into_iter().enumerate() {
+        let mem = match (rank, inputs) {
+            (0, Some((a, _))) => lab_memory(LabRole::Input, Some(a)),
+            (1, Some((_, b))) => lab_memory(LabRole::Input, Some(b)),
+            (0, None) | (1, None) => lab_memory(LabRole::Input, None),
+            _ => lab_memory(LabRole::Output, step),
+        };
+        memory::set_structure_memory(labs[i].untyped_id(), &mem)?;
+    }
+    Ok(())
+}
+
+fn lab_memory(role: LabRole, compound: Option<ResourceType>) -> StructureMemory {
+    StructureMemory::Lab { role, compound }
+}
+
+/// The role and compound the lab was given, if any.
+pub fn lab_role(id: RawObjectId) -> Option<(LabRole, Option<ResourceType>)> {
+    match memory::get_structure_memory(id).ok().flatten() {
+        Some(StructureMemory::Lab { role, compound }) => Some((role, compound)),
+        _ => None,
+    }
+}
+
+pub fn boosted_parts(creep: &Creep) -> u32 {
+    creep.body().iter().filter(|p| p.boost.is_some()).count() as u32
+}
+
+/// Whether the lab is given a compound to make or react with, as opposed to boost labs and
+/// labs nothing has claimed.
+fn in_reaction(lab: &StructureLab) -> Result<bool, BotError> {
+    Ok(match memory::get_structure_memory(lab.untyped_id())? {
+        Some(StructureMemory::Lab { role, compound }) => {
+            role != LabRole::Boost && compound.is_some()
+        }
+        _ => false,
+    })
+}
+
+/// Whether `lab` would unboost a creep with `parts` boosted parts.
+fn will_unboost(lab: &StructureLab, parts: u32) -> Result<bool, BotError> {
+    if !in_reaction(lab)? {
+        return Ok(true);
+    }
+    let min = settings::u32_or("unboost_reaction_min", REACTION_INTERRUPT_MIN);
+    Ok(parts * MINERALS_PER_PART >= min)
+}
+
+/// The closest of our labs in `room` that would take `creep`'s boosts back.
+pub fn unboost_lab(room: &Room, creep: &Creep) -> Result<Option<StructureLab>, BotError> {
+    let parts = boosted_parts(creep);
+    let mut best: Option<(u32, StructureLab)> = None;
+    for structure in room_cache::structures(room).iter() {
+        let lab = match structure {
+            Structure::Lab(l) if l.my() => l.clone(),
+            _ => continue,
+        };
+        let range = creep.pos().get_range_to(&lab);
+        if best.as_ref().map_or(true, |(r, _)| range < *r) && will_unboost(&lab, parts)? {
+            best = Some((range, lab));
+        }
+    }
+    Ok(best.map(|(_, lab)| lab))
+}
+
+fn unboost_creep(lab: &StructureLab, creep: &Creep) -> ReturnCode {
+    js!(return @{lab.as_ref()}.unboostCreep(@{creep.as_ref()});)
+        .try_into()
+        .unwrap_or(ReturnCode::InvalidArgs)
+}
+
+/// Runs an output lab's reaction from the input labs in range holding its reagents.
+fn react(lab: &StructureLab, room: &Room) -> Result<(), BotError> {
+    let step = match lab_role(lab.untyped_id()) {
+        Some((LabRole::Output, Some(step))) => step,
+        _ => return Ok(()),
+    };
+    let (a, b) = match reagents(step) {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+    let other = lab
+        .store_types()
+        .into_iter()
+        .any(|r| r != step && r != ResourceType::Energy);
+    if other || lab.store_free_capacity(Some(step)) < LAB_REACTION_AMOUNT {
+        return Ok(());
+    }
+    let input = |reagent: ResourceType| {
+        room_cache::structures(room).iter().find_map(|s| match s {
+            Structure::Lab(l)
+                if l.pos().get_range_to(lab) <= REACTION_RANGE
+                    && lab_role(l.untyped_id()) == Some((LabRole::Input, Some(reagent)))
+                    && l.store_of(reagent) >= LAB_REACTION_AMOUNT =>
+            {
+                Some(l.clone())
+            }
+            _ => None,
+        })
+    };
+    let (first, second) = match (input(a), input(b)) {
+        (Some(x), Some(y)) => (x, y),
+        _ => return Ok(()),
+    };
+    let r = intents::issue("lab", "run_reaction", &lab.id().to_string(), None, || {
+        lab.run_reaction(&first, &second)
+    });
+    error::check("run_reaction", r)
+}
+
+/// Unboosts a creep standing next to the lab that's waiting on this lab for it, and otherwise
+/// runs the lab's reaction.
+pub fn run_lab(lab: &StructureLab) -> Result<(), BotError> {
+    if lab.cooldown() > 0 {
+        return Ok(());
+    }
+    let room = match lab.room() {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+    if threat::critical(room.name()) {
+        return Ok(());
+    }
+    let id = lab.id().to_string();
+    for creep in lab.pos().find_in_range(find::MY_CREEPS, 1) {
+        let waiting = memory::creep_memory(&creep).string("unboost_lab").ok().flatten();
+        let parts = boosted_parts(&creep);
+        if waiting.as_deref() != Some(id.as_str()) || parts == 0 || !will_unboost(lab, parts)? {
+            continue;
+        }
+        let r = intents::issue("lab", "unboost_creep", &creep.name(), Some(parts), || {
+            unboost_creep(lab, &creep)
+        });
+        debug!("{} unboosting {} parts of {}", room.name(), parts, creep.name());
+        return error::check("unboost_creep", r);
+    }
+    react(lab, &room)
+}
@@ -0,0 +1,203 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use log::*;
+use screeps::{prelude::*, ResourceType, ReturnCode, Room, Structure, StructureLink};
+
+use crate::{
+    error::{self, BotError},
+    intents,
+    memory::{self, LinkClass, StructureMemory},
+    room::RoomMemory,
+    room_cache, settings,
+};
+
+const CONTROLLER_RANGE: u32 = 3;
+const HUB_RANGE: u32 = 2;
+/// The controller link is topped up whenever it drops below this.
+const CONTROLLER_REFILL_BELOW: u32 = 400;
+/// Most a hauler is asked to put in the hub link at once.
+const HUB_REQUEST_AMOUNT: u32 = 400;
+
+fn classify(link: &StructureLink) -> Result<LinkClass, BotError> {
+    let room = link.room().ok_or(BotError::MissingRoomObject { what: "link room" })?;
+    // the planner's pick wins over the range guess
+    let planned = memory::get_room_memory(room.name())?.controller_link;
+    if planned == Some(link.pos().packed_repr()) {
+        return Ok(LinkClass::Controller);
+    }
+    if let Some(c) = room.controller() {
+        if link.pos().in_range_to(&c, CONTROLLER_RANGE) {
+            return Ok(LinkClass::Controller);
+        }
+    }
+    if let Some(s) = room.storage() {
+        if link.pos().in_range_to(&s, HUB_RANGE) {
+            return Ok(LinkClass::Hub);
+        }
+    }
+    Ok(LinkClass::Source)
+}
+
+fn store_class(link: &StructureLink) -> Result<LinkClass, BotError> {
+    let class = classify(link)?;
+    debug!("classified link {} as {:?}", link.id(), class);
+    memory::set_structure_memory(link.untyped_id(), &StructureMemory::Link { class })?;
+    Ok(class)
+}
+
+/// The link's class from structure memory, classifying it on first sight.
+pub fn link_class(link: &StructureLink) -> Result<LinkClass, BotError> {
+    if let Some(StructureMemory::Link { class }) = memory::get_structure_memory(link.untyped_id())?
+    {
+        return Ok(class);
+    }
+    store_class(link)
+}
+
+fn room_links(room: &Room) -> Vec<StructureLink> {
+    room_cache::structures(room)
+        .iter()
+        .filter_map(|s| match s {
+            Structure::Link(l) if l.my() => Some(l.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A fingerprint of where the links and both anchors are: the storage and the planned controller
+/// link. Any of them moving changes it, links trading places included.
+fn fingerprint(links: &[u32], storage: Option<u32>, controller_link: Option<u32>) -> u32 {
+    let mut links = links.to_vec();
+    links.sort_unstable();
+    let mut hasher = DefaultHasher::new();
+    (links, storage, controller_link).hash(&mut hasher);
+    hasher.finish() as u32
+}
+
+/// Reclassifies every link in the room when a link or the storage has been built, lost or
+/// moved, or the planner picked another controller link, since any of these can change which
+/// link is which.
+pub fn refresh_classes(room: &Room, mem: &mut RoomMemory) -> Result<(), BotError> {
+    let links = room_links(room);
+    let positions: Vec<u32> = links.iter().map(|l| l.pos().packed_repr()).collect();
+    let storage = room.storage().map(|s| s.pos().packed_repr());
+    let layout = fingerprint(&positions, storage, mem.controller_link);
+    if mem.link_layout == Some(layout) {
+        return Ok(());
+    }
+    for link in &links {
+        store_class(link)?;
+    }
+    mem.link_layout = Some(layout);
+    Ok(())
+}
+
+pub fn links_of_class(room: &Room, class: LinkClass) -> Vec<StructureLink> {
+    room_links(room)
+        .into_iter()
+        .filter(|l| link_class(l).ok() == Some(class))
+        .collect()
+}
+
+fn at_least_half(link: &StructureLink) -> bool {
+    link.store_of(ResourceType::Energy) * 2 >= link.store_capacity(Some(ResourceType::Energy))
+}
+
+fn needs_refill(link: &StructureLink) -> bool {
+    link.store_of(ResourceType::Energy) < CONTROLLER_REFILL_BELOW
+}
+
+/// Storage below the reserve keeps its energy for spawning rather than the controller.
+fn reserve_ok(room: &Room) -> bool {
+    room.storage().map_or(false, |s| {
+        s.store_of(ResourceType::Energy) >= settings::u32_or("storage_reserve", 10_000)
+    })
+}
+
+/// The hub link and how much a hauler should put in it, when the controller link needs a
+/// refill the hub can't cover. Never asked for while a source link has energy on the way.
+pub fn hub_request(room: &Room) -> Option<(StructureLink, u32)> {
+    if !reserve_ok(room) {
+        return None;
+    }
+    let controller = links_of_class(room, LinkClass::Controller).into_iter().next()?;
+    if !needs_refill(&controller) {
+        return None;
+    }
+    let sources_pending = links_of_class(room, LinkClass::Source)
+        .iter()
+        .any(|l| l.store_of(ResourceType::Energy) > 0);
+    if sources_pending {
+        return None;
+    }
+    let hub = links_of_class(room, LinkClass::Hub).into_iter().next()?;
+    let free = hub.store_free_capacity(Some(ResourceType::Energy));
+    if hub.store_of(ResourceType::Energy) >= CONTROLLER_REFILL_BELOW || free == 0 {
+        return None;
+    }
+    Some((hub, free.min(HUB_REQUEST_AMOUNT)))
+}
+
+pub fn run_link(link: &StructureLink) -> Result<(), BotError> {
+    if link.cooldown() > 0 || link.store_of(ResourceType::Energy) == 0 {
+        return Ok(());
+    }
+    let room = link.room().ok_or(BotError::MissingRoomObject { what: "link room" })?;
+
+    let target = match link_class(link)? {
+        LinkClass::Controller => None,
+        LinkClass::Hub if reserve_ok(&room) => links_of_class(&room, LinkClass::Controller)
+            .into_iter()
+            .find(needs_refill),
+        LinkClass::Hub => None,
+        // without a hub, source links feed the controller link directly
+        LinkClass::Source if at_least_half(link) => links_of_class(&room, LinkClass::Hub)
+            .into_iter()
+            .next()
+            .or_else(|| {
+                links_of_class(&room, LinkClass::Controller)
+                    .into_iter()
+                    .find(needs_refill)
+            }),
+        LinkClass::Source => None,
+    };
+
+    match target {
+        Some(target) => match intents::issue(
+            "link",
+            "transfer_energy",
+            &target.id().to_string(),
+            Some(link.store_of(ResourceType::Energy)),
+            || link.transfer_energy(&target, None),
+        ) {
+            // the target filled up from another link this tick
+            ReturnCode::Full => Ok(()),
+            r => error::check("transfer_energy", r),
+        },
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_ignores_link_order() {
+        let sorted = fingerprint(&[1, 2, 3], Some(9), Some(3));
+        assert_eq!(sorted, fingerprint(&[3, 1, 2], Some(9), Some(3)));
+    }
+
+    #[test]
+    fn fingerprint_changes_when_an_anchor_moves() {
+        let before = fingerprint(&[1, 2, 3], Some(9), Some(3));
+        // the controller link and a storage-side link trade places
+        assert_ne!(before, fingerprint(&[1, 2, 3], Some(9), Some(1)));
+        assert_ne!(before, fingerprint(&[1, 2, 3], Some(8), Some(3)));
+        assert_ne!(before, fingerprint(&[1, 2, 4], Some(9), Some(3)));
+        assert_ne!(before, fingerprint(&[1, 2, 3], None, Some(3)));
+    }
+}
@@ -1,9 +1,64 @@
-use stdweb::js;
+use std::cell::RefCell;
+
+use serde::{Deserialize, Serialize};
+use stdweb::{js, js_deserializable, js_serializable};
+
+use crate::shard;
 
 pub use log::LevelFilter::*;
 
+/// Records kept in `Memory.log_records`.
+const KEPT_RECORDS: usize = 100;
+/// Longest message kept, in characters.
+const MESSAGE_CHARS: usize = 200;
+
+/// A warning or error, kept for `recent_errors()`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LoggedRecord {
+    /// The last time it was logged.
+    #[serde(default)]
+    pub tick: u32,
+    #[serde(default)]
+    pub level: String,
+    #[serde(default)]
+    pub target: String,
+    #[serde(default)]
+    pub message: String,
+    /// Times it was logged in a row.
+    #[serde(default)]
+    pub repeats: u32,
+}
+
+js_serializable!(LoggedRecord);
+js_deserializable!(LoggedRecord);
+
+impl LoggedRecord {
+    fn same(&self, other: &LoggedRecord) -> bool {
+        self.level == other.level && self.target == other.target && self.message == other.message
+    }
+}
+
+/// Adds `record` to `records`, or counts it as a repeat of the last one.
+fn append(records: &mut Vec<LoggedRecord>, record: LoggedRecord) {
+    match records.last_mut() {
+        Some(last) if last.same(&record) => {
+            last.tick = record.tick;
+            last.repeats += record.repeats;
+        }
+        _ => records.push(record),
+    }
+}
+
+thread_local! {
+    /// Records logged this tick, for `flush` to move into Memory.
+    static PENDING: RefCell<Vec<LoggedRecord>> = RefCell::new(Vec::new());
+}
+
 struct JsLog;
 struct JsNotify;
+/// Keeps warnings and errors for `flush`; touches nothing but `PENDING`, so it can't fail or
+/// log in turn.
+struct MemorySink;
 
 impl log::Log for JsLog {
     fn enabled(&self, _: &log::Metadata<'_>) -> bool {
@@ -30,27 +85,104 @@ impl log::Log for JsNotify {
     fn flush(&self) {}
 }
 
+impl log::Log for MemorySink {
+    fn enabled(&self, _: &log::Metadata<'_>) -> bool {
+        true
+    }
+    fn log(&self, record: &log::Record<'_>) {
+        let entry = LoggedRecord {
+            tick: screeps::game::time(),
+            level: record.level().to_string(),
+            target: record.target().to_owned(),
+            message: format!("{}", record.args()).chars().take(MESSAGE_CHARS).collect(),
+            repeats: 1,
+        };
+        // a record logged while the buffer is being flushed is dropped rather than waited on
+        PENDING.with(|p| {
+            if let Ok(mut pending) = p.try_borrow_mut() {
+                append(&mut pending, entry);
+            }
+        });
+    }
+    fn flush(&self) {}
+}
+
 pub fn setup_logging(verbosity: log::LevelFilter) {
     fern::Dispatch::new()
         .level(verbosity)
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "({}) {}: {}",
-                record.level(),
-                record.target(),
-                message
-            ))
-        })
-        .chain(Box::new(JsLog) as Box<dyn log::Log>)
         .chain(
             fern::Dispatch::new()
-                .level(log::LevelFilter::Warn)
-                .format(|out, message, _record| {
-                    let time = screeps::game::time();
-                    out.finish(format_args!("[{}] {}", time, message))
+                .format(|out, message, record| {
+                    let shard = shard::name().map(|s| format!("[{}] ", s)).unwrap_or_default();
+                    out.finish(format_args!(
+                        "{}({}) {}: {}",
+                        shard,
+                        record.level(),
+                        record.target(),
+                        message
+                    ))
                 })
-                .chain(Box::new(JsNotify) as Box<dyn log::Log>),
+                .chain(Box::new(JsLog) as Box<dyn log::Log>)
+                .chain(
+                    fern::Dispatch::new()
+                        .level(log::LevelFilter::Warn)
+                        .format(|out, message, _record| {
+                            let time = screeps::game::time();
+                            out.finish(format_args!("[{}] {}", time, message))
+                        })
+                        .chain(Box::new(JsNotify) as Box<dyn log::Log>),
+                ),
+        )
+        .chain(
+            fern::Dispatch::new()
+                .level(log::LevelFilter::Warn)
+                .chain(Box::new(MemorySink) as Box<dyn log::Log>),
         )
         .apply()
         .expect("expected setup_logging to only ever be called once per instance");
 }
+
+fn load() -> Vec<LoggedRecord> {
+    screeps::memory::root()
+        .get::<Vec<LoggedRecord>>("log_records")
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+}
+
+/// Moves this tick's warnings and errors into `Memory.log_records`, keeping the last
+/// `KEPT_RECORDS`. Nothing is read or written on a tick without any. Logs nothing itself.
+pub fn flush() {
+    let pending = PENDING.with(|p| std::mem::take(&mut *p.borrow_mut()));
+    if pending.is_empty() {
+        return;
+    }
+    let mut records = load();
+    for record in pending {
+        append(&mut records, record);
+    }
+    let excess = records.len().saturating_sub(KEPT_RECORDS);
+    records.drain(..excess);
+    screeps::memory::root().set("log_records", &records);
+}
+
+/// Console command: `recent_errors(n)` shows the last `n` warnings and errors, 10 by default.
+pub fn recent_errors(n: Option<u32>) -> String {
+    let records = load();
+    if records.is_empty() {
+        return "no warnings or errors recorded".to_owned();
+    }
+    let skip = records.len().saturating_sub(n.unwrap_or(10) as usize);
+    records[skip..]
+        .iter()
+        .map(|r| {
+            let repeats = if r.repeats > 1 {
+                format!(" (x{})", r.repeats)
+            } else {
+                String::new()
+            };
+            format!("[{}] {} {}: {}{}", r.tick, r.level, r.target, r.message, repeats)
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
@@ -0,0 +1,823 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+};
+
+use log::*;
+use screeps::{
+    find, prelude::*, Creep, ObjectId, Position, RawObjectId, Resource, ResourceType, Room,
+    RoomName, Structure, StructureContainer, StructureTerminal, StructureType,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bands,
+    error::BotError,
+    links,
+    memory::{self, ContainerClass, LinkClass, StructureMemory},
+    mining, objects, phase, planner, population,
+    role::Role,
+    room::{self, RoomMode},
+    room_cache, route, settings,
+    spawn::SpawnState,
+};
+
+/// Consecutive ticks spawn+extensions must sit below half full before the room counts as
+/// starved.
+pub const STARVED_AFTER_TICKS: u32 = 20;
+/// Controller ticks-to-downgrade under which upgrading takes over.
+pub const DOWNGRADE_IMMINENT_TICKS: u32 = 5000;
+const TOP_PRIORITY: u32 = 1000;
+const TOWER_REFILL_BELOW: u32 = 800;
+/// Towers below this always get energy, ahead of extensions and even when the room is short.
+const TOWER_FLOOR: u32 = 500;
+const TOWER_FLOOR_PRIORITY: u32 = 95;
+const UPGRADE_BUFFER_RANGE: u32 = 3;
+/// Extra upgrade buffer priority per active upgrader draining it.
+const PRIORITY_PER_UPGRADER: u32 = 10;
+/// Added to spawn and extension refills while the next spawn can't be afforded yet.
+const SPAWN_PRESTOCK_BONUS: u32 = 20;
+/// Further added for the spawn that will take the next queue entry.
+const NEXT_SPAWN_BONUS: u32 = 10;
+/// Extensions right by a spawn get this much over the farthest ones, one less per
+/// `EXTENSION_RANGE_STEP` tiles, so they fill closest first.
+const EXTENSION_NEAR_BONUS: u32 = 5;
+const EXTENSION_RANGE_STEP: u32 = 3;
+/// Extension requests this close to one already in a batch join the same trip.
+const BATCH_RANGE: u32 = 4;
+/// Smaller piles under a drop miner aren't worth a trip yet.
+const PICKUP_MIN: u32 = 100;
+/// Default for `Memory.settings.pickup_min_value`: hundredths of an energy per tick of round
+/// trip a pile must be worth on arrival.
+const PICKUP_MIN_VALUE: u32 = 100;
+/// Only this many of the closest piles are pathed to when choosing one.
+const PICKUP_CANDIDATES: usize = 3;
+/// A creep that found no pile worth the trip looks again after this long.
+const PICKUP_RECHECK_TICKS: u32 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RequestKind {
+    FillSpawn,
+    /// Energy for a worker building the first tower, brought to it at the site.
+    FeedBuilder,
+    FillExtension,
+    FillTower,
+    FillUpgradeBuffer,
+    FillHubLink,
+    /// Bringing a terminal, factory or nuker back inside its `bands::Band`, or taking what one
+    /// had too much of to storage.
+    Rebalance,
+}
+
+impl RequestKind {
+    pub fn base_priority(self) -> u32 {
+        match self {
+            RequestKind::FillSpawn => 100,
+            RequestKind::FeedBuilder => 95,
+            RequestKind::FillExtension => 90,
+            RequestKind::FillTower => 60,
+            RequestKind::FillUpgradeBuffer => 20,
+            RequestKind::FillHubLink => 15,
+            RequestKind::Rebalance => 10,
+        }
+    }
+}
+
+/// What a request fills: a structure, or a builder creep for `RequestKind::FeedBuilder`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Recipient {
+    Structure(ObjectId<Structure>),
+    Creep(ObjectId<Creep>),
+}
+
+#[derive(Clone, Debug)]
+pub struct LogisticsRequest {
+    pub kind: RequestKind,
+    pub target: Recipient,
+    pub pos: Position,
+    pub resource: ResourceType,
+    pub amount: u32,
+    pub base_priority: u32,
+    /// Priority after the room state modifiers; recomputed every tick.
+    pub priority: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RoomEnergyState {
+    pub starved_ticks: u32,
+    pub downgrade_imminent: bool,
+    pub under_attack: bool,
+    /// Critical threat with siege support allowed; haulers may draw on the terminal.
+    pub besieged: bool,
+    pub upgraders: u32,
+    pub mode: RoomMode,
+    pub energy_available: u32,
+    pub spawn: SpawnState,
+}
+
+impl RoomEnergyState {
+    pub fn starved(&self) -> bool {
+        self.starved_ticks >= STARVED_AFTER_TICKS
+    }
+
+    /// The queue head costs more than the room has on hand.
+    pub fn short_for_spawn(&self) -> bool {
+        self.spawn.next_cost > self.energy_available
+    }
+}
+
+/// Applies the room state to a request's base priority. `None` means the request is
+/// suppressed this tick.
+///
+/// Overrides, strongest first: an imminent downgrade puts the upgrade buffer on top (losing the
+/// controller is worse than a slow spawn), an attack keeps towers fed even when starved, and a
+/// starved room drops towers and the upgrade buffer to refill spawning energy. Conserve mode
+/// stops feeding upgraders at all, hub link included, and a downgraded room doubles the buffer's
+/// base priority to win its level back; otherwise the buffer gets more urgent the more
+/// upgraders are drawing from it, except in a mature room. Spawns are also raised while the
+/// next queue entry can't be afforded, so the energy is ready when a spawn frees up; `refresh`
+/// raises only the extensions that entry still needs. Rebalancing waits out starvation and
+/// attacks.
+pub fn effective_priority(kind: RequestKind, base: u32, state: &RoomEnergyState) -> Option<u32> {
+    match kind {
+        RequestKind::FillUpgradeBuffer if state.downgrade_imminent => Some(TOP_PRIORITY),
+        RequestKind::Rebalance if state.starved() || state.under_attack => None,
+        RequestKind::FillTower if state.under_attack => Some(base * 2),
+        RequestKind::FillTower | RequestKind::FillUpgradeBuffer | RequestKind::FillHubLink
+            if state.starved() =>
+        {
+            None
+        }
+        RequestKind::FillUpgradeBuffer | RequestKind::FillHubLink
+            if state.mode == RoomMode::Conserve =>
+        {
+            None
+        }
+        RequestKind::FillUpgradeBuffer if state.mode == RoomMode::PushRcl => {
+            Some(base * 2 + PRIORITY_PER_UPGRADER * state.upgraders)
+        }
+        // the one upgrader left can't use a rush of energy
+        RequestKind::FillUpgradeBuffer if state.mode == RoomMode::Mature => Some(base),
+        RequestKind::FillUpgradeBuffer => Some(base + PRIORITY_PER_UPGRADER * state.upgraders),
+        RequestKind::FillSpawn | RequestKind::FillExtension if state.starved() => Some(base * 2),
+        RequestKind::FillSpawn if state.short_for_spawn() => Some(base + SPAWN_PRESTOCK_BONUS),
+        _ => Some(base),
+    }
+}
+
+/// The controller link if there is one, else a container next to the controller. Upgraders
+/// draw exclusively from this when it exists.
+pub fn upgrade_buffer(room: &Room) -> Option<Structure> {
+    if let Some(link) = links::links_of_class(room, LinkClass::Controller)
+        .into_iter()
+        .next()
+    {
+        return Some(Structure::Link(link));
+    }
+    let controller = room.controller()?;
+    room_cache::containers(room)
+        .iter()
+        .find(|c| c.pos().in_range_to(&controller, UPGRADE_BUFFER_RANGE))
+        .cloned()
+        .map(Structure::Container)
+}
+
+fn classify_container(room: &Room, container: &StructureContainer) -> ContainerClass {
+    let pos = container.pos();
+    if room
+        .controller()
+        .map_or(false, |c| pos.in_range_to(&c, UPGRADE_BUFFER_RANGE))
+    {
+        ContainerClass::ControllerBuffer
+    } else if room_cache::sources(room).iter().any(|s| pos.is_near_to(s)) {
+        ContainerClass::Source
+    } else {
+        ContainerClass::SpawnBuffer
+    }
+}
+
+/// The container's class from structure memory, classifying it on first sight by what it
+/// stands next to: the controller, a source, or otherwise the spawn's supply.
+pub fn container_class(
+    room: &Room,
+    container: &StructureContainer,
+) -> Result<ContainerClass, BotError> {
+    let id = container.untyped_id();
+    if let Some(StructureMemory::Container { class }) = memory::get_structure_memory(id)? {
+        return Ok(class);
+    }
+    let class = classify_container(room, container);
+    debug!("classified container {} as {:?}", container.id(), class);
+    memory::set_structure_memory(id, &StructureMemory::Container { class })?;
+    Ok(class)
+}
+
+/// Whether the container holds energy meant for the spawn and extensions.
+pub fn feeds_spawn(room: &Room, container: &StructureContainer) -> bool {
+    match container_class(room, container) {
+        Ok(ContainerClass::Source) | Ok(ContainerClass::SpawnBuffer) => true,
+        Ok(ContainerClass::ControllerBuffer) => false,
+        // unknown means careful
+        Err(_) => true,
+    }
+}
+
+/// Whether spawn and extensions hold at least `percent` of their capacity.
+pub fn spawn_energy_at(room: &Room, percent: u32) -> bool {
+    room.energy_available() * 100 >= room.energy_capacity_available() * percent
+}
+
+/// Energy on the ground that should be collected before it decays.
+#[derive(Clone, Debug)]
+pub struct PickupRequest {
+    pub target: ObjectId<Resource>,
+    pub pos: Position,
+    pub amount: u32,
+}
+
+thread_local! {
+    static REQUESTS: RefCell<HashMap<RoomName, Vec<LogisticsRequest>>> =
+        RefCell::new(HashMap::new());
+    static PICKUPS: RefCell<HashMap<RoomName, Vec<PickupRequest>>> =
+        RefCell::new(HashMap::new());
+    static BESIEGED: RefCell<HashSet<RoomName>> = RefCell::new(HashSet::new());
+    /// Energy harvesters passed to each creep this tick, with the tick it's for.
+    static HANDOFFS: RefCell<(u32, HashMap<String, u32>)> = RefCell::new((0, HashMap::new()));
+}
+
+fn request_kind(structure: &Structure, buffer: Option<&Structure>) -> Option<RequestKind> {
+    match structure.structure_type() {
+        StructureType::Spawn => Some(RequestKind::FillSpawn),
+        StructureType::Extension => Some(RequestKind::FillExtension),
+        StructureType::Tower => Some(RequestKind::FillTower),
+        _ if buffer.map_or(false, |b| b.untyped_id() == structure.untyped_id()) => {
+            Some(RequestKind::FillUpgradeBuffer)
+        }
+        _ => None,
+    }
+}
+
+fn wants(kind: RequestKind, free: u32, capacity: u32) -> bool {
+    match kind {
+        RequestKind::FillTower => capacity - free < TOWER_REFILL_BELOW,
+        RequestKind::FillUpgradeBuffer => free * 2 > capacity,
+        _ => free > 0,
+    }
+}
+
+/// Range from `pos` to the closest spawn in `room`.
+fn spawn_range(room: &Room, pos: Position) -> u32 {
+    room_cache::my_spawns(room)
+        .iter()
+        .map(|s| s.pos().get_range_to(&pos))
+        .min()
+        .unwrap_or(0)
+}
+
+/// The extensions closest to a spawn whose free space, after the spawns' own, covers what the
+/// queue head still lacks. Empty while the head can be afforded.
+fn needed_extensions(room: &Room, state: &RoomEnergyState) -> HashSet<RawObjectId> {
+    let mut needed = HashSet::new();
+    if !state.short_for_spawn() {
+        return needed;
+    }
+    let spawn_free: u32 = room_cache::my_spawns(room)
+        .iter()
+        .map(|s| s.store_free_capacity(Some(ResourceType::Energy)))
+        .sum();
+    let mut missing = (state.spawn.next_cost - state.energy_available).saturating_sub(spawn_free);
+    let mut extensions: Vec<(u32, u32, RawObjectId)> = room_cache::extensions(room)
+        .iter()
+        .filter(|e| !room::is_inactive(room.name(), e.untyped_id()))
+        .map(|e| {
+            let free = e.store_free_capacity(Some(ResourceType::Energy));
+            (spawn_range(room, e.pos()), free, e.untyped_id())
+        })
+        .filter(|(_, free, _)| *free > 0)
+        .collect();
+    extensions.sort_by_key(|(range, _, _)| *range);
+    for (_, free, id) in extensions {
+        if missing == 0 {
+            break;
+        }
+        needed.insert(id);
+        missing = missing.saturating_sub(free);
+    }
+    needed
+}
+
+/// Rebuilds this tick's energy requests for `room`, sorted by effective priority.
+pub fn refresh(room: &Room, state: &RoomEnergyState) {
+    phase::check_cache_write("logistics requests");
+    let mut requests = Vec::new();
+    let buffer = upgrade_buffer(room);
+    let needed = needed_extensions(room, state);
+    for structure in room.find(find::STRUCTURES) {
+        // extensions past the controller level take no energy
+        if room::is_inactive(room.name(), structure.untyped_id()) {
+            continue;
+        }
+        let kind = match request_kind(&structure, buffer.as_ref()) {
+            Some(k) => k,
+            None => continue,
+        };
+        let store = match structure.as_has_store() {
+            Some(s) => s,
+            None => continue,
+        };
+        let free = store.store_free_capacity(Some(ResourceType::Energy));
+        let capacity = store.store_capacity(Some(ResourceType::Energy));
+        if !wants(kind, free, capacity) {
+            continue;
+        }
+        let base_priority = kind.base_priority();
+        let mut effective = effective_priority(kind, base_priority, state);
+        if kind == RequestKind::FillTower && capacity - free < TOWER_FLOOR {
+            effective = Some(effective.unwrap_or(0).max(TOWER_FLOOR_PRIORITY));
+        }
+        if let Some(mut priority) = effective {
+            if state.short_for_spawn() && state.spawn.next_spawn == Some(structure.untyped_id()) {
+                priority += NEXT_SPAWN_BONUS;
+            }
+            if kind == RequestKind::FillExtension {
+                let range = spawn_range(room, structure.pos());
+                priority += EXTENSION_NEAR_BONUS.saturating_sub(range / EXTENSION_RANGE_STEP);
+                if needed.contains(&structure.untyped_id()) {
+                    priority += SPAWN_PRESTOCK_BONUS;
+                }
+            }
+            requests.push(LogisticsRequest {
+                kind,
+                target: Recipient::Structure(structure.id()),
+                pos: structure.pos(),
+                resource: ResourceType::Energy,
+                amount: free,
+                base_priority,
+                priority,
+            });
+        }
+    }
+    if let Some((hub, amount)) = links::hub_request(room) {
+        let kind = RequestKind::FillHubLink;
+        if let Some(priority) = effective_priority(kind, kind.base_priority(), state) {
+            requests.push(LogisticsRequest {
+                kind,
+                target: Recipient::Structure(Structure::Link(hub.clone()).id()),
+                pos: hub.pos(),
+                resource: ResourceType::Energy,
+                amount,
+                base_priority: kind.base_priority(),
+                priority,
+            });
+        }
+    }
+    if planner::tower_rush(room.name()) {
+        requests.extend(builder_feeds(room, state));
+    }
+    let kind = RequestKind::Rebalance;
+    let rebalancing = effective_priority(kind, kind.base_priority(), state).is_some();
+    requests.extend(bands::rebalance(room, rebalancing));
+    requests.sort_by_key(|r| std::cmp::Reverse(r.priority));
+    REQUESTS.with(|r| {
+        r.borrow_mut().insert(room.name(), requests);
+    });
+    let pickups = drop_mining_pickups(room);
+    PICKUPS.with(|p| {
+        p.borrow_mut().insert(room.name(), pickups);
+    });
+    BESIEGED.with(|b| {
+        let mut b = b.borrow_mut();
+        if state.besieged {
+            b.insert(room.name());
+        } else {
+            b.remove(&room.name());
+        }
+    });
+}
+
+/// A `FeedBuilder` request for each worker building while the first tower is rushed once it's
+/// down to half a load, so the energy comes to the site instead of the builder leaving it. The
+/// builders draw nothing from spawn energy themselves; these requests rank them between the
+/// spawns and the extensions.
+fn builder_feeds(room: &Room, state: &RoomEnergyState) -> Vec<LogisticsRequest> {
+    let kind = RequestKind::FeedBuilder;
+    let priority = match effective_priority(kind, kind.base_priority(), state) {
+        Some(p) => p,
+        None => return Vec::new(),
+    };
+    room_cache::my_creeps(room)
+        .iter()
+        .filter(|c| population::role_of(c) == Role::Worker)
+        .filter(|c| {
+            let mem = memory::creep_memory(c);
+            mem.bool("building") && !mem.bool("harvesting")
+        })
+        .filter_map(|c| {
+            let free = c.store_free_capacity(Some(ResourceType::Energy));
+            if free == 0 || free * 2 < c.store_capacity(Some(ResourceType::Energy)) {
+                return None;
+            }
+            Some(LogisticsRequest {
+                kind,
+                target: Recipient::Creep(c.id()),
+                pos: c.pos(),
+                resource: ResourceType::Energy,
+                amount: free,
+                base_priority: kind.base_priority(),
+                priority,
+            })
+        })
+        .collect()
+}
+
+/// The terminal, when the room is besieged and its towers want energy the terminal has. Siege
+/// support sends energy there, so haulers refill towers from it before anything else.
+pub fn siege_terminal(room: &Room) -> Option<StructureTerminal> {
+    if !BESIEGED.with(|b| b.borrow().contains(&room.name())) {
+        return None;
+    }
+    let towers_low = requests(room.name())
+        .iter()
+        .any(|r| r.kind == RequestKind::FillTower);
+    room.terminal()
+        .filter(|t| towers_low && t.store_of(ResourceType::Energy) > 0)
+}
+
+/// Piles around harvesters that lost their container, largest first. Collecting these comes
+/// before any other energy source, since the ground loses energy every tick.
+fn drop_mining_pickups(room: &Room) -> Vec<PickupRequest> {
+    let mut seen = HashSet::new();
+    let mut pickups: Vec<PickupRequest> = mining::uncontained_stands(room)
+        .iter()
+        .flat_map(|stand| stand.find_in_range(find::DROPPED_RESOURCES, 1))
+        .filter(|r| r.resource_type() == ResourceType::Energy && r.amount() >= PICKUP_MIN)
+        .filter(|r| seen.insert(r.id()))
+        .map(|r| PickupRequest {
+            target: r.id(),
+            pos: r.pos(),
+            amount: r.amount(),
+        })
+        .collect();
+    pickups.sort_by_key(|p| std::cmp::Reverse(p.amount));
+    pickups
+}
+
+/// Records `amount` energy a harvester passed straight to `to`, so the load is counted before
+/// the transfer lands next tick.
+pub fn hand_off(to: &Creep, amount: u32) {
+    let now = screeps::game::time();
+    HANDOFFS.with(|h| {
+        let h = &mut *h.borrow_mut();
+        if h.0 != now {
+            *h = (now, HashMap::new());
+        }
+        *h.1.entry(to.name()).or_insert(0) += amount;
+    });
+}
+
+/// Energy harvesters passed to `creep` this tick.
+pub fn handed_to(creep: &Creep) -> u32 {
+    let now = screeps::game::time();
+    HANDOFFS.with(|h| {
+        let h = h.borrow();
+        if h.0 == now {
+            h.1.get(&creep.name()).copied().unwrap_or(0)
+        } else {
+            0
+        }
+    })
+}
+
+/// This tick's requests for `room`, highest priority first.
+pub fn requests(room: RoomName) -> Vec<LogisticsRequest> {
+    REQUESTS.with(|r| r.borrow().get(&room).cloned().unwrap_or_default())
+}
+
+/// This tick's ground pickups for `room`, largest first.
+pub fn pickups(room: RoomName) -> Vec<PickupRequest> {
+    PICKUPS.with(|p| p.borrow().get(&room).cloned().unwrap_or_default())
+}
+
+/// What's left of a dropped pile after `ticks` of decay, which takes a thousandth (rounded up)
+/// each tick.
+fn amount_after(amount: u32, ticks: u32) -> u32 {
+    let mut left = amount;
+    for _ in 0..ticks {
+        if left == 0 {
+            break;
+        }
+        left -= (left + 999) / 1000;
+    }
+    left
+}
+
+/// Energy the pile still holds when a creep at `from` gets there, per tick of the round trip,
+/// in hundredths.
+fn pickup_value(from: Position, pile: &PickupRequest) -> Option<u32> {
+    let trip = route::measure(from, pile.pos, 1)?;
+    let value = amount_after(pile.amount, trip.tiles) * 100 / trip.round_trip_ticks().max(1);
+    Some(value)
+}
+
+/// The pile `creep` should collect: the one it already chose while it's still there, else the
+/// best of the closest few by `pickup_value`. The choice is kept in creep memory under
+/// `pickup` until the load is full so it isn't pathed again every tick, and a creep that found
+/// nothing worth it waits `PICKUP_RECHECK_TICKS` before looking again.
+pub fn assigned_pickup(creep: &Creep, room: RoomName) -> Option<Resource> {
+    let mut piles = pickups(room);
+    let mem = memory::creep_memory(creep);
+    if let Some(id) = mem.string("pickup").ok().flatten() {
+        if let Some(pile) = piles.iter().find(|p| p.target.to_string() == id) {
+            return objects::get_cached(pile.target);
+        }
+        mem.del("pickup");
+    }
+    let now = screeps::game::time();
+    let checked = mem.i32("pickup_checked").ok().flatten().map_or(0, |t| t as u32);
+    if piles.is_empty() || now.saturating_sub(checked) < PICKUP_RECHECK_TICKS {
+        return None;
+    }
+    piles.sort_by_key(|p| creep.pos().get_range_to(&p.pos));
+    let min = settings::u32_or("pickup_min_value", PICKUP_MIN_VALUE);
+    let best = piles
+        .iter()
+        .take(PICKUP_CANDIDATES)
+        .filter_map(|p| pickup_value(creep.pos(), p).map(|v| (v, p)))
+        .filter(|(v, _)| *v >= min)
+        .max_by_key(|(v, _)| *v)
+        .map(|(_, p)| p.target);
+    match best {
+        Some(target) => {
+            mem.set("pickup", target.to_string().as_str());
+            objects::get_cached(target)
+        }
+        None => {
+            mem.set("pickup_checked", now);
+            None
+        }
+    }
+}
+
+/// Orders requests so each is the closest remaining one to the previous, starting at `from`.
+fn order_nearest(from: Position, mut left: Vec<LogisticsRequest>) -> Vec<LogisticsRequest> {
+    let mut ordered = Vec::with_capacity(left.len());
+    let mut at = from;
+    while !left.is_empty() {
+        let next = left
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, r)| at.get_range_to(&r.pos))
+            .map(|(i, _)| i)
+            .unwrap_or(0);
+        let request = left.remove(next);
+        at = request.pos;
+        ordered.push(request);
+    }
+    ordered
+}
+
+/// What a creep is carrying, by resource.
+pub fn cargo(creep: &Creep) -> Vec<(ResourceType, u32)> {
+    creep
+        .store_types()
+        .into_iter()
+        .map(|r| (r, creep.store_of(r)))
+        .filter(|(_, amount)| *amount > 0)
+        .collect()
+}
+
+/// The order a hauler unloads mixed cargo in: resources some request wants, highest priority
+/// request first, then everything else, which goes to storage.
+pub fn unload_order(
+    cargo: &[(ResourceType, u32)],
+    requests: &[LogisticsRequest],
+) -> Vec<ResourceType> {
+    let mut order: Vec<ResourceType> = Vec::new();
+    let carried = |r: ResourceType| cargo.iter().any(|(c, _)| *c == r);
+    for request in requests {
+        if carried(request.resource) && !order.contains(&request.resource) {
+            order.push(request.resource);
+        }
+    }
+    for (resource, _) in cargo {
+        if !order.contains(resource) {
+            order.push(*resource);
+        }
+    }
+    order
+}
+
+/// The top request for a hauler at `from` with `cargo`, only ever for a resource it carries,
+/// so one run never mixes resources. An extension request pulls in the extension requests
+/// around it for as long as the load covers them, in visiting order. A hauler that is itself
+/// `building` builds with its load rather than feeding other builders.
+pub fn batch(
+    room: RoomName,
+    from: Position,
+    cargo: &[(ResourceType, u32)],
+    building: bool,
+) -> Vec<LogisticsRequest> {
+    let mut pending = requests(room);
+    if building {
+        pending.retain(|r| r.kind != RequestKind::FeedBuilder);
+    }
+    let head = match pending
+        .iter()
+        .position(|r| cargo.iter().any(|(c, _)| *c == r.resource))
+    {
+        Some(i) => pending.remove(i),
+        None => return Vec::new(),
+    };
+    if head.kind != RequestKind::FillExtension {
+        return vec![head];
+    }
+    let carried = cargo
+        .iter()
+        .find(|(c, _)| *c == head.resource)
+        .map_or(0, |(_, amount)| *amount);
+
+    let mut total = head.amount;
+    let resource = head.resource;
+    let mut picked = vec![head];
+    loop {
+        let next = pending.iter().position(|r| {
+            r.kind == RequestKind::FillExtension
+                && r.resource == resource
+                && total + r.amount <= carried
+                && picked.iter().any(|p| p.pos.in_range_to(&r.pos, BATCH_RANGE))
+        });
+        match next {
+            Some(i) => {
+                let request = pending.remove(i);
+                total += request.amount;
+                picked.push(request);
+            }
+            None => break,
+        }
+    }
+    order_nearest(from, picked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(resource: ResourceType) -> LogisticsRequest {
+        let kind = RequestKind::Rebalance;
+        LogisticsRequest {
+            kind,
+            target: Recipient::Structure("5bbcac4d9099fc012e635cb1".parse().unwrap()),
+            pos: Position::new(25, 25, "W1N1".parse().unwrap()),
+            resource,
+            amount: 100,
+            base_priority: kind.base_priority(),
+            priority: kind.base_priority(),
+        }
+    }
+
+    fn state() -> RoomEnergyState {
+        RoomEnergyState {
+            starved_ticks: 0,
+            downgrade_imminent: false,
+            under_attack: false,
+            besieged: false,
+            upgraders: 2,
+            mode: RoomMode::Normal,
+            energy_available: 300,
+            spawn: SpawnState::default(),
+        }
+    }
+
+    fn starved() -> RoomEnergyState {
+        RoomEnergyState {
+            starved_ticks: STARVED_AFTER_TICKS,
+            ..state()
+        }
+    }
+
+    fn priority(kind: RequestKind, state: &RoomEnergyState) -> Option<u32> {
+        effective_priority(kind, kind.base_priority(), state)
+    }
+
+    #[test]
+    fn calm_room_keeps_base_priorities() {
+        let s = state();
+        assert_eq!(priority(RequestKind::FillSpawn, &s), Some(100));
+        assert_eq!(priority(RequestKind::FillTower, &s), Some(60));
+        assert_eq!(
+            priority(RequestKind::FillUpgradeBuffer, &s),
+            Some(20 + 2 * PRIORITY_PER_UPGRADER)
+        );
+        assert_eq!(priority(RequestKind::Rebalance, &s), Some(10));
+    }
+
+    #[test]
+    fn builders_are_fed_between_extensions_and_spawns() {
+        for s in &[state(), starved()] {
+            let feed = priority(RequestKind::FeedBuilder, s).unwrap();
+            assert!(feed < priority(RequestKind::FillSpawn, s).unwrap());
+        }
+        let s = state();
+        let feed = priority(RequestKind::FeedBuilder, &s).unwrap();
+        assert!(feed > priority(RequestKind::FillExtension, &s).unwrap());
+    }
+
+    #[test]
+    fn starved_and_downgrade_imminent() {
+        let s = RoomEnergyState {
+            downgrade_imminent: true,
+            ..starved()
+        };
+        assert_eq!(priority(RequestKind::FillUpgradeBuffer, &s), Some(TOP_PRIORITY));
+        assert_eq!(priority(RequestKind::FillSpawn, &s), Some(200));
+        assert_eq!(priority(RequestKind::FillTower, &s), None);
+        assert_eq!(priority(RequestKind::FillHubLink, &s), None);
+        assert_eq!(priority(RequestKind::Rebalance, &s), None);
+    }
+
+    #[test]
+    fn attacked_and_starved() {
+        let s = RoomEnergyState {
+            under_attack: true,
+            ..starved()
+        };
+        assert_eq!(priority(RequestKind::FillTower, &s), Some(120));
+        assert_eq!(priority(RequestKind::FillExtension, &s), Some(180));
+        assert_eq!(priority(RequestKind::FillUpgradeBuffer, &s), None);
+        assert_eq!(priority(RequestKind::Rebalance, &s), None);
+    }
+
+    #[test]
+    fn attacked_with_downgrade_imminent_still_upgrades_first() {
+        let s = RoomEnergyState {
+            under_attack: true,
+            downgrade_imminent: true,
+            ..state()
+        };
+        assert_eq!(priority(RequestKind::FillUpgradeBuffer, &s), Some(TOP_PRIORITY));
+        assert_eq!(priority(RequestKind::FillTower, &s), Some(120));
+    }
+
+    #[test]
+    fn room_modes_shape_the_upgrade_buffer() {
+        let mode = |mode| RoomEnergyState { mode, ..state() };
+        let buffer = RequestKind::FillUpgradeBuffer;
+        assert_eq!(priority(buffer, &mode(RoomMode::Conserve)), None);
+        assert_eq!(priority(RequestKind::FillHubLink, &mode(RoomMode::Conserve)), None);
+        assert_eq!(
+            priority(buffer, &mode(RoomMode::PushRcl)),
+            Some(40 + 2 * PRIORITY_PER_UPGRADER)
+        );
+        assert_eq!(priority(buffer, &mode(RoomMode::Mature)), Some(20));
+    }
+
+    #[test]
+    fn spawn_prestocks_for_an_unaffordable_head() {
+        let s = RoomEnergyState {
+            spawn: SpawnState {
+                next_cost: 550,
+                ..SpawnState::default()
+            },
+            ..state()
+        };
+        assert_eq!(priority(RequestKind::FillSpawn, &s), Some(100 + SPAWN_PRESTOCK_BONUS));
+        assert_eq!(priority(RequestKind::FillExtension, &s), Some(90));
+    }
+
+    #[test]
+    fn requested_cargo_unloads_first_in_request_order() {
+        let cargo = [
+            (ResourceType::Energy, 50),
+            (ResourceType::Hydrogen, 100),
+            (ResourceType::Oxygen, 25),
+        ];
+        let requests = [request(ResourceType::Oxygen), request(ResourceType::Energy)];
+        assert_eq!(
+            unload_order(&cargo, &requests),
+            vec![ResourceType::Oxygen, ResourceType::Energy, ResourceType::Hydrogen]
+        );
+    }
+
+    #[test]
+    fn requests_for_uncarried_resources_are_skipped() {
+        let cargo = [(ResourceType::Utrium, 200)];
+        let requests = [request(ResourceType::Energy), request(ResourceType::Utrium)];
+        assert_eq!(unload_order(&cargo, &requests), vec![ResourceType::Utrium]);
+    }
+
+    #[test]
+    fn each_resource_appears_once() {
+        let cargo = [(ResourceType::Energy, 50)];
+        let requests = [request(ResourceType::Energy), request(ResourceType::Energy)];
+        assert_eq!(unload_order(&cargo, &requests), vec![ResourceType::Energy]);
+    }
+
+    #[test]
+    fn unrequested_cargo_keeps_its_order() {
+        let cargo = [(ResourceType::Keanium, 10), (ResourceType::Lemergium, 10)];
+        assert_eq!(
+            unload_order(&cargo, &[]),
+            vec![ResourceType::Keanium, ResourceType::Lemergium]
+        );
+    }
+}
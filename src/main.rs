@@ -1,13 +1,75 @@
-use std::collections::HashSet;
-
 use log::*;
-use screeps::{find, prelude::*, Part, ResourceType, ReturnCode, RoomObjectProperties};
+use screeps::{prelude::*, Room, RoomObjectProperties};
 use stdweb::js;
 
+use crate::{phase::Phase, role::Role};
+
+mod accounts;
+mod anomaly;
+mod allies;
+mod avoid;
+mod bands;
+mod combat;
+mod console;
+mod construction;
+mod coord;
+mod creep;
+mod deploy;
+mod deposits;
+mod destruction;
+mod diplomacy;
+mod error;
+mod expansion;
+mod group;
+mod growth;
+mod history;
+mod inspect;
+mod intel;
+mod intents;
+mod invaders;
+mod inventory;
+mod labs;
+mod links;
 mod logging;
+mod logistics;
+mod memory;
+mod memory_budget;
+mod mining;
+mod objects;
+mod perimeter;
+mod phase;
+mod planner;
+mod population;
+mod power;
+mod presets;
+mod rampart;
+mod reconcile;
+mod remote;
+mod renewal;
+mod repair;
+mod role;
+mod room;
+mod room_cache;
+mod route;
+mod scout;
+mod settings;
+mod shard;
+mod spawn;
+mod stats;
+mod structure;
+mod tasklog;
+mod terminal;
+mod terrain;
+mod threat;
+mod tower;
+mod traffic;
+mod triage;
+mod visuals;
 
 fn main() {
+    shard::detect();
     logging::setup_logging(logging::Info);
+    console::register();
 
     js! {
         var game_loop = @{game_loop};
@@ -31,111 +93,254 @@ fn main() {
     }
 }
 
+/// Runs one tick in the phases described on `phase::Phase`. Intents only resolve after the
+/// tick, so the order matters for what each phase decides on: creeps act on this tick's
+/// logistics requests and spawn queue, and spawns and structures act last so a queue entry is
+/// only popped once the creeps have seen it.
 fn game_loop() {
     debug!("loop starting! CPU: {}", screeps::game::cpu::get_used());
+    // before anything reads memory or fills a cache this tick
+    console::apply_queued();
+    let time = screeps::game::time();
+    intents::begin_tick();
+    deploy::check();
 
-    debug!("running spawns");
-    for spawn in screeps::game::spawns::values() {
-        debug!("running spawn {}", spawn.name());
-        let body = [Part::Move, Part::Move, Part::Carry, Part::Work];
-
-        if spawn.energy() >= body.iter().map(|p| p.cost()).sum() {
-            // create a unique name, spawn.
-            let name_base = screeps::game::time();
-            let mut additional = 0;
-            let res = loop {
-                let name = format!("{}-{}", name_base, additional);
-                let res = spawn.spawn_creep(&body, &name);
-
-                if res == ReturnCode::NameExists {
-                    additional += 1;
-                } else {
-                    break res;
-                }
-            };
+    let owned: Vec<Room> = screeps::game::rooms::values()
+        .into_iter()
+        .filter(|r| r.controller().map(|c| c.my()).unwrap_or(false))
+        .collect();
 
-            if res != ReturnCode::Ok {
-                warn!("couldn't spawn: {:?}", res);
-            }
+    phase::enter(Phase::Cache);
+    run_rooms(&owned);
+
+    if time % 50 == 11 {
+        if let Err(e) = intel::run_intel() {
+            error::report("intel", "Memory.intel", "-", &e);
         }
     }
 
-    debug!("running creeps");
-    for creep in screeps::game::creeps::values() {
-        let name = creep.name();
-        debug!("running creep {}", name);
-        if creep.spawning() {
-            continue;
+    if time % 20 == 5 {
+        inventory::refresh();
+    }
+
+    if time % 100 == 7 {
+        if let Err(e) = remote::run_remotes() {
+            error::report("remotes", "Memory.remotes", "-", &e);
         }
+    }
+    if time % 50 == 37 {
+        if let Err(e) = expansion::run_pioneers() {
+            error::report("pioneers", "expansion", "-", &e);
+        }
+    }
+    if time % 100 == 61 {
+        if let Err(e) = deposits::run_deposits() {
+            error::report("deposits", "Memory.deposits", "-", &e);
+        }
+    }
 
-        if creep.memory().bool("harvesting") {
-            if creep.store_free_capacity(Some(ResourceType::Energy)) == 0 {
-                creep.memory().set("harvesting", false);
-            }
-        } else {
-            if creep.store_used_capacity(None) == 0 {
-                creep.memory().set("harvesting", true);
-            }
+    phase::enter(Phase::Creeps);
+    if let Err(e) = group::run_groups() {
+        error::report("groups", "Memory.groups", "-", &e);
+    }
+
+    run_creeps();
+    if let Err(e) = power::run_power_creeps() {
+        error::report("power creeps", "Game.powerCreeps", "-", &e);
+    }
+    if let Err(e) = invaders::flush() {
+        error::report("invader clocks", "Memory.invaders", "-", &e);
+    }
+    if let Err(e) = remote::watch_remotes() {
+        error::report("remote attacks", "Memory.intel", "-", &e);
+    }
+
+    phase::enter(Phase::Structures);
+    for structure in screeps::game::structures::values() {
+        if let Err(e) = structure::run_structure(&structure) {
+            error::report(
+                "structure",
+                &structure.untyped_id().to_string(),
+                &room_label(&structure),
+                &e,
+            );
         }
+    }
 
-        if creep.memory().bool("harvesting") {
-            let source = &creep
-                .room()
-                .expect("room is not visible to you")
-                .find(find::SOURCES)[0];
-            if creep.pos().is_near_to(source) {
-                let r = creep.harvest(source);
-                if r != ReturnCode::Ok {
-                    warn!("couldn't harvest: {:?}", r);
-                }
-            } else {
-                creep.move_to(source);
-            }
-        } else {
-            if let Some(c) = creep
-                .room()
-                .expect("room is not visible to you")
-                .controller()
-            {
-                let r = creep.upgrade_controller(&c);
-                if r == ReturnCode::NotInRange {
-                    creep.move_to(&c);
-                } else if r != ReturnCode::Ok {
-                    warn!("couldn't upgrade: {:?}", r);
-                }
-            } else {
-                warn!("creep room has no controller!");
-            }
+    for room in &owned {
+        if let Err(e) = spawn::run_spawns(room) {
+            error::report("spawn queue", &room.name().to_string(), &room.name().to_string(), &e);
         }
     }
+    if let Err(e) = terminal::run_siege_support() {
+        error::report("siege support", "terminals", "-", &e);
+    }
 
-    let time = screeps::game::time();
+    phase::enter(Phase::Report);
+    for room in &owned {
+        if let Err(e) = room::draw_visuals(room) {
+            error::report("visuals", &room.name().to_string(), &room.name().to_string(), &e);
+        }
+    }
 
+    if time % 1000 == 13 {
+        if let Err(e) = expansion::publish_report() {
+            error::report("expansion report", "Memory.stats", "-", &e);
+        }
+    }
+
+    if let Err(e) = memory::flush() {
+        error::report("memory flush", "Memory", "-", &e);
+    }
+    if time % 500 == 7 {
+        anomaly::note("memory budget");
+        if let Err(e) = memory_budget::run_memory_budget() {
+            error::report("memory budget", "Memory", "-", &e);
+        }
+    }
     if time % 32 == 3 {
         info!("running memory cleanup");
-        cleanup_memory().expect("expected Memory.creeps format to be a regular memory object");
+        anomaly::note("memory cleanup");
+        if let Err(e) = memory::cleanup_memory() {
+            error::report("memory cleanup", "Memory", "-", &e);
+        }
     }
 
+    objects::report();
+    room_cache::report();
+    shard::record_stats();
+    shard::publish(intel::shard_portals());
+    history::run_history();
+    intents::end_tick();
+    anomaly::end_tick(owned.len() as u32);
+    logging::flush();
     info!("done! cpu: {}", screeps::game::cpu::get_used())
 }
 
-fn cleanup_memory() -> Result<(), Box<dyn std::error::Error>> {
-    let alive_creeps: HashSet<String> = screeps::game::creeps::keys().into_iter().collect();
+/// Where a room comes in the room pass: under attack first, then developing rooms lowest
+/// controller level first, then mature ones. Rooms deferred last tick go ahead of all but
+/// those under attack.
+fn room_order(room: &Room, deferred: &[String]) -> (bool, bool, bool, u32) {
+    let mem = memory::get_room_memory(room.name()).unwrap_or_default();
+    let attacked = mem.threat_since.is_some();
+    let mature = mem.mode == room::RoomMode::Mature;
+    let waited = deferred.contains(&room.name().to_string());
+    (!attacked, !waited, mature, mem.rcl)
+}
 
-    let screeps_memory = match screeps::memory::root().dict("creeps")? {
-        Some(v) => v,
-        None => {
-            warn!("not cleaning game creep memory: no Memory.creeps dict");
-            return Ok(());
+/// Runs each owned room's pass in `room_order`, a failing room logging its error and the rest
+/// carrying on. Once the cache phase is over its CPU budget the remaining rooms wait, and
+/// `Memory.deferred_rooms` lists them so they go first next tick. Each room's CPU goes to
+/// `Memory.stats.room_cpu`.
+fn run_rooms(owned: &[Room]) {
+    let root = screeps::memory::root();
+    let deferred: Vec<String> = root
+        .string("deferred_rooms")
+        .ok()
+        .flatten()
+        .map(|s| s.split(',').map(str::to_owned).collect())
+        .unwrap_or_default();
+    let mut rooms: Vec<&Room> = owned.iter().collect();
+    rooms.sort_by_cached_key(|r| room_order(r, &deferred));
+
+    let budget = phase::budget(Phase::Cache);
+    for (i, room) in rooms.iter().enumerate() {
+        if i > 0 && screeps::game::cpu::get_used() > budget {
+            let skipped: Vec<String> = rooms[i..].iter().map(|r| r.name().to_string()).collect();
+            info!("room pass over budget, deferring {}", skipped.join(", "));
+            root.set("deferred_rooms", skipped.join(","));
+            return;
+        }
+        let start = screeps::game::cpu::get_used();
+        if let Err(e) = room::run_room(room) {
+            error::report("room", &room.name().to_string(), &room.name().to_string(), &e);
         }
-    };
+        let used = screeps::game::cpu::get_used() - start;
+        stats::increment_room("room_cpu", Some(room.name()), "centi_cpu", (used * 100.0) as i32);
+        stats::increment_room("room_cpu", Some(room.name()), "runs", 1);
+    }
+    root.del("deferred_rooms");
+}
+
+/// Creeps between CPU budget checks.
+const CREEPS_PER_BUDGET_CHECK: usize = 5;
+
+/// Indexes of creeps sorted by name, split by whether each is `defending`: defenders first,
+/// in name order, then everyone else in name order rotated to start at `cursor`.
+fn creep_order(defending: &[bool], cursor: usize) -> (Vec<usize>, Vec<usize>) {
+    let (defense, mut rotation): (Vec<usize>, Vec<usize>) =
+        (0..defending.len()).partition(|&i| defending[i]);
+    if !rotation.is_empty() {
+        let start = cursor % rotation.len();
+        rotation.rotate_left(start);
+    }
+    (defense, rotation)
+}
+
+fn run_one(creep: &screeps::Creep) {
+    if let Err(e) = creep::run_creep(creep) {
+        error::report("creep", &creep.name(), &room_label(creep), &e);
+    }
+    if let Err(e) = memory::flush_creep(&creep.name()) {
+        error::report("creep memory", &creep.name(), &room_label(creep), &e);
+    }
+}
+
+/// Runs defenders, then every other creep in name order starting at `Memory.creep_cursor`.
+/// Defense is never deferred; when the rest run over the creep phase's CPU budget they're
+/// deferred, and the cursor points at the first skipped creep so it goes first next tick.
+fn run_creeps() {
+    let mut creeps = screeps::game::creeps::values();
+    if creeps.is_empty() {
+        return;
+    }
+    creeps.sort_by_key(|c| c.name());
+    let root = screeps::memory::root();
+    let cursor = root.i32("creep_cursor").ok().flatten().unwrap_or(0).max(0) as usize;
+    let defending: Vec<bool> = creeps
+        .iter()
+        .map(|c| population::role_of(c) == Role::Defender)
+        .collect();
+    let (defense, rotation) = creep_order(&defending, cursor);
+    for &i in &defense {
+        run_one(&creeps[i]);
+    }
 
-    for mem_name in screeps_memory.keys() {
-        if !alive_creeps.contains(&mem_name) {
-            debug!("cleaning up creep memory of dead creep {}", mem_name);
-            screeps_memory.del(&mem_name);
+    let budget = phase::budget(Phase::Creeps);
+    for (n, &i) in rotation.iter().enumerate() {
+        if n > 0 && n % CREEPS_PER_BUDGET_CHECK == 0 && screeps::game::cpu::get_used() > budget {
+            info!("creep phase over budget, deferring {} creeps", rotation.len() - n);
+            phase::note_deferred();
+            root.set("creep_cursor", ((cursor + n) % rotation.len()) as i32);
+            return;
         }
+        run_one(&creeps[i]);
     }
+}
+
+fn room_label<T: RoomObjectProperties>(obj: &T) -> String {
+    obj.room()
+        .map(|r| r.name().to_string())
+        .unwrap_or_else(|| "<no room>".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(())
+    #[test]
+    fn defenders_run_first_and_outside_the_rotation() {
+        let defending = [false, true, false, false, true];
+        let (defense, rotation) = creep_order(&defending, 0);
+        assert_eq!(defense, vec![1, 4]);
+        assert_eq!(rotation, vec![0, 2, 3]);
+    }
+
+    #[test]
+    fn the_rotation_starts_at_the_cursor() {
+        let defending = [false, true, false, false];
+        assert_eq!(creep_order(&defending, 1).1, vec![2, 3, 0]);
+        assert_eq!(creep_order(&defending, 4).1, vec![2, 3, 0]);
+        assert_eq!(creep_order(&[true, true], 3), (vec![0, 1], Vec::new()));
+    }
 }
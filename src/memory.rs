@@ -0,0 +1,675 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+};
+
+use log::*;
+use screeps::{
+    memory::MemoryReference, prelude::*, Creep, Part, RawObjectId, ResourceType, RoomName,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value as JsonValue};
+use stdweb::{js, js_deserializable, js_serializable, unstable::TryInto, Value};
+
+use crate::{avoid, creep, error::BotError, role::Role, room::RoomMemory, stats, tasklog};
+
+/// Memory of a creep that went through a portal is kept this long after it disappears.
+const PORTAL_GRACE_TICKS: u32 = 1500;
+/// Bumped when the memory spawned creeps get changes shape; `migrate_creeps` brings older
+/// creeps up to it.
+pub const CREEP_MEMORY_VERSION: u32 = 1;
+/// Lifespans, for estimating when creeps from before `born` was recorded came out.
+const CREEP_LIFE_TICKS: u32 = 1500;
+const CLAIM_CREEP_LIFE_TICKS: u32 = 600;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LabRole {
+    Input,
+    Output,
+    Boost,
+}
+
+/// What a container is for, so roles can tell which energy they may take.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContainerClass {
+    /// Next to a source, filled by its harvester.
+    Source,
+    /// Holds energy on its way to the spawn and extensions.
+    SpawnBuffer,
+    /// The upgraders' buffer next to the controller.
+    ControllerBuffer,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkClass {
+    Source,
+    Hub,
+    Controller,
+}
+
+/// Per-structure memory, stored in `Memory.structures` keyed by object id.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum StructureMemory {
+    Spawn { queue_cursor: u32 },
+    Tower { last_target: Option<RawObjectId> },
+    Lab {
+        role: LabRole,
+        compound: Option<ResourceType>,
+    },
+    Link { class: LinkClass },
+    Container { class: ContainerClass },
+}
+
+js_serializable!(StructureMemory);
+js_deserializable!(StructureMemory);
+
+/// A room's memory as loaded this tick, with the JSON it was loaded as so a write that changed
+/// nothing can be dropped. Kept as a `serde_json::Value`, whose maps are sorted, so the
+/// `HashMap` fields compare the same whatever order they iterate in.
+struct CachedRoom {
+    mem: RoomMemory,
+    loaded: JsonValue,
+    dirty: bool,
+}
+
+/// A creep's memory as loaded this tick, key by key, with the keys it was loaded as so
+/// `flush_creep` only writes back the ones that changed.
+struct CachedCreep {
+    keys: Map<String, JsonValue>,
+    loaded: Map<String, JsonValue>,
+}
+
+/// The keys of a creep's memory `flush_creep` writes back in one call.
+#[derive(Serialize)]
+struct CreepChanges {
+    set: Map<String, JsonValue>,
+    del: Vec<String>,
+}
+
+/// Room, structure and creep memory read this tick. Reads after the first and all writes stay
+/// on the Rust side; creeps are written back by `flush_creep` at the end of their turn, and
+/// `flush` writes back the rest of what changed once at the end of the tick, so a tick that
+/// dies before then loses its writes.
+#[derive(Default)]
+struct MemoryCache {
+    tick: u32,
+    rooms: HashMap<RoomName, CachedRoom>,
+    structures: HashMap<RawObjectId, (Option<StructureMemory>, bool)>,
+    creeps: HashMap<String, CachedCreep>,
+    reads: u32,
+    creep_writes: i32,
+}
+
+thread_local! {
+    static CACHE: RefCell<MemoryCache> = RefCell::new(MemoryCache::default());
+}
+
+/// Runs `f` on this tick's cache, starting a fresh one when the tick changed. Anything left
+/// unflushed from an earlier tick is dropped.
+fn with_cache<R>(f: impl FnOnce(&mut MemoryCache) -> R) -> R {
+    let now = screeps::game::time();
+    CACHE.with(|c| {
+        let mut c = c.borrow_mut();
+        if c.tick != now {
+            *c = MemoryCache {
+                tick: now,
+                ..MemoryCache::default()
+            };
+        }
+        f(&mut c)
+    })
+}
+
+fn dict_or_create(parent: &MemoryReference, key: &'static str) -> Result<MemoryReference, BotError> {
+    parent
+        .dict_or_create(key)
+        .map_err(|e| BotError::Deserialize {
+            target: key,
+            source: e.to_string(),
+        })
+}
+
+fn structures() -> Result<MemoryReference, BotError> {
+    dict_or_create(&screeps::memory::root(), "structures")
+}
+
+pub fn get_structure_memory(id: RawObjectId) -> Result<Option<StructureMemory>, BotError> {
+    if let Some(mem) = with_cache(|c| c.structures.get(&id).map(|(m, _)| m.clone())) {
+        return Ok(mem);
+    }
+    let mem = structures()?
+        .get::<StructureMemory>(&id.to_string())
+        .map_err(|e| BotError::Deserialize {
+            target: "StructureMemory",
+            source: e.to_string(),
+        })?;
+    with_cache(|c| {
+        c.reads += 1;
+        c.structures.insert(id, (mem.clone(), false));
+    });
+    Ok(mem)
+}
+
+pub fn set_structure_memory(id: RawObjectId, mem: &StructureMemory) -> Result<(), BotError> {
+    let current = get_structure_memory(id)?;
+    if current.as_ref() != Some(mem) {
+        with_cache(|c| c.structures.insert(id, (Some(mem.clone()), true)));
+    }
+    Ok(())
+}
+
+/// Drops a destroyed structure's memory; `flush` deletes it from `Memory.structures`.
+pub fn forget_structure(id: RawObjectId) {
+    with_cache(|c| c.structures.insert(id, (None, true)));
+}
+
+fn creep_error(e: impl ToString) -> BotError {
+    BotError::Deserialize {
+        target: "creep memory",
+        source: e.to_string(),
+    }
+}
+
+/// Every key of `name`'s memory, in one call.
+fn load_creep(name: &str) -> Result<Map<String, JsonValue>, BotError> {
+    let json: String = js!(
+        var creeps = Memory.creeps;
+        return JSON.stringify((creeps && creeps[@{name}]) || {});
+    )
+    .try_into()
+    .map_err(creep_error)?;
+    serde_json::from_str(&json).map_err(creep_error)
+}
+
+/// Runs `f` on `name`'s memory in this tick's cache, loading it on first use.
+fn with_creep<R>(name: &str, f: impl FnOnce(&mut CachedCreep) -> R) -> Result<R, BotError> {
+    let loaded = if with_cache(|c| c.creeps.contains_key(name)) {
+        None
+    } else {
+        Some(load_creep(name)?)
+    };
+    Ok(with_cache(|c| {
+        if loaded.is_some() {
+            c.reads += 1;
+        }
+        let cached = c.creeps.entry(name.to_owned()).or_insert_with(|| {
+            let keys = loaded.unwrap_or_default();
+            CachedCreep {
+                loaded: keys.clone(),
+                keys,
+            }
+        });
+        f(cached)
+    }))
+}
+
+/// Whether JavaScript would take `value` as true, as `MemoryReference::bool` does.
+fn truthy(value: &JsonValue) -> bool {
+    match value {
+        JsonValue::Null => false,
+        JsonValue::Bool(b) => *b,
+        JsonValue::Number(n) => n.as_f64().map_or(false, |n| n != 0.0),
+        JsonValue::String(s) => !s.is_empty(),
+        JsonValue::Array(_) | JsonValue::Object(_) => true,
+    }
+}
+
+/// A creep's memory, read and written through this tick's cache: the first read loads every
+/// key in one go, and writes stay on the Rust side until `flush_creep`. Same accessors as
+/// `MemoryReference`.
+pub struct CreepMemory {
+    name: String,
+}
+
+pub fn creep_memory(creep: &Creep) -> CreepMemory {
+    CreepMemory { name: creep.name() }
+}
+
+impl CreepMemory {
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, BotError> {
+        match with_creep(&self.name, |c| c.keys.get(key).cloned())? {
+            None | Some(JsonValue::Null) => Ok(None),
+            Some(v) => serde_json::from_value(v).map(Some).map_err(creep_error),
+        }
+    }
+
+    pub fn string(&self, key: &str) -> Result<Option<String>, BotError> {
+        self.get(key)
+    }
+
+    pub fn f64(&self, key: &str) -> Result<Option<f64>, BotError> {
+        self.get(key)
+    }
+
+    pub fn i32(&self, key: &str) -> Result<Option<i32>, BotError> {
+        Ok(self.f64(key)?.map(|n| n as i32))
+    }
+
+    pub fn bool(&self, key: &str) -> bool {
+        with_creep(&self.name, |c| c.keys.get(key).map_or(false, truthy)).unwrap_or(false)
+    }
+
+    pub fn set<T: Serialize>(&self, key: &str, value: T) {
+        let res = serde_json::to_value(value)
+            .map_err(creep_error)
+            .and_then(|v| with_creep(&self.name, |c| c.keys.insert(key.to_owned(), v)));
+        if let Err(e) = res {
+            warn!("can't set {} of creep {}: {}", key, self.name, e);
+        }
+    }
+
+    pub fn del(&self, key: &str) {
+        if let Err(e) = with_creep(&self.name, |c| c.keys.remove(key)) {
+            warn!("can't delete {} of creep {}: {}", key, self.name, e);
+        }
+    }
+}
+
+/// Writes back the keys of `name`'s memory that changed since it was loaded or last flushed,
+/// in one call; a creep whose memory didn't change costs nothing. Run at the end of each
+/// creep's turn.
+pub fn flush_creep(name: &str) -> Result<(), BotError> {
+    let changes = with_cache(|c| {
+        let cached = c.creeps.get_mut(name)?;
+        let set: Map<String, JsonValue> = cached
+            .keys
+            .iter()
+            .filter(|(k, v)| cached.loaded.get(*k) != Some(*v))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        let del: Vec<String> = cached
+            .loaded
+            .keys()
+            .filter(|k| !cached.keys.contains_key(*k))
+            .cloned()
+            .collect();
+        if set.is_empty() && del.is_empty() {
+            return None;
+        }
+        cached.loaded = cached.keys.clone();
+        c.creep_writes += 1;
+        Some(CreepChanges { set, del })
+    });
+    let changes = match changes {
+        Some(c) => serde_json::to_string(&c).map_err(creep_error)?,
+        None => return Ok(()),
+    };
+    js! {
+        var creeps = Memory.creeps = Memory.creeps || {};
+        var mem = creeps[@{name}] = creeps[@{name}] || {};
+        var changes = JSON.parse(@{changes});
+        Object.assign(mem, changes.set);
+        changes.del.forEach(function (key) { delete mem[key]; });
+    }
+    Ok(())
+}
+
+/// Flushes and drops `name`'s cached memory, before something writes it directly.
+fn forget_creep(name: &str) -> Result<(), BotError> {
+    flush_creep(name)?;
+    with_cache(|c| c.creeps.remove(name));
+    Ok(())
+}
+
+/// The room a creep working away from home was sent to, under `target_room`.
+pub fn creep_target(creep: &Creep) -> Option<String> {
+    creep_memory(creep).string("target_room").ok().flatten()
+}
+
+pub fn creep_role(creep: &Creep) -> Result<Option<Role>, BotError> {
+    creep_memory(creep).get::<Role>("role")
+}
+
+/// Handles a creep whose role memory is missing or from a shape this code no longer
+/// understands: the raw value is kept under `legacy`, a role is inferred from the body and
+/// written back so this only happens (and is only logged) once per creep.
+pub fn quarantine_creep_role(creep: &Creep, err: Option<BotError>) -> Role {
+    if let Err(e) = forget_creep(&creep.name()) {
+        warn!("can't flush creep {} before quarantine: {}", creep.name(), e);
+    }
+    let mem = creep.memory();
+    let raw = mem.get::<Value>("role").ok().flatten().unwrap_or(Value::Null);
+    let payload: String = js!(return JSON.stringify(@{&raw}) || "undefined";)
+        .try_into()
+        .unwrap_or_default();
+    let role = Role::infer_from_body(&creep.body());
+
+    warn!(
+        "creep {} has unreadable role memory {} ({}), inferred {:?} from its body",
+        creep.name(),
+        payload,
+        err.map(|e| e.to_string())
+            .unwrap_or_else(|| "missing".to_owned()),
+        role
+    );
+    mem.set("legacy", raw);
+    mem.set("role", role);
+    role
+}
+
+/// A creep's memory by name, which works while it's still spawning. Writes through it go
+/// straight to `Memory`, so anything cached for the creep is flushed and dropped first.
+pub fn creep_memory_by_name(name: &str) -> Result<MemoryReference, BotError> {
+    forget_creep(name)?;
+    let creeps = dict_or_create(&screeps::memory::root(), "creeps")?;
+    creeps.dict_or_create(name).map_err(|e| BotError::Deserialize {
+        target: "creep memory",
+        source: e.to_string(),
+    })
+}
+
+pub fn set_creep_role(name: &str, role: Role) -> Result<(), BotError> {
+    creep_memory_by_name(name)?.set("role", role);
+    Ok(())
+}
+
+/// What every creep's memory holds whatever its role. Kept as plain keys (`role`,
+/// `home_room`, `born`, `version`) next to the role's own, so older creeps read fine.
+#[derive(Clone, Debug)]
+pub struct CreepEnvelope {
+    pub role: Option<Role>,
+    pub home: Option<RoomName>,
+    /// Tick the creep was spawned; 0 when unknown.
+    pub born: u32,
+    /// `CREEP_MEMORY_VERSION` when the creep was spawned or last migrated; 0 before that.
+    pub version: u32,
+}
+
+impl CreepEnvelope {
+    /// The envelope of a creep `home` is spawning now.
+    pub fn spawned(role: Role, home: RoomName) -> CreepEnvelope {
+        CreepEnvelope {
+            role: Some(role),
+            home: Some(home),
+            born: screeps::game::time(),
+            version: CREEP_MEMORY_VERSION,
+        }
+    }
+}
+
+/// The envelope from `role` and the keys `string` and `number` read, so it works the same
+/// over a cached creep and a dead one's raw memory.
+fn read_envelope(
+    role: Option<Role>,
+    string: impl Fn(&str) -> Option<String>,
+    number: impl Fn(&str) -> Option<i32>,
+) -> CreepEnvelope {
+    let room = |key| string(key)?.parse::<RoomName>().ok();
+    let number = |key| number(key).map_or(0, |n| n as u32);
+    CreepEnvelope {
+        role,
+        // creeps from before home_room only have it if they worked elsewhere
+        home: room("home_room").or_else(|| room("owner_room")),
+        born: number("born"),
+        version: number("version"),
+    }
+}
+
+pub fn creep_envelope(creep: &Creep) -> CreepEnvelope {
+    let mem = creep_memory(creep);
+    read_envelope(
+        mem.get::<Role>("role").ok().flatten(),
+        |k| mem.string(k).ok().flatten(),
+        |k| mem.i32(k).ok().flatten(),
+    )
+}
+
+/// The room that spawned the creep.
+pub fn creep_home(creep: &Creep) -> Option<RoomName> {
+    creep_envelope(creep).home
+}
+
+/// Writes the envelope by name, which works while the creep is still spawning. Keys the
+/// envelope doesn't know are left alone.
+pub fn set_creep_envelope(name: &str, envelope: &CreepEnvelope) -> Result<(), BotError> {
+    let mem = creep_memory_by_name(name)?;
+    if let Some(role) = envelope.role {
+        mem.set("role", role);
+    }
+    if let Some(home) = envelope.home {
+        mem.set("home_room", home.to_string().as_str());
+    }
+    mem.set("born", envelope.born);
+    mem.set("version", envelope.version);
+    Ok(())
+}
+
+/// Brings creeps spawned before the current `CREEP_MEMORY_VERSION` up to it. A creep with no
+/// home gets the room it's in, and its birth tick is worked out from its ticks to live.
+pub fn migrate_creeps() -> Result<(), BotError> {
+    let now = screeps::game::time();
+    for creep in screeps::game::creeps::values() {
+        let mut envelope = creep_envelope(&creep);
+        if creep.spawning() || envelope.version >= CREEP_MEMORY_VERSION {
+            continue;
+        }
+        if envelope.home.is_none() {
+            envelope.home = Some(creep.pos().room_name());
+        }
+        if envelope.born == 0 {
+            let life = if creep.body().iter().any(|b| b.part == Part::Claim) {
+                CLAIM_CREEP_LIFE_TICKS
+            } else {
+                CREEP_LIFE_TICKS
+            };
+            envelope.born = now.saturating_sub(life.saturating_sub(creep.ticks_to_live()));
+        }
+        debug!("migrating {} to creep memory version {}", creep.name(), CREEP_MEMORY_VERSION);
+        envelope.version = CREEP_MEMORY_VERSION;
+        set_creep_envelope(&creep.name(), &envelope)?;
+    }
+    Ok(())
+}
+
+pub fn deposits() -> Result<MemoryReference, BotError> {
+    dict_or_create(&screeps::memory::root(), "deposits")
+}
+
+/// `Memory.finished_deposits`: the tick each deposit worked to the end would have decayed,
+/// by deposit id.
+pub fn finished_deposits() -> Result<MemoryReference, BotError> {
+    dict_or_create(&screeps::memory::root(), "finished_deposits")
+}
+
+/// `Memory.power_creeps`, our own records of each power creep, kept across its deaths.
+pub fn power_creeps() -> Result<MemoryReference, BotError> {
+    dict_or_create(&screeps::memory::root(), "power_creeps")
+}
+
+pub fn groups() -> Result<MemoryReference, BotError> {
+    dict_or_create(&screeps::memory::root(), "groups")
+}
+
+pub fn intel() -> Result<MemoryReference, BotError> {
+    dict_or_create(&screeps::memory::root(), "intel")
+}
+
+pub fn invaders() -> Result<MemoryReference, BotError> {
+    dict_or_create(&screeps::memory::root(), "invaders")
+}
+
+pub fn remotes() -> Result<MemoryReference, BotError> {
+    dict_or_create(&screeps::memory::root(), "remotes")
+}
+
+fn rooms() -> Result<MemoryReference, BotError> {
+    dict_or_create(&screeps::memory::root(), "rooms")
+}
+
+fn to_json(mem: &RoomMemory) -> Result<JsonValue, BotError> {
+    serde_json::to_value(mem).map_err(|e| BotError::Deserialize {
+        target: "RoomMemory",
+        source: e.to_string(),
+    })
+}
+
+pub fn get_room_memory(room: RoomName) -> Result<RoomMemory, BotError> {
+    if let Some(mem) = with_cache(|c| c.rooms.get(&room).map(|r| r.mem.clone())) {
+        return Ok(mem);
+    }
+    let mem = rooms()?
+        .get::<RoomMemory>(&room.to_string())
+        .map_err(|e| BotError::Deserialize {
+            target: "RoomMemory",
+            source: e.to_string(),
+        })?
+        .unwrap_or_default();
+    let loaded = to_json(&mem)?;
+    with_cache(|c| {
+        c.reads += 1;
+        c.rooms.insert(
+            room,
+            CachedRoom {
+                mem: mem.clone(),
+                loaded,
+                dirty: false,
+            },
+        );
+    });
+    Ok(mem)
+}
+
+pub fn set_room_memory(room: RoomName, mem: &RoomMemory) -> Result<(), BotError> {
+    // loads the snapshot to compare against if nothing read the room yet
+    get_room_memory(room)?;
+    with_cache(|c| {
+        if let Some(cached) = c.rooms.get_mut(&room) {
+            cached.mem = mem.clone();
+            cached.dirty = true;
+        }
+    });
+    Ok(())
+}
+
+/// Writes back the room, structure and creep memory that changed this tick, and adds the
+/// cache's reads and writes to `Memory.stats.memory`. Once per tick, after the last writer.
+pub fn flush() -> Result<(), BotError> {
+    // creeps written outside their own turn
+    let creeps: Vec<String> = with_cache(|c| c.creeps.keys().cloned().collect());
+    for name in creeps {
+        flush_creep(&name)?;
+    }
+    let (dirty_rooms, dirty_structures, reads, creep_writes) = with_cache(|c| {
+        let rooms: Vec<_> = c
+            .rooms
+            .iter_mut()
+            .filter(|(_, r)| r.dirty)
+            .map(|(name, r)| {
+                r.dirty = false;
+                (*name, r.mem.clone(), r.loaded.clone())
+            })
+            .collect();
+        let structures: Vec<_> = c
+            .structures
+            .iter_mut()
+            .filter(|(_, (_, dirty))| *dirty)
+            .map(|(id, (mem, dirty))| {
+                *dirty = false;
+                (*id, mem.clone())
+            })
+            .collect();
+        (rooms, structures, c.reads, c.creep_writes)
+    });
+    let (mut writes, mut unchanged) = (0, 0);
+    if !dirty_rooms.is_empty() {
+        let dict = rooms()?;
+        for (name, mem, loaded) in dirty_rooms {
+            if to_json(&mem)? == loaded {
+                unchanged += 1;
+                continue;
+            }
+            dict.set(&name.to_string(), &mem);
+            writes += 1;
+        }
+    }
+    if !dirty_structures.is_empty() {
+        let dict = structures()?;
+        for (id, mem) in dirty_structures {
+            match mem {
+                Some(mem) => dict.set(&id.to_string(), &mem),
+                None => dict.del(&id.to_string()),
+            }
+            writes += 1;
+        }
+    }
+    debug!(
+        "memory cache: {} reads, {} writes, {} creep writes, {} unchanged",
+        reads, writes, creep_writes, unchanged
+    );
+    stats::increment("memory", "reads", reads as i32);
+    stats::increment("memory", "writes", writes);
+    stats::increment("memory", "creep_writes", creep_writes);
+    stats::increment("memory", "unchanged", unchanged);
+    Ok(())
+}
+
+pub fn cleanup_memory() -> Result<(), BotError> {
+    cleanup_creep_memory()?;
+    migrate_creeps()?;
+    creep::send_home_orphans()?;
+    cleanup_structure_memory()
+}
+
+fn cleanup_creep_memory() -> Result<(), BotError> {
+    let alive_creeps: HashSet<String> = screeps::game::creeps::keys().into_iter().collect();
+
+    let screeps_memory = match screeps::memory::root().dict("creeps").map_err(|e| {
+        BotError::Deserialize {
+            target: "Memory.creeps",
+            source: e.to_string(),
+        }
+    })? {
+        Some(v) => v,
+        None => {
+            warn!("not cleaning game creep memory: no Memory.creeps dict");
+            return Ok(());
+        }
+    };
+
+    let now = screeps::game::time();
+    for mem_name in screeps_memory.keys() {
+        if !alive_creeps.contains(&mem_name) {
+            let mem = screeps_memory.dict(&mem_name).ok().flatten();
+            let portal_at = mem.as_ref().and_then(|m| m.i32("portal_at").ok().flatten());
+            if portal_at.map_or(false, |t| now.saturating_sub(t as u32) < PORTAL_GRACE_TICKS) {
+                // it may have come out on another shard
+                continue;
+            }
+            debug!("cleaning up creep memory of dead creep {}", mem_name);
+            if let Some(mem) = mem {
+                tasklog::report_death(&mem_name, &mem);
+                avoid::record_death(&mem, now);
+                let born = read_envelope(
+                    None,
+                    |k| mem.string(k).ok().flatten(),
+                    |k| mem.i32(k).ok().flatten(),
+                )
+                .born;
+                if born > 0 {
+                    stats::increment("creeps", "deaths", 1);
+                    stats::increment("creeps", "lifetime_ticks", now.saturating_sub(born) as i32);
+                }
+            }
+            screeps_memory.del(&mem_name);
+        }
+    }
+
+    Ok(())
+}
+
+fn cleanup_structure_memory() -> Result<(), BotError> {
+    let structures = structures()?;
+
+    for key in structures.keys() {
+        let alive = match key.parse::<RawObjectId>() {
+            Ok(id) => screeps::game::get_object_erased(id).is_some(),
+            Err(_) => false,
+        };
+        if !alive {
+            debug!("cleaning up memory of destroyed structure {}", key);
+            structures.del(&key);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+
+use log::*;
+use stdweb::{js, unstable::TryInto};
+
+use crate::{error::BotError, memory, stats};
+
+/// Memory stops saving past 2MB of serialized JSON.
+const MEMORY_LIMIT: u32 = 2 * 1024 * 1024;
+const WARN_PERCENT: u32 = 70;
+const SHED_PERCENT: u32 = 85;
+/// Intel not refreshed for this long is the first thing shed.
+const STALE_INTEL_TICKS: u32 = 20_000;
+
+/// Serialized length of every top-level `Memory` section.
+fn section_sizes() -> HashMap<String, u32> {
+    let raw: String = js! {
+        var sizes = {};
+        for (var key in Memory) {
+            sizes[key] = (JSON.stringify(Memory[key]) || "").length;
+        }
+        return JSON.stringify(sizes);
+    }
+    .try_into()
+    .unwrap_or_default();
+    serde_json::from_str(&raw).unwrap_or_default()
+}
+
+fn section_size(key: &str) -> u32 {
+    js!(return (JSON.stringify(Memory[@{key}]) || "").length;)
+        .try_into()
+        .unwrap_or(0)
+}
+
+/// Drops intel for rooms nobody has looked at in `STALE_INTEL_TICKS`, except rooms our
+/// remotes and deposit operations are in.
+fn shed_stale_intel() -> Result<(), BotError> {
+    let intel = memory::intel()?;
+    let now = screeps::game::time();
+    let mut keep = memory::remotes()?.keys();
+    keep.extend(memory::deposits()?.keys());
+    for key in intel.keys() {
+        if keep.contains(&key) {
+            continue;
+        }
+        let updated = intel
+            .dict(&key)
+            .ok()
+            .flatten()
+            .and_then(|i| i.i32("updated").ok().flatten())
+            .map_or(0, |t| t as u32);
+        if now.saturating_sub(updated) > STALE_INTEL_TICKS {
+            intel.del(&key);
+        }
+    }
+    Ok(())
+}
+
+/// Counters in `Memory.stats` start over; anything graphing them sees a reset.
+fn shed_stats() -> Result<(), BotError> {
+    screeps::memory::root().del("stats");
+    Ok(())
+}
+
+/// What can go when Memory runs full, least valuable first. Creeps, rooms, structures and
+/// running operations are never on it. The traffic heatmap and path caches live on the heap,
+/// so they cost Memory nothing.
+const SHED_ORDER: [(&str, fn() -> Result<(), BotError>); 2] =
+    [("intel", shed_stale_intel), ("stats", shed_stats)];
+
+/// Measures each top-level Memory section and publishes the sizes to
+/// `Memory.stats.memory_sizes`. Warns past `WARN_PERCENT` of the limit, and past
+/// `SHED_PERCENT` sheds sections in `SHED_ORDER` until it's back under.
+pub fn run_memory_budget() -> Result<(), BotError> {
+    let sizes = section_sizes();
+    let mut total: u32 = sizes.values().sum();
+    let percent = |total: u32| total as u64 * 100 / MEMORY_LIMIT as u64;
+    if percent(total) >= WARN_PERCENT as u64 {
+        let mut largest: Vec<(&String, &u32)> = sizes.iter().collect();
+        largest.sort_by_key(|(_, size)| std::cmp::Reverse(**size));
+        let top: Vec<String> = largest
+            .iter()
+            .take(3)
+            .map(|(key, size)| format!("{} {}", key, size))
+            .collect();
+        warn!(
+            "Memory is {}% full ({} of {} chars), largest: {}",
+            percent(total),
+            total,
+            MEMORY_LIMIT,
+            top.join(", ")
+        );
+    }
+    for (section, shed) in SHED_ORDER.iter() {
+        if percent(total) < SHED_PERCENT as u64 {
+            break;
+        }
+        let before = sizes.get(*section).cloned().unwrap_or(0);
+        shed()?;
+        let freed = before.saturating_sub(section_size(section));
+        total = total.saturating_sub(freed);
+        warn!("Memory over {}%: shed {}, freed {} chars", SHED_PERCENT, section, freed);
+    }
+
+    if let Some(dict) = stats::section("memory_sizes") {
+        for (key, size) in &sizes {
+            dict.set(key, *size as i32);
+        }
+        dict.set("total", total as i32);
+    }
+    Ok(())
+}
@@ -0,0 +1,150 @@
+use log::*;
+use screeps::{
+    find, prelude::*, Creep, ObjectId, Position, Room, Source, Structure, StructureContainer,
+};
+use serde::{Deserialize, Serialize};
+use stdweb::{js_deserializable, js_serializable};
+
+use crate::{
+    error::BotError,
+    memory, objects, population,
+    role::Role,
+    terrain::{self, ROOM_SIZE},
+};
+
+/// A harvester's source and the exact tile it works from, under `mining` in its memory.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HarvestAssignment {
+    pub source: ObjectId<Source>,
+    pub stand: u32,
+}
+
+js_serializable!(HarvestAssignment);
+js_deserializable!(HarvestAssignment);
+
+/// The container next to `source`, if one has been built.
+pub fn source_container(source: &Source) -> Option<StructureContainer> {
+    source
+        .pos()
+        .find_in_range(find::STRUCTURES, 1)
+        .into_iter()
+        .find_map(|s| match s {
+            Structure::Container(c) => Some(c),
+            _ => None,
+        })
+}
+
+/// The container tile if there is one, else the open tile next to the source closest to the
+/// spawn.
+pub fn standing_position(room: &Room, source: &Source) -> Option<Position> {
+    if let Some(container) = source_container(source) {
+        return Some(container.pos());
+    }
+    let anchor = room
+        .find(find::MY_SPAWNS)
+        .into_iter()
+        .next()
+        .map(|s| s.pos())
+        .or_else(|| room.controller().map(|c| c.pos()))?;
+    let walls = terrain::walls(room.name());
+    let (sx, sy) = (source.pos().x() as i32, source.pos().y() as i32);
+    let mut best: Option<Position> = None;
+    for dy in -1..=1 {
+        for dx in -1..=1 {
+            let (x, y) = (sx + dx, sy + dy);
+            if x <= 0 || y <= 0 || x >= ROOM_SIZE as i32 - 1 || y >= ROOM_SIZE as i32 - 1 {
+                continue;
+            }
+            if walls[terrain::index(x as usize, y as usize)] {
+                continue;
+            }
+            let pos = Position::new(x as u32, y as u32, room.name());
+            if best.map_or(true, |b| pos.get_range_to(&anchor) < b.get_range_to(&anchor)) {
+                best = Some(pos);
+            }
+        }
+    }
+    best
+}
+
+pub fn assignment(creep: &Creep) -> Result<Option<HarvestAssignment>, BotError> {
+    memory::creep_memory(creep).get::<HarvestAssignment>("mining")
+}
+
+/// Standing tiles of harvesters in `room` whose source container is gone, so they're dropping
+/// what they mine on the ground.
+pub fn uncontained_stands(room: &Room) -> Vec<Position> {
+    room.find(find::MY_CREEPS)
+        .iter()
+        .filter(|c| population::role_of(c) == Role::Harvester)
+        .filter_map(|c| assignment(c).ok().flatten())
+        .filter(|a| {
+            objects::get_cached(a.source).map_or(false, |s| source_container(&s).is_none())
+        })
+        .map(|a| Position::from_packed(a.stand))
+        .collect()
+}
+
+/// Sources that get a static harvester: the ones with a container to fill.
+pub fn mined_sources(room: &Room) -> Vec<Source> {
+    room.find(find::SOURCES)
+        .into_iter()
+        .filter(|s| source_container(s).is_some())
+        .collect()
+}
+
+/// Gives `creep` the mined source with the fewest harvesters on it.
+pub fn assign(creep: &Creep, room: &Room) -> Result<Option<HarvestAssignment>, BotError> {
+    let taken: Vec<ObjectId<Source>> = room
+        .find(find::MY_CREEPS)
+        .iter()
+        .filter(|c| c.name() != creep.name())
+        .filter_map(|c| assignment(c).ok().flatten())
+        .map(|a| a.source)
+        .collect();
+    let source = match mined_sources(room)
+        .into_iter()
+        .min_by_key(|s| taken.iter().filter(|t| **t == s.id()).count())
+    {
+        Some(s) => s,
+        None => return Ok(None),
+    };
+    let stand = match standing_position(room, &source) {
+        Some(p) => p,
+        None => return Ok(None),
+    };
+    let assigned = HarvestAssignment {
+        source: source.id(),
+        stand: stand.packed_repr(),
+    };
+    debug!("assigned {} to source at {}, standing on {}", creep.name(), source.pos(), stand);
+    memory::creep_memory(creep).set("mining", &assigned);
+    Ok(Some(assigned))
+}
+
+/// Moves harvesters whose standing tile changed, e.g. because their container was built
+/// somewhere else than where they were standing.
+pub fn run_mining(room: &Room) -> Result<(), BotError> {
+    for creep in room.find(find::MY_CREEPS) {
+        if population::role_of(&creep) != Role::Harvester {
+            continue;
+        }
+        let mut assigned = match assignment(&creep)? {
+            Some(a) => a,
+            None => continue,
+        };
+        let stand = match objects::get_cached(assigned.source)
+            .and_then(|s| standing_position(room, &s))
+        {
+            Some(p) => p.packed_repr(),
+            None => continue,
+        };
+        if stand != assigned.stand {
+            info!("{} relocating to {}", creep.name(), Position::from_packed(stand));
+            assigned.stand = stand;
+            memory::creep_memory(&creep).set("mining", &assigned);
+            memory::creep_memory(&creep).del("anchored");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,71 @@
+use std::{any::Any, cell::RefCell, collections::HashMap};
+
+use log::*;
+use screeps::{ObjectId, RawObjectId, SizedRoomObject};
+
+use crate::stats;
+
+/// Game objects resolved this tick, so an id touched from several code paths only crosses into
+/// JS once. Objects are only good for the tick they were fetched in, so the whole cache goes
+/// when the tick changes.
+#[derive(Default)]
+struct ObjectCache {
+    tick: u32,
+    objects: HashMap<RawObjectId, Option<Box<dyn Any>>>,
+    resolves: u32,
+    hits: u32,
+}
+
+thread_local! {
+    static CACHE: RefCell<ObjectCache> = RefCell::new(ObjectCache::default());
+}
+
+/// `id.resolve()`, at most once per id per tick.
+pub fn get_cached<T>(id: ObjectId<T>) -> Option<T>
+where
+    T: SizedRoomObject + Clone + 'static,
+{
+    let now = screeps::game::time();
+    let raw = RawObjectId::from(id);
+    CACHE.with(|c| {
+        let mut c = c.borrow_mut();
+        if c.tick != now {
+            *c = ObjectCache {
+                tick: now,
+                ..ObjectCache::default()
+            };
+        }
+        if let Some(cached) = c.objects.get(&raw) {
+            // the same id always resolves to the same type
+            if let Some(object) = cached.as_ref().and_then(|o| o.downcast_ref::<T>()) {
+                c.hits += 1;
+                return Some(object.clone());
+            }
+            if cached.is_none() {
+                c.hits += 1;
+                return None;
+            }
+        }
+        c.resolves += 1;
+        let object = id.resolve();
+        c.objects
+            .insert(raw, object.clone().map(|o| Box::new(o) as Box<dyn Any>));
+        object
+    })
+}
+
+/// Logs this tick's resolves and cache hits and adds them to `Memory.stats.objects`; without
+/// the cache every hit would have been another resolve.
+pub fn report() {
+    let (resolves, hits) = CACHE.with(|c| {
+        let c = c.borrow();
+        if c.tick == screeps::game::time() {
+            (c.resolves, c.hits)
+        } else {
+            (0, 0)
+        }
+    });
+    debug!("object cache: {} resolves, {} hits", resolves, hits);
+    stats::increment("objects", "resolves", resolves as i32);
+    stats::increment("objects", "hits", hits as i32);
+}
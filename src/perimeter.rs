@@ -0,0 +1,478 @@
+use std::collections::VecDeque;
+
+use log::*;
+use screeps::{prelude::*, Position, Room, RoomName, StructureType};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    room::RoomMemory,
+    room_cache,
+    terrain::{self, ROOM_SIZE},
+};
+
+/// How far outside the critical structures the proposed cut runs.
+const CUT_MARGIN: usize = 3;
+/// Ramparts can't go on exit tiles or right next to them.
+const BUILD_MARGIN: usize = 2;
+/// Defenders holding one way in share at most this many tiles.
+const MAX_CHOKE_TILES: usize = 4;
+
+/// Result of the last perimeter check, kept in room memory. Tiles are `terrain::index`
+/// values.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PerimeterReport {
+    pub checked: u32,
+    /// Whether something walking in from an exit can reach a critical structure.
+    pub open: bool,
+    /// One such walk, exit first.
+    #[serde(default)]
+    pub breach: Vec<u16>,
+    /// Rampart positions that close the perimeter.
+    #[serde(default)]
+    pub proposed: Vec<u16>,
+}
+
+/// Structures whose loss hurts enough that they must be inside the walls.
+fn is_critical(ty: StructureType) -> bool {
+    match ty {
+        StructureType::Spawn
+        | StructureType::Storage
+        | StructureType::Terminal
+        | StructureType::Tower
+        | StructureType::Lab
+        | StructureType::Factory
+        | StructureType::PowerSpawn
+        | StructureType::Nuker => true,
+        _ => false,
+    }
+}
+
+fn on_edge(x: usize, y: usize) -> bool {
+    x == 0 || y == 0 || x == ROOM_SIZE - 1 || y == ROOM_SIZE - 1
+}
+
+fn neighbors(i: usize) -> impl Iterator<Item = usize> {
+    let (x, y) = ((i % ROOM_SIZE) as isize, (i / ROOM_SIZE) as isize);
+    (-1..=1)
+        .flat_map(move |dy| (-1..=1).map(move |dx| (x + dx, y + dy)))
+        .filter(move |&(nx, ny)| {
+            (nx, ny) != (x, y)
+                && nx >= 0
+                && ny >= 0
+                && nx < ROOM_SIZE as isize
+                && ny < ROOM_SIZE as isize
+        })
+        .map(|(nx, ny)| terrain::index(nx as usize, ny as usize))
+}
+
+/// Tiles taken off the fill frontier per `step`.
+const STEP_TILES: usize = 500;
+/// `PerimeterScan::parents` markers; anything else is a `DIRS` digit.
+const UNREACHED: u8 = b'.';
+const EXIT: u8 = b'e';
+/// Offsets from a tile to the tile it was reached from, encoded as `b'1' + index`.
+const DIRS: [(isize, isize); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
+/// A perimeter check in progress, kept in room memory so it resumes across ticks and global
+/// resets.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PerimeterScan {
+    pub started: u32,
+    /// One byte per tile: `.` not reached yet, `e` an exit, otherwise the direction digit back
+    /// towards the exit it was reached from.
+    pub parents: String,
+    pub frontier: VecDeque<u16>,
+    /// The first critical structure the fill reached.
+    #[serde(default)]
+    pub breach: Option<u16>,
+}
+
+impl PerimeterScan {
+    pub fn reached(&self) -> usize {
+        self.parents.bytes().filter(|b| *b != UNREACHED).count()
+    }
+}
+
+struct Layout {
+    blocked: Vec<bool>,
+    ramparts: Vec<bool>,
+    /// Walls and ramparts in the room; a change means the choke tiles are stale.
+    barriers: u32,
+    critical: Vec<bool>,
+    /// Bounding box of the critical structures, `(min_x, min_y, max_x, max_y)`.
+    bounds: Option<(usize, usize, usize, usize)>,
+}
+
+/// Terrain walls, constructed walls and ramparts block the fill.
+fn layout(room: &Room) -> Layout {
+    let mut blocked = terrain::walls(room.name());
+    let mut ramparts = vec![false; ROOM_SIZE * ROOM_SIZE];
+    let mut barriers = 0;
+    let mut critical = vec![false; ROOM_SIZE * ROOM_SIZE];
+    let mut bounds: Option<(usize, usize, usize, usize)> = None;
+    for structure in room_cache::structures(room).iter() {
+        let pos = structure.pos();
+        let (x, y) = (pos.x() as usize, pos.y() as usize);
+        match structure.structure_type() {
+            StructureType::Wall => {
+                blocked[terrain::index(x, y)] = true;
+                barriers += 1;
+            }
+            StructureType::Rampart => {
+                blocked[terrain::index(x, y)] = true;
+                ramparts[terrain::index(x, y)] = true;
+                barriers += 1;
+            }
+            ty if is_critical(ty) => {
+                critical[terrain::index(x, y)] = true;
+                bounds = Some(match bounds {
+                    Some((x0, y0, x1, y1)) => (x0.min(x), y0.min(y), x1.max(x), y1.max(y)),
+                    None => (x, y, x, y),
+                });
+            }
+            _ => {}
+        }
+    }
+    Layout {
+        blocked,
+        ramparts,
+        barriers,
+        critical,
+        bounds,
+    }
+}
+
+fn direction_code(from: usize, to: usize) -> u8 {
+    let dx = (to % ROOM_SIZE) as isize - (from % ROOM_SIZE) as isize;
+    let dy = (to / ROOM_SIZE) as isize - (from / ROOM_SIZE) as isize;
+    let k = DIRS.iter().position(|d| *d == (dx, dy)).unwrap_or(0);
+    b'1' + k as u8
+}
+
+/// Tiles reachable from the exits without crossing walls or ramparts, in one go. Cheap next to
+/// the full check since it keeps no parents and proposes nothing.
+pub fn outside(room: &Room) -> Vec<bool> {
+    let blocked = layout(room).blocked;
+    let mut reached = vec![false; ROOM_SIZE * ROOM_SIZE];
+    let mut queue = VecDeque::new();
+    for y in 0..ROOM_SIZE {
+        for x in 0..ROOM_SIZE {
+            let i = terrain::index(x, y);
+            if on_edge(x, y) && !blocked[i] {
+                reached[i] = true;
+                queue.push_back(i);
+            }
+        }
+    }
+    while let Some(i) = queue.pop_front() {
+        for n in neighbors(i) {
+            if !reached[n] && !blocked[n] {
+                reached[n] = true;
+                queue.push_back(n);
+            }
+        }
+    }
+    reached
+}
+
+/// Starts a check by seeding the fill with every open exit tile.
+pub fn start(room: &Room) -> PerimeterScan {
+    let walls = terrain::walls(room.name());
+    let mut parents = vec![UNREACHED; ROOM_SIZE * ROOM_SIZE];
+    let mut frontier = VecDeque::new();
+    for y in 0..ROOM_SIZE {
+        for x in 0..ROOM_SIZE {
+            let i = terrain::index(x, y);
+            if on_edge(x, y) && !walls[i] {
+                parents[i] = EXIT;
+                frontier.push_back(i as u16);
+            }
+        }
+    }
+    PerimeterScan {
+        started: screeps::game::time(),
+        parents: String::from_utf8(parents).unwrap_or_default(),
+        frontier,
+        breach: None,
+    }
+}
+
+/// Steps a check may take this tick, by bucket.
+pub fn steps_allowed() -> usize {
+    match screeps::game::cpu::bucket() as u32 {
+        b if b >= 8000 => 4,
+        b if b >= 3000 => 1,
+        _ => 0,
+    }
+}
+
+/// Advances the flood fill from the exits by up to `STEP_TILES` tiles, returning the report
+/// once the fill is done. If the fill touched a critical structure the perimeter is open, and
+/// a greedy cut around the critical structures' bounding box is proposed to close it.
+pub fn step(room: &Room, scan: &mut PerimeterScan) -> Option<PerimeterReport> {
+    let layout = layout(room);
+    let mut report = PerimeterReport {
+        checked: screeps::game::time(),
+        ..PerimeterReport::default()
+    };
+    let (min_x, min_y, max_x, max_y) = match layout.bounds {
+        Some(b) => b,
+        None => return Some(report),
+    };
+
+    let mut parents = std::mem::replace(&mut scan.parents, String::new()).into_bytes();
+    if parents.len() != ROOM_SIZE * ROOM_SIZE {
+        // from an older layout; start over
+        *scan = start(room);
+        return None;
+    }
+    for _ in 0..STEP_TILES {
+        let i = match scan.frontier.pop_front() {
+            Some(i) => i as usize,
+            None => break,
+        };
+        for n in neighbors(i) {
+            if parents[n] != UNREACHED || layout.blocked[n] {
+                continue;
+            }
+            // critical structures aren't walkable, but standing next to one is enough
+            if layout.critical[n] {
+                if scan.breach.is_none() {
+                    parents[n] = direction_code(n, i);
+                    scan.breach = Some(n as u16);
+                }
+                continue;
+            }
+            parents[n] = direction_code(n, i);
+            scan.frontier.push_back(n as u16);
+        }
+    }
+    scan.parents = String::from_utf8(parents).unwrap_or_default();
+    if !scan.frontier.is_empty() {
+        return None;
+    }
+
+    let parents = scan.parents.as_bytes();
+    let breach = match scan.breach {
+        Some(b) => b as usize,
+        None => return Some(report),
+    };
+    report.open = true;
+    let mut at = breach;
+    loop {
+        report.breach.push(at as u16);
+        let code = parents[at];
+        if code == EXIT || code == UNREACHED {
+            break;
+        }
+        let (dx, dy) = DIRS[(code - b'1') as usize];
+        let x = (at % ROOM_SIZE) as isize + dx;
+        let y = (at / ROOM_SIZE) as isize + dy;
+        at = terrain::index(x as usize, y as usize);
+    }
+    report.breach.reverse();
+
+    // only tiles the outside can actually get to need covering
+    let lo_x = min_x.saturating_sub(CUT_MARGIN).max(BUILD_MARGIN);
+    let lo_y = min_y.saturating_sub(CUT_MARGIN).max(BUILD_MARGIN);
+    let hi_x = (max_x + CUT_MARGIN).min(ROOM_SIZE - 1 - BUILD_MARGIN);
+    let hi_y = (max_y + CUT_MARGIN).min(ROOM_SIZE - 1 - BUILD_MARGIN);
+    for y in lo_y..=hi_y {
+        for x in lo_x..=hi_x {
+            let border = x == lo_x || x == hi_x || y == lo_y || y == hi_y;
+            let i = terrain::index(x, y);
+            if border && parents[i] != UNREACHED && !layout.critical[i] {
+                report.proposed.push(i as u16);
+            }
+        }
+    }
+    info!(
+        "{} perimeter is open, {} ramparts proposed",
+        room.name(),
+        report.proposed.len()
+    );
+    Some(report)
+}
+
+/// The room edge hostiles came in by.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitSide {
+    Top,
+    Right,
+    Bottom,
+    Left,
+}
+
+impl ExitSide {
+    /// The edge nearest `(x, y)`.
+    pub fn nearest(x: u32, y: u32) -> ExitSide {
+        let last = ROOM_SIZE as u32 - 1;
+        let sides = [
+            (y, ExitSide::Top),
+            (last - x, ExitSide::Right),
+            (last - y, ExitSide::Bottom),
+            (x, ExitSide::Left),
+        ];
+        sides.iter().min_by_key(|s| s.0).map_or(ExitSide::Top, |s| s.1)
+    }
+
+    fn contains(self, x: usize, y: usize) -> bool {
+        match self {
+            ExitSide::Top => y == 0,
+            ExitSide::Right => x == ROOM_SIZE - 1,
+            ExitSide::Bottom => y == ROOM_SIZE - 1,
+            ExitSide::Left => x == 0,
+        }
+    }
+}
+
+/// Where defenders stand against hostiles coming in from one side, kept in room memory.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChokeSet {
+    pub side: ExitSide,
+    /// `Layout::barriers` when these were worked out.
+    pub barriers: u32,
+    /// Rampart tiles first; empty when that side can't reach anything critical.
+    pub tiles: Vec<u16>,
+    /// The critical structure the walk in reached.
+    #[serde(default)]
+    pub core: Option<u16>,
+}
+
+/// Walks in from `side` to the nearest critical structure, through our ramparts but not the
+/// walls. Ramparts the walk crosses, and the ones in line with them, are where the line holds;
+/// with none on the way, the narrowest tiles of the walk are.
+fn find_chokes(room: &Room, layout: &Layout, side: ExitSide) -> ChokeSet {
+    let blocked: Vec<bool> = layout
+        .blocked
+        .iter()
+        .zip(layout.ramparts.iter())
+        .map(|(b, r)| *b && !*r)
+        .collect();
+    let mut set = ChokeSet {
+        side,
+        barriers: layout.barriers,
+        tiles: Vec::new(),
+        core: None,
+    };
+    let mut parent = vec![usize::MAX; ROOM_SIZE * ROOM_SIZE];
+    let mut queue = VecDeque::new();
+    for y in 0..ROOM_SIZE {
+        for x in 0..ROOM_SIZE {
+            let i = terrain::index(x, y);
+            if side.contains(x, y) && !blocked[i] {
+                parent[i] = i;
+                queue.push_back(i);
+            }
+        }
+    }
+    let mut reached = None;
+    'fill: while let Some(i) = queue.pop_front() {
+        for n in neighbors(i) {
+            if parent[n] != usize::MAX || blocked[n] {
+                continue;
+            }
+            if layout.critical[n] {
+                set.core = Some(n as u16);
+                reached = Some(i);
+                break 'fill;
+            }
+            parent[n] = i;
+            queue.push_back(n);
+        }
+    }
+    let mut walk = Vec::new();
+    let mut at = match reached {
+        Some(at) => at,
+        None => return set,
+    };
+    loop {
+        walk.push(at);
+        if parent[at] == at {
+            break;
+        }
+        at = parent[at];
+    }
+    walk.reverse();
+
+    // the outermost rampart crossed, then the line it's part of
+    if let Some(first) = walk.iter().copied().find(|i| layout.ramparts[*i]) {
+        let mut tiles = vec![first];
+        let mut k = 0;
+        while k < tiles.len() && tiles.len() < MAX_CHOKE_TILES {
+            for n in neighbors(tiles[k]) {
+                if layout.ramparts[n] && !tiles.contains(&n) && tiles.len() < MAX_CHOKE_TILES {
+                    tiles.push(n);
+                }
+            }
+            k += 1;
+        }
+        set.tiles = tiles.into_iter().map(|i| i as u16).collect();
+        return set;
+    }
+
+    let clearance = terrain::distance_transform(&blocked);
+    let inside = |i: usize| {
+        let (x, y) = (i % ROOM_SIZE, i / ROOM_SIZE);
+        x >= BUILD_MARGIN
+            && y >= BUILD_MARGIN
+            && x < ROOM_SIZE - BUILD_MARGIN
+            && y < ROOM_SIZE - BUILD_MARGIN
+    };
+    // ties go to the tile nearest the exit, furthest from the core
+    let narrowest = walk
+        .iter()
+        .copied()
+        .filter(|i| inside(*i))
+        .min_by_key(|i| clearance[*i]);
+    if let Some(narrowest) = narrowest {
+        let width = clearance[narrowest];
+        let mut tiles = vec![narrowest];
+        tiles.extend(
+            neighbors(narrowest)
+                .filter(|n| !blocked[*n] && !layout.critical[*n] && clearance[*n] <= width)
+                .take(MAX_CHOKE_TILES - 1),
+        );
+        set.tiles = tiles.into_iter().map(|i| i as u16).collect();
+    }
+    debug!("{} chokes from {:?}: {:?}", room.name(), side, set.tiles);
+    set
+}
+
+/// The choke tiles for hostiles coming in from `side`, worked out again whenever a wall or
+/// rampart has been built or lost since they were cached.
+pub fn chokes(room: &Room, mem: &mut RoomMemory, side: ExitSide) -> ChokeSet {
+    let barriers = room_cache::structures(room)
+        .iter()
+        .filter(|s| match s.structure_type() {
+            StructureType::Wall | StructureType::Rampart => true,
+            _ => false,
+        })
+        .count() as u32;
+    let cached = mem
+        .chokes
+        .iter()
+        .find(|c| c.side == side && c.barriers == barriers);
+    if let Some(set) = cached {
+        return set.clone();
+    }
+    let set = find_chokes(room, &layout(room), side);
+    mem.chokes.retain(|c| c.side != side);
+    mem.chokes.push(set.clone());
+    set
+}
+
+/// A `terrain::index` tile as a position in `room`.
+pub fn tile_position(room: RoomName, i: u16) -> Position {
+    let (x, y) = (i as u32 % ROOM_SIZE as u32, i as u32 / ROOM_SIZE as u32);
+    Position::new(x, y, room)
+}
@@ -0,0 +1,85 @@
+use std::cell::{Cell, RefCell};
+
+use log::*;
+
+use crate::settings;
+
+/// The parts of a tick, in the order `game_loop` runs them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    /// Room state, logistics requests, spawn queue top-ups, intel and remote planning.
+    Cache,
+    /// Group movement, then every creep's role logic.
+    Creeps,
+    /// Links and other structures, then the spawn queues.
+    Structures,
+    /// Visuals, reports and memory cleanup.
+    Report,
+}
+
+thread_local! {
+    static CURRENT: Cell<Phase> = Cell::new(Phase::Cache);
+    static DEFERRED: Cell<Option<u32>> = Cell::new(None);
+    /// CPU used as each phase of the current tick was entered.
+    static STARTS: RefCell<Vec<(Phase, f64)>> = RefCell::new(Vec::new());
+}
+
+pub fn enter(phase: Phase) {
+    CURRENT.with(|c| c.set(phase));
+    let used = screeps::game::cpu::get_used();
+    STARTS.with(|s| {
+        let mut s = s.borrow_mut();
+        if phase == Phase::Cache {
+            s.clear();
+        }
+        s.push((phase, used));
+    });
+}
+
+/// CPU each phase has used so far this tick, in the order they ran.
+pub fn breakdown() -> Vec<(Phase, f64)> {
+    let now = screeps::game::cpu::get_used();
+    STARTS.with(|s| {
+        let s = s.borrow();
+        s.iter()
+            .enumerate()
+            .map(|(i, (phase, start))| {
+                let end = s.get(i + 1).map_or(now, |(_, e)| *e);
+                (*phase, end - start)
+            })
+            .collect()
+    })
+}
+
+pub fn current() -> Phase {
+    CURRENT.with(|c| c.get())
+}
+
+/// Share of `cpu::tick_limit` that may be used by the end of `phase`. Only the creep phase
+/// defers work to keep to it; structures and spawning always run.
+pub fn budget(phase: Phase) -> f64 {
+    let share = match phase {
+        Phase::Cache => 0.3,
+        Phase::Creeps => settings::u32_or("cpu_creep_budget_percent", 80) as f64 / 100.0,
+        Phase::Structures | Phase::Report => 1.0,
+    };
+    screeps::game::cpu::tick_limit() * share
+}
+
+/// Records that the creep phase ran out of budget this tick.
+pub fn note_deferred() {
+    DEFERRED.with(|d| d.set(Some(screeps::game::time())));
+}
+
+/// The last tick the creep phase ran out of budget, since the last global reset.
+pub fn deferred_at() -> Option<u32> {
+    DEFERRED.with(|d| d.get())
+}
+
+/// Logs an error if per-tick cache `what` is rebuilt after creeps may already have read it.
+pub fn check_cache_write(what: &str) {
+    let phase = current();
+    if phase > Phase::Cache {
+        error!("{} rebuilt during the {:?} phase, after creeps read it", what, phase);
+    }
+}
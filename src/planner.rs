@@ -0,0 +1,747 @@
+use std::{cell::RefCell, collections::HashSet};
+
+use log::*;
+use screeps::{find, look, prelude::*, Position, Room, RoomName, StructureType};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    construction,
+    error::{self, BotError},
+    intents, memory, mining, perimeter, remote,
+    room::RoomMemory,
+    room_cache, route,
+    terrain::{self, ROOM_SIZE},
+    traffic,
+};
+
+thread_local! {
+    static TOWER_RUSH: RefCell<HashSet<RoomName>> = RefCell::new(HashSet::new());
+}
+
+/// Clearance a spawn needs from walls and other structures so creeps can leave it freely.
+const SPAWN_CLEARANCE: u8 = 2;
+/// Tiles this close to the room edge are never built on.
+const EDGE_MARGIN: usize = 2;
+/// Upgraders park within this range of the controller.
+const UPGRADE_RANGE: i32 = 3;
+/// The controller container and link go within this range of the controller.
+const BUFFER_RANGE: i32 = 2;
+/// Idle workers wait on this many tiles...
+const STAGING_TILES: usize = 4;
+/// ...within this range of storage, or of the spawn before there's storage.
+const STAGING_RANGE: i32 = 2;
+/// How often the critical routes are planned again.
+pub const ROAD_PLAN_TICKS: u32 = 1000;
+/// Roads on the critical routes go down from this controller level...
+const ROAD_MIN_LEVEL: u32 = 3;
+/// ...a few sites at a time.
+const MAX_ROAD_SITES: usize = 5;
+/// Routes to a remote end once they're this close to its middle.
+const REMOTE_ROUTE_RANGE: u32 = 20;
+/// Rows an open tile search works through: the distance transform's, then one scoring pass.
+pub const TILE_PLAN_ROWS: usize = terrain::DISTANCE_ROWS + ROOM_SIZE;
+/// Rows of an open tile search worked per step; see `perimeter::steps_allowed`.
+const TILE_PLAN_STEP_ROWS: usize = 25;
+/// A finished search its planner hasn't come back for in this long is dropped.
+const TILE_PLAN_STALE_TICKS: u32 = 250;
+
+/// Walls, non-walkable structures, blacklisted tiles and the room border, row-major.
+fn blocked_tiles(room: &Room, mem: &RoomMemory) -> Vec<bool> {
+    let mut blocked = terrain::walls(room.name());
+    for packed in &mem.site_blacklist {
+        let pos = Position::from_packed(*packed);
+        blocked[terrain::index(pos.x() as usize, pos.y() as usize)] = true;
+    }
+    for structure in room.find(find::STRUCTURES) {
+        match structure.structure_type() {
+            StructureType::Road | StructureType::Container | StructureType::Rampart => {}
+            _ => {
+                let pos = structure.pos();
+                blocked[terrain::index(pos.x() as usize, pos.y() as usize)] = true;
+            }
+        }
+    }
+    // nothing gets built where creeps wait to be renewed or staged
+    for packed in mem.renew_spots.values().chain(mem.staging.iter()) {
+        let pos = Position::from_packed(*packed);
+        blocked[terrain::index(pos.x() as usize, pos.y() as usize)] = true;
+    }
+    for y in 0..ROOM_SIZE {
+        for x in 0..ROOM_SIZE {
+            if x < EDGE_MARGIN
+                || y < EDGE_MARGIN
+                || x >= ROOM_SIZE - EDGE_MARGIN
+                || y >= ROOM_SIZE - EDGE_MARGIN
+            {
+                blocked[terrain::index(x, y)] = true;
+            }
+        }
+    }
+    blocked
+}
+
+/// An open tile search in progress, kept in room memory so it resumes across ticks and global
+/// resets. `step_tile_plan` works it a few rows at a time; the planner that started it picks
+/// up the tile once it's finished.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TilePlan {
+    /// The planner that asked, e.g. "extra spawns".
+    pub origin: String,
+    pub started: u32,
+    /// Packed positions the tile should be close to.
+    pub anchors: Vec<u32>,
+    pub min_clearance: u8,
+    /// One byte per tile: the distance transform worked so far, offset from `0`.
+    pub clearance: String,
+    /// Rows done out of `TILE_PLAN_ROWS`.
+    pub rows: usize,
+    /// The cheapest tile scored so far, as `(summed range, tile index)`.
+    #[serde(default)]
+    pub best: Option<(u32, usize)>,
+    #[serde(default)]
+    pub finished_at: Option<u32>,
+}
+
+/// Where a planner's open tile search stands.
+pub enum PlanStep {
+    Searching,
+    /// The search is finished, with the tile it found if any.
+    Done(Option<Position>),
+}
+
+fn encode_clearance(dist: &[u8]) -> String {
+    dist.iter().map(|d| (b'0' + (*d).min(b'z' - b'0')) as char).collect()
+}
+
+fn decode_clearance(clearance: &str) -> Vec<u8> {
+    clearance.bytes().map(|b| b - b'0').collect()
+}
+
+/// Scores row `y`: every tile with at least `min_clearance` is weighed by its summed range to
+/// `anchors`, and the cheapest so far kept in `best`.
+fn score_row(
+    clearance: &[u8],
+    y: usize,
+    anchors: &[(u32, u32)],
+    min_clearance: u8,
+    best: &mut Option<(u32, usize)>,
+) {
+    for x in 0..ROOM_SIZE {
+        let i = terrain::index(x, y);
+        if clearance[i] < min_clearance {
+            continue;
+        }
+        let cost: u32 = anchors
+            .iter()
+            .map(|&(ax, ay)| {
+                let dx = (x as i32 - ax as i32).abs();
+                let dy = (y as i32 - ay as i32).abs();
+                dx.max(dy) as u32
+            })
+            .sum();
+        if best.map_or(true, |(c, _)| cost < c) {
+            *best = Some((cost, i));
+        }
+    }
+}
+
+/// Where `origin`'s search for the open tile with at least `min_clearance` nearest `anchors`
+/// stands. Starts one when no search is in progress; while another planner's search is, this
+/// one waits its turn.
+fn open_tile(
+    room: &Room,
+    mem: &mut RoomMemory,
+    origin: &str,
+    anchors: &[Position],
+    min_clearance: u8,
+) -> PlanStep {
+    match mem.tile_plan.take() {
+        Some(plan) if plan.origin == origin && plan.finished_at.is_some() => {
+            let pos = plan.best.map(|(_, i)| {
+                Position::new((i % ROOM_SIZE) as u32, (i / ROOM_SIZE) as u32, room.name())
+            });
+            PlanStep::Done(pos)
+        }
+        Some(plan) => {
+            mem.tile_plan = Some(plan);
+            PlanStep::Searching
+        }
+        None => {
+            debug!("{} starting an open tile search for {}", room.name(), origin);
+            mem.tile_plan = Some(TilePlan {
+                origin: origin.to_owned(),
+                started: screeps::game::time(),
+                anchors: anchors.iter().map(|a| a.packed_repr()).collect(),
+                min_clearance,
+                clearance: encode_clearance(&[0; ROOM_SIZE * ROOM_SIZE]),
+                ..TilePlan::default()
+            });
+            PlanStep::Searching
+        }
+    }
+}
+
+/// Works the room's open tile search as far as the bucket allows this tick, and drops a
+/// finished one its planner hasn't come back for.
+pub fn step_tile_plan(room: &Room, mem: &mut RoomMemory) {
+    let now = screeps::game::time();
+    let mut plan = match mem.tile_plan.take() {
+        Some(plan) => plan,
+        None => return,
+    };
+    if let Some(at) = plan.finished_at {
+        if now.saturating_sub(at) < TILE_PLAN_STALE_TICKS {
+            mem.tile_plan = Some(plan);
+        } else {
+            debug!("{} dropping the unclaimed tile search for {}", room.name(), plan.origin);
+        }
+        return;
+    }
+    let steps = perimeter::steps_allowed();
+    if steps > 0 {
+        let budget = steps * TILE_PLAN_STEP_ROWS;
+        let mut clearance = decode_clearance(&plan.clearance);
+        let mut rows = plan.rows;
+        if rows < terrain::DISTANCE_ROWS {
+            let blocked = blocked_tiles(room, mem);
+            rows = terrain::distance_rows(&blocked, &mut clearance, rows, budget);
+            plan.clearance = encode_clearance(&clearance);
+        }
+        let anchors: Vec<(u32, u32)> = plan
+            .anchors
+            .iter()
+            .map(|p| {
+                let pos = Position::from_packed(*p);
+                (pos.x(), pos.y())
+            })
+            .collect();
+        let end = (plan.rows + budget).min(TILE_PLAN_ROWS);
+        while rows < end {
+            let y = rows - terrain::DISTANCE_ROWS;
+            score_row(&clearance, y, &anchors, plan.min_clearance, &mut plan.best);
+            rows += 1;
+        }
+        plan.rows = rows;
+        if rows >= TILE_PLAN_ROWS {
+            debug!(
+                "{} finished the tile search for {} in {} ticks",
+                room.name(),
+                plan.origin,
+                now - plan.started
+            );
+            plan.finished_at = Some(now);
+        }
+    }
+    mem.tile_plan = Some(plan);
+}
+
+/// Picks a spawn position with room to build around it, as close as possible to the sources
+/// and controller. Doesn't need an existing spawn to anchor on.
+pub fn choose_spawn_position(room: &Room, mem: &mut RoomMemory) -> PlanStep {
+    let mut anchors: Vec<Position> = room.find(find::SOURCES).iter().map(|s| s.pos()).collect();
+    if let Some(c) = room.controller() {
+        anchors.push(c.pos());
+    }
+    open_tile(room, mem, "spawn rebuild", &anchors, SPAWN_CLEARANCE)
+}
+
+/// Spawns the controller level allows.
+fn max_spawns(level: u32) -> usize {
+    match level {
+        8 => 3,
+        7 => 2,
+        _ => 1,
+    }
+}
+
+pub fn spawn_sites(room: &Room) -> usize {
+    room.find(find::MY_CONSTRUCTION_SITES)
+        .iter()
+        .filter(|s| s.structure_type() == StructureType::Spawn)
+        .count()
+}
+
+/// Places one more spawn site next to the extensions when the controller level allows more
+/// spawns than the room has.
+pub fn ensure_extra_spawns(room: &Room, mem: &mut RoomMemory) -> Result<(), BotError> {
+    let level = room.controller().map(|c| c.level()).unwrap_or(0);
+    let have = room_cache::my_spawns(room).len() + spawn_sites(room);
+    if have >= max_spawns(level) {
+        return Ok(());
+    }
+
+    // extensions are what the extra spawns draw from, so keep them at the cluster
+    let anchors: Vec<Position> = room_cache::extensions(room).iter().map(|e| e.pos()).collect();
+    let anchors = if anchors.is_empty() {
+        room_cache::my_spawns(room).iter().map(|s| s.pos()).collect()
+    } else {
+        anchors
+    };
+    let pos = match open_tile(room, mem, "extra spawns", &anchors, 1) {
+        PlanStep::Searching => return Ok(()),
+        PlanStep::Done(pos) => pos.ok_or(BotError::MissingRoomObject {
+            what: "open tile for a spawn",
+        })?,
+    };
+    info!("{} placing spawn site {} at {}", room.name(), have + 1, pos);
+    place_site(room, mem, pos, StructureType::Spawn, "extra spawns")
+}
+
+/// Keeps one renewal tile next to each spawn: the open neighbour creeps walk over least, off
+/// the source and controller buffer tiles. A spot stays put while it's still open.
+pub fn ensure_renew_spots(room: &Room, mem: &mut RoomMemory) {
+    let spawns = room_cache::my_spawns(room);
+    mem.renew_spots.retain(|name, _| spawns.iter().any(|s| s.name() == *name));
+    let planned: Vec<Position> = planned_tiles(room, mem).into_iter().map(|(p, _)| p).collect();
+    for spawn in spawns.iter() {
+        let name = spawn.name();
+        let previous = mem.renew_spots.remove(&name).map(Position::from_packed);
+        let blocked = blocked_tiles(room, mem);
+        let open = |p: &Position| {
+            !blocked[terrain::index(p.x() as usize, p.y() as usize)] && !planned.contains(p)
+        };
+        if let Some(p) = previous.filter(|p| open(p)) {
+            mem.renew_spots.insert(name, p.packed_repr());
+            continue;
+        }
+        let at = spawn.pos();
+        let spot = (-1i32..=1)
+            .flat_map(|dy| (-1i32..=1).map(move |dx| (dx, dy)))
+            .filter(|&(dx, dy)| dx != 0 || dy != 0)
+            .map(|(dx, dy)| {
+                let x = (at.x() as i32 + dx) as u32;
+                let y = (at.y() as i32 + dy) as u32;
+                Position::new(x, y, room.name())
+            })
+            .filter(|p| open(p))
+            .min_by_key(|p| traffic::visits(*p));
+        match spot {
+            Some(p) => {
+                info!("{} renewal spot for {} at {}", room.name(), name, p);
+                mem.renew_spots.insert(name, p.packed_repr());
+            }
+            None => debug!("{} no open tile by {} to renew at", room.name(), name),
+        }
+    }
+}
+
+/// Harvester standing tiles and the controller buffer tiles, which nobody else waits on, with
+/// what the planner puts on each.
+pub fn planned_tiles(room: &Room, mem: &RoomMemory) -> Vec<(Position, StructureType)> {
+    let mut planned: Vec<(Position, StructureType)> = room_cache::sources(room)
+        .iter()
+        .filter_map(|s| mining::standing_position(room, s))
+        .map(|p| (p, StructureType::Container))
+        .collect();
+    if let Some(p) = mem.controller_container {
+        planned.push((Position::from_packed(p), StructureType::Container));
+    }
+    if let Some(p) = mem.controller_link {
+        planned.push((Position::from_packed(p), StructureType::Link));
+    }
+    planned
+}
+
+/// Keeps `STAGING_TILES` tiles by storage, or by the spawn before there's storage, for idle
+/// workers to wait on: the open tiles creeps walk over least, off roads, sites and the planned
+/// tiles. Tiles stay put while they're still open.
+pub fn ensure_staging(room: &Room, mem: &mut RoomMemory) {
+    let anchor = match room
+        .storage()
+        .map(|s| s.pos())
+        .or_else(|| room_cache::my_spawns(room).first().map(|s| s.pos()))
+    {
+        Some(p) => p,
+        None => return,
+    };
+    let previous: Vec<Position> =
+        std::mem::take(&mut mem.staging).into_iter().map(Position::from_packed).collect();
+    let blocked = blocked_tiles(room, mem);
+    let mut taken: Vec<Position> = planned_tiles(room, mem).into_iter().map(|(p, _)| p).collect();
+    taken.extend(
+        room_cache::structures(room)
+            .iter()
+            .filter(|s| s.structure_type() == StructureType::Road)
+            .map(|s| s.pos()),
+    );
+    taken.extend(room_cache::construction_sites(room).iter().map(|s| s.pos()));
+    let open = |p: &Position| {
+        !blocked[terrain::index(p.x() as usize, p.y() as usize)]
+            && !taken.contains(p)
+            && p.get_range_to(&anchor) <= STAGING_RANGE as u32
+    };
+
+    let mut tiles: Vec<Position> = previous.iter().filter(|p| open(*p)).cloned().collect();
+    if tiles.len() < STAGING_TILES {
+        let mut candidates: Vec<Position> = (-STAGING_RANGE..=STAGING_RANGE)
+            .flat_map(|dy| (-STAGING_RANGE..=STAGING_RANGE).map(move |dx| (dx, dy)))
+            .map(|(dx, dy)| (anchor.x() as i32 + dx, anchor.y() as i32 + dy))
+            .filter(|&(x, y)| x >= 0 && y >= 0 && x < ROOM_SIZE as i32 && y < ROOM_SIZE as i32)
+            .map(|(x, y)| Position::new(x as u32, y as u32, room.name()))
+            .filter(|p| open(p) && !tiles.contains(p))
+            .collect();
+        candidates.sort_by_key(|p| (traffic::visits(*p), p.get_range_to(&anchor)));
+        candidates.truncate(STAGING_TILES - tiles.len());
+        if !candidates.is_empty() {
+            info!("{} staging {} more tiles by {}", room.name(), candidates.len(), anchor);
+        }
+        tiles.extend(candidates);
+    }
+    mem.staging = tiles.iter().map(|p| p.packed_repr()).collect();
+}
+
+/// Plans the room's critical routes: from the spawn to each source and the controller, and
+/// from storage to each remote. Each is kept in `mem.road_plans` under `source:<id>`,
+/// `controller` or `remote:<room>`, so a road can be told apart by the route it's on.
+pub fn plan_critical_roads(room: &Room, mem: &mut RoomMemory) {
+    let spawn = match room_cache::my_spawns(room).first() {
+        Some(s) => s.pos(),
+        None => return,
+    };
+    let hub = room.storage().map_or(spawn, |s| s.pos());
+    let mut routes: Vec<(String, Position, Position, u32)> = room_cache::sources(room)
+        .iter()
+        .map(|s| (format!("source:{}", s.id()), spawn, s.pos(), 1))
+        .collect();
+    if let Some(c) = room.controller() {
+        routes.push(("controller".to_owned(), spawn, c.pos(), UPGRADE_RANGE as u32));
+    }
+    for remote in remote::remotes_of(room.name()) {
+        let to = Position::new(25, 25, remote);
+        routes.push((format!("remote:{}", remote), hub, to, REMOTE_ROUTE_RANGE));
+    }
+    mem.road_plans = routes
+        .into_iter()
+        .map(|(plan, from, to, range)| {
+            let tiles: Vec<u32> = route::path(from, to, range)
+                .into_iter()
+                .filter(|p| p.room_name() == room.name())
+                .map(|p| p.packed_repr())
+                .collect();
+            debug!("{} route {} is {} tiles", room.name(), plan, tiles.len());
+            (plan, tiles)
+        })
+        .collect();
+}
+
+/// Puts road sites down along the planned critical routes, up to `MAX_ROAD_SITES` at a time,
+/// once the controller reaches `ROAD_MIN_LEVEL`. Tiles kept clear for renewal or staging
+/// are left bare.
+pub fn ensure_critical_roads(room: &Room, mem: &mut RoomMemory) -> Result<(), BotError> {
+    if room.controller().map_or(0, |c| c.level()) < ROAD_MIN_LEVEL {
+        return Ok(());
+    }
+    place_planned_roads(room, mem)
+}
+
+/// Puts road sites down along a remote room's hauling routes, which the remote manager only
+/// plans once they pay for themselves.
+pub fn ensure_remote_roads(room: &Room, mem: &mut RoomMemory) -> Result<(), BotError> {
+    place_planned_roads(room, mem)
+}
+
+fn place_planned_roads(room: &Room, mem: &mut RoomMemory) -> Result<(), BotError> {
+    let pending = room_cache::construction_sites(room)
+        .iter()
+        .filter(|s| s.structure_type() == StructureType::Road)
+        .count();
+    let mut taken: HashSet<u32> = room_cache::structures(room)
+        .iter()
+        .filter(|s| s.structure_type() == StructureType::Road)
+        .map(|s| s.pos().packed_repr())
+        .collect();
+    taken.extend(room_cache::construction_sites(room).iter().map(|s| s.pos().packed_repr()));
+    taken.extend(mem.site_blacklist.iter().cloned());
+    taken.extend(mem.renew_spots.values().cloned());
+    taken.extend(mem.staging.iter().cloned());
+    let edge = |p: Position| {
+        let far = ROOM_SIZE as u32 - 1;
+        p.x() == 0 || p.y() == 0 || p.x() == far || p.y() == far
+    };
+    let mut wanted: Vec<(String, Position)> = Vec::new();
+    for (plan, tiles) in &mem.road_plans {
+        for packed in tiles {
+            let pos = Position::from_packed(*packed);
+            if !edge(pos) && taken.insert(*packed) {
+                wanted.push((plan.clone(), pos));
+            }
+        }
+    }
+    for (plan, pos) in wanted.into_iter().take(MAX_ROAD_SITES.saturating_sub(pending)) {
+        debug!("{} placing road site at {} for {}", room.name(), pos, plan);
+        place_site(room, mem, pos, StructureType::Road, &format!("roads {}", plan))?;
+    }
+    Ok(())
+}
+
+/// Whether `room` is at RCL 3 or above without a tower yet, as of its room pass this tick.
+pub fn tower_rush(room: RoomName) -> bool {
+    TOWER_RUSH.with(|t| t.borrow().contains(&room))
+}
+
+/// From RCL 3 until the room's first tower stands, keeps a tower site placed by the spawn
+/// and marks the room as rushing it. The time from RCL 3 to the finished tower is logged.
+pub fn ensure_first_tower(room: &Room, mem: &mut RoomMemory) -> Result<(), BotError> {
+    let rushing = mem.first_tower_at.is_none() && mem.rcl >= 3;
+    TOWER_RUSH.with(|t| {
+        let mut t = t.borrow_mut();
+        if rushing {
+            t.insert(room.name());
+        } else {
+            t.remove(&room.name());
+        }
+    });
+    if !rushing {
+        return Ok(());
+    }
+    let now = screeps::game::time();
+    let towers = room
+        .find(find::MY_STRUCTURES)
+        .iter()
+        .any(|s| s.structure_type() == StructureType::Tower);
+    if towers {
+        match mem.tower_rush_since.take() {
+            Some(since) => info!(
+                "{} first tower finished {} ticks after reaching RCL 3",
+                room.name(),
+                now - since
+            ),
+            None => debug!("{} already has a tower", room.name()),
+        }
+        mem.first_tower_at = Some(now);
+        TOWER_RUSH.with(|t| t.borrow_mut().remove(&room.name()));
+        return Ok(());
+    }
+    if mem.tower_rush_since.is_none() {
+        info!("{} reached RCL 3 without a tower, rushing one", room.name());
+        mem.tower_rush_since = Some(now);
+    }
+    let placed = room
+        .find(find::MY_CONSTRUCTION_SITES)
+        .iter()
+        .any(|s| s.structure_type() == StructureType::Tower);
+    if placed {
+        return Ok(());
+    }
+    let anchors: Vec<Position> = room.find(find::MY_SPAWNS).iter().map(|s| s.pos()).collect();
+    if anchors.is_empty() {
+        return Ok(());
+    }
+    let pos = match open_tile(room, mem, "first tower", &anchors, 1) {
+        PlanStep::Searching => return Ok(()),
+        PlanStep::Done(pos) => pos.ok_or(BotError::MissingRoomObject {
+            what: "open tile for a tower",
+        })?,
+    };
+    info!("{} placing its first tower site at {}", room.name(), pos);
+    place_site(room, mem, pos, StructureType::Tower, "first tower")
+}
+
+/// Puts a site back where a planned structure was destroyed.
+pub fn replace_site(
+    room: &Room,
+    mem: &mut RoomMemory,
+    pos: Position,
+    ty: StructureType,
+) -> Result<(), BotError> {
+    if has_at(room, pos, ty) {
+        return Ok(());
+    }
+    info!("{} re-placing {:?} site at {}", room.name(), ty, pos);
+    place_site(room, mem, pos, ty, "rebuild")
+}
+
+/// Places a construction site and remembers which planner asked for it. A placement
+/// `construction::validate` turns down is an error naming the planner, and a bad tile is
+/// blacklisted so the planner picks another next time.
+fn place_site(
+    room: &Room,
+    mem: &mut RoomMemory,
+    pos: Position,
+    ty: StructureType,
+    origin: &str,
+) -> Result<(), BotError> {
+    if let Err(problem) = construction::validate(room, mem, pos, ty) {
+        if problem.blacklists() {
+            construction::blacklist(mem, pos.packed_repr());
+        }
+        return Err(BotError::InvalidSite {
+            planner: origin.to_owned(),
+            site: format!("a {:?} site at {}", ty, pos),
+            reason: problem.to_string(),
+        });
+    }
+    let r = intents::issue(origin, "create_construction_site", &pos.to_string(), None, || {
+        room.create_construction_site(&pos, ty)
+    });
+    error::check("create_construction_site", r)?;
+    construction::record_origin(mem, pos, origin);
+    Ok(())
+}
+
+/// Places a spawn construction site in a room that has lost (or never had) a spawn, once
+/// `spawn::recovery` says nothing is rebuilding one.
+pub fn ensure_spawn_site(room: &Room, mem: &mut RoomMemory) -> Result<(), BotError> {
+    let pos = match choose_spawn_position(room, mem) {
+        PlanStep::Searching => return Ok(()),
+        PlanStep::Done(pos) => pos.ok_or(BotError::MissingRoomObject {
+            what: "open tile for a spawn",
+        })?,
+    };
+    info!("{} has no spawn, placing a spawn site at {}", room.name(), pos);
+    place_site(room, mem, pos, StructureType::Spawn, "spawn rebuild")
+}
+
+/// The tile within `BUFFER_RANGE` of the controller next to the most upgrader parking tiles,
+/// skipping `exclude`.
+fn buffer_tile(
+    room: &Room,
+    mem: &RoomMemory,
+    controller: Position,
+    exclude: Option<u32>,
+) -> Option<Position> {
+    let blocked = blocked_tiles(room, mem);
+    let open = |x: i32, y: i32| {
+        x >= 0
+            && y >= 0
+            && x < ROOM_SIZE as i32
+            && y < ROOM_SIZE as i32
+            && !blocked[terrain::index(x as usize, y as usize)]
+    };
+    let (cx, cy) = (controller.x() as i32, controller.y() as i32);
+    let mut best: Option<(usize, Position)> = None;
+    for y in cy - BUFFER_RANGE..=cy + BUFFER_RANGE {
+        for x in cx - BUFFER_RANGE..=cx + BUFFER_RANGE {
+            if !open(x, y) {
+                continue;
+            }
+            let pos = Position::new(x as u32, y as u32, room.name());
+            if exclude == Some(pos.packed_repr()) {
+                continue;
+            }
+            let mut parking = 0;
+            for dy in -1..=1 {
+                for dx in -1..=1 {
+                    let (px, py) = (x + dx, y + dy);
+                    let in_range =
+                        (px - cx).abs() <= UPGRADE_RANGE && (py - cy).abs() <= UPGRADE_RANGE;
+                    if (dx, dy) != (0, 0) && in_range && open(px, py) {
+                        parking += 1;
+                    }
+                }
+            }
+            if best.map_or(true, |(p, _)| parking > p) {
+                best = Some((parking, pos));
+            }
+        }
+    }
+    best.map(|(_, pos)| pos)
+}
+
+/// Whether `pos` holds a structure or construction site of type `ty`.
+fn has_at(room: &Room, pos: Position, ty: StructureType) -> bool {
+    room.look_for_at(look::STRUCTURES, &pos)
+        .iter()
+        .any(|s| s.structure_type() == ty)
+        || room
+            .look_for_at(look::CONSTRUCTION_SITES, &pos)
+            .iter()
+            .any(|s| s.structure_type() == ty)
+}
+
+/// Places (or replaces) one upgrade buffer structure at the position recorded in `slot`,
+/// choosing and recording a position first if there isn't one.
+fn ensure_buffer(
+    room: &Room,
+    mem: &mut RoomMemory,
+    slot: Option<u32>,
+    ty: StructureType,
+    exclude: Option<u32>,
+) -> Result<u32, BotError> {
+    let controller = room.controller().ok_or(BotError::MissingRoomObject {
+        what: "controller",
+    })?;
+    let slot = slot.filter(|p| !mem.site_blacklist.contains(p));
+    let pos = match slot {
+        Some(p) => Position::from_packed(p),
+        None => buffer_tile(room, mem, controller.pos(), exclude).ok_or(
+            BotError::MissingRoomObject {
+                what: "open tile next to the controller",
+            },
+        )?,
+    };
+    if !has_at(room, pos, ty) {
+        info!("{} placing controller {:?} at {}", room.name(), ty, pos);
+        place_site(room, mem, pos, ty, "upgrade buffer")?;
+    }
+    Ok(pos.packed_repr())
+}
+
+/// Keeps the upgraders' energy buffer built: a container next to the controller from RCL 2, and
+/// a link from RCL 6. Once the link stands the container is forgotten; nothing repairs it, so
+/// it decays and frees the tile.
+pub fn ensure_upgrade_buffer(room: &Room, mem: &mut RoomMemory) -> Result<(), BotError> {
+    let level = room.controller().map(|c| c.level()).unwrap_or(0);
+    if level >= 6 {
+        let link_built = mem.controller_link.map_or(false, |p| {
+            room.look_for_at(look::STRUCTURES, &Position::from_packed(p))
+                .iter()
+                .any(|s| s.structure_type() == StructureType::Link)
+        });
+        if link_built {
+            if let Some(container) = mem.controller_container.take() {
+                info!(
+                    "{} controller link is up, retiring the container at {}",
+                    room.name(),
+                    Position::from_packed(container)
+                );
+            }
+            return Ok(());
+        }
+        let (slot, exclude) = (mem.controller_link, mem.controller_container);
+        mem.controller_link = Some(ensure_buffer(room, mem, slot, StructureType::Link, exclude)?);
+    } else if level >= 2 {
+        let slot = mem.controller_container;
+        mem.controller_container =
+            Some(ensure_buffer(room, mem, slot, StructureType::Container, None)?);
+    }
+    Ok(())
+}
+
+/// Places a container site on a harvester's standing tile after its container decayed.
+pub fn ensure_source_container(room: &Room, stand: Position) -> Result<(), BotError> {
+    if has_at(room, stand, StructureType::Container) {
+        return Ok(());
+    }
+    let mut mem = memory::get_room_memory(room.name())?;
+    info!("{} replacing the source container at {}", room.name(), stand);
+    // saved either way, so a blacklisted tile sticks
+    let placed = place_site(room, &mut mem, stand, StructureType::Container, "source container");
+    memory::set_room_memory(room.name(), &mem)?;
+    placed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clearance_survives_the_round_trip() {
+        let dist: Vec<u8> = (0..ROOM_SIZE * ROOM_SIZE).map(|i| (i % 26) as u8).collect();
+        assert_eq!(decode_clearance(&encode_clearance(&dist)), dist);
+    }
+
+    #[test]
+    fn score_row_keeps_the_nearest_clear_tile() {
+        let mut clearance = vec![0u8; ROOM_SIZE * ROOM_SIZE];
+        for x in 10..20 {
+            clearance[terrain::index(x, 5)] = 2;
+        }
+        clearance[terrain::index(30, 5)] = 1;
+        let mut best = None;
+        score_row(&clearance, 5, &[(30, 5)], 2, &mut best);
+        assert_eq!(best, Some((11, terrain::index(19, 5))));
+        score_row(&clearance, 6, &[(30, 5)], 2, &mut best);
+        assert_eq!(best, Some((11, terrain::index(19, 5))));
+    }
+}
@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+
+use log::*;
+use screeps::{find, prelude::*, Creep, Part, ResourceType, Room};
+
+use crate::{
+    logistics, memory, mining, planner,
+    role::Role,
+    room::{RoomMemory, RoomMode},
+    settings,
+    spawn::SpawnRequest,
+};
+
+/// Where a new creep of `role` will spend its life, so it can come out of the nearest spawn.
+fn work_position(room: &Room, role: Role) -> Option<u32> {
+    match role {
+        Role::Upgrader => room.controller().map(|c| c.pos().packed_repr()),
+        Role::Harvester => mining::mined_sources(room).first().map(|s| s.pos().packed_repr()),
+        Role::Worker
+        | Role::Defender
+        | Role::DepositHarvester
+        | Role::DepositHauler
+        | Role::Pioneer
+        | Role::Reserver
+        | Role::Scout
+        | Role::RemoteMiner
+        | Role::RemoteHauler => None,
+    }
+}
+
+/// Body budget for upgraders in a `Mature` room: enough to hold the controller.
+const MATURE_UPGRADER_BUDGET: u32 = 600;
+/// Upgraders a room keeps however short it is, so the controller doesn't downgrade.
+const MIN_UPGRADERS: u32 = 1;
+const MAX_UPGRADERS: u32 = 8;
+/// Defaults for `Memory.settings.upgrade_storage_high`, `upgrade_storage_low` and
+/// `upgrade_storage_emergency`: storage energy above which the upgrader target is pushed up,
+/// below which it's pulled down, and below which it drops to `MIN_UPGRADERS`.
+const STORAGE_HIGH: u32 = 300_000;
+const STORAGE_LOW: u32 = 50_000;
+const STORAGE_EMERGENCY: u32 = 10_000;
+
+/// Upgrade boost a room pushing its controller back up wants per upgrader Work part: what
+/// boosting the part takes.
+const UPGRADER_BOOST_PER_WORK: u32 = 30;
+
+/// Work parts in the upgrader body the room can build.
+fn upgrader_works(room: &Room) -> u32 {
+    let capacity = room.energy_capacity_available();
+    let body = Role::Upgrader.body(capacity, capacity);
+    body.iter().filter(|p| **p == Part::Work).count().max(1) as u32
+}
+
+/// Boosts the room's upgraders want in stock: upgrade boost for all of them while the room is
+/// pushing its controller back up.
+pub fn boost_demand(room: &Room, mem: &RoomMemory) -> Vec<(ResourceType, u32)> {
+    if mem.mode != RoomMode::PushRcl {
+        return Vec::new();
+    }
+    let upgraders = mem.upgrader_target.unwrap_or_else(default_upgraders) + 1;
+    let amount = upgraders * upgrader_works(room) * UPGRADER_BOOST_PER_WORK;
+    vec![(ResourceType::CatalyzedGhodiumAcid, amount)]
+}
+
+/// The upgrader target before the room's first books are closed: `Memory.settings.upgraders`.
+fn default_upgraders() -> u32 {
+    settings::u32_or("upgraders", 2)
+}
+
+/// Sets `mem.upgrader_target` from the net income per tick of the room's last closed books:
+/// enough upgraders for their Work parts, at one energy a tick each, to spend it. Storage
+/// biases the count a step up when it's filling past its high mark and a step down below its
+/// low one, and the target moves at most one upgrader per review so it settles instead of
+/// swinging. With storage down to its emergency reserve only `MIN_UPGRADERS` are kept.
+pub fn review_upgraders(room: &Room, mem: &mut RoomMemory, rate: f64) {
+    let works = upgrader_works(room) as f64;
+    let sustainable = (rate.max(0.0) / works).round() as u32;
+    let stored = room.storage().map(|s| s.store_of(ResourceType::Energy));
+    let high = settings::u32_or("upgrade_storage_high", STORAGE_HIGH);
+    let low = settings::u32_or("upgrade_storage_low", STORAGE_LOW);
+    let emergency = settings::u32_or("upgrade_storage_emergency", STORAGE_EMERGENCY);
+    let previous = mem.upgrader_target.unwrap_or_else(default_upgraders);
+    let target = match stored {
+        Some(e) if e < emergency => MIN_UPGRADERS,
+        _ => {
+            let wanted = match stored {
+                Some(e) if e >= high => sustainable + 1,
+                Some(e) if e < low => sustainable.saturating_sub(1),
+                _ => sustainable,
+            };
+            let step = if wanted > previous {
+                previous + 1
+            } else if wanted < previous {
+                previous - 1
+            } else {
+                previous
+            };
+            step.max(MIN_UPGRADERS).min(MAX_UPGRADERS)
+        }
+    };
+    if target != previous {
+        info!(
+            "{} upgraders {} -> {}: {:.1} energy/tick to spare, storage {:?}",
+            room.name(),
+            previous,
+            target,
+            rate,
+            stored
+        );
+    }
+    mem.upgrader_target = Some(target);
+}
+
+/// `(role, target count, spawn priority)` for every role the room maintains.
+pub fn targets(room: &Room, mem: &RoomMemory) -> Vec<(Role, u32, u32)> {
+    let sources = room.find(find::SOURCES).len() as u32;
+    let upgraders = mem.upgrader_target.unwrap_or_else(default_upgraders);
+    let upgraders = if logistics::upgrade_buffer(room).is_none() {
+        0
+    } else if mem.mode == RoomMode::Mature {
+        1
+    } else if mem.mode == RoomMode::PushRcl {
+        upgraders + 1
+    } else {
+        upgraders
+    };
+    let mut workers = settings::u32_or("workers_per_source", 3) * sources.max(1);
+    // workers do the building; the first tower gets an extra one
+    if planner::tower_rush(room.name()) {
+        workers += 1;
+    }
+    vec![
+        (Role::Worker, workers, 50),
+        (Role::Upgrader, upgraders, 30),
+        (Role::Harvester, mining::mined_sources(room).len() as u32, 60),
+        (Role::Scout, settings::u32_or("scouts", 1), 10),
+    ]
+}
+
+pub fn role_of(creep: &Creep) -> Role {
+    match memory::creep_role(creep) {
+        Ok(Some(role)) => role,
+        Ok(None) => memory::quarantine_creep_role(creep, None),
+        Err(e) => memory::quarantine_creep_role(creep, Some(e)),
+    }
+}
+
+/// Living creeps of `role` in `room`.
+pub fn count(room: &Room, role: Role) -> u32 {
+    room.find(find::MY_CREEPS)
+        .iter()
+        .filter(|c| role_of(c) == role)
+        .count() as u32
+}
+
+/// A creep is retasked at most once in this many ticks.
+const RETASK_INTERVAL: i32 = 300;
+/// Memory keys that only make sense for the role a creep is leaving: its delivery run holds
+/// logistics claims.
+const ROLE_KEYS: [&str; 6] = [
+    "build_site",
+    "building",
+    "deliveries",
+    "harvesting",
+    "pickup",
+    "tower_refill",
+];
+
+/// Moves surplus creeps to a role sharing their body that's under target, rather than waiting
+/// for them to die. A role has a surplus when it's more than one over target.
+fn retask(room: &Room, mem: &RoomMemory) {
+    let now = screeps::game::time() as i32;
+    let creeps = room.find(find::MY_CREEPS);
+    let mut roles: Vec<Role> = creeps.iter().map(role_of).collect();
+    let targets = targets(room, mem);
+    let target_of = |role| targets.iter().find(|t| t.0 == role).map_or(0, |t| t.1);
+    let living = |roles: &[Role], role| roles.iter().filter(|r| **r == role).count() as u32;
+
+    for (i, creep) in creeps.iter().enumerate() {
+        let from = roles[i];
+        if living(&roles, from) <= target_of(from) + 1 {
+            continue;
+        }
+        let last = memory::creep_memory(creep).i32("retasked").ok().flatten();
+        if last.map_or(false, |t| now - t < RETASK_INTERVAL) {
+            continue;
+        }
+        let to = match targets
+            .iter()
+            .map(|t| t.0)
+            .find(|r| *r != from && from.shares_body_with(*r) && living(&roles, *r) < target_of(*r))
+        {
+            Some(r) => r,
+            None => continue,
+        };
+        info!("{} retasking {} from {:?} to {:?}", room.name(), creep.name(), from, to);
+        if let Err(e) = memory::set_creep_role(&creep.name(), to) {
+            warn!("couldn't retask {}: {}", creep.name(), e);
+            continue;
+        }
+        for key in &ROLE_KEYS {
+            memory::creep_memory(creep).del(key);
+        }
+        memory::creep_memory(creep).set("retasked", now);
+        roles[i] = to;
+    }
+}
+
+/// Queues spawn requests for every role below its target, counting both living creeps and
+/// requests already in the queue. Surplus creeps are retasked first.
+pub fn run_population(room: &Room, mem: &mut RoomMemory) {
+    retask(room, mem);
+    let mut counts: HashMap<Role, u32> = HashMap::new();
+    for creep in room.find(find::MY_CREEPS) {
+        *counts.entry(role_of(&creep)).or_insert(0) += 1;
+    }
+    for request in &mem.spawn_queue {
+        *counts.entry(request.role).or_insert(0) += 1;
+    }
+
+    for (role, target, priority) in targets(room, mem) {
+        let budget = match (role, mem.mode) {
+            (Role::Upgrader, RoomMode::Mature) => Some(MATURE_UPGRADER_BUDGET),
+            _ => None,
+        };
+        let have = counts.get(&role).cloned().unwrap_or(0);
+        for slot in have..target {
+            debug!("{} queueing {:?} ({}/{})", room.name(), role, have, target);
+            mem.enqueue(SpawnRequest {
+                hint: work_position(room, role),
+                enqueued: screeps::game::time(),
+                dedupe: Some(format!("{:?}-{}", role, slot)),
+                budget,
+                ..SpawnRequest::new(role, priority)
+            });
+        }
+    }
+}
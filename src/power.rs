@@ -0,0 +1,235 @@
+use std::collections::HashMap;
+
+use log::*;
+use screeps::{find, prelude::*, ReturnCode, Room, RoomName, Structure};
+use serde::{Deserialize, Serialize};
+use stdweb::{js, js_deserializable, js_serializable, unstable::TryInto};
+
+use crate::{
+    error::{self, BotError},
+    intents, memory,
+};
+
+/// `PWR_GENERATE_OPS` and `PWR_REGEN_SOURCE`.
+const GENERATE_OPS: u32 = 1;
+const REGEN_SOURCE: u32 = 13;
+/// Power creeps head back to renew below this many ticks to live.
+const RENEW_TTL: u32 = 1000;
+/// Ops kept on the creep; the rest go to storage.
+const OPS_KEEP: u32 = 100;
+
+/// Our record of one power creep in `Memory.power_creeps`, by name. Unlike creep memory it
+/// outlives the creep, which respawns under the same name.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct PowerCreepMemory {
+    /// Room whose power spawn it spawns and renews at, and whose sources it boosts.
+    #[serde(default)]
+    pub home: Option<RoomName>,
+    #[serde(default)]
+    pub spawned: bool,
+    #[serde(default)]
+    pub deaths: u32,
+}
+
+js_serializable!(PowerCreepMemory);
+js_deserializable!(PowerCreepMemory);
+
+/// What `Game.powerCreeps` says about one power creep, read in a single call.
+#[derive(Deserialize, Debug)]
+struct PowerCreepStatus {
+    name: String,
+    /// Unset while the creep isn't spawned.
+    #[serde(default)]
+    room: Option<String>,
+    /// Off its spawn cooldown; only meaningful while not spawned.
+    #[serde(default)]
+    can_spawn: bool,
+    #[serde(default)]
+    ticks_to_live: u32,
+    #[serde(default)]
+    ops: u32,
+    #[serde(default)]
+    free: u32,
+    /// Cooldown left on each power it has, by power constant.
+    #[serde(default)]
+    powers: HashMap<String, u32>,
+    #[serde(default)]
+    room_enabled: bool,
+}
+
+/// Every power creep on the account; empty on servers without power.
+fn statuses() -> Vec<PowerCreepStatus> {
+    let raw: String = js! {
+        if (typeof Game.powerCreeps === "undefined") {
+            return "[]";
+        }
+        return JSON.stringify(Object.values(Game.powerCreeps).map(function (pc) {
+            var out = { name: pc.name, powers: {} };
+            for (var p in pc.powers) {
+                out.powers[p] = pc.powers[p].cooldown || 0;
+            }
+            if (pc.room) {
+                out.room = pc.room.name;
+                out.ticks_to_live = pc.ticksToLive;
+                out.ops = pc.store[RESOURCE_OPS] || 0;
+                out.free = pc.store.getFreeCapacity();
+                out.room_enabled = !pc.room.controller || pc.room.controller.isPowerEnabled;
+            } else {
+                out.can_spawn = !pc.spawnCooldownTime || pc.spawnCooldownTime < Date.now();
+            }
+            return out;
+        }));
+    }
+    .try_into()
+    .unwrap_or_default();
+    serde_json::from_str(&raw).unwrap_or_else(|e| {
+        warn!("couldn't read Game.powerCreeps: {}", e);
+        Vec::new()
+    })
+}
+
+/// Calls `method` on the named power creep with the object `target` and, for `usePower`, the
+/// power constant.
+fn call(name: &str, method: &'static str, target: Option<&str>, power: u32) -> ReturnCode {
+    let target = target.map(|t| t.to_string());
+    let label = target.clone().unwrap_or_default();
+    intents::issue(&format!("power creep {}", name), method, &label, None, || {
+        let code = js! {
+            var pc = Game.powerCreeps[@{name}];
+            var t = @{target.clone()} ? Game.getObjectById(@{target}) : undefined;
+            if (!pc) {
+                return ERR_NOT_FOUND;
+            }
+            switch (@{method}) {
+                case "spawn": return pc.spawn(t);
+                case "renew": return pc.renew(t);
+                case "enableRoom": return pc.enableRoom(t);
+                case "usePower": return pc.usePower(@{power}, t);
+                case "transfer": return pc.transfer(t, RESOURCE_OPS,
+                    Math.max(0, pc.store[RESOURCE_OPS] - @{OPS_KEEP}));
+                case "moveTo": return pc.moveTo(t);
+            }
+            return ERR_INVALID_ARGS;
+        };
+        code.try_into().unwrap_or(ReturnCode::InvalidArgs)
+    })
+}
+
+/// `call`, walking to the target first when it's out of range.
+fn act(name: &str, method: &'static str, target: &str, power: u32) -> Result<(), BotError> {
+    match call(name, method, Some(target), power) {
+        ReturnCode::NotInRange => {
+            call(name, "moveTo", Some(target), 0);
+            Ok(())
+        }
+        r => error::check(method, r),
+    }
+}
+
+fn power_spawn(room: &Room) -> Option<String> {
+    room.find(find::STRUCTURES).into_iter().find_map(|s| match s {
+        Structure::PowerSpawn(p) if p.my() => Some(p.id().to_string()),
+        _ => None,
+    })
+}
+
+/// The remembered home while it still has a power spawn, otherwise the first room that does.
+fn home_room(mem: &PowerCreepMemory) -> Option<(Room, String)> {
+    let remembered = mem.home.and_then(screeps::game::rooms::get);
+    remembered
+        .into_iter()
+        .chain(screeps::game::rooms::values())
+        .filter(|r| r.controller().map_or(false, |c| c.my()))
+        .find_map(|r| power_spawn(&r).map(|s| (r, s)))
+}
+
+/// A home source without a regen effect about to run for a while.
+fn regen_target(room: &Room) -> Option<String> {
+    let room = room.name().to_string();
+    let id = js! {
+        var room = Game.rooms[@{room}];
+        var source = room && room.find(FIND_SOURCES).find(function (s) {
+            return !(s.effects || []).some(function (e) {
+                return e.effect === PWR_REGEN_SOURCE && e.ticksRemaining > 30;
+            });
+        });
+        return source ? source.id : null;
+    };
+    id.try_into().ok().flatten()
+}
+
+fn run_power_creep(pc: &PowerCreepStatus, mem: &mut PowerCreepMemory) -> Result<(), BotError> {
+    let (home, spawn) = match home_room(mem) {
+        Some(h) => h,
+        None => return Ok(()),
+    };
+    mem.home = Some(home.name());
+    let room = match &pc.room {
+        Some(r) => r,
+        None => {
+            if mem.spawned {
+                mem.spawned = false;
+                mem.deaths += 1;
+                info!("power creep {} died, respawning in {}", pc.name, home.name());
+            }
+            if pc.can_spawn {
+                error::check("power creep spawn", call(&pc.name, "spawn", Some(&spawn), 0))?;
+            }
+            return Ok(());
+        }
+    };
+    mem.spawned = true;
+
+    if pc.ticks_to_live < RENEW_TTL || *room != home.name().to_string() {
+        return act(&pc.name, "renew", &spawn, 0);
+    }
+    if !pc.room_enabled {
+        if let Some(c) = home.controller() {
+            return act(&pc.name, "enableRoom", &c.id().to_string(), 0);
+        }
+    }
+    // one power a tick: a source going without regen costs more than a tick of ops
+    let ready = |power: u32| pc.powers.get(&power.to_string()) == Some(&0);
+    if ready(REGEN_SOURCE) {
+        if let Some(source) = regen_target(&home) {
+            return act(&pc.name, "usePower", &source, REGEN_SOURCE);
+        }
+    }
+    if ready(GENERATE_OPS) && pc.free > 0 {
+        return error::check("generate ops", call(&pc.name, "usePower", None, GENERATE_OPS));
+    }
+    if pc.ops > OPS_KEEP {
+        if let Some(storage) = home.storage() {
+            return act(&pc.name, "transfer", &storage.id().to_string(), 0);
+        }
+    }
+    Ok(())
+}
+
+/// Spawns, renews and works every operator power creep on the account: Regen Source on each
+/// home source that needs it, and Generate Ops on the ticks it has nothing to regenerate.
+/// Does nothing without power creeps.
+pub fn run_power_creeps() -> Result<(), BotError> {
+    let statuses = statuses();
+    if statuses.is_empty() {
+        return Ok(());
+    }
+    let records = memory::power_creeps()?;
+    for pc in &statuses {
+        let mut mem = records
+            .get::<PowerCreepMemory>(&pc.name)
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        if let Err(e) = run_power_creep(pc, &mut mem) {
+            error::report("power creep", &pc.name, pc.room.as_deref().unwrap_or("-"), &e);
+        }
+        records.set(&pc.name, &mem);
+    }
+    for name in records.keys() {
+        if !statuses.iter().any(|pc| pc.name == name) {
+            records.del(&name);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,237 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use log::*;
+use screeps::{Part, RoomName};
+
+use crate::{
+    role::{Role, MAX_PARTS},
+    settings,
+    spawn::body_cost,
+};
+
+/// The same template problem is only warned about this often.
+const WARN_INTERVAL: u32 = 100;
+
+thread_local! {
+    static WARNED: RefCell<HashMap<String, u32>> = RefCell::new(HashMap::new());
+}
+
+fn part(letter: char) -> Option<Part> {
+    match letter.to_ascii_uppercase() {
+        'W' => Some(Part::Work),
+        'C' => Some(Part::Carry),
+        'M' => Some(Part::Move),
+        'A' => Some(Part::Attack),
+        'R' => Some(Part::RangedAttack),
+        'H' => Some(Part::Heal),
+        'K' => Some(Part::Claim),
+        'T' => Some(Part::Tough),
+        _ => None,
+    }
+}
+
+/// Parses a body template: parts as letters (`W` work, `C` carry, `M` move, `A` attack, `R`
+/// ranged attack, `H` heal, `K` claim, `T` tough), each optionally preceded by a count, and
+/// bracketed groups repeated the same way. `5W1C3M` and `3[WCM]` are both valid.
+pub fn parse(template: &str) -> Result<Vec<Part>, String> {
+    let lookup = |c: char| part(c).ok_or_else(|| format!("unknown part {}", c));
+    let mut body = Vec::new();
+    let mut chars = template.chars().filter(|c| !c.is_whitespace()).peekable();
+    while chars.peek().is_some() {
+        let mut digits = String::new();
+        while let Some(d) = chars.peek().filter(|c| c.is_ascii_digit()) {
+            digits.push(*d);
+            chars.next();
+        }
+        let count: usize = if digits.is_empty() {
+            1
+        } else {
+            digits.parse().map_err(|_| format!("bad count {}", digits))?
+        };
+        // checked before anything is multiplied by it
+        if count > MAX_PARTS {
+            return Err(format!("more than {} parts", MAX_PARTS));
+        }
+        let unit = match chars.next() {
+            Some('[') => {
+                let mut unit = Vec::new();
+                loop {
+                    match chars.next() {
+                        Some(']') => break,
+                        Some(c) => unit.push(lookup(c)?),
+                        None => return Err("unclosed [".to_owned()),
+                    }
+                }
+                unit
+            }
+            Some(c) => vec![lookup(c)?],
+            None => return Err(format!("count {} with no part after it", count)),
+        };
+        if count == 0 || unit.is_empty() {
+            return Err("empty group".to_owned());
+        }
+        if body.len() + count * unit.len() > MAX_PARTS {
+            return Err(format!("more than {} parts", MAX_PARTS));
+        }
+        for _ in 0..count {
+            body.extend_from_slice(&unit);
+        }
+    }
+    if body.is_empty() {
+        return Err("no parts".to_owned());
+    }
+    Ok(body)
+}
+
+fn key(room: RoomName, role: Role) -> String {
+    format!("body_{}_{:?}", room, role).to_lowercase()
+}
+
+/// Warns at most every `WARN_INTERVAL` ticks per room and role.
+fn warn_once(room: RoomName, role: Role, message: String) {
+    let now = screeps::game::time();
+    let due = WARNED.with(|w| {
+        let mut w = w.borrow_mut();
+        let last = w.entry(key(room, role)).or_insert(0);
+        if *last == 0 || now.saturating_sub(*last) >= WARN_INTERVAL {
+            *last = now;
+            true
+        } else {
+            false
+        }
+    });
+    if due {
+        warn!("{}", message);
+    }
+}
+
+/// The body template set for `role` in `room` with `set_body`, if it parses and the room can
+/// ever hold its cost.
+fn preset(room: RoomName, role: Role, capacity: u32) -> Option<Vec<Part>> {
+    let template = settings::string(&key(room, role))?;
+    let body = match parse(&template) {
+        Ok(b) => b,
+        Err(e) => {
+            warn_once(room, role, format!("{} {:?} body {}: {}", room, role, template, e));
+            return None;
+        }
+    };
+    let cost = body_cost(&body);
+    if cost > capacity {
+        let message = format!(
+            "{} {:?} body {} costs {}, more than the room's {}; using the default",
+            room, role, template, cost, capacity
+        );
+        warn_once(room, role, message);
+        return None;
+    }
+    Some(body)
+}
+
+/// `Role::body`, unless a template is set for the role in this room: then the template, or
+/// nothing while `energy` can't pay for it. Bad templates fall back to `Role::body`.
+pub fn body(room: RoomName, role: Role, energy: u32, capacity: u32) -> Vec<Part> {
+    match preset(room, role, capacity) {
+        Some(body) if body_cost(&body) <= energy.min(capacity) => body,
+        Some(_) => Vec::new(),
+        None => role.body(energy, capacity),
+    }
+}
+
+/// Says why the spawn is waiting when it's a template the room can't pay for yet.
+pub fn note_unaffordable(room: RoomName, role: Role, available: u32, capacity: u32) {
+    if let Some(body) = preset(room, role, capacity) {
+        let message = format!(
+            "{} can't afford its {:?} body yet: costs {}, has {}",
+            room,
+            role,
+            body_cost(&body),
+            available
+        );
+        warn_once(room, role, message);
+    }
+}
+
+/// Console command: `set_body("W5N8", "worker", "10C10M")` sets the body template for a
+/// role in a room; an empty template goes back to the built-in body.
+pub fn set_body(room: String, role: String, template: String) {
+    let room: RoomName = match room.parse() {
+        Ok(r) => r,
+        Err(_) => {
+            warn!("set_body: bad room name {}", room);
+            return;
+        }
+    };
+    let role = match Role::parse(&role) {
+        Some(r) => r,
+        None => {
+            warn!("set_body: unknown role {}", role);
+            return;
+        }
+    };
+    if template.trim().is_empty() {
+        settings::set_string(&key(room, role), None);
+        info!("{} {:?} back to the built-in body", room, role);
+        return;
+    }
+    match parse(&template) {
+        Ok(body) => {
+            settings::set_string(&key(room, role), Some(&template));
+            info!(
+                "{} {:?} body set to {} ({} parts, {} energy)",
+                room,
+                role,
+                template,
+                body.len(),
+                body_cost(&body)
+            );
+        }
+        Err(e) => warn!("set_body: {}: {}", template, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use screeps::Part::{Carry as C, Move as M, Work as W};
+
+    #[test]
+    fn counted_parts() {
+        assert_eq!(parse("5W1C3M"), Ok(vec![W, W, W, W, W, C, M, M, M]));
+    }
+
+    #[test]
+    fn repeated_group() {
+        assert_eq!(parse("3[WCM]"), Ok(vec![W, C, M, W, C, M, W, C, M]));
+        assert_eq!(parse("2[ w c ] m"), Ok(vec![W, C, W, C, M]));
+    }
+
+    #[test]
+    fn unclosed_group() {
+        assert_eq!(parse("2[WC"), Err("unclosed [".to_owned()));
+    }
+
+    #[test]
+    fn zero_count() {
+        assert_eq!(parse("0W"), Err("empty group".to_owned()));
+        assert_eq!(parse("2[]"), Err("empty group".to_owned()));
+    }
+
+    #[test]
+    fn part_limit() {
+        assert_eq!(parse("50M").map(|b| b.len()), Ok(50));
+        let over = Err(format!("more than {} parts", MAX_PARTS));
+        assert_eq!(parse("51M"), over);
+        assert_eq!(parse("25[WM]1C"), over);
+        assert_eq!(parse("18446744073709551615[WCM]"), over);
+        assert!(parse("99999999999999999999999W").is_err());
+    }
+
+    #[test]
+    fn bad_templates() {
+        assert_eq!(parse("3X"), Err("unknown part X".to_owned()));
+        assert_eq!(parse("3"), Err("count 3 with no part after it".to_owned()));
+        assert_eq!(parse(""), Err("no parts".to_owned()));
+    }
+}
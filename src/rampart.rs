@@ -0,0 +1,35 @@
+use log::*;
+use screeps::{look, prelude::*, StructureRampart, StructureType};
+
+use crate::{error::BotError, intents};
+
+const CHECK_TICKS: u32 = 100;
+
+/// Keeps ramparts over roads, built or planned, closed. Our own creeps pass through our
+/// ramparts either way, so making one public only ever lets hostiles walk the road in.
+pub fn run_rampart(rampart: &StructureRampart) -> Result<(), BotError> {
+    if screeps::game::time() % CHECK_TICKS != 0 || !rampart.is_public() {
+        return Ok(());
+    }
+    let room = match rampart.room() {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+    // a road still being built counts, so it's closed before anyone relies on it
+    let pos = rampart.pos();
+    let on_road = room
+        .look_for_at(look::STRUCTURES, &pos)
+        .iter()
+        .any(|s| s.structure_type() == StructureType::Road)
+        || room
+            .look_for_at(look::CONSTRUCTION_SITES, &pos)
+            .iter()
+            .any(|s| s.structure_type() == StructureType::Road);
+    if on_road {
+        info!("{} closing public rampart on the road at {}", room.name(), pos);
+        intents::issue("rampart", "set_public", &pos.to_string(), None, || {
+            rampart.set_public(false)
+        });
+    }
+    Ok(())
+}
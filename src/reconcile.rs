@@ -0,0 +1,143 @@
+use log::*;
+use screeps::{prelude::*, ObjectId, Position, Room, Structure, StructureType};
+
+use crate::{memory, objects, planner, room::RoomMemory, room_cache, settings};
+
+/// How often each room's structures are checked against the plan.
+pub const RECONCILE_TICKS: u32 = 1500;
+/// Default for `Memory.settings.reconcile_min_bucket`.
+const MIN_BUCKET: u32 = 5000;
+/// Containers this close to the controller are upgrade buffers, as in `logistics`.
+const CONTROLLER_RANGE: u32 = 3;
+
+/// Everything the planner expects to see built: its source and controller buffer tiles, and
+/// a road on every tile of every planned route.
+fn expected_layout(room: &Room, mem: &RoomMemory) -> Vec<(Position, StructureType)> {
+    let mut expected = planner::planned_tiles(room, mem);
+    expected.extend(
+        mem.road_plans
+            .values()
+            .flatten()
+            .map(|p| (Position::from_packed(*p), StructureType::Road)),
+    );
+    expected
+}
+
+/// Whether `structure` is in the way of the plan: something else on an expected tile, a road
+/// off every planned route, a second container by a source or the controller, or a link by
+/// the controller that isn't its planned one. Ramparts and roads can share a container's tile,
+/// and ramparts a road's.
+fn conflicts(
+    room: &Room,
+    mem: &RoomMemory,
+    expected: &[(Position, StructureType)],
+    s: &Structure,
+) -> bool {
+    let ty = s.structure_type();
+    let pos = s.pos();
+    let wanted: Vec<StructureType> =
+        expected.iter().filter(|(p, _)| *p == pos).map(|(_, t)| *t).collect();
+    if !wanted.is_empty() {
+        let shares = match ty {
+            StructureType::Road => wanted.contains(&StructureType::Container),
+            StructureType::Rampart => wanted
+                .iter()
+                .any(|t| *t == StructureType::Container || *t == StructureType::Road),
+            _ => false,
+        };
+        return !wanted.contains(&ty) && !shares;
+    }
+    let by_controller = room
+        .controller()
+        .map_or(false, |c| pos.in_range_to(&c, CONTROLLER_RANGE));
+    match ty {
+        // until the routes are planned there's nothing to hold roads against
+        StructureType::Road => !mem.road_plans.is_empty(),
+        StructureType::Container => {
+            let by_source = room_cache::sources(room).iter().any(|src| pos.is_near_to(src));
+            by_source || (by_controller && mem.controller_container.is_some())
+        }
+        StructureType::Link => by_controller && mem.controller_link.is_some(),
+        _ => false,
+    }
+}
+
+/// Checks the room's structures against the plan, now and then while the bucket allows.
+/// Roads and containers in the way are queued for workers to dismantle; anything dearer waits
+/// in `demolish_pending` for `confirm_demolish`. Spawns, storage and terminals are never
+/// touched.
+pub fn run_reconcile(room: &Room, mem: &mut RoomMemory) {
+    let min = settings::u32_or("reconcile_min_bucket", MIN_BUCKET);
+    if (screeps::game::cpu::bucket() as u32) < min {
+        return;
+    }
+    let expected = expected_layout(room, mem);
+    mem.demolish.retain(|id| objects::get_cached(*id).is_some());
+    mem.demolish_pending.retain(|id| objects::get_cached(*id).is_some());
+    for s in room_cache::structures(room).iter() {
+        let ty = s.structure_type();
+        match ty {
+            StructureType::Spawn
+            | StructureType::Storage
+            | StructureType::Terminal
+            | StructureType::Controller => continue,
+            _ => {}
+        }
+        let mine = s.as_owned().map_or(true, |o| o.my());
+        if !mine || !conflicts(room, mem, &expected, s) {
+            continue;
+        }
+        let id: ObjectId<Structure> = s.id();
+        if mem.demolish.contains(&id) || mem.demolish_pending.contains(&id) {
+            continue;
+        }
+        match ty {
+            StructureType::Road | StructureType::Container => {
+                info!("{} dismantling {:?} at {}, not in the plan", room.name(), ty, s.pos());
+                mem.demolish.push(id);
+            }
+            _ => {
+                info!(
+                    "{} {:?} at {} isn't in the plan; confirm_demolish(\"{}\") to take it down",
+                    room.name(),
+                    ty,
+                    s.pos(),
+                    room.name()
+                );
+                mem.demolish_pending.push(id);
+            }
+        }
+    }
+}
+
+/// The structure queued for dismantling in `room` closest to `from`.
+pub fn dismantle_target(room: &Room, from: Position) -> Option<Structure> {
+    let mem = memory::get_room_memory(room.name()).ok()?;
+    mem.demolish
+        .iter()
+        .filter_map(|id| objects::get_cached(*id))
+        .min_by_key(|s| from.get_range_to(s))
+}
+
+/// Console command: `confirm_demolish("W1N1")` queues the structures the last check flagged for
+/// dismantling.
+pub fn confirm_demolish(room: String) -> String {
+    let name = match room.parse() {
+        Ok(n) => n,
+        Err(_) => return format!("{} isn't a room name", room),
+    };
+    let mut mem = match memory::get_room_memory(name) {
+        Ok(m) => m,
+        Err(e) => return format!("{}: {}", room, e),
+    };
+    if mem.demolish_pending.is_empty() {
+        return format!("nothing flagged in {}", room);
+    }
+    let count = mem.demolish_pending.len();
+    let pending = std::mem::take(&mut mem.demolish_pending);
+    mem.demolish.extend(pending);
+    match memory::set_room_memory(name, &mem) {
+        Ok(()) => format!("{} structures in {} queued for dismantling", count, room),
+        Err(e) => format!("{}: {}", room, e),
+    }
+}
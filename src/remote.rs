@@ -0,0 +1,626 @@
+use log::*;
+use screeps::{find, prelude::*, Creep, Part, Position, RoomName, StructureType, Terrain};
+use serde::{Deserialize, Serialize};
+use stdweb::{js_deserializable, js_serializable};
+
+use crate::{
+    accounts::{self, RemoteBooks},
+    creep, diplomacy,
+    error::{self, BotError},
+    intel, invaders, memory, planner, population,
+    role::{Role, HAULER_UNIT},
+    room_cache, route, settings,
+    spawn::SpawnRequest,
+    stats,
+};
+
+const CARRY_CAPACITY: u32 = 50;
+/// Ticks between source regenerations.
+const SOURCE_REGEN_TICKS: u32 = 300;
+const RESERVER_PRIORITY: u32 = 35;
+/// Added to the reserver's priority per creep working the remote.
+const PRIORITY_PER_WORKER: u32 = 5;
+/// Ticks to spawn one body part.
+const SPAWN_TICKS_PER_PART: u32 = 3;
+/// Travel estimate for remotes whose routes haven't been measured.
+const DEFAULT_TRAVEL: u32 = 100;
+/// Default for `Memory.settings.harassment_suspend_attacks`: player attacks within
+/// `intel::ATTACK_MEMORY_TICKS` that get a remote suspended.
+const HARASSMENT_SUSPEND_ATTACKS: u32 = 5;
+/// Energy to build a road on plain and swamp.
+const ROAD_COST: u32 = 300;
+const SWAMP_ROAD_COST: u32 = 1500;
+/// Energy to repair what a road on plain and swamp decays over 1000 ticks.
+const ROAD_UPKEEP: u32 = 1;
+const SWAMP_ROAD_UPKEEP: u32 = 5;
+/// Ticks a hauler lives, over which its body's cost is spread.
+const CREEP_LIFE_TICKS: u32 = 1500;
+/// Default for `Memory.settings.remote_road_payback`: roads are only built to a remote that
+/// pays back their cost within this many ticks.
+const ROAD_PAYBACK_TICKS: u32 = 30_000;
+/// Ticks between looks at whether a remote's roads pay.
+const ROAD_REVIEW_TICKS: u32 = 5000;
+const ROAD_BUILDER_PRIORITY: u32 = 25;
+/// Haulers come just behind the miners, who have to fill the containers first.
+const MINER_PRIORITY: u32 = 30;
+const HAULER_PRIORITY: u32 = 29;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RemoteSource {
+    /// Packed position of the container (or of the source until one exists).
+    pub pos: u32,
+    pub energy_capacity: u32,
+    #[serde(default)]
+    pub distance: Option<u32>,
+    #[serde(default)]
+    pub round_trip: Option<u32>,
+    /// Roads visible in the home and remote rooms when `round_trip` was measured.
+    #[serde(default)]
+    pub roads_seen: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HaulerPlan {
+    pub carry_parts: u32,
+    pub units_per_hauler: u32,
+    pub count: u32,
+}
+
+impl HaulerPlan {
+    pub fn body(&self) -> Vec<Part> {
+        HAULER_UNIT
+            .iter()
+            .cycle()
+            .take((self.units_per_hauler as usize) * HAULER_UNIT.len())
+            .cloned()
+            .collect()
+    }
+}
+
+/// Remote rooms mined from `home`.
+pub fn remotes_of(home: RoomName) -> Vec<RoomName> {
+    let remotes = match memory::remotes() {
+        Ok(r) => r,
+        Err(_) => return Vec::new(),
+    };
+    remotes
+        .keys()
+        .into_iter()
+        .filter(|key| {
+            remotes
+                .get::<RemoteOperation>(key)
+                .ok()
+                .flatten()
+                .map_or(false, |op| op.home == home)
+        })
+        .filter_map(|key| key.parse().ok())
+        .collect()
+}
+
+/// A remote mining operation, stored in `Memory.remotes` keyed by the remote room's name.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RemoteOperation {
+    pub home: RoomName,
+    #[serde(default)]
+    pub sources: Vec<RemoteSource>,
+    #[serde(default)]
+    pub haulers: HaulerPlan,
+    /// Tick our reservation ran out, while it's still lapsed.
+    #[serde(default)]
+    pub lapsed_since: Option<u32>,
+    /// Ticks spent unreserved over the operation's life.
+    #[serde(default)]
+    pub lapsed_ticks: u32,
+    #[serde(default)]
+    pub books: RemoteBooks,
+    /// Packed tiles of the approved road plan in the remote room; empty until roads pay.
+    #[serde(default)]
+    pub road_plan: Vec<u32>,
+    #[serde(default)]
+    pub roads_reviewed: Option<u32>,
+}
+
+js_serializable!(RemoteOperation);
+js_deserializable!(RemoteOperation);
+
+/// The operation mining `remote`, if there is one.
+pub fn operation(remote: RoomName) -> Result<Option<RemoteOperation>, BotError> {
+    memory::remotes()?
+        .get::<RemoteOperation>(&remote.to_string())
+        .map_err(|e| BotError::Deserialize {
+            target: "RemoteOperation",
+            source: e.to_string(),
+        })
+}
+
+/// The source a remote miner or hauler works, under `remote_source` in its memory: the one
+/// with the fewest creeps of its role, picked once and kept while the source is in the plan.
+pub fn post_of(creep: &Creep, remote: RoomName, op: &RemoteOperation) -> Option<RemoteSource> {
+    let post = |c: &Creep| {
+        let packed = memory::creep_memory(c).f64("remote_source").ok().flatten();
+        packed.map(|p| p as u32)
+    };
+    if let Some(source) = post(creep).and_then(|p| op.sources.iter().find(|s| s.pos == p)) {
+        return Some(source.clone());
+    }
+    let role = population::role_of(creep);
+    let others: Vec<u32> = creeps_in(remote)
+        .iter()
+        .filter(|c| c.name() != creep.name() && population::role_of(c) == role)
+        .filter_map(post)
+        .collect();
+    let source = op
+        .sources
+        .iter()
+        .min_by_key(|s| others.iter().filter(|p| **p == s.pos).count())?;
+    memory::creep_memory(creep).set("remote_source", source.pos);
+    Some(source.clone())
+}
+
+/// Carry parts needed to move `income` energy per tick over a route of `round_trip` ticks.
+fn carry_parts_needed(income: f64, round_trip: u32) -> u32 {
+    (income * round_trip as f64 / CARRY_CAPACITY as f64).ceil() as u32
+}
+
+fn plan_haulers(sources: &[RemoteSource], home_capacity: u32) -> HaulerPlan {
+    let carry_parts: u32 = sources
+        .iter()
+        .filter_map(|s| {
+            s.round_trip.map(|rtt| {
+                carry_parts_needed(s.energy_capacity as f64 / SOURCE_REGEN_TICKS as f64, rtt)
+            })
+        })
+        .sum();
+    let unit_cost: u32 = HAULER_UNIT.iter().map(|p| p.cost()).sum();
+    let max_units = (50 / HAULER_UNIT.len() as u32).min(home_capacity / unit_cost).max(1);
+    let carry_per_unit = HAULER_UNIT.iter().filter(|p| **p == Part::Carry).count() as u32;
+    let units_needed = (carry_parts + carry_per_unit - 1) / carry_per_unit;
+    let count = (units_needed + max_units - 1) / max_units;
+    let units_per_hauler = if count == 0 {
+        0
+    } else {
+        (units_needed + count - 1) / count
+    };
+    HaulerPlan {
+        carry_parts,
+        units_per_hauler,
+        count,
+    }
+}
+
+/// Records sources (and their containers) for remote rooms we currently have vision of.
+fn discover_sources(op: &mut RemoteOperation, remote: RoomName) {
+    if !op.sources.is_empty() {
+        return;
+    }
+    let room = match screeps::game::rooms::get(remote) {
+        Some(r) => r,
+        None => return,
+    };
+    let containers: Vec<Position> = room
+        .find(find::STRUCTURES)
+        .into_iter()
+        .filter(|s| s.structure_type() == StructureType::Container)
+        .map(|s| s.pos())
+        .collect();
+    for source in room.find(find::SOURCES) {
+        let pos = containers
+            .iter()
+            .find(|c| c.is_near_to(&source))
+            .cloned()
+            .unwrap_or_else(|| source.pos());
+        op.sources.push(RemoteSource {
+            pos: pos.packed_repr(),
+            energy_capacity: source.energy_capacity(),
+            distance: None,
+            round_trip: None,
+            roads_seen: 0,
+        });
+    }
+}
+
+fn home_anchor(home: RoomName) -> Option<Position> {
+    let room = screeps::game::rooms::get(home)?;
+    if let Some(storage) = room.storage() {
+        return Some(storage.pos());
+    }
+    room.find(find::MY_SPAWNS).into_iter().next().map(|s| s.pos())
+}
+
+pub fn run_remote(remote: RoomName, op: &mut RemoteOperation) -> Result<(), BotError> {
+    discover_sources(op, remote);
+
+    let anchor = home_anchor(op.home).ok_or(BotError::MissingRoomObject {
+        what: "remote home storage or spawn",
+    })?;
+    let roads = route::road_count(&[op.home, remote]);
+
+    let mut changed = false;
+    for source in op.sources.iter_mut() {
+        if source.round_trip.is_some() && source.roads_seen == roads {
+            continue;
+        }
+        let target = Position::from_packed(source.pos);
+        match route::measure(anchor, target, 1) {
+            Some(m) => {
+                debug!(
+                    "remote {}: route to {} is {} tiles, round trip {} ticks",
+                    remote,
+                    target,
+                    m.tiles,
+                    m.round_trip_ticks()
+                );
+                source.distance = Some(m.tiles);
+                source.round_trip = Some(m.round_trip_ticks());
+                source.roads_seen = roads;
+                changed = true;
+            }
+            None => warn!("remote {}: no complete route to {}", remote, target),
+        }
+    }
+
+    if changed {
+        let home_capacity = screeps::game::rooms::get(op.home)
+            .map(|r| r.energy_capacity_available())
+            .unwrap_or(300);
+        let plan = plan_haulers(&op.sources, home_capacity);
+        if plan != op.haulers {
+            info!(
+                "remote {}: {} carry parts needed, planning {} haulers of {} parts",
+                remote,
+                plan.carry_parts,
+                plan.count,
+                plan.body().len()
+            );
+            op.haulers = plan;
+        }
+    }
+
+    staff(remote, op)?;
+    review_roads(remote, op, anchor);
+    build_roads(remote, op)
+}
+
+/// Queues a miner per source and the planned haulers in `op.home`, counting the creeps already
+/// working the remote and those queued for it.
+fn staff(remote: RoomName, op: &RemoteOperation) -> Result<(), BotError> {
+    let creeps = creeps_in(remote);
+    let mut home = memory::get_room_memory(op.home)?;
+    let target = remote.to_string();
+    let unit_cost: u32 = HAULER_UNIT.iter().map(|p| p.cost()).sum();
+    let wanted = [
+        (Role::RemoteMiner, op.sources.len() as u32, MINER_PRIORITY, None),
+        (
+            Role::RemoteHauler,
+            op.haulers.count,
+            HAULER_PRIORITY,
+            Some(op.haulers.units_per_hauler * unit_cost),
+        ),
+    ];
+    let mut queued = false;
+    for (role, count, priority, budget) in wanted.iter().cloned() {
+        let living = creeps.iter().filter(|c| population::role_of(c) == role).count();
+        let waiting = home
+            .spawn_queue
+            .iter()
+            .filter(|r| r.role == role && r.target_room.as_deref() == Some(target.as_str()))
+            .count();
+        for slot in (living + waiting) as u32..count {
+            debug!("{} queueing {:?} for remote {}", op.home, role, remote);
+            home.enqueue(SpawnRequest {
+                enqueued: screeps::game::time(),
+                dedupe: Some(format!("{:?}-{}-{}", role, remote, slot)),
+                target_room: Some(target.clone()),
+                budget,
+                ..SpawnRequest::new(role, priority)
+            });
+            queued = true;
+        }
+    }
+    if queued {
+        memory::set_room_memory(op.home, &home)?;
+    }
+    Ok(())
+}
+
+/// What roading a remote's hauling routes would cost and save, in energy per 1000 ticks.
+struct RoadEstimate {
+    build: u32,
+    upkeep: u32,
+    saved: u32,
+    tiles: Vec<u32>,
+}
+
+impl RoadEstimate {
+    /// Ticks until the savings cover the build, if they ever outrun the upkeep.
+    fn payback(&self) -> Option<u32> {
+        if self.saved <= self.upkeep {
+            return None;
+        }
+        Some(self.build * 1000 / (self.saved - self.upkeep))
+    }
+}
+
+/// Prices roads from `anchor` to each of the remote's containers. Roads halve a loaded
+/// hauler's time per tile and let an empty one keep the same pace, so the routes take fewer
+/// hauler units (and their Move parts) to move what the haulers have actually been bringing
+/// home, split between the sources by their capacity. Tiles outside the remote are left to
+/// the home room's own route planner.
+fn estimate_roads(
+    remote: RoomName,
+    op: &RemoteOperation,
+    anchor: Position,
+    hauled: f64,
+) -> RoadEstimate {
+    let terrain = screeps::game::map::get_room_terrain(remote);
+    let room = screeps::game::rooms::get(remote);
+    let roaded = |p: &Position| {
+        room.as_ref().map_or(false, |r| {
+            room_cache::structures(r)
+                .iter()
+                .any(|s| s.structure_type() == StructureType::Road && s.pos() == *p)
+        })
+    };
+    let unit_cost: u32 = HAULER_UNIT.iter().map(|p| p.cost()).sum();
+    let carry_per_unit = HAULER_UNIT.iter().filter(|p| **p == Part::Carry).count() as u32;
+    let capacity: u32 = op.sources.iter().map(|s| s.energy_capacity).sum();
+    let mut estimate = RoadEstimate {
+        build: 0,
+        upkeep: 0,
+        saved: 0,
+        tiles: Vec::new(),
+    };
+    for source in &op.sources {
+        let rtt = match source.round_trip {
+            Some(r) => r,
+            None => continue,
+        };
+        let path = route::path(anchor, Position::from_packed(source.pos), 1);
+        if path.is_empty() {
+            continue;
+        }
+        for p in path.iter().filter(|p| p.room_name() == remote) {
+            if roaded(p) || estimate.tiles.contains(&p.packed_repr()) {
+                continue;
+            }
+            let swamp = terrain.get(p.x(), p.y()) == Terrain::Swamp;
+            estimate.build += if swamp { SWAMP_ROAD_COST } else { ROAD_COST };
+            estimate.upkeep += if swamp { SWAMP_ROAD_UPKEEP } else { ROAD_UPKEEP };
+            estimate.tiles.push(p.packed_repr());
+        }
+        let income = hauled * source.energy_capacity as f64 / capacity.max(1) as f64;
+        let saved_carry = carry_parts_needed(income, rtt)
+            .saturating_sub(carry_parts_needed(income, 2 * path.len() as u32));
+        estimate.saved += saved_carry * unit_cost * 1000 / carry_per_unit / CREEP_LIFE_TICKS;
+    }
+    estimate
+}
+
+/// Approves the remote's road plan once its payback is within `remote_road_payback` ticks,
+/// looking again every `ROAD_REVIEW_TICKS` as the routes and their roads change. Nothing is
+/// approved until the books have measured some hauling to price the roads against.
+fn review_roads(remote: RoomName, op: &mut RemoteOperation, anchor: Position) {
+    let now = screeps::game::time();
+    if op.roads_reviewed.map_or(false, |t| now - t < ROAD_REVIEW_TICKS)
+        || op.sources.iter().any(|s| s.round_trip.is_none())
+    {
+        return;
+    }
+    let hauled = match op.books.income_rate() {
+        Some(r) if r > 0.0 => r,
+        _ => return,
+    };
+    op.roads_reviewed = Some(now);
+    let estimate = estimate_roads(remote, op, anchor, hauled);
+    if estimate.tiles.is_empty() {
+        return;
+    }
+    let horizon = settings::u32_or("remote_road_payback", ROAD_PAYBACK_TICKS);
+    let payback = estimate.payback();
+    let approved = payback.map_or(false, |p| p <= horizon);
+    info!(
+        "remote {}: {} road tiles cost {} to build and {} per 1000 ticks to keep up, save {} \
+         per 1000 ticks of hauling; payback {} against {} ticks, {}",
+        remote,
+        estimate.tiles.len(),
+        estimate.build,
+        estimate.upkeep,
+        estimate.saved,
+        payback.map_or_else(|| "never".to_owned(), |p| p.to_string()),
+        horizon,
+        if approved { "building" } else { "not building" }
+    );
+    op.road_plan = if approved { estimate.tiles } else { Vec::new() };
+}
+
+/// Keeps sites down along the approved road plan, through the remote's own `road_plans` so its
+/// roads are repaired as a critical route, and sends a pioneer out from home to build them.
+/// The plan is dropped while no remote hauler is alive to use the roads.
+fn build_roads(remote: RoomName, op: &RemoteOperation) -> Result<(), BotError> {
+    let room = match screeps::game::rooms::get(remote) {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+    let mut mem = memory::get_room_memory(remote)?;
+    let plan = format!("haul:{}", op.home);
+    let creeps = creeps_in(remote);
+    let hauling = creeps
+        .iter()
+        .any(|c| population::role_of(c) == Role::RemoteHauler);
+    if op.road_plan.is_empty() || !hauling {
+        if mem.road_plans.remove(&plan).is_some() {
+            memory::set_room_memory(remote, &mem)?;
+        }
+        return Ok(());
+    }
+    mem.road_plans.insert(plan, op.road_plan.clone());
+    let placed = planner::ensure_remote_roads(&room, &mut mem);
+    memory::set_room_memory(remote, &mem)?;
+    placed?;
+
+    let sites = room_cache::construction_sites(&room).len();
+    let building = creeps.iter().any(|c| population::role_of(c) == Role::Pioneer);
+    if sites == 0 || building {
+        return Ok(());
+    }
+    let mut home = memory::get_room_memory(op.home)?;
+    home.enqueue(SpawnRequest {
+        enqueued: screeps::game::time(),
+        dedupe: Some(format!("RoadBuilder-{}", remote)),
+        target_room: Some(remote.to_string()),
+        ..SpawnRequest::new(Role::Pioneer, ROAD_BUILDER_PRIORITY)
+    });
+    memory::set_room_memory(op.home, &home)
+}
+
+/// Creeps of any role with `remote` as their target room. A reserver counts itself.
+fn creeps_in(remote: RoomName) -> Vec<Creep> {
+    let target = remote.to_string();
+    screeps::game::creeps::values()
+        .into_iter()
+        .filter(|c| memory::creep_target(c).as_deref() == Some(&target))
+        .collect()
+}
+
+/// Queues a reserver in `op.home` once the reservation has less left than it takes to spawn a
+/// reserver and walk it over, more urgently the more creeps work the remote. Lapses are
+/// warned about, with the gap, once the room is reserved again.
+fn keep_reserved(remote: RoomName, op: &mut RemoteOperation) -> Result<(), BotError> {
+    let info = match intel::record_reservation(remote)? {
+        Some(i) if i.has_controller => i,
+        _ => return Ok(()),
+    };
+    let now = screeps::game::time();
+    let me = intel::my_username();
+    let ours = info.reserved_by.as_deref() == Some(me.as_str());
+    let left = if ours {
+        info.reservation_ends.map_or(0, |end| end.saturating_sub(now))
+    } else {
+        0
+    };
+    match (left, op.lapsed_since) {
+        (0, None) => op.lapsed_since = Some(now),
+        (l, Some(since)) if l > 0 => {
+            let gap = now - since;
+            warn!("{} reservation lapsed for {} ticks before it was renewed", remote, gap);
+            op.lapsed_ticks += gap;
+            stats::increment_room("remotes", Some(remote), "unreserved_ticks", gap as i32);
+            op.lapsed_since = None;
+        }
+        _ => {}
+    }
+
+    let creeps = creeps_in(remote);
+    if creeps.iter().any(|c| population::role_of(c) == Role::Reserver) {
+        return Ok(());
+    }
+    let capacity = screeps::game::rooms::get(op.home)
+        .map(|r| r.energy_capacity_available())
+        .unwrap_or(0);
+    let spawn_ticks = Role::Reserver.body(capacity, capacity).len() as u32 * SPAWN_TICKS_PER_PART;
+    let travel = op.sources.iter().filter_map(|s| s.distance).min().unwrap_or(DEFAULT_TRAVEL);
+    if left >= travel + spawn_ticks {
+        return Ok(());
+    }
+    let priority = RESERVER_PRIORITY + PRIORITY_PER_WORKER * creeps.len() as u32;
+    let mut home = memory::get_room_memory(op.home)?;
+    home.enqueue(SpawnRequest {
+        enqueued: now,
+        dedupe: Some(format!("Reserver-{}", remote)),
+        target_room: Some(remote.to_string()),
+        ..SpawnRequest::new(Role::Reserver, priority)
+    });
+    memory::set_room_memory(op.home, &home)
+}
+
+/// Takes every queued request for `remote` off its home's spawn queue.
+fn drop_queued(remote: RoomName, home: RoomName) {
+    let target = remote.to_string();
+    let res = memory::get_room_memory(home).and_then(|mut mem| {
+        let before = mem.spawn_queue.len();
+        mem.spawn_queue.retain(|r| r.target_room.as_deref() != Some(target.as_str()));
+        if mem.spawn_queue.len() == before {
+            return Ok(());
+        }
+        memory::set_room_memory(home, &mem)
+    });
+    if let Err(e) = res {
+        error::report("remote suspension", &target, &home.to_string(), &e);
+    }
+}
+
+/// Stops spawning for `remote` and sends the creeps working it home.
+fn tear_down(remote: RoomName, home: RoomName, why: &str) {
+    drop_queued(remote, home);
+    for c in creeps_in(remote) {
+        creep::send_home(&c, why);
+    }
+}
+
+/// Records player attacks in every remote we can see this tick.
+pub fn watch_remotes() -> Result<(), BotError> {
+    for key in memory::remotes()?.keys() {
+        let room = key.parse().ok().and_then(screeps::game::rooms::get);
+        if let Some(room) = room {
+            intel::record_attackers(&room)?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs every remote operation that isn't suspended for losing energy or for being attacked
+/// too often.
+pub fn run_remotes() -> Result<(), BotError> {
+    let remotes = memory::remotes()?;
+    for key in remotes.keys() {
+        let remote: RoomName = match key.parse() {
+            Ok(r) => r,
+            Err(_) => {
+                warn!("ignoring Memory.remotes entry with invalid room name {}", key);
+                continue;
+            }
+        };
+        let mut op = match remotes.get::<RemoteOperation>(&key) {
+            Ok(Some(op)) => op,
+            Ok(None) => continue,
+            Err(e) => {
+                let err = BotError::Deserialize {
+                    target: "RemoteOperation",
+                    source: e.to_string(),
+                };
+                error::report("remote", &key, "-", &err);
+                continue;
+            }
+        };
+        // a neighbour's reservation or sign is a claim we keep out of
+        let info = intel::get(remote).ok().flatten();
+        if let Some(player) = info.as_ref().and_then(diplomacy::respected_claim) {
+            warn!("remote {} is claimed by {}, not mining it", remote, player);
+            tear_down(remote, op.home, "its remote is claimed");
+            continue;
+        }
+        accounts::review(remote, &mut op);
+        let attacks = info.as_ref().map_or(0, |i| intel::recent_attacks(i).len() as u32);
+        let limit = settings::u32_or("harassment_suspend_attacks", HARASSMENT_SUSPEND_ATTACKS);
+        if attacks >= limit && !op.books.suspended() {
+            let summary = info.as_ref().and_then(intel::harassment_summary).unwrap_or_default();
+            accounts::suspend(remote, &mut op.books, format!("harassed, {}", summary));
+        }
+        if op.books.suspended() {
+            tear_down(remote, op.home, "its remote is suspended");
+            remotes.set(&key, &op);
+            continue;
+        }
+        if let Err(e) = run_remote(remote, &mut op) {
+            error::report("remote", &key, &op.home.to_string(), &e);
+        }
+        if let Err(e) = invaders::guard_remote(remote, op.home) {
+            error::report("remote defense", &key, &op.home.to_string(), &e);
+        }
+        if let Err(e) = keep_reserved(remote, &mut op) {
+            error::report("remote reservation", &key, &op.home.to_string(), &e);
+        }
+        remotes.set(&key, &op);
+    }
+    Ok(())
+}
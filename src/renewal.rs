@@ -0,0 +1,125 @@
+use log::*;
+use screeps::{prelude::*, Creep, Part, Position, ReturnCode, Room, StructureSpawn};
+
+use crate::{
+    error::BotError, intents, memory, population, room::RoomMemory, room_cache, settings,
+    spawn, stats,
+};
+
+/// Creeps are taken in for renewal below this many ticks to live...
+const ADMIT_TTL: u32 = 600;
+/// ...and let go once renewed to this.
+const RELEASE_TTL: u32 = 1400;
+/// Default for `Memory.settings.renew_min_energy`: percent of capacity the room must hold for
+/// a spawn to spend on renewing.
+const MIN_ENERGY_PERCENT: u32 = 80;
+/// Default for `Memory.settings.renew_min_body_cost`: cheaper bodies are just spawned again.
+const MIN_BODY_COST: u32 = 1200;
+/// Queued requests aged to this priority take the spawn back from a renewing creep.
+const EVICT_PRIORITY: u32 = 30;
+
+/// Whether `creep` is worth renewing: a role that stays at home, no Claim parts (the game
+/// won't renew those), and a dear body or boosts. Renewing drops the boosts.
+pub fn renewable(creep: &Creep) -> bool {
+    if !population::role_of(creep).renewable() {
+        return false;
+    }
+    let body = creep.body();
+    if body.iter().any(|p| p.part == Part::Claim) {
+        return false;
+    }
+    let parts: Vec<Part> = body.iter().map(|p| p.part).collect();
+    let min = settings::u32_or("renew_min_body_cost", MIN_BODY_COST);
+    body.iter().any(|p| p.boost.is_some()) || spawn::body_cost(&parts) >= min
+}
+
+/// The renewal tile of the spawn named `spawn`.
+pub fn spot(mem: &RoomMemory, spawn: &str) -> Option<Position> {
+    mem.renew_spots.get(spawn).map(|p| Position::from_packed(*p))
+}
+
+/// Whether `pos` is one of the room's renewal tiles, which nobody waits on.
+pub fn is_spot(mem: &RoomMemory, pos: Position) -> bool {
+    mem.renew_spots.values().any(|p| *p == pos.packed_repr())
+}
+
+/// The renewal tile `creep` has been let onto, if any.
+pub fn assigned_spot(creep: &Creep, room: &Room) -> Result<Option<Position>, BotError> {
+    let mem = memory::get_room_memory(room.name())?;
+    let name = creep.name();
+    Ok(mem
+        .renewing
+        .iter()
+        .find(|(_, c)| **c == name)
+        .and_then(|(spawn, _)| spot(&mem, spawn)))
+}
+
+/// The room's creep most in need of renewal that isn't already on a spot.
+fn candidate(room: &Room, mem: &RoomMemory) -> Option<Creep> {
+    room_cache::my_creeps(room)
+        .iter()
+        .filter(|c| !c.spawning() && c.ticks_to_live() < ADMIT_TTL)
+        .filter(|c| memory::creep_home(c) == Some(room.name()))
+        .filter(|c| !mem.renewing.values().any(|n| *n == c.name()))
+        .filter(|c| renewable(c))
+        .min_by_key(|c| c.ticks_to_live())
+        .cloned()
+}
+
+/// Runs each spawn's renewal station after the spawn queue has had its turn; `idle` are the
+/// spawns left with nothing to do this tick. A station calls in one creep at a time while its
+/// spawn is idle, nothing queued is urgent and the room holds enough energy, and renews it
+/// until `RELEASE_TTL`. A spawn that's needed again lets its creep go straight away.
+pub fn run_renewal(room: &Room, mem: &mut RoomMemory, idle: &[StructureSpawn]) {
+    let now = screeps::game::time();
+    let urgent = mem
+        .spawn_queue
+        .iter()
+        .any(|r| r.aged_priority(now) >= EVICT_PRIORITY);
+    let percent = settings::u32_or("renew_min_energy", MIN_ENERGY_PERCENT);
+    let funded = room.energy_available() * 100 >= room.energy_capacity_available() * percent;
+
+    for spawn in room_cache::my_spawns(room).iter() {
+        let name = spawn.name();
+        let at = match spot(mem, &name) {
+            Some(p) => p,
+            None => continue,
+        };
+        let open = !urgent && idle.iter().any(|s| s.name() == name);
+        let current = mem
+            .renewing
+            .get(&name)
+            .and_then(|c| screeps::game::creeps::get(c));
+        let creep = match current {
+            Some(c) if open && c.ticks_to_live() < RELEASE_TTL => c,
+            Some(c) => {
+                debug!("{} letting {} go at {} ticks", name, c.name(), c.ticks_to_live());
+                mem.renewing.remove(&name);
+                continue;
+            }
+            None => {
+                mem.renewing.remove(&name);
+                if open && funded {
+                    if let Some(c) = candidate(room, mem) {
+                        debug!("{} calling {} in for renewal", name, c.name());
+                        mem.renewing.insert(name, c.name());
+                    }
+                }
+                continue;
+            }
+        };
+        if creep.pos() != at {
+            continue;
+        }
+        let r = intents::issue("spawn", "renew_creep", &name, None, || {
+            spawn.renew_creep(&creep)
+        });
+        match r {
+            ReturnCode::Ok => stats::increment_room("spawn", Some(room.name()), "renews", 1),
+            r => {
+                debug!("{} couldn't renew {}: {:?}", name, creep.name(), r);
+                mem.renewing.remove(&name);
+            }
+        }
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::HashSet;
+
+use log::*;
+use screeps::{find, prelude::*, Position, Room, Structure, StructureType, Terrain};
+
+use crate::{memory, traffic};
+
+/// Hits a creep repairs per energy spent.
+pub const CREEP_HITS_PER_ENERGY: u32 = 100;
+/// Decaying structures are only considered once they're below this fraction of their hits.
+const REPAIR_BELOW_PERCENT: u32 = 50;
+/// Roads on a critical route are kept above this percent and go before any other road...
+const CRITICAL_ROAD_PERCENT: u32 = 60;
+/// ...the rest are repaired below this...
+const ROAD_PERCENT: u32 = 30;
+/// ...unless they've already decayed below this, when they're left to die.
+const ABANDONED_ROAD_PERCENT: u32 = 10;
+/// Keeping a structure means paying for its decay over this long, against rebuilding it.
+const UPKEEP_TICKS: u32 = 10_000;
+
+/// What it costs to put the structure back up from a fresh site, and the hits it decays over
+/// `UPKEEP_TICKS`. Roads cost and decay more on swamps and walls; containers decay five
+/// times faster outside owned rooms.
+fn rebuild_and_decay(structure: &Structure) -> Option<(u32, u32)> {
+    let pos = structure.pos();
+    match structure.structure_type() {
+        StructureType::Road => {
+            let ratio = match screeps::game::map::get_room_terrain(pos.room_name())
+                .get(pos.x(), pos.y())
+            {
+                Terrain::Swamp => 5,
+                Terrain::Wall => 150,
+                Terrain::Plain => 1,
+            };
+            Some((300 * ratio, 100 * ratio * UPKEEP_TICKS / 1000))
+        }
+        StructureType::Container => {
+            let owned = structure
+                .room()
+                .and_then(|r| r.controller())
+                .map_or(false, |c| c.my());
+            let ticks = if owned { 500 } else { 100 };
+            Some((5000, 5000 * UPKEEP_TICKS / ticks))
+        }
+        _ => None,
+    }
+}
+
+/// Whether repairing `missing` hits and paying for `decay` more over `UPKEEP_TICKS` costs no
+/// more than a `rebuild`. Creep repairs are cheap enough that this only rejects structures
+/// that decay fast, such as containers in remotes.
+fn cheaper_to_keep(missing: u32, decay: u32, rebuild: u32, hits_per_energy: u32) -> bool {
+    (missing + decay) / hits_per_energy.max(1) <= rebuild
+}
+
+/// Whether used tiles depend on it: a road creeps keep walking over, or a container next to
+/// one.
+fn load_bearing(structure: &Structure) -> bool {
+    let pos = structure.pos();
+    match structure.structure_type() {
+        StructureType::Container => (-1..=1).any(|dy| {
+            (-1..=1).any(|dx| {
+                let (x, y) = (pos.x() as i32 + dx, pos.y() as i32 + dy);
+                (0..50).contains(&x)
+                    && (0..50).contains(&y)
+                    && traffic::on_route(Position::new(x as u32, y as u32, pos.room_name()))
+            })
+        }),
+        _ => traffic::on_route(pos),
+    }
+}
+
+/// Whether topping `structure` up at `hits_per_energy` is worth it. A decaying structure that
+/// costs more to repair and keep up than to rebuild is left to die unless it's load-bearing;
+/// structures that can't simply be rebuilt are always worth it.
+pub fn worth_repairing(structure: &Structure, hits_per_energy: u32) -> bool {
+    let (hits, max) = match structure.as_attackable() {
+        Some(a) => (a.hits(), a.hits_max()),
+        None => return false,
+    };
+    let (rebuild, decay) = match rebuild_and_decay(structure) {
+        Some(r) => r,
+        None => return true,
+    };
+    if cheaper_to_keep(max - hits, decay, rebuild, hits_per_energy) || load_bearing(structure) {
+        return true;
+    }
+    debug!(
+        "not repairing {:?} at {}: {} energy to repair and keep up, {} to rebuild, off route",
+        structure.structure_type(),
+        structure.pos(),
+        (max - hits + decay) / hits_per_energy.max(1),
+        rebuild
+    );
+    false
+}
+
+/// The closest of our roads and containers due a creep's energy. Roads on the room's
+/// critical routes come first, from `CRITICAL_ROAD_PERCENT` down; other roads are only
+/// repaired between `ABANDONED_ROAD_PERCENT` and `ROAD_PERCENT`, and containers below half,
+/// when they're worth it.
+pub fn creep_target(room: &Room, from: Position) -> Option<Structure> {
+    let critical: HashSet<u32> = memory::get_room_memory(room.name())
+        .map(|m| m.road_plans.values().flatten().cloned().collect())
+        .unwrap_or_default();
+    room.find(find::STRUCTURES)
+        .into_iter()
+        .filter_map(|s| {
+            let (hits, max) = s.as_attackable().map(|a| (a.hits() * 100, a.hits_max()))?;
+            let below = |percent: u32| hits < max * percent;
+            let first = match s.structure_type() {
+                StructureType::Road if critical.contains(&s.pos().packed_repr()) => {
+                    if !below(CRITICAL_ROAD_PERCENT) {
+                        return None;
+                    }
+                    true
+                }
+                StructureType::Road => {
+                    if !below(ROAD_PERCENT) || below(ABANDONED_ROAD_PERCENT) {
+                        return None;
+                    }
+                    false
+                }
+                StructureType::Container if below(REPAIR_BELOW_PERCENT) => false,
+                _ => return None,
+            };
+            if !first && !worth_repairing(&s, CREEP_HITS_PER_ENERGY) {
+                return None;
+            }
+            Some((!first, from.get_range_to(&s), s))
+        })
+        .min_by_key(|(later, range, _)| (*later, *range))
+        .map(|(_, _, s)| s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roads_are_always_cheaper_to_keep() {
+        // a plain road at 1 hit, and a swamp road at 1% of its hits
+        assert!(cheaper_to_keep(4_999, 1_000, 300, CREEP_HITS_PER_ENERGY));
+        assert!(cheaper_to_keep(24_750, 5_000, 1_500, CREEP_HITS_PER_ENERGY));
+    }
+
+    #[test]
+    fn remote_containers_are_cheaper_to_rebuild() {
+        // decaying 5000 hits every 100 ticks outside an owned room
+        assert!(!cheaper_to_keep(2_500, 500_000, 5_000, CREEP_HITS_PER_ENERGY));
+        // and every 500 inside one
+        assert!(cheaper_to_keep(2_500, 100_000, 5_000, CREEP_HITS_PER_ENERGY));
+    }
+}
@@ -0,0 +1,348 @@
+use screeps::{Bodypart, Part};
+use serde::{Deserialize, Serialize};
+use stdweb::{js_deserializable, js_serializable};
+
+const WORKER_UNIT: [Part; 4] = [Part::Move, Part::Move, Part::Carry, Part::Work];
+const UPGRADER_UNIT: [Part; 4] = [Part::Work, Part::Work, Part::Carry, Part::Move];
+pub const MAX_PARTS: usize = 50;
+const DEFENDER_UNIT: [Part; 2] = [Part::Attack, Part::Move];
+const DEPOSIT_HARVESTER_UNIT: [Part; 4] = [Part::Work, Part::Work, Part::Carry, Part::Move];
+const DEPOSIT_HAULER_UNIT: [Part; 2] = [Part::Carry, Part::Move];
+const PIONEER_UNIT: [Part; 4] = [Part::Work, Part::Carry, Part::Move, Part::Move];
+const RESERVER_UNIT: [Part; 2] = [Part::Claim, Part::Move];
+/// Two Claim parts out-reserve the decay, so bigger reservers buy nothing.
+const RESERVER_UNITS: u32 = 2;
+/// Five Work parts empty a source exactly as it regenerates.
+const HARVESTER_WORK: usize = 5;
+const REMOTE_MINER_UNIT: [Part; 3] = [Part::Work, Part::Work, Part::Move];
+/// Three units' six Work parts keep up with a reserved source and make up for the walk in.
+const REMOTE_MINER_UNITS: u32 = 3;
+/// Share of a body, as `(numerator, denominator)`, that Carry must make up for a body without
+/// Work to be taken for a hauler.
+const HAULER_CARRY_SHARE: (usize, usize) = (1, 2);
+/// Remote haulers are built from this unit, as many as `remote::HaulerPlan` asks for.
+pub const HAULER_UNIT: [Part; 3] = [Part::Carry, Part::Carry, Part::Move];
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Role {
+    Worker,
+    /// Upgrades from the controller container or link, never walking to storage or sources.
+    Upgrader,
+    /// Sits on a fixed tile next to a source and harvests into its container, handing its load
+    /// to a worker collecting next to it first.
+    Harvester,
+    /// Guards a room it's sent to, usually a remote that is due for invaders.
+    Defender,
+    /// Works a highway deposit and hands the output to a `DepositHauler`.
+    DepositHarvester,
+    DepositHauler,
+    /// Builds the first spawn in a claimed room, then becomes one of its workers.
+    Pioneer,
+    /// Keeps a remote room's controller reserved.
+    Reserver,
+    /// A single Move part that walks from room to room so intel stays fresh.
+    Scout,
+    /// Drop-mines a remote source into its container, or onto the ground until there is one.
+    RemoteMiner,
+    /// Carries a remote source's energy home.
+    RemoteHauler,
+}
+
+js_serializable!(Role);
+js_deserializable!(Role);
+
+/// Repeats `unit` as many times as `energy` and the part limit allow.
+pub fn repeat_unit(unit: &[Part], energy: u32) -> Vec<Part> {
+    let unit_cost: u32 = unit.iter().map(|p| p.cost()).sum();
+    let units = ((energy / unit_cost) as usize).min(MAX_PARTS / unit.len());
+    unit.iter()
+        .cycle()
+        .take(units * unit.len())
+        .cloned()
+        .collect()
+}
+
+/// What a damaged creep should do about its body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BodyVerdict {
+    Intact,
+    /// A critical part is gone but the room can heal it back; wait by a tower.
+    Retreat,
+    /// A critical part is gone for good; the energy is worth more recycled.
+    Recycle,
+    /// A critical part is gone and so is every Move; wait where it stands for a tower to heal.
+    StayPut,
+    /// Broken, immobile and out of reach of healing; it only takes up a tile.
+    Suicide,
+}
+
+/// Whether `role` can still work with `body`, counting only parts with hits left. A broken
+/// creep without a working Move part can't walk to a tower or a spawn, so it waits or dies
+/// where it is.
+pub fn body_verdict(role: Role, body: &[Bodypart], can_heal: bool) -> BodyVerdict {
+    let working = |part: Part| body.iter().any(|b| b.part == part && b.hits > 0);
+    let broken = role.critical_parts().iter().any(|part| !working(*part));
+    match (broken, can_heal, working(Part::Move)) {
+        (false, _, _) => BodyVerdict::Intact,
+        (true, true, true) => BodyVerdict::Retreat,
+        (true, true, false) => BodyVerdict::StayPut,
+        (true, false, true) => BodyVerdict::Recycle,
+        (true, false, false) => BodyVerdict::Suicide,
+    }
+}
+
+impl Role {
+    pub const ALL: [Role; 11] = [
+        Role::Worker,
+        Role::Upgrader,
+        Role::Harvester,
+        Role::Defender,
+        Role::DepositHarvester,
+        Role::DepositHauler,
+        Role::Pioneer,
+        Role::Reserver,
+        Role::Scout,
+        Role::RemoteMiner,
+        Role::RemoteHauler,
+    ];
+
+    /// Best guess at the role of a creep whose memory we can't read: anything with Attack is a
+    /// defender, anything with Claim a reserver, nothing but Move a scout, and a body at least
+    /// `HAULER_CARRY_SHARE` Carry with no Work a hauler. Work without Carry, or Work-heavy on a
+    /// single Move since it parks, is a harvester; other Work-heavy bodies are upgraders, and
+    /// anything else is a general worker. Roles sharing a body with one of these (deposit
+    /// harvesters, deposit haulers, remote miners) come out as that one.
+    pub fn infer_from_body(body: &[Bodypart]) -> Role {
+        let count = |part| body.iter().filter(|b| b.part == part).count();
+        let (work, carry) = (count(Part::Work), count(Part::Carry));
+        if count(Part::Attack) > 0 {
+            Role::Defender
+        } else if count(Part::Claim) > 0 {
+            Role::Reserver
+        } else if count(Part::Move) == body.len() {
+            Role::Scout
+        } else if work == 0 && carry * HAULER_CARRY_SHARE.1 >= body.len() * HAULER_CARRY_SHARE.0 {
+            Role::RemoteHauler
+        } else if work > 0 && (carry == 0 || (work >= 2 * carry && count(Part::Move) == 1)) {
+            Role::Harvester
+        } else if work >= 2 * carry.max(1) {
+            Role::Upgrader
+        } else {
+            Role::Worker
+        }
+    }
+
+    /// The biggest body for `energy`, clamped to `capacity` so no design ever costs more than
+    /// the room can hold. Empty when not even the smallest body fits.
+    pub fn body(self, energy: u32, capacity: u32) -> Vec<Part> {
+        let energy = energy.min(capacity);
+        match self {
+            Role::Worker => repeat_unit(&WORKER_UNIT, energy),
+            Role::Upgrader => repeat_unit(&UPGRADER_UNIT, energy),
+            Role::Harvester => {
+                let work = (energy.saturating_sub(Part::Move.cost()) / Part::Work.cost()) as usize;
+                if work == 0 {
+                    return Vec::new();
+                }
+                let work = work.min(HARVESTER_WORK);
+                let mut body = vec![Part::Work; work];
+                // a Carry to hand loads to workers and build its container, out of what the
+                // Work parts leave over
+                let left = energy - Part::Move.cost() - work as u32 * Part::Work.cost();
+                if left >= Part::Carry.cost() {
+                    body.push(Part::Carry);
+                }
+                body.push(Part::Move);
+                body
+            }
+            Role::Defender => repeat_unit(&DEFENDER_UNIT, energy),
+            Role::DepositHarvester => repeat_unit(&DEPOSIT_HARVESTER_UNIT, energy),
+            Role::DepositHauler => repeat_unit(&DEPOSIT_HAULER_UNIT, energy),
+            Role::Pioneer => repeat_unit(&PIONEER_UNIT, energy),
+            Role::Reserver => {
+                let unit: u32 = RESERVER_UNIT.iter().map(|p| p.cost()).sum();
+                repeat_unit(&RESERVER_UNIT, energy.min(unit * RESERVER_UNITS))
+            }
+            Role::Scout => repeat_unit(&[Part::Move], energy.min(Part::Move.cost())),
+            Role::RemoteMiner => {
+                let unit: u32 = REMOTE_MINER_UNIT.iter().map(|p| p.cost()).sum();
+                repeat_unit(&REMOTE_MINER_UNIT, energy.min(unit * REMOTE_MINER_UNITS))
+            }
+            Role::RemoteHauler => repeat_unit(&HAULER_UNIT, energy),
+        }
+    }
+
+    /// Parses a role name as typed at the console, ignoring case and underscores, so
+    /// `deposit_hauler` and `DepositHauler` both work.
+    pub fn parse(name: &str) -> Option<Role> {
+        let name: String = name
+            .chars()
+            .filter(|c| *c != '_')
+            .map(|c| c.to_ascii_lowercase())
+            .collect();
+        match name.as_str() {
+            "worker" => Some(Role::Worker),
+            "upgrader" => Some(Role::Upgrader),
+            "harvester" => Some(Role::Harvester),
+            "defender" => Some(Role::Defender),
+            "depositharvester" => Some(Role::DepositHarvester),
+            "deposithauler" => Some(Role::DepositHauler),
+            "pioneer" => Some(Role::Pioneer),
+            "reserver" => Some(Role::Reserver),
+            "scout" => Some(Role::Scout),
+            "remoteminer" => Some(Role::RemoteMiner),
+            "remotehauler" => Some(Role::RemoteHauler),
+            _ => None,
+        }
+    }
+
+    /// Whether a creep built for `self` can do `other`'s job just as well. Workers and upgraders
+    /// are both general work/carry/move bodies.
+    pub fn shares_body_with(self, other: Role) -> bool {
+        let general = |r| r == Role::Worker || r == Role::Upgrader;
+        self == other || (general(self) && general(other))
+    }
+
+    /// Parts the role can't do its job without. Harvesters never move once parked, so they
+    /// don't need Move.
+    pub fn critical_parts(self) -> &'static [Part] {
+        match self {
+            Role::Worker | Role::Upgrader | Role::Pioneer | Role::DepositHarvester => {
+                &[Part::Work, Part::Carry, Part::Move]
+            }
+            Role::Harvester => &[Part::Work],
+            Role::Defender => &[Part::Attack, Part::Move],
+            Role::DepositHauler | Role::RemoteHauler => &[Part::Carry, Part::Move],
+            Role::RemoteMiner => &[Part::Work, Part::Move],
+            Role::Reserver => &[Part::Claim, Part::Move],
+            Role::Scout => &[Part::Move],
+        }
+    }
+
+    /// Roles worth keeping alive at a spawn's renewal spot rather than replacing. Creeps that
+    /// work away from home, or are cheap, aren't.
+    pub fn renewable(self) -> bool {
+        match self {
+            Role::Worker
+            | Role::Upgrader
+            | Role::Harvester
+            | Role::Defender
+            | Role::DepositHarvester
+            | Role::DepositHauler => true,
+            Role::Pioneer
+            | Role::Reserver
+            | Role::Scout
+            | Role::RemoteMiner
+            | Role::RemoteHauler => false,
+        }
+    }
+
+    /// Urgent roles are spawned with whatever energy is available rather than waiting for a
+    /// bigger body.
+    pub fn urgent(self) -> bool {
+        match self {
+            Role::Defender => true,
+            Role::Worker
+            | Role::Upgrader
+            | Role::Harvester
+            | Role::DepositHarvester
+            | Role::DepositHauler
+            | Role::Pioneer
+            | Role::Reserver
+            | Role::Scout
+            | Role::RemoteMiner
+            | Role::RemoteHauler => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(parts: &[(Part, u32)]) -> Vec<Bodypart> {
+        parts
+            .iter()
+            .map(|(part, hits)| Bodypart {
+                boost: None,
+                part: *part,
+                hits: *hits,
+            })
+            .collect()
+    }
+
+    fn designed(role: Role) -> Vec<Bodypart> {
+        let parts: Vec<(Part, u32)> = role.body(800, 800).into_iter().map(|p| (p, 100)).collect();
+        body(&parts)
+    }
+
+    #[test]
+    fn infers_one_body_per_role() {
+        let expected = [
+            (Role::Worker, Role::Worker),
+            (Role::Upgrader, Role::Upgrader),
+            (Role::Harvester, Role::Harvester),
+            (Role::Defender, Role::Defender),
+            (Role::DepositHarvester, Role::Upgrader),
+            (Role::DepositHauler, Role::RemoteHauler),
+            (Role::Pioneer, Role::Worker),
+            (Role::Reserver, Role::Reserver),
+            (Role::Scout, Role::Scout),
+            (Role::RemoteMiner, Role::Harvester),
+            (Role::RemoteHauler, Role::RemoteHauler),
+        ];
+        assert_eq!(expected.len(), Role::ALL.len());
+        for (role, inferred) in expected.iter() {
+            assert_eq!(Role::infer_from_body(&designed(*role)), *inferred, "{:?}", role);
+        }
+    }
+
+    #[test]
+    fn carry_heavy_bodies_are_haulers() {
+        let b = body(&[(Part::Carry, 100), (Part::Carry, 100), (Part::Move, 100)]);
+        assert_eq!(Role::infer_from_body(&b), Role::RemoteHauler);
+        let b = body(&[(Part::Carry, 100), (Part::Move, 100), (Part::Move, 100)]);
+        assert_eq!(Role::infer_from_body(&b), Role::Worker);
+    }
+
+    #[test]
+    fn intact_with_every_critical_part() {
+        let b = body(&[(Part::Work, 100), (Part::Carry, 0), (Part::Carry, 100), (Part::Move, 40)]);
+        assert_eq!(body_verdict(Role::Worker, &b, false), BodyVerdict::Intact);
+    }
+
+    #[test]
+    fn parked_harvester_needs_no_move() {
+        let b = body(&[(Part::Work, 100), (Part::Move, 0)]);
+        assert_eq!(body_verdict(Role::Harvester, &b, false), BodyVerdict::Intact);
+    }
+
+    #[test]
+    fn broken_creep_retreats_or_recycles() {
+        let b = body(&[(Part::Work, 0), (Part::Carry, 100), (Part::Move, 100)]);
+        assert_eq!(body_verdict(Role::Worker, &b, true), BodyVerdict::Retreat);
+        assert_eq!(body_verdict(Role::Worker, &b, false), BodyVerdict::Recycle);
+    }
+
+    #[test]
+    fn immobile_creep_stays_put_or_suicides() {
+        let b = body(&[(Part::Attack, 100), (Part::Move, 0), (Part::Move, 0)]);
+        assert_eq!(body_verdict(Role::Defender, &b, true), BodyVerdict::StayPut);
+        assert_eq!(body_verdict(Role::Defender, &b, false), BodyVerdict::Suicide);
+    }
+
+    #[test]
+    fn harvester_carries_with_what_the_work_parts_leave() {
+        let carries = |energy| Role::Harvester.body(energy, energy).contains(&Part::Carry);
+        assert!(carries(300));
+        assert!(!carries(550));
+        assert!(carries(800));
+        assert_eq!(Role::Harvester.body(800, 800).len(), HARVESTER_WORK + 2);
+    }
+
+    #[test]
+    fn broken_harvester_without_move_suicides() {
+        let b = body(&[(Part::Work, 0), (Part::Move, 0)]);
+        assert_eq!(body_verdict(Role::Harvester, &b, false), BodyVerdict::Suicide);
+    }
+}
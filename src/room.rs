@@ -0,0 +1,484 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
+};
+
+use log::*;
+use screeps::{find, prelude::*, ObjectId, RawObjectId, Room, RoomName, Structure};
+use serde::{Deserialize, Serialize};
+use stdweb::{js_deserializable, js_serializable};
+
+use crate::{
+    accounts::{self, RoomBooks},
+    allies, anomaly,
+    construction::{self, SiteTrack},
+    destruction,
+    error::{self, BotError},
+    growth::{self, GrowthWindow},
+    labs, links,
+    logistics::{self, RoomEnergyState, DOWNGRADE_IMMINENT_TICKS},
+    memory, mining,
+    perimeter::{self, ChokeSet, ExitSide, PerimeterReport, PerimeterScan},
+    planner::{self, TilePlan},
+    population, reconcile,
+    role::Role,
+    spawn::{self, SpawnDiagnostics, SpawnRecovery, SpawnRequest},
+    terminal,
+    threat::{self, AttackerTotals},
+    visuals,
+};
+
+thread_local! {
+    static INACTIVE: RefCell<HashMap<RoomName, HashSet<RawObjectId>>> =
+        RefCell::new(HashMap::new());
+}
+
+/// Number of ticks of `energy_available` history kept to estimate the refill rate.
+pub const ENERGY_SAMPLE_TICKS: usize = 50;
+const PERIMETER_CHECK_TICKS: u32 = 500;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoomMode {
+    Normal,
+    /// Spend nothing beyond spawning and defense: no upgrade buffer refills.
+    Conserve,
+    /// RCL 8, where upgrading is capped at 15 energy a tick: one small upgrader keeps the
+    /// controller from downgrading and the rest of the energy stays in storage. Entered and
+    /// left automatically with the controller level.
+    Mature,
+    /// The controller lost a level: the upgrade buffer is fed first and an extra upgrader
+    /// works until the room is back at its highest level. Entered and left automatically.
+    PushRcl,
+}
+
+impl Default for RoomMode {
+    fn default() -> Self {
+        RoomMode::Normal
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+pub struct RoomMemory {
+    #[serde(default)]
+    pub mode: RoomMode,
+    #[serde(default)]
+    pub energy_samples: VecDeque<u32>,
+    /// Tick the spawns started holding out for a bigger body, if they currently are.
+    #[serde(default)]
+    pub spawn_wait_since: Option<u32>,
+    /// Tick an idle spawn first found the queue head's full body unaffordable, if it still is.
+    #[serde(default)]
+    pub fill_wait_since: Option<u32>,
+    /// Consecutive ticks spawn+extensions have been below half full.
+    #[serde(default)]
+    pub starved_ticks: u32,
+    #[serde(default)]
+    pub spawn_queue: Vec<SpawnRequest>,
+    /// Suffix for the next creep name; shared by every spawn in the room.
+    #[serde(default)]
+    pub spawn_counter: u32,
+    /// Fingerprint of the link, storage and controller link positions when links were last
+    /// classified; see `links::refresh_classes`.
+    #[serde(default)]
+    pub link_layout: Option<u32>,
+    #[serde(default)]
+    pub perimeter: Option<PerimeterReport>,
+    /// The perimeter check still being worked through, if any.
+    #[serde(default)]
+    pub perimeter_scan: Option<PerimeterScan>,
+    /// The planners' open tile search still being worked through, if any.
+    #[serde(default)]
+    pub tile_plan: Option<TilePlan>,
+    /// Packed positions the planner chose for the upgraders' container and link.
+    #[serde(default)]
+    pub controller_container: Option<u32>,
+    #[serde(default)]
+    pub controller_link: Option<u32>,
+    /// Our construction sites by id.
+    #[serde(default)]
+    pub sites: HashMap<String, SiteTrack>,
+    /// `(packed position, planner)` for sites placed but not yet seen.
+    #[serde(default)]
+    pub site_origins: Vec<(u32, String)>,
+    /// Packed positions the planner must not place sites on again.
+    #[serde(default)]
+    pub site_blacklist: Vec<u32>,
+    /// Tick hostiles showed up, while they're still around.
+    #[serde(default)]
+    pub threat_since: Option<u32>,
+    /// What each hostile player has done to the room during the current attack.
+    #[serde(default)]
+    pub attackers: HashMap<String, AttackerTotals>,
+    /// Hostile players with combat parts are in the room.
+    #[serde(default)]
+    pub critical_threat: bool,
+    /// Our creeps path only inside the ramparts while this is set.
+    #[serde(default)]
+    pub lockdown: bool,
+    /// Besieged room this room's terminal is currently sending energy to.
+    #[serde(default)]
+    pub supporting: Option<String>,
+    #[serde(default)]
+    pub spawn_diagnostics: SpawnDiagnostics,
+    /// Controller level last tick, and the highest it has been.
+    #[serde(default)]
+    pub rcl: u32,
+    #[serde(default)]
+    pub rcl_peak: u32,
+    /// Our structures the controller level doesn't support, as of the last level change.
+    #[serde(default)]
+    pub inactive: Vec<RawObjectId>,
+    /// Packed middle of the hostiles over the last few ticks, oldest first.
+    #[serde(default)]
+    pub hostile_trail: VecDeque<u32>,
+    /// Each hostile's last few packed positions, oldest first, by creep id.
+    #[serde(default)]
+    pub hostile_tracks: HashMap<String, Vec<u32>>,
+    /// Tick the hostile tracks were last added to.
+    #[serde(default)]
+    pub hostile_tracks_at: u32,
+    /// How long each hostile seen lately has spent at the border, by creep id.
+    #[serde(default)]
+    pub border_watch: HashMap<String, threat::BorderWatch>,
+    /// Tower energy held back from border campers, by owner, since the room was first
+    /// harassed.
+    #[serde(default)]
+    pub camper_held: HashMap<String, u32>,
+    /// Ticks the room's recent attacks started, oldest first.
+    #[serde(default)]
+    pub attack_starts: VecDeque<u32>,
+    /// The boost the labs are working towards; see `labs::select_reaction`.
+    #[serde(default)]
+    pub reaction: Option<labs::Reaction>,
+    /// The side the hostiles of the latest attack came in by.
+    #[serde(default)]
+    pub hostile_entry: Option<ExitSide>,
+    /// Where defenders hold against each side hostiles have come in by.
+    #[serde(default)]
+    pub chokes: Vec<ChokeSet>,
+    #[serde(default)]
+    pub growth: GrowthWindow,
+    /// Tick the room reached RCL 3 without a tower, until its first tower is built.
+    #[serde(default)]
+    pub tower_rush_since: Option<u32>,
+    /// Tick the first tower was seen standing.
+    #[serde(default)]
+    pub first_tower_at: Option<u32>,
+    /// Structures in the plan's way, for workers to dismantle.
+    #[serde(default)]
+    pub demolish: Vec<ObjectId<Structure>>,
+    /// Costlier structures in the plan's way, waiting on `confirm_demolish`.
+    #[serde(default)]
+    pub demolish_pending: Vec<ObjectId<Structure>>,
+    /// Packed renewal tile next to each spawn, by spawn name.
+    #[serde(default)]
+    pub renew_spots: HashMap<String, u32>,
+    /// The creep each spawn has called in for renewal, by spawn name.
+    #[serde(default)]
+    pub renewing: HashMap<String, String>,
+    /// Packed tiles by storage where idle workers wait.
+    #[serde(default)]
+    pub staging: Vec<u32>,
+    /// Packed road tiles of each critical route, by route; see `planner::plan_critical_roads`.
+    #[serde(default)]
+    pub road_plans: HashMap<String, Vec<u32>>,
+    #[serde(default)]
+    pub books: RoomBooks,
+    /// Upgraders the room's income supports, as of its last closed books.
+    #[serde(default)]
+    pub upgrader_target: Option<u32>,
+}
+
+js_serializable!(RoomMemory);
+js_deserializable!(RoomMemory);
+
+impl RoomMemory {
+    pub fn record_energy(&mut self, energy: u32) {
+        self.energy_samples.push_back(energy);
+        while self.energy_samples.len() > ENERGY_SAMPLE_TICKS {
+            self.energy_samples.pop_front();
+        }
+    }
+
+    /// Average energy gained per tick over the sample window. Ticks where energy was spent
+    /// (spawning) are ignored so a spawn doesn't make the refill rate look negative.
+    pub fn fill_rate(&self) -> f64 {
+        if self.energy_samples.len() < 2 {
+            return 0.0;
+        }
+        let gained: u32 = self
+            .energy_samples
+            .iter()
+            .zip(self.energy_samples.iter().skip(1))
+            .map(|(before, after)| after.saturating_sub(*before))
+            .sum();
+        gained as f64 / (self.energy_samples.len() - 1) as f64
+    }
+
+    /// Inserts `request` after every queued request of equal or higher priority. A request
+    /// whose dedupe key is already queued updates that entry instead, keeping its age.
+    pub fn enqueue(&mut self, mut request: SpawnRequest) {
+        if request.dedupe.is_some() {
+            if let Some(at) = self
+                .spawn_queue
+                .iter()
+                .position(|r| r.dedupe == request.dedupe)
+            {
+                request.enqueued = self.spawn_queue[at].enqueued;
+                request.starved = self.spawn_queue[at].starved;
+                // a downgrade from the deadlock check outlives re-queueing
+                if let (Some(new), Some(old)) = (request.budget, self.spawn_queue[at].budget) {
+                    request.budget = Some(new.min(old));
+                }
+                request.unaffordable_since = self.spawn_queue[at].unaffordable_since;
+                self.spawn_queue.remove(at);
+            }
+        }
+        let at = self
+            .spawn_queue
+            .iter()
+            .position(|r| r.priority < request.priority)
+            .unwrap_or_else(|| self.spawn_queue.len());
+        self.spawn_queue.insert(at, request);
+    }
+}
+
+/// Whether `id` is one of the structures its room's controller level leaves switched off.
+pub fn is_inactive(room: RoomName, id: RawObjectId) -> bool {
+    INACTIVE.with(|i| i.borrow().get(&room).map_or(false, |ids| ids.contains(&id)))
+}
+
+/// Tracks the controller level. When it changes, or the room gained or lost structures, the
+/// inactive structures are worked out again; `is_active` is only asked then, not every tick.
+fn update_level(room: &Room, mem: &mut RoomMemory, structures_changed: bool) {
+    let level = room.controller().map_or(0, |c| c.level());
+    if level != mem.rcl || structures_changed {
+        if level < mem.rcl {
+            warn!("{} controller dropped from RCL {} to {}", room.name(), mem.rcl, level);
+        }
+        let inactive: Vec<RawObjectId> = room
+            .find(find::STRUCTURES)
+            .into_iter()
+            .filter(|s| s.as_owned().map_or(false, |o| o.my()) && !s.is_active())
+            .map(|s| s.untyped_id())
+            .collect();
+        let resized = inactive.len() != mem.inactive.len();
+        mem.inactive = inactive;
+        if resized && !mem.inactive.is_empty() {
+            info!("{} has {} inactive structures", room.name(), mem.inactive.len());
+        }
+        mem.rcl = level;
+    }
+    mem.rcl_peak = mem.rcl_peak.max(level);
+    INACTIVE.with(|i| {
+        i.borrow_mut()
+            .insert(room.name(), mem.inactive.iter().cloned().collect());
+    });
+}
+
+/// The mode a room in `current` moves to at controller `level`, having reached `peak`:
+/// `Normal` and `Mature` as the controller reaches or drops below RCL 8, and `PushRcl` while
+/// the controller is below the highest level it reached. `Conserve` is set by hand and left
+/// alone.
+fn next_mode(current: RoomMode, level: u32, peak: u32) -> RoomMode {
+    match current {
+        RoomMode::Conserve => RoomMode::Conserve,
+        _ if level < peak => RoomMode::PushRcl,
+        _ if level >= 8 => RoomMode::Mature,
+        _ => RoomMode::Normal,
+    }
+}
+
+fn update_mode(room: &Room, mem: &mut RoomMemory) {
+    let mode = next_mode(mem.mode, mem.rcl, mem.rcl_peak);
+    if mode != mem.mode {
+        info!("{} switching from {:?} to {:?} mode", room.name(), mem.mode, mode);
+        mem.mode = mode;
+    }
+}
+
+pub fn run_room(room: &Room) -> Result<(), BotError> {
+    let mut mem = memory::get_room_memory(room.name())?;
+    let destroyed = destruction::detect(room);
+    let changed = !destroyed.is_empty() || destruction::built(room.name());
+    update_level(room, &mut mem, changed);
+    update_mode(room, &mut mem);
+    destruction::handle(room, &mut mem, &destroyed);
+    growth::sample(room, &mut mem);
+    growth::run_growth(room, &mem);
+    if let Err(e) = planner::ensure_first_tower(room, &mut mem) {
+        let name = room.name().to_string();
+        error::report("first tower", &name, &name, &e);
+    }
+    let available = room.energy_available();
+    mem.record_energy(available);
+    if available * 2 < room.energy_capacity_available() {
+        mem.starved_ticks += 1;
+    } else {
+        mem.starved_ticks = 0;
+    }
+
+    let state = RoomEnergyState {
+        starved_ticks: mem.starved_ticks,
+        downgrade_imminent: room
+            .controller()
+            .map(|c| c.ticks_to_downgrade() < DOWNGRADE_IMMINENT_TICKS)
+            .unwrap_or(false),
+        under_attack: !allies::hostile_creeps(room).is_empty(),
+        besieged: terminal::besieged(room.name(), &mem),
+        upgraders: population::count(room, Role::Upgrader),
+        mode: mem.mode,
+        energy_available: available,
+        spawn: spawn::spawn_state(room, &mem),
+    };
+    links::refresh_classes(room, &mut mem)?;
+    logistics::refresh(room, &state);
+
+    if let Some(rate) = accounts::close_room_books(room.name(), &mut mem.books) {
+        population::review_upgraders(room, &mut mem, rate);
+    }
+    population::run_population(room, &mut mem);
+    construction::run_construction(room, &mut mem);
+    if screeps::game::time() % 100 == 41 {
+        mining::run_mining(room)?;
+    }
+    let spawns = room.find(find::MY_SPAWNS).len();
+    match spawn::recovery(spawns, planner::spawn_sites(room)) {
+        SpawnRecovery::PlaceSite => planner::ensure_spawn_site(room, &mut mem)?,
+        SpawnRecovery::Rebuilding => {}
+        SpawnRecovery::Spawning if screeps::game::time() % 100 == 17 => {
+            anomaly::note("planner");
+            planner::ensure_extra_spawns(room, &mut mem)?;
+            planner::ensure_renew_spots(room, &mut mem);
+            planner::ensure_staging(room, &mut mem);
+            planner::ensure_upgrade_buffer(room, &mut mem)?;
+            planner::ensure_critical_roads(room, &mut mem)?;
+        }
+        SpawnRecovery::Spawning => {}
+    }
+
+    if mem.road_plans.is_empty() || screeps::game::time() % planner::ROAD_PLAN_TICKS == 317 {
+        anomaly::note("road planner");
+        planner::plan_critical_roads(room, &mut mem);
+    }
+
+    if screeps::game::time() % 100 == 73 {
+        anomaly::note("reactions");
+        labs::select_reaction(room, &mut mem)?;
+    }
+
+    if screeps::game::time() % reconcile::RECONCILE_TICKS == 53 {
+        anomaly::note("reconcile");
+        reconcile::run_reconcile(room, &mut mem);
+    }
+
+    threat::run_threat(room, &mut mem);
+    if mem.perimeter_scan.is_none() && screeps::game::time() % PERIMETER_CHECK_TICKS == 29 {
+        mem.perimeter_scan = Some(perimeter::start(room));
+    }
+    if let Some(mut scan) = mem.perimeter_scan.take() {
+        anomaly::note("perimeter scan");
+        let report = (0..perimeter::steps_allowed()).find_map(|_| perimeter::step(room, &mut scan));
+        match report {
+            Some(report) => mem.perimeter = Some(report),
+            None => mem.perimeter_scan = Some(scan),
+        }
+    }
+    if mem.tile_plan.is_some() {
+        anomaly::note("tile plan");
+        planner::step_tile_plan(room, &mut mem);
+    }
+
+    memory::set_room_memory(room.name(), &mem)
+}
+
+pub fn draw_visuals(room: &Room) -> Result<(), BotError> {
+    visuals::draw_dashboard(room);
+    if let Some(report) = memory::get_room_memory(room.name())?.perimeter {
+        visuals::draw_perimeter(room, &report);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(role: Role, priority: u32, dedupe: Option<&str>) -> SpawnRequest {
+        SpawnRequest {
+            dedupe: dedupe.map(|d| d.to_owned()),
+            ..SpawnRequest::new(role, priority)
+        }
+    }
+
+    fn roles(mem: &RoomMemory) -> Vec<(Role, u32)> {
+        mem.spawn_queue.iter().map(|r| (r.role, r.priority)).collect()
+    }
+
+    #[test]
+    fn queue_orders_by_priority_then_arrival() {
+        let mut mem = RoomMemory::default();
+        mem.enqueue(request(Role::Worker, 50, None));
+        mem.enqueue(request(Role::Harvester, 60, None));
+        mem.enqueue(request(Role::Upgrader, 50, None));
+        mem.enqueue(request(Role::Scout, 10, None));
+        assert_eq!(
+            roles(&mem),
+            vec![
+                (Role::Harvester, 60),
+                (Role::Worker, 50),
+                (Role::Upgrader, 50),
+                (Role::Scout, 10)
+            ]
+        );
+    }
+
+    #[test]
+    fn requeued_entry_keeps_its_wait_and_downgrade() {
+        let mut mem = RoomMemory::default();
+        let mut first = request(Role::Worker, 50, Some("Worker-0"));
+        first.enqueued = 100;
+        first.starved = true;
+        first.budget = Some(500);
+        first.unaffordable_since = Some(150);
+        mem.enqueue(first);
+        let mut again = request(Role::Worker, 70, Some("Worker-0"));
+        again.enqueued = 900;
+        again.budget = Some(800);
+        mem.enqueue(again);
+        assert_eq!(mem.spawn_queue.len(), 1);
+        let r = &mem.spawn_queue[0];
+        assert_eq!((r.priority, r.enqueued, r.starved), (70, 100, true));
+        assert_eq!((r.budget, r.unaffordable_since), (Some(500), Some(150)));
+    }
+
+    #[test]
+    fn queue_survives_a_memory_round_trip() {
+        let mut mem = RoomMemory::default();
+        mem.enqueue(request(Role::Pioneer, 40, Some("Pioneer-0")));
+        let json = serde_json::to_string(&mem).unwrap();
+        let back: RoomMemory = serde_json::from_str(&json).unwrap();
+        assert_eq!(roles(&back), vec![(Role::Pioneer, 40)]);
+        assert_eq!(back.spawn_queue[0].dedupe.as_deref(), Some("Pioneer-0"));
+    }
+
+    #[test]
+    fn reaching_rcl8_turns_mature() {
+        assert_eq!(next_mode(RoomMode::Normal, 7, 7), RoomMode::Normal);
+        assert_eq!(next_mode(RoomMode::Normal, 8, 8), RoomMode::Mature);
+        assert_eq!(next_mode(RoomMode::Mature, 8, 8), RoomMode::Mature);
+    }
+
+    #[test]
+    fn dropping_back_to_rcl7_restores_upgrading() {
+        assert_eq!(next_mode(RoomMode::Mature, 7, 8), RoomMode::PushRcl);
+        assert_eq!(next_mode(RoomMode::PushRcl, 7, 8), RoomMode::PushRcl);
+        assert_eq!(next_mode(RoomMode::PushRcl, 8, 8), RoomMode::Mature);
+    }
+
+    #[test]
+    fn conserve_is_left_alone() {
+        assert_eq!(next_mode(RoomMode::Conserve, 8, 8), RoomMode::Conserve);
+        assert_eq!(next_mode(RoomMode::Conserve, 7, 8), RoomMode::Conserve);
+    }
+}
@@ -0,0 +1,162 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use log::*;
+use screeps::{
+    find, prelude::*, ConstructionSite, Creep, Room, RoomName, Source, Structure,
+    StructureContainer, StructureExtension, StructureSpawn, StructureTower,
+};
+
+use crate::stats;
+
+/// One room's find results this tick. Each list is fetched the first time something asks for
+/// it, so a room nobody asks about hostiles in never pays for that find.
+#[derive(Default)]
+struct RoomFinds {
+    structures: Option<Rc<[Structure]>>,
+    my_creeps: Option<Rc<[Creep]>>,
+    damaged_creeps: Option<Rc<[Creep]>>,
+    hostile_creeps: Option<Rc<[Creep]>>,
+    sources: Option<Rc<[Source]>>,
+    my_spawns: Option<Rc<[StructureSpawn]>>,
+    construction_sites: Option<Rc<[ConstructionSite]>>,
+    extensions: Option<Rc<[StructureExtension]>>,
+    towers: Option<Rc<[StructureTower]>>,
+    containers: Option<Rc<[StructureContainer]>>,
+}
+
+/// Like the object cache, only good for the tick it was filled in.
+#[derive(Default)]
+struct RoomCache {
+    tick: u32,
+    rooms: HashMap<RoomName, RoomFinds>,
+    finds: u32,
+    hits: u32,
+}
+
+thread_local! {
+    static CACHE: RefCell<RoomCache> = RefCell::new(RoomCache::default());
+}
+
+type Slot<T> = fn(&mut RoomFinds) -> &mut Option<Rc<[T]>>;
+
+/// The list in `slot` for `room`, running `fetch` the first time this tick.
+fn cached<T>(room: &Room, slot: Slot<T>, fetch: impl FnOnce() -> Vec<T>) -> Rc<[T]> {
+    let now = screeps::game::time();
+    let name = room.name();
+    let found = CACHE.with(|c| {
+        let c = &mut *c.borrow_mut();
+        if c.tick != now {
+            *c = RoomCache {
+                tick: now,
+                ..RoomCache::default()
+            };
+        }
+        let found = slot(c.rooms.entry(name).or_default()).clone();
+        if found.is_some() {
+            c.hits += 1;
+        }
+        found
+    });
+    if let Some(found) = found {
+        return found;
+    }
+    // outside the borrow: the typed structure lists fetch `structures` through here too
+    let found: Rc<[T]> = fetch().into();
+    CACHE.with(|c| {
+        let c = &mut *c.borrow_mut();
+        c.finds += 1;
+        *slot(c.rooms.entry(name).or_default()) = Some(found.clone());
+    });
+    found
+}
+
+/// `find::STRUCTURES`, everyone's.
+pub fn structures(room: &Room) -> Rc<[Structure]> {
+    cached(room, |f| &mut f.structures, || room.find(find::STRUCTURES))
+}
+
+pub fn my_creeps(room: &Room) -> Rc<[Creep]> {
+    cached(room, |f| &mut f.my_creeps, || room.find(find::MY_CREEPS))
+}
+
+/// Our creeps below full hits.
+pub fn damaged_creeps(room: &Room) -> Rc<[Creep]> {
+    cached(room, |f| &mut f.damaged_creeps, || {
+        my_creeps(room)
+            .iter()
+            .filter(|c| c.hits() < c.hits_max())
+            .cloned()
+            .collect()
+    })
+}
+
+/// `find::HOSTILE_CREEPS`, allies included; `allies::hostile_creeps` leaves them out.
+pub fn hostile_creeps(room: &Room) -> Rc<[Creep]> {
+    cached(room, |f| &mut f.hostile_creeps, || room.find(find::HOSTILE_CREEPS))
+}
+
+pub fn sources(room: &Room) -> Rc<[Source]> {
+    cached(room, |f| &mut f.sources, || room.find(find::SOURCES))
+}
+
+pub fn my_spawns(room: &Room) -> Rc<[StructureSpawn]> {
+    cached(room, |f| &mut f.my_spawns, || room.find(find::MY_SPAWNS))
+}
+
+/// Our construction sites.
+pub fn construction_sites(room: &Room) -> Rc<[ConstructionSite]> {
+    cached(room, |f| &mut f.construction_sites, || {
+        room.find(find::MY_CONSTRUCTION_SITES)
+    })
+}
+
+pub fn extensions(room: &Room) -> Rc<[StructureExtension]> {
+    cached(room, |f| &mut f.extensions, || {
+        structures(room)
+            .iter()
+            .filter_map(|s| match s {
+                Structure::Extension(e) if e.my() => Some(e.clone()),
+                _ => None,
+            })
+            .collect()
+    })
+}
+
+pub fn towers(room: &Room) -> Rc<[StructureTower]> {
+    cached(room, |f| &mut f.towers, || {
+        structures(room)
+            .iter()
+            .filter_map(|s| match s {
+                Structure::Tower(t) if t.my() => Some(t.clone()),
+                _ => None,
+            })
+            .collect()
+    })
+}
+
+pub fn containers(room: &Room) -> Rc<[StructureContainer]> {
+    cached(room, |f| &mut f.containers, || {
+        structures(room)
+            .iter()
+            .filter_map(|s| match s {
+                Structure::Container(c) => Some(c.clone()),
+                _ => None,
+            })
+            .collect()
+    })
+}
+
+/// Logs this tick's finds and cache hits and adds them to `Memory.stats.room_cache`.
+pub fn report() {
+    let (finds, hits) = CACHE.with(|c| {
+        let c = c.borrow();
+        if c.tick == screeps::game::time() {
+            (c.finds, c.hits)
+        } else {
+            (0, 0)
+        }
+    });
+    debug!("room cache: {} finds, {} hits", finds, hits);
+    stats::increment("room_cache", "finds", finds as i32);
+    stats::increment("room_cache", "hits", hits as i32);
+}
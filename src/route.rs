@@ -0,0 +1,298 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use screeps::{
+    find,
+    pathfinder::{self, CostMatrix, SearchOptions},
+    prelude::*,
+    Creep, MoveToOptions, Position, ReturnCode, Room, RoomName, SingleRoomCostResult, Structure,
+    StructureType, Terrain,
+};
+
+use crate::{
+    avoid, construction, coord, diplomacy, intel, perimeter,
+    terrain::{self, ROOM_SIZE},
+    threat,
+};
+
+thread_local! {
+    static OUTSIDE: RefCell<HashMap<RoomName, (u32, Vec<bool>)>> = RefCell::new(HashMap::new());
+}
+
+pub const PLAIN_COST: u8 = 2;
+pub const SWAMP_COST: u8 = 10;
+pub const ROAD_COST: u8 = 1;
+/// Tiles in a danger zone are only crossed when there's no way around.
+pub const DANGER_COST: u8 = 100;
+/// Every walkable tile in a room of a player we avoid, roads included.
+const AVOID_COST: u8 = 20;
+/// Our own sites for structures creeps can't stand on, so paths go round them rather than
+/// leave a creep in the way of the build.
+const SITE_COST: u8 = 30;
+/// How long a locked down room's outside tiles are reused.
+const OUTSIDE_TICKS: u32 = 50;
+/// The pathfinder's own default room limit.
+const MAX_ROOMS: u8 = 16;
+/// Scouts pay extra for plain tiles more than this far from the room edge.
+const EDGE_TILES: u32 = 2;
+const INNER_PENALTY: u8 = 3;
+/// Tiles this close to a hostile that can fight are danger to a scout.
+const THREAT_RANGE: i32 = 3;
+
+/// Result of pathing between two points with road-aware costs.
+#[derive(Clone, Copy, Debug)]
+pub struct RouteMeasure {
+    pub tiles: u32,
+    /// Ticks for a fully loaded 2:1 Carry:Move creep; with these terrain costs that is exactly
+    /// the path cost.
+    pub loaded_ticks: u32,
+}
+
+impl RouteMeasure {
+    /// Out empty (one tile per tick) and back loaded.
+    pub fn round_trip_ticks(&self) -> u32 {
+        self.tiles + self.loaded_ticks
+    }
+}
+
+/// Tiles outside the ramparts of a locked down room, recomputed every `OUTSIDE_TICKS`.
+fn outside_tiles(room: &Room) -> Vec<bool> {
+    let now = screeps::game::time();
+    OUTSIDE.with(|o| {
+        let mut o = o.borrow_mut();
+        match o.get(&room.name()) {
+            Some((at, tiles)) if now - at < OUTSIDE_TICKS => tiles.clone(),
+            _ => {
+                let tiles = perimeter::outside(room);
+                o.insert(room.name(), (now, tiles.clone()));
+                tiles
+            }
+        }
+    })
+}
+
+/// Marks every tile within `range` of `(cx, cy)` as `DANGER_COST`, leaving blocked tiles be.
+fn mark_danger(costs: &mut CostMatrix, blocked: &[bool], (cx, cy): (i32, i32), range: i32) {
+    for y in (cy - range).max(0)..=(cy + range).min(ROOM_SIZE as i32 - 1) {
+        for x in (cx - range).max(0)..=(cx + range).min(ROOM_SIZE as i32 - 1) {
+            if !blocked[terrain::index(x as usize, y as usize)] {
+                costs.set(x as u8, y as u8, DANGER_COST);
+            }
+        }
+    }
+}
+
+/// Sets the normal route costs for `room_name` on top of whatever `costs` already holds, and
+/// returns which tiles are impassable.
+fn fill_costs(room_name: RoomName, costs: &mut CostMatrix) -> Vec<bool> {
+    let mut blocked = vec![false; ROOM_SIZE * ROOM_SIZE];
+    let avoid = diplomacy::avoided(room_name);
+    if avoid {
+        for (i, wall) in terrain::walls(room_name).into_iter().enumerate() {
+            if !wall {
+                costs.set((i % ROOM_SIZE) as u8, (i / ROOM_SIZE) as u8, AVOID_COST);
+            }
+        }
+    }
+    let road_cost = if avoid { AVOID_COST } else { ROAD_COST };
+    if let Some(room) = screeps::game::rooms::get(room_name) {
+        for structure in room.find(find::STRUCTURES) {
+            let pos = structure.pos();
+            match structure {
+                Structure::Road(_) => costs.set(pos.x() as u8, pos.y() as u8, road_cost),
+                Structure::Container(_) => {}
+                // our own ramparts let us through whether they're public or not
+                Structure::Rampart(ref r) if r.my() || r.is_public() => {}
+                // portals included: walkable, but a creep that steps on one is gone
+                _ => {
+                    costs.set(pos.x() as u8, pos.y() as u8, 0xff);
+                    blocked[terrain::index(pos.x() as usize, pos.y() as usize)] = true;
+                }
+            }
+        }
+        for site in construction::blocking_sites(&room) {
+            if !blocked[terrain::index(site.x() as usize, site.y() as usize)] {
+                costs.set(site.x() as u8, site.y() as u8, SITE_COST);
+            }
+        }
+        if threat::locked_down(room_name) {
+            for (i, out) in outside_tiles(&room).into_iter().enumerate() {
+                if out && !blocked[i] {
+                    let (x, y) = ((i % ROOM_SIZE) as u8, (i / ROOM_SIZE) as u8);
+                    costs.set(x, y, DANGER_COST);
+                }
+            }
+        }
+    }
+    // from intel, so this works in rooms we can't see
+    for portal in intel::portals(room_name) {
+        costs.set(portal.x() as u8, portal.y() as u8, 0xff);
+        blocked[terrain::index(portal.x() as usize, portal.y() as usize)] = true;
+    }
+    for (center, range) in intel::danger_zones(room_name) {
+        let center = (center.x() as i32, center.y() as i32);
+        mark_danger(costs, &blocked, center, range as i32);
+    }
+    blocked
+}
+
+/// Costs that shut a search out of a room, wall to wall.
+fn closed_costs<'a>() -> CostMatrix<'a> {
+    let mut costs = CostMatrix::default();
+    for y in 0..ROOM_SIZE as u8 {
+        for x in 0..ROOM_SIZE as u8 {
+            costs.set(x, y, 0xff);
+        }
+    }
+    costs
+}
+
+/// Whether a search starting in `from` must keep out of `room_name`. A creep already inside an
+/// avoided room can still path its way out.
+fn closed(room_name: RoomName, from: RoomName) -> bool {
+    room_name != from && avoid::avoided(room_name)
+}
+
+fn room_costs<'a>(room_name: RoomName, from: RoomName) -> CostMatrix<'a> {
+    if closed(room_name, from) {
+        return closed_costs();
+    }
+    let mut costs = CostMatrix::default();
+    fill_costs(room_name, &mut costs);
+    costs
+}
+
+/// Route costs for scouts: plain tiles away from the edges cost a little more, so paths keep
+/// to where an exit is a step away, and tiles near `threats` are danger.
+fn scout_costs<'a>(room_name: RoomName, from: RoomName, threats: &[Position]) -> CostMatrix<'a> {
+    if closed(room_name, from) {
+        return closed_costs();
+    }
+    let mut costs = CostMatrix::default();
+    let terrain = screeps::game::map::get_room_terrain(room_name);
+    let inner = EDGE_TILES..ROOM_SIZE as u32 - EDGE_TILES;
+    for y in inner.clone() {
+        for x in inner.clone() {
+            if terrain.get(x, y) == Terrain::Plain {
+                costs.set(x as u8, y as u8, PLAIN_COST + INNER_PENALTY);
+            }
+        }
+    }
+    let blocked = fill_costs(room_name, &mut costs);
+    for threat in threats.iter().filter(|t| t.room_name() == room_name) {
+        let center = (threat.x() as i32, threat.y() as i32);
+        mark_danger(&mut costs, &blocked, center, THREAT_RANGE);
+    }
+    costs
+}
+
+/// Rooms a search may enter: enough to detour around a room or two on the way.
+fn room_budget(from: RoomName, to: RoomName) -> u8 {
+    let distance = coord::distance(from, to).unwrap_or(MAX_ROOMS as u32);
+    (distance * 2 + 3).min(MAX_ROOMS as u32) as u8
+}
+
+fn search(from: Position, to: Position, range: u32) -> pathfinder::SearchResults {
+    let origin = from.room_name();
+    let opts = SearchOptions::new()
+        .plain_cost(PLAIN_COST)
+        .swamp_cost(SWAMP_COST)
+        .max_ops(20_000)
+        .max_rooms(room_budget(origin, to.room_name()))
+        .room_callback(move |room_name| room_costs(room_name, origin));
+    pathfinder::search(&from, &to, range, opts)
+}
+
+pub fn measure(from: Position, to: Position, range: u32) -> Option<RouteMeasure> {
+    let res = search(from, to, range);
+    if res.incomplete {
+        return None;
+    }
+    Some(RouteMeasure {
+        tiles: res.path().len() as u32,
+        loaded_ticks: res.cost,
+    })
+}
+
+/// Every tile of the route from `from` to within `range` of `to`, for laying roads along;
+/// empty if there's no complete route.
+pub fn path(from: Position, to: Position, range: u32) -> Vec<Position> {
+    let res = search(from, to, range);
+    if res.incomplete {
+        return Vec::new();
+    }
+    res.path()
+}
+
+/// Moves `creep` towards `to` with the game's own pathing and path reuse, but over the same
+/// costs as every other route: danger zones, lockdown outsides, portals, our blocking sites
+/// and closed rooms.
+pub fn move_to(creep: &Creep, to: Position) -> ReturnCode {
+    let origin = creep.pos().room_name();
+    let opts = MoveToOptions::new()
+        .plain_cost(PLAIN_COST)
+        .swamp_cost(SWAMP_COST)
+        .cost_callback(move |room_name, mut costs| {
+            if closed(room_name, origin) {
+                return SingleRoomCostResult::Impassable;
+            }
+            fill_costs(room_name, &mut costs);
+            SingleRoomCostResult::CostMatrix(costs)
+        });
+    creep.move_to_with_options(&to, opts)
+}
+
+/// The first tile of the route from `from` towards `to`, for creeps that move one step at a
+/// time (such as group leaders).
+pub fn next_step(from: Position, to: Position, range: u32) -> Option<Position> {
+    search(from, to, range).path().into_iter().next()
+}
+
+/// The first tile of a path that gets at least `range` away from every threat, avoiding the
+/// same danger zones as normal routes.
+pub fn flee_step(from: Position, threats: &[Position], range: u32) -> Option<Position> {
+    let opts = SearchOptions::new()
+        .plain_cost(PLAIN_COST)
+        .swamp_cost(SWAMP_COST)
+        .flee(true)
+        .room_callback(move |room_name| room_costs(room_name, from.room_name()));
+    pathfinder::search_many(&from, threats.iter().map(|t| (*t, range)), opts)
+        .path()
+        .into_iter()
+        .next()
+}
+
+/// The first tile of a scout's route from `from` towards `to`, hugging room edges and keeping
+/// clear of `threats`.
+pub fn scout_step(
+    from: Position,
+    to: Position,
+    range: u32,
+    threats: &[Position],
+) -> Option<Position> {
+    let threats = threats.to_vec();
+    let opts = SearchOptions::new()
+        .plain_cost(PLAIN_COST)
+        .swamp_cost(SWAMP_COST)
+        .max_ops(20_000)
+        .max_rooms(room_budget(from.room_name(), to.room_name()))
+        .room_callback(move |room_name| scout_costs(room_name, from.room_name(), &threats));
+    pathfinder::search(&from, &to, range, opts)
+        .path()
+        .into_iter()
+        .next()
+}
+
+/// Number of road structures in the given rooms we currently have vision of; used to notice
+/// when road construction has changed travel times.
+pub fn road_count(rooms: &[RoomName]) -> u32 {
+    rooms
+        .iter()
+        .filter_map(|name| screeps::game::rooms::get(*name))
+        .map(|room| {
+            room.find(find::STRUCTURES)
+                .iter()
+                .filter(|s| s.structure_type() == StructureType::Road)
+                .count() as u32
+        })
+        .sum()
+}
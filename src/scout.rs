@@ -0,0 +1,114 @@
+use log::*;
+use screeps::{find, prelude::*, Creep, Part, Position, Room, RoomName};
+
+use crate::{
+    allies, avoid, coord,
+    creep::Task,
+    error::BotError,
+    intel::{self, RoomIntel},
+    intents, memory, route, terrain,
+};
+
+/// Hostiles that can fight this close send the scout out by the nearest exit.
+const FLEE_RANGE: u32 = 4;
+/// Rooms where a scout met hostiles are left alone this long.
+const HOSTILE_PRESENCE_TICKS: u32 = 1500;
+/// Scouts only need to get into a room, not to its middle.
+const PATH_RANGE: u32 = 20;
+
+/// Hostile creeps in `room` with anything to hurt a scout with.
+fn threats(room: &Room) -> Vec<Position> {
+    allies::hostile_creeps(room)
+        .iter()
+        .filter(|h| {
+            h.get_active_bodyparts(Part::Attack) > 0
+                || h.get_active_bodyparts(Part::RangedAttack) > 0
+        })
+        .map(|h| h.pos())
+        .collect()
+}
+
+/// Rooms a scout can't come back from: a player's towers, or hostiles met there lately.
+fn deadly(info: &RoomIntel) -> bool {
+    let now = screeps::game::time();
+    info.hostile_towers > 0
+        || info
+            .hostile_seen
+            .map_or(false, |t| now.saturating_sub(t) < HOSTILE_PRESENCE_TICKS)
+}
+
+/// The neighbour of `room` we know least about, across a border the terrain leaves open,
+/// leaving out deadly and avoided ones.
+fn next_target(room: RoomName) -> Result<Option<RoomName>, BotError> {
+    let walls = terrain::walls(room);
+    let mut best = None;
+    for (side, next) in coord::adjacent(room) {
+        if !terrain::exit_open(&walls, side) || avoid::avoided(next) {
+            continue;
+        }
+        let updated = match intel::get(next)? {
+            Some(ref i) if deadly(i) => continue,
+            Some(i) => i.updated,
+            None => 0,
+        };
+        if best.map_or(true, |(_, u)| updated < u) {
+            best = Some((next, updated));
+        }
+    }
+    Ok(best.map(|(name, _)| name))
+}
+
+fn step(creep: &Creep, to: Position) {
+    let dir = match coord::direction(creep.pos(), to) {
+        Some(d) => d,
+        None => return,
+    };
+    intents::issue(
+        &intents::creep_actor(creep),
+        "move_direction",
+        &to.to_string(),
+        None,
+        || creep.move_direction(dir),
+    );
+}
+
+/// Walks from room to room, stalest intel first, along the room edges and around hostiles.
+/// A hostile that gets within `FLEE_RANGE` marks the room and sends the scout out the nearest
+/// exit.
+pub fn run_scout(creep: &Creep, room: &Room) -> Result<Task, BotError> {
+    let threats = threats(room);
+    let pos = creep.pos();
+    if threats.iter().any(|t| pos.get_range_to(t) <= FLEE_RANGE) {
+        intel::record_hostile_presence(room.name())?;
+        memory::creep_memory(creep).del("scout_target");
+        if let Some(exit) = pos.find_closest_by_range(find::EXIT) {
+            debug!("{} fleeing hostiles in {}", creep.name(), room.name());
+            if let Some(next) = route::scout_step(pos, exit, 0, &threats) {
+                step(creep, next);
+            }
+        }
+        return Ok(Task::Flee);
+    }
+
+    let current = memory::creep_memory(creep)
+        .string("scout_target")
+        .ok()
+        .flatten()
+        .and_then(|r| r.parse::<RoomName>().ok())
+        .filter(|r| *r != room.name());
+    let target = match current {
+        Some(t) => t,
+        None => match next_target(room.name())? {
+            Some(t) => {
+                memory::creep_memory(creep).set("scout_target", t.to_string());
+                t
+            }
+            None => return Ok(Task::Idle),
+        },
+    };
+    let to = Position::new(25, 25, target);
+    if let Some(next) = route::scout_step(pos, to, PATH_RANGE, &threats) {
+        step(creep, next);
+    }
+    Ok(Task::Idle)
+}
@@ -0,0 +1,35 @@
+use screeps::memory::MemoryReference;
+
+fn settings() -> Option<MemoryReference> {
+    screeps::memory::root().dict("settings").ok().flatten()
+}
+
+/// Reads `Memory.settings.<key>`, falling back to `default` when unset or not a number.
+pub fn u32_or(key: &str, default: u32) -> u32 {
+    settings()
+        .and_then(|s| s.i32(key).ok().flatten())
+        .map(|v| v.max(0) as u32)
+        .unwrap_or(default)
+}
+
+/// Reads `Memory.settings.<key>` as a flag; unset means off.
+pub fn flag(key: &str) -> bool {
+    settings().map(|s| s.bool(key)).unwrap_or(false)
+}
+
+/// Reads `Memory.settings.<key>` as a string.
+pub fn string(key: &str) -> Option<String> {
+    settings().and_then(|s| s.string(key).ok().flatten())
+}
+
+/// Sets `Memory.settings.<key>`, or clears it when `value` is `None`.
+pub fn set_string(key: &str, value: Option<&str>) {
+    let settings = match screeps::memory::root().dict_or_create("settings") {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    match value {
+        Some(v) => settings.set(key, v),
+        None => settings.del(key),
+    }
+}
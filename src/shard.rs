@@ -0,0 +1,139 @@
+use std::cell::RefCell;
+
+use log::*;
+use screeps::prelude::*;
+use serde::{Deserialize, Serialize};
+use stdweb::{js, unstable::TryInto};
+
+/// How often our blob in `InterShardMemory` is rewritten.
+const PUBLISH_TICKS: u32 = 100;
+
+/// What the server offers, detected once at setup.
+#[derive(Clone, Debug, Default)]
+struct ShardInfo {
+    /// `Game.shard.name`; unset on private servers without shards.
+    name: Option<String>,
+    intershard: bool,
+}
+
+thread_local! {
+    static SHARD: RefCell<ShardInfo> = RefCell::new(ShardInfo::default());
+}
+
+/// A portal another shard's code may want to know about.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ShardPortal {
+    pub room: String,
+    /// Shard and room the portal leads to.
+    pub shard: String,
+    pub destination: String,
+}
+
+/// The blob each shard publishes in its `InterShardMemory` for the others to read.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ShardBlob {
+    #[serde(default)]
+    pub updated: u32,
+    /// Rooms we own here, which is this shard's share of the GCL.
+    #[serde(default)]
+    pub rooms: u32,
+    #[serde(default)]
+    pub gcl: u32,
+    #[serde(default)]
+    pub portals: Vec<ShardPortal>,
+}
+
+/// Looks for `Game.shard` and `InterShardMemory`. Call once at setup, before logging.
+pub fn detect() {
+    let name: Option<String> = js! {
+        return (typeof Game !== "undefined" && Game.shard) ? Game.shard.name : null;
+    }
+    .try_into()
+    .ok()
+    .flatten();
+    let intershard: bool = js!(return typeof InterShardMemory !== "undefined";)
+        .try_into()
+        .unwrap_or(false);
+    SHARD.with(|s| *s.borrow_mut() = ShardInfo { name, intershard });
+}
+
+/// This shard's name, if the server has shards.
+pub fn name() -> Option<String> {
+    SHARD.with(|s| s.borrow().name.clone())
+}
+
+fn intershard() -> bool {
+    SHARD.with(|s| s.borrow().intershard)
+}
+
+/// Puts the shard name at `Memory.stats.shard` so dashboards over several shards can tell the
+/// numbers apart.
+pub fn record_stats() {
+    if let Some(name) = name() {
+        if let Ok(stats) = screeps::memory::root().dict_or_create("stats") {
+            stats.set("shard", name.as_str());
+        }
+    }
+}
+
+/// Another shard's blob, if there's intershard memory and it has published one.
+pub fn read(shard: &str) -> Option<ShardBlob> {
+    if !intershard() {
+        return None;
+    }
+    let shard = shard.to_string();
+    let raw: Option<String> = js!(return InterShardMemory.getRemote(@{shard}) || null;)
+        .try_into()
+        .ok()
+        .flatten();
+    serde_json::from_str(&raw?).ok()
+}
+
+/// Console command: `print_shard("shard1")` logs what another shard last published.
+pub fn print_shard(shard: String) {
+    let blob = match read(&shard) {
+        Some(b) => b,
+        None => {
+            info!("no blob published by {}", shard);
+            return;
+        }
+    };
+    let mut out = format!(
+        "{}: {} rooms, GCL {}, updated at {}\n",
+        shard, blob.rooms, blob.gcl, blob.updated
+    );
+    for p in &blob.portals {
+        out.push_str(&format!("  {} -> {} {}\n", p.room, p.shard, p.destination));
+    }
+    info!("{}", out);
+}
+
+fn write(blob: &ShardBlob) {
+    let raw = match serde_json::to_string(blob) {
+        Ok(r) => r,
+        Err(e) => {
+            warn!("couldn't encode the shard blob: {}", e);
+            return;
+        }
+    };
+    js! {
+        InterShardMemory.setLocal(@{raw});
+    }
+}
+
+/// Rewrites our blob every `PUBLISH_TICKS`. Does nothing without intershard memory.
+pub fn publish(portals: Vec<ShardPortal>) {
+    if !intershard() || screeps::game::time() % PUBLISH_TICKS != 0 {
+        return;
+    }
+    let rooms = screeps::game::rooms::values()
+        .iter()
+        .filter(|r| r.controller().map_or(false, |c| c.my()))
+        .count() as u32;
+    write(&ShardBlob {
+        updated: screeps::game::time(),
+        rooms,
+        gcl: screeps::game::gcl::level(),
+        portals,
+    });
+}
@@ -0,0 +1,614 @@
+use std::collections::HashMap;
+
+use log::*;
+use screeps::{
+    find, prelude::*, Part, Position, RawObjectId, ReturnCode, Room, StructureSpawn,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    accounts,
+    error::{self, BotError},
+    group, intents,
+    memory::{self, CreepEnvelope},
+    presets, renewal,
+    role::Role,
+    room::RoomMemory,
+    settings, stats,
+};
+
+/// An entry in a room's spawn queue, kept in `RoomMemory` so it survives losing every spawn.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SpawnRequest {
+    pub role: Role,
+    pub priority: u32,
+    /// Packed position the creep will work at; the closest idle spawn gets the request.
+    #[serde(default)]
+    pub hint: Option<u32>,
+    #[serde(default)]
+    pub enqueued: u32,
+    /// Requests with the same key replace each other instead of queueing twice.
+    #[serde(default)]
+    pub dedupe: Option<String>,
+    /// Set once the starvation alarm has gone off for this entry.
+    #[serde(default)]
+    pub starved: bool,
+    /// Room the creep works in, when that isn't the room spawning it.
+    #[serde(default)]
+    pub target_room: Option<String>,
+    /// Energy the body is designed for; `None` designs for the room's full capacity.
+    #[serde(default)]
+    pub budget: Option<u32>,
+    /// First tick the budget was found to be more than the room can ever hold.
+    #[serde(default)]
+    pub unaffordable_since: Option<u32>,
+    /// Group the creep joins once spawned, as a squad's replacement.
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+/// Default for `Memory.settings.spawn_wait_window`: the longest a spawn is held for a bigger
+/// body.
+const SPAWN_WAIT_WINDOW: u32 = 20;
+/// Entries waiting longer than this raise the starvation alarm.
+const STARVATION_TICKS: u32 = 3000;
+/// A queue head whose budget is over capacity this long has its budget cut to fit.
+const DEADLOCK_TICKS: u32 = 100;
+/// Length of the rolling window failure causes are counted over, and how long a room with a
+/// queue can go without spawning before it's reported.
+const DIAGNOSTIC_WINDOW: u32 = 1500;
+
+/// What happened when the room's spawns looked at the queue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SpawnOutcome {
+    Spawned,
+    NotEnoughEnergy,
+    /// Every spawn is already spawning.
+    Busy,
+    QueueEmpty,
+    /// The queue head is designed for more energy than the room can hold.
+    Unaffordable,
+    NameCollision,
+    Failed(ReturnCode),
+}
+
+impl SpawnOutcome {
+    /// Why `request` can't be spawned with the energy there is.
+    fn short_of_energy(request: &SpawnRequest) -> SpawnOutcome {
+        if request.unaffordable_since.is_some() {
+            SpawnOutcome::Unaffordable
+        } else {
+            SpawnOutcome::NotEnoughEnergy
+        }
+    }
+
+    fn key(self) -> &'static str {
+        match self {
+            SpawnOutcome::Spawned => "spawned",
+            SpawnOutcome::NotEnoughEnergy => "not_enough_energy",
+            SpawnOutcome::Busy => "busy",
+            SpawnOutcome::QueueEmpty => "queue_empty",
+            SpawnOutcome::Unaffordable => "unaffordable",
+            SpawnOutcome::NameCollision => "name_collision",
+            SpawnOutcome::Failed(_) => "other",
+        }
+    }
+}
+
+/// Spawn outcome counts over the current `DIAGNOSTIC_WINDOW`, kept in `RoomMemory`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SpawnDiagnostics {
+    #[serde(default)]
+    pub window_start: u32,
+    #[serde(default)]
+    pub counts: HashMap<String, u32>,
+    /// Last tick anything spawned, or when tracking started.
+    #[serde(default)]
+    pub last_spawned: Option<u32>,
+    /// Set once the no-spawn warning has gone off, until the next successful spawn.
+    #[serde(default)]
+    pub warned: bool,
+}
+
+/// The one place spawn outcomes go: counts them for the window and in
+/// `Memory.stats.spawn.<room>`, and warns when a room with a queue hasn't spawned for a whole
+/// window.
+fn record(room: &Room, room_mem: &mut RoomMemory, outcome: SpawnOutcome) {
+    let now = screeps::game::time();
+    if let SpawnOutcome::Failed(r) = outcome {
+        debug!("{} spawn_creep failed: {:?}", room.name(), r);
+    }
+    stats::increment_room("spawn", Some(room.name()), outcome.key(), 1);
+    let queued = !room_mem.spawn_queue.is_empty();
+    let diag = &mut room_mem.spawn_diagnostics;
+    if now.saturating_sub(diag.window_start) >= DIAGNOSTIC_WINDOW {
+        diag.window_start = now;
+        diag.counts.clear();
+    }
+    *diag.counts.entry(outcome.key().to_string()).or_insert(0) += 1;
+    let last = *diag.last_spawned.get_or_insert(now);
+    if outcome == SpawnOutcome::Spawned {
+        diag.last_spawned = Some(now);
+        diag.warned = false;
+    } else if queued && !diag.warned && now - last >= DIAGNOSTIC_WINDOW {
+        let mut counts: Vec<_> = diag.counts.iter().collect();
+        counts.sort();
+        warn!(
+            "{} hasn't spawned in {} ticks with {} queued; last {} ticks: {:?}",
+            room.name(),
+            now - last,
+            room_mem.spawn_queue.len(),
+            now - diag.window_start,
+            counts
+        );
+        diag.warned = true;
+    }
+}
+
+impl SpawnRequest {
+    /// A request for `role` at `priority` with nothing else set; callers fill in the rest
+    /// with struct update syntax.
+    pub fn new(role: Role, priority: u32) -> SpawnRequest {
+        SpawnRequest {
+            role,
+            priority,
+            hint: None,
+            enqueued: 0,
+            dedupe: None,
+            starved: false,
+            target_room: None,
+            budget: None,
+            unaffordable_since: None,
+            group: None,
+        }
+    }
+
+    /// Base priority plus `Memory.settings.spawn_aging_<role>` (default 1) per 100 ticks
+    /// waited, so low priority entries can't be starved forever.
+    pub fn aged_priority(&self, now: u32) -> u32 {
+        let slope = settings::u32_or(&format!("spawn_aging_{:?}", self.role).to_lowercase(), 1);
+        self.priority + now.saturating_sub(self.enqueued) * slope / 100
+    }
+
+    /// The energy the body is designed for, never more than `capacity`.
+    pub fn design_energy(&self, capacity: u32) -> u32 {
+        self.budget.map_or(capacity, |b| b.min(capacity))
+    }
+}
+
+/// Where a room stands on spawning, from the spawns and spawn sites it has.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpawnRecovery {
+    /// A spawn is up and the queue runs.
+    Spawning,
+    /// Every spawn is gone and nothing is rebuilding one: the planner places a site.
+    PlaceSite,
+    /// The spawn site is down; builders work on it from storage and containers, and the queue
+    /// waits in room memory until it's done.
+    Rebuilding,
+}
+
+pub fn recovery(spawns: usize, spawn_sites: usize) -> SpawnRecovery {
+    if spawns > 0 {
+        SpawnRecovery::Spawning
+    } else if spawn_sites == 0 {
+        SpawnRecovery::PlaceSite
+    } else {
+        SpawnRecovery::Rebuilding
+    }
+}
+
+/// Index of the queue entry to spawn next: highest aged priority, queue order on ties.
+pub fn next_request(room_mem: &RoomMemory, now: u32) -> Option<usize> {
+    let mut best: Option<(usize, u32)> = None;
+    for (i, request) in room_mem.spawn_queue.iter().enumerate() {
+        let priority = request.aged_priority(now);
+        if best.map_or(true, |(_, p)| priority > p) {
+            best = Some((i, priority));
+        }
+    }
+    best.map(|(i, _)| i)
+}
+
+/// Warns (once per entry) and counts in `Memory.stats.spawn` when an entry has waited too long.
+fn check_starvation(room: &Room, room_mem: &mut RoomMemory, now: u32) {
+    for request in room_mem.spawn_queue.iter_mut() {
+        if request.starved || now.saturating_sub(request.enqueued) <= STARVATION_TICKS {
+            continue;
+        }
+        warn!(
+            "{} spawn queue entry {:?} has waited {} ticks",
+            room.name(),
+            request.role,
+            now - request.enqueued
+        );
+        stats::increment("spawn", "starved_entries", 1);
+        request.starved = true;
+    }
+}
+
+/// Shrinks `request`'s budget to `capacity` once it has been over it for `DEADLOCK_TICKS`,
+/// returning the budget it had when it does.
+fn downgrade(request: &mut SpawnRequest, capacity: u32, now: u32) -> Option<u32> {
+    match request.budget {
+        Some(budget) if budget > capacity => {
+            let since = *request.unaffordable_since.get_or_insert(now);
+            if now - since < DEADLOCK_TICKS {
+                return None;
+            }
+            request.budget = Some(capacity);
+            request.unaffordable_since = None;
+            Some(budget)
+        }
+        _ => {
+            request.unaffordable_since = None;
+            None
+        }
+    }
+}
+
+/// Shrinks the queue head's budget to the room's capacity once it has been over it for
+/// `DEADLOCK_TICKS`, such as after losing extensions. Bodies are clamped to capacity either way;
+/// this makes the downgrade stick and shows up in the log.
+fn check_deadlock(room: &Room, room_mem: &mut RoomMemory, capacity: u32, now: u32) {
+    let index = match next_request(room_mem, now) {
+        Some(i) => i,
+        None => return,
+    };
+    let request = &mut room_mem.spawn_queue[index];
+    if let Some(budget) = downgrade(request, capacity, now) {
+        warn!(
+            "{} downgraded queued {:?} from {} to {} energy, capacity dropped",
+            room.name(),
+            request.role,
+            budget,
+            capacity
+        );
+    }
+}
+
+/// What the room's spawns are up to, gathered once a tick so haulers can stock up for the next
+/// entry before a spawn frees up.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SpawnState {
+    /// Ticks until the first spawn frees up, while every spawn is busy.
+    pub remaining: Option<u32>,
+    /// The spawn that will take the next queue entry.
+    pub next_spawn: Option<RawObjectId>,
+    /// Full-capacity body cost of the queue head. Bodies already spawning were paid up front.
+    pub next_cost: u32,
+}
+
+pub fn body_cost(body: &[Part]) -> u32 {
+    body.iter().map(|p| p.cost()).sum()
+}
+
+pub fn spawn_state(room: &Room, room_mem: &RoomMemory) -> SpawnState {
+    let spawns = room.find(find::MY_SPAWNS);
+    let remaining_of = |s: &StructureSpawn| s.spawning().map_or(0, |sp| sp.remaining_time());
+    let next = spawns.iter().min_by_key(|s| remaining_of(s));
+    SpawnState {
+        remaining: next.map(remaining_of).filter(|r| *r > 0),
+        next_spawn: next.map(|s| s.untyped_id()),
+        next_cost: next_request(room_mem, screeps::game::time()).map_or(0, |i| {
+            let r = &room_mem.spawn_queue[i];
+            let capacity = room.energy_capacity_available();
+            let body = presets::body(room.name(), r.role, r.design_energy(capacity), capacity);
+            body_cost(&body)
+        }),
+    }
+}
+
+/// Decides whether it's worth holding the spawn until the room has `capacity` energy (the
+/// design energy of the entry), returning the expected number of ticks to wait if so. It's
+/// only worth it when the room's fill rate gets there within `window` ticks.
+///
+/// `urgent` entries (defenders, emergency bootstrap) never wait.
+fn forecast_wait(
+    room_mem: &RoomMemory,
+    available: u32,
+    capacity: u32,
+    refillers_alive: bool,
+    urgent: bool,
+    window: u32,
+) -> Option<u32> {
+    if urgent || !refillers_alive || available >= capacity {
+        return None;
+    }
+    let rate = room_mem.fill_rate();
+    if rate <= 0.0 {
+        return None;
+    }
+    let eta = ((capacity - available) as f64 / rate).ceil() as u32;
+    if eta <= window {
+        Some(eta)
+    } else {
+        None
+    }
+}
+
+/// The idle spawn closest to `hint`, or the first idle one without a hint.
+fn pick_spawn(idle: &[StructureSpawn], hint: Option<Position>) -> usize {
+    match hint {
+        Some(hint) => idle
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, s)| s.pos().get_range_to(&hint))
+            .map(|(i, _)| i)
+            .unwrap_or(0),
+        None => 0,
+    }
+}
+
+/// Works through the room's spawn queue, handing the head entry to an idle spawn until the
+/// spawns are all busy or the head can't be afforded yet. Every pass ends in one
+/// `SpawnOutcome` for `record`.
+pub fn run_spawns(room: &Room) -> Result<(), BotError> {
+    let spawns = room.find(find::MY_SPAWNS);
+    // the queue stays in room memory while the spawn is rebuilt
+    if spawns.is_empty() {
+        return Ok(());
+    }
+    let mut idle: Vec<StructureSpawn> =
+        spawns.into_iter().filter(|s| s.spawning().is_none()).collect();
+    let mut room_mem = memory::get_room_memory(room.name())?;
+    let now = screeps::game::time();
+    if idle.is_empty() {
+        if !room_mem.spawn_queue.is_empty() {
+            record(room, &mut room_mem, SpawnOutcome::Busy);
+        }
+        renewal::run_renewal(room, &mut room_mem, &idle);
+        memory::set_room_memory(room.name(), &room_mem)?;
+        return Ok(());
+    }
+    check_starvation(room, &mut room_mem, now);
+    // spawning in this tick doesn't show up in energy_available until the next one
+    let mut available = room.energy_available();
+    let capacity = room.energy_capacity_available();
+    check_deadlock(room, &mut room_mem, capacity, now);
+    let mut res = Ok(());
+
+    while !idle.is_empty() {
+        let index = match next_request(&room_mem, now) {
+            Some(i) => i,
+            None => {
+                record(room, &mut room_mem, SpawnOutcome::QueueEmpty);
+                break;
+            }
+        };
+        let request = room_mem.spawn_queue[index].clone();
+        let role = request.role;
+        let design = request.design_energy(capacity);
+        let full = presets::body(room.name(), role, design, capacity);
+        if available < body_cost(&full) {
+            room_mem.fill_wait_since.get_or_insert(now);
+        }
+        let body = presets::body(room.name(), role, available.min(design), capacity);
+        if body.is_empty() {
+            presets::note_unaffordable(room.name(), role, available, capacity);
+            record(room, &mut room_mem, SpawnOutcome::short_of_energy(&request));
+            break;
+        }
+
+        if full.len() > body.len() {
+            let creeps = room.find(find::MY_CREEPS);
+            // with no creeps at all nobody will refill the spawn, so spawn whatever we can.
+            let bootstrap = creeps.is_empty();
+            let refillers_alive = creeps
+                .iter()
+                .any(|c| c.get_active_bodyparts(Part::Carry) > 0);
+
+            let urgent = role.urgent() || bootstrap;
+
+            let window = settings::u32_or("spawn_wait_window", SPAWN_WAIT_WINDOW);
+            if let Some(eta) =
+                forecast_wait(&room_mem, available, design, refillers_alive, urgent, window)
+            {
+                if room_mem.spawn_wait_since.is_none() {
+                    info!(
+                        "{} holding spawn for a {}-energy body, expecting full capacity in ~{} ticks",
+                        room.name(),
+                        design,
+                        eta
+                    );
+                    room_mem.spawn_wait_since = Some(screeps::game::time());
+                }
+                record(room, &mut room_mem, SpawnOutcome::short_of_energy(&request));
+                break;
+            }
+        }
+
+        let spawn = idle.remove(pick_spawn(&idle, request.hint.map(Position::from_packed)));
+
+        // names come from a per-room counter so they stay unique across spawns.
+        let (r, name) = loop {
+            let name = format!("{}-{}", room.name(), room_mem.spawn_counter);
+            let r = intents::issue(
+                "spawn",
+                "spawn_creep",
+                &spawn.name(),
+                Some(body_cost(&body)),
+                || spawn.spawn_creep(&body, &name),
+            );
+            room_mem.spawn_counter += 1;
+
+            if r != ReturnCode::NameExists {
+                break (r, name);
+            }
+            record(room, &mut room_mem, SpawnOutcome::NameCollision);
+        };
+
+        match r {
+            ReturnCode::Ok => record(room, &mut room_mem, SpawnOutcome::Spawned),
+            ReturnCode::NotEnough => {
+                record(room, &mut room_mem, SpawnOutcome::NotEnoughEnergy);
+                break;
+            }
+            ReturnCode::Busy => {
+                record(room, &mut room_mem, SpawnOutcome::Busy);
+                break;
+            }
+            r => {
+                record(room, &mut room_mem, SpawnOutcome::Failed(r));
+                res = error::check("spawn_creep", r);
+                break;
+            }
+        }
+        debug!("{} spawning {} as {:?}", spawn.name(), name, role);
+        room_mem.spawn_queue.remove(index);
+        available -= body_cost(&body);
+        accounts::spent(room.name(), body_cost(&body));
+        memory::set_creep_envelope(&name, &CreepEnvelope::spawned(role, room.name()))?;
+        if let Some(target) = &request.target_room {
+            // creeps working elsewhere record who spawned them, since they're not counted here
+            let mem = memory::creep_memory_by_name(&name)?;
+            mem.set("target_room", target.as_str());
+            mem.set("owner_room", room.name().to_string().as_str());
+            if let Ok(remote) = target.parse() {
+                accounts::spawned(remote, body_cost(&body));
+            }
+        }
+        if let Some(id) = &request.group {
+            group::enlist(id, &name)?;
+        }
+        if let Some(since) = room_mem.spawn_wait_since.take() {
+            debug!(
+                "{} waited {} ticks for a bigger body",
+                room.name(),
+                screeps::game::time() - since
+            );
+        }
+        // how long refilling took, from an idle spawn that couldn't afford the head to spawned
+        if let Some(since) = room_mem.fill_wait_since.take() {
+            let waited = (now - since) as i32;
+            stats::increment_room("spawn", Some(room.name()), "fill_wait_ticks", waited);
+            stats::increment_room("spawn", Some(room.name()), "fill_waits", 1);
+        }
+    }
+
+    renewal::run_renewal(room, &mut room_mem, &idle);
+    memory::set_room_memory(room.name(), &room_mem)?;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(role: Role, budget: Option<u32>) -> SpawnRequest {
+        SpawnRequest {
+            budget,
+            ..SpawnRequest::new(role, 50)
+        }
+    }
+
+    #[test]
+    fn rcl1_bodies_fit_the_spawn_alone() {
+        for role in Role::ALL.iter().copied() {
+            let body = role.body(800, 300);
+            assert!(body_cost(&body) <= 300, "{:?}", role);
+        }
+        assert!(!Role::Worker.body(300, 300).is_empty());
+        assert!(!Role::Harvester.body(300, 300).is_empty());
+        assert_eq!(request(Role::Worker, Some(800)).design_energy(300), 300);
+    }
+
+    #[test]
+    fn rcl2_bodies_use_the_extensions() {
+        assert_eq!(body_cost(&Role::Harvester.body(550, 550)), 550);
+        let worker = Role::Worker.body(550, 550);
+        assert!(body_cost(&worker) > 300 && body_cost(&worker) <= 550);
+        assert_eq!(request(Role::Worker, None).design_energy(550), 550);
+    }
+
+    #[test]
+    fn lost_extensions_downgrade_after_deadlock_ticks() {
+        let mut r = request(Role::Upgrader, Some(800));
+        assert_eq!(downgrade(&mut r, 500, 1000), None);
+        assert_eq!(r.unaffordable_since, Some(1000));
+        assert_eq!(downgrade(&mut r, 500, 1000 + DEADLOCK_TICKS - 1), None);
+        assert_eq!(downgrade(&mut r, 500, 1000 + DEADLOCK_TICKS), Some(800));
+        assert_eq!((r.budget, r.unaffordable_since), (Some(500), None));
+        assert_eq!(downgrade(&mut r, 500, 5000), None);
+    }
+
+    #[test]
+    fn rebuilt_extensions_clear_the_wait() {
+        let mut r = request(Role::Upgrader, Some(800));
+        downgrade(&mut r, 500, 1000);
+        assert_eq!(downgrade(&mut r, 800, 1050), None);
+        assert_eq!((r.budget, r.unaffordable_since), (Some(800), None));
+        let mut unbudgeted = request(Role::Worker, None);
+        assert_eq!(downgrade(&mut unbudgeted, 300, 1000), None);
+    }
+    #[test]
+    fn losing_the_only_spawn_places_one_site() {
+        let mut sites = 0;
+        let mut placed = 0;
+        for _ in 0..10 {
+            match recovery(0, sites) {
+                SpawnRecovery::PlaceSite => {
+                    placed += 1;
+                    sites += 1;
+                }
+                SpawnRecovery::Rebuilding => {}
+                SpawnRecovery::Spawning => panic!("no spawn to spawn from"),
+            }
+        }
+        assert_eq!(placed, 1);
+        assert_eq!(recovery(1, 0), SpawnRecovery::Spawning);
+        assert_eq!(recovery(1, 1), SpawnRecovery::Spawning);
+    }
+
+    #[test]
+    fn the_queue_resumes_once_the_spawn_is_rebuilt() {
+        let mut mem = RoomMemory::default();
+        mem.enqueue(request(Role::Worker, None));
+        let mut harvester = request(Role::Harvester, None);
+        harvester.priority = 90;
+        mem.enqueue(harvester);
+        let waiting: Vec<Role> = mem.spawn_queue.iter().map(|r| r.role).collect();
+
+        // the room rebuilds for a while; nothing takes entries off the queue
+        assert_eq!(recovery(0, 1), SpawnRecovery::Rebuilding);
+        let kept: Vec<Role> = mem.spawn_queue.iter().map(|r| r.role).collect();
+        assert_eq!(kept, waiting);
+
+        assert_eq!(recovery(1, 0), SpawnRecovery::Spawning);
+        assert_eq!(mem.spawn_queue.first().map(|r| r.role), Some(Role::Harvester));
+    }
+    /// Room memory whose spawn energy has been filling at 10 a tick.
+    fn filling() -> RoomMemory {
+        let mut mem = RoomMemory::default();
+        for energy in &[0, 10, 20, 30] {
+            mem.record_energy(*energy);
+        }
+        mem
+    }
+
+    #[test]
+    fn urgent_entries_and_rooms_without_refillers_never_wait() {
+        let mem = filling();
+        assert_eq!(forecast_wait(&mem, 300, 400, true, true, 20), None);
+        assert_eq!(forecast_wait(&mem, 300, 400, false, false, 20), None);
+    }
+
+    #[test]
+    fn no_wait_without_a_fill_rate_or_once_full() {
+        let mut flat = RoomMemory::default();
+        flat.record_energy(300);
+        flat.record_energy(300);
+        assert_eq!(forecast_wait(&flat, 300, 400, true, false, 20), None);
+        assert_eq!(forecast_wait(&RoomMemory::default(), 300, 400, true, false, 20), None);
+        assert_eq!(forecast_wait(&filling(), 400, 400, true, false, 20), None);
+        assert_eq!(forecast_wait(&filling(), 500, 400, true, false, 20), None);
+    }
+
+    #[test]
+    fn waits_only_inside_the_window() {
+        let mem = filling();
+        assert_eq!(forecast_wait(&mem, 300, 500, true, false, 20), Some(20));
+        assert_eq!(forecast_wait(&mem, 300, 510, true, false, 20), None);
+        assert_eq!(forecast_wait(&mem, 300, 301, true, false, 20), Some(1));
+    }
+}
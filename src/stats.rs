@@ -0,0 +1,44 @@
+use log::*;
+use screeps::{memory::MemoryReference, RoomName};
+
+use crate::error::BotError;
+
+/// Returns `Memory.stats.<section>`, creating it if needed.
+pub fn section(name: &str) -> Option<MemoryReference> {
+    let res = screeps::memory::root()
+        .dict_or_create("stats")
+        .and_then(|stats| stats.dict_or_create(name));
+    match res {
+        Ok(dict) => Some(dict),
+        Err(e) => {
+            warn!("Memory.stats.{} is not a dict: {}", name, e);
+            None
+        }
+    }
+}
+
+pub fn increment(section_name: &str, key: &str, by: i32) {
+    if let Some(dict) = section(section_name) {
+        let current = dict.i32(key).ok().flatten().unwrap_or(0);
+        dict.set(key, current + by);
+    }
+}
+
+/// Like `increment`, but under `Memory.stats.<section>.<room>`.
+pub fn increment_room(section_name: &str, room: Option<RoomName>, key: &str, by: i32) {
+    let room = match room {
+        Some(r) => r.to_string(),
+        None => return,
+    };
+    let dict = section(section_name).and_then(|s| s.dict_or_create(&room).ok());
+    if let Some(dict) = dict {
+        let current = dict.i32(key).ok().flatten().unwrap_or(0);
+        dict.set(key, current + by);
+    }
+}
+
+/// Bumps the cumulative counter for this error's variant, so spikes show up as a change in
+/// rate on whatever is graphing `Memory.stats.errors`.
+pub fn record_error(err: &BotError) {
+    increment("errors", err.kind(), 1);
+}
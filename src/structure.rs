@@ -0,0 +1,162 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use log::*;
+use screeps::{prelude::*, Structure, StructureType};
+
+use crate::{error::BotError, labs, links, rampart, tower};
+
+/// An unhandled structure type is only warned about this often.
+const UNHANDLED_WARN_TICKS: u32 = 1000;
+
+thread_local! {
+    static UNHANDLED: RefCell<HashMap<String, u32>> = RefCell::new(HashMap::new());
+}
+
+/// Per-tick logic for the structures of the types it `wants`. `run_structure` hands each
+/// structure to the first manager in `MANAGERS` that wants its type.
+trait StructureManager {
+    fn name(&self) -> &'static str;
+    fn wants(&self, ty: StructureType) -> bool;
+    fn run(&self, structure: &Structure) -> Result<(), BotError>;
+}
+
+/// Logs a structure handed to a manager that doesn't take its type, so a wrong `wants` is a
+/// warning rather than a panic.
+fn mismatch(manager: &dyn StructureManager, structure: &Structure) -> Result<(), BotError> {
+    warn!(
+        "{} manager was handed a {:?} ({})",
+        manager.name(),
+        structure.structure_type(),
+        structure.untyped_id()
+    );
+    Ok(())
+}
+
+struct Labs;
+
+impl StructureManager for Labs {
+    fn name(&self) -> &'static str {
+        "lab"
+    }
+    fn wants(&self, ty: StructureType) -> bool {
+        ty == StructureType::Lab
+    }
+    fn run(&self, structure: &Structure) -> Result<(), BotError> {
+        match structure {
+            Structure::Lab(lab) => labs::run_lab(lab),
+            other => mismatch(self, other),
+        }
+    }
+}
+
+struct Links;
+
+impl StructureManager for Links {
+    fn name(&self) -> &'static str {
+        "link"
+    }
+    fn wants(&self, ty: StructureType) -> bool {
+        ty == StructureType::Link
+    }
+    fn run(&self, structure: &Structure) -> Result<(), BotError> {
+        match structure {
+            Structure::Link(link) => links::run_link(link),
+            other => mismatch(self, other),
+        }
+    }
+}
+
+struct Ramparts;
+
+impl StructureManager for Ramparts {
+    fn name(&self) -> &'static str {
+        "rampart"
+    }
+    fn wants(&self, ty: StructureType) -> bool {
+        ty == StructureType::Rampart
+    }
+    fn run(&self, structure: &Structure) -> Result<(), BotError> {
+        match structure {
+            Structure::Rampart(r) => rampart::run_rampart(r),
+            other => mismatch(self, other),
+        }
+    }
+}
+
+struct Towers;
+
+impl StructureManager for Towers {
+    fn name(&self) -> &'static str {
+        "tower"
+    }
+    fn wants(&self, ty: StructureType) -> bool {
+        ty == StructureType::Tower
+    }
+    fn run(&self, structure: &Structure) -> Result<(), BotError> {
+        match structure {
+            Structure::Tower(t) => tower::run_tower(t),
+            other => mismatch(self, other),
+        }
+    }
+}
+
+/// Structures run from somewhere other than the structure phase, or that never act: spawns
+/// work through `spawn::run_spawns`, the controller and terminal through the room pass and
+/// siege support, and the rest are only ever filled, emptied or repaired by creeps.
+struct Passive;
+
+impl StructureManager for Passive {
+    fn name(&self) -> &'static str {
+        "passive"
+    }
+    fn wants(&self, ty: StructureType) -> bool {
+        match ty {
+            StructureType::Spawn
+            | StructureType::Controller
+            | StructureType::Terminal
+            | StructureType::Extension
+            | StructureType::Storage
+            | StructureType::Container
+            | StructureType::Road
+            | StructureType::Wall
+            | StructureType::Extractor => true,
+            _ => false,
+        }
+    }
+    fn run(&self, _structure: &Structure) -> Result<(), BotError> {
+        Ok(())
+    }
+}
+
+static MANAGERS: &[&(dyn StructureManager + Sync)] =
+    &[&Labs, &Links, &Ramparts, &Towers, &Passive];
+
+/// Warns that nothing runs structures of type `ty`, at most every `UNHANDLED_WARN_TICKS`.
+fn warn_unhandled(ty: StructureType) {
+    let now = screeps::game::time();
+    let due = UNHANDLED.with(|u| {
+        let mut u = u.borrow_mut();
+        let last = u.entry(format!("{:?}", ty)).or_insert(0);
+        if *last == 0 || now.saturating_sub(*last) >= UNHANDLED_WARN_TICKS {
+            *last = now;
+            true
+        } else {
+            false
+        }
+    });
+    if due {
+        warn!("no structure manager runs {:?} structures", ty);
+    }
+}
+
+/// Runs `structure` through the manager for its type.
+pub fn run_structure(structure: &Structure) -> Result<(), BotError> {
+    let ty = structure.structure_type();
+    match MANAGERS.iter().find(|m| m.wants(ty)) {
+        Some(manager) => manager.run(structure),
+        None => {
+            warn_unhandled(ty);
+            Ok(())
+        }
+    }
+}
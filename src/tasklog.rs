@@ -0,0 +1,98 @@
+use std::collections::VecDeque;
+
+use log::*;
+use screeps::{memory::MemoryReference, prelude::*, Creep, Position};
+use serde::{Deserialize, Serialize};
+use stdweb::{js_deserializable, js_serializable};
+
+use crate::{creep::Task, memory, settings};
+
+const MAX_ENTRIES: usize = 50;
+
+/// The last ticks of a flagged creep's life, kept in its memory under `tasklog`. Entries are
+/// `(tick, task code, packed position)` so a full log stays at a few hundred bytes.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TaskLog {
+    pub entries: VecDeque<(u32, u8, u32)>,
+    /// Ticks to live as of the last entry; tells old age apart from getting killed.
+    #[serde(default)]
+    pub ttl: u32,
+}
+
+js_serializable!(TaskLog);
+js_deserializable!(TaskLog);
+
+/// Logging is opt-in: `log_tasks` in the creep's memory, or `Memory.settings.log_tasks_<role>`.
+fn enabled(creep: &Creep) -> bool {
+    if memory::creep_memory(creep).bool("log_tasks") {
+        return true;
+    }
+    match memory::creep_role(creep) {
+        Ok(Some(role)) => settings::flag(&format!("log_tasks_{:?}", role).to_lowercase()),
+        _ => false,
+    }
+}
+
+pub fn record(creep: &Creep, task: Task) {
+    if !enabled(creep) {
+        return;
+    }
+    let mem = memory::creep_memory(creep);
+    let mut log = mem
+        .get::<TaskLog>("tasklog")
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    log.entries.push_back((
+        screeps::game::time(),
+        task.code(),
+        creep.pos().packed_repr(),
+    ));
+    while log.entries.len() > MAX_ENTRIES {
+        log.entries.pop_front();
+    }
+    log.ttl = creep.ticks_to_live();
+    mem.set("tasklog", &log);
+}
+
+/// The last `n` entries of a living creep's log, oldest first; empty when it isn't logging.
+pub fn recent(creep: &Creep, n: usize) -> Vec<String> {
+    let log = match memory::creep_memory(creep).get::<TaskLog>("tasklog") {
+        Ok(Some(log)) => log,
+        _ => return Vec::new(),
+    };
+    let skip = log.entries.len().saturating_sub(n);
+    log.entries
+        .iter()
+        .skip(skip)
+        .map(|(tick, code, pos)| {
+            let task = match Task::from_code(*code) {
+                Some(t) => format!("{:?}", t),
+                None => code.to_string(),
+            };
+            format!("{} {} {}", tick, task, Position::from_packed(*pos))
+        })
+        .collect()
+}
+
+/// Dumps a dead creep's log unless it simply died of old age.
+pub fn report_death(name: &str, mem: &MemoryReference) {
+    let log = match mem.get::<TaskLog>("tasklog") {
+        Ok(Some(log)) => log,
+        _ => return,
+    };
+    if log.ttl <= 1 {
+        return;
+    }
+    let entries: Vec<String> = log
+        .entries
+        .iter()
+        .map(|(tick, task, pos)| format!("{} {} {}", tick, task, Position::from_packed(*pos)))
+        .collect();
+    debug!(
+        "{} died with {} ticks to live, last tasks:\n{}",
+        name,
+        log.ttl,
+        entries.join("\n")
+    );
+}
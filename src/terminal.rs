@@ -0,0 +1,141 @@
+use log::*;
+use screeps::{prelude::*, ResourceType, ReturnCode, Room, RoomName};
+
+use crate::{
+    coord,
+    error::{self, BotError},
+    intents, memory,
+    room::{RoomMemory, RoomMode},
+    settings,
+};
+
+/// Storage energy a room needs before it starts sending to a besieged room.
+const HIGH_WATER: u32 = 200_000;
+/// A sender stops once its own storage drops below this.
+const RESERVE_FLOOR: u32 = 50_000;
+const SEND_AMOUNT: u32 = 5_000;
+/// Default for `Memory.settings.mature_surplus`: storage energy above which a `Mature` room
+/// ships the excess to a room still levelling up.
+const MATURE_SURPLUS: u32 = 300_000;
+/// Default for `Memory.settings.developing_low`: a developing room takes surplus energy while
+/// its storage is below this.
+const DEVELOPING_LOW: u32 = 100_000;
+
+/// Under critical threat and not marked `Memory.settings.let_it_die_<room>`.
+pub fn besieged(room: RoomName, mem: &RoomMemory) -> bool {
+    mem.critical_threat && !settings::flag(&format!("let_it_die_{}", room))
+}
+
+fn storage_energy(room: &Room) -> u32 {
+    room.storage().map_or(0, |s| s.store_of(ResourceType::Energy))
+}
+
+/// Besieged rooms with a terminal to receive energy.
+fn besieged_rooms(rooms: &[(Room, RoomMemory)]) -> Vec<RoomName> {
+    rooms
+        .iter()
+        .filter(|(room, mem)| room.terminal().is_some() && besieged(room.name(), mem))
+        .map(|(room, _)| room.name())
+        .collect()
+}
+
+/// The nearest owned room below RCL 8 with storage under the low mark, for a `Mature` room
+/// to send its surplus to.
+fn developing_room(from: &Room, rooms: &[(Room, RoomMemory)]) -> Option<RoomName> {
+    let low = settings::u32_or("developing_low", DEVELOPING_LOW);
+    rooms
+        .iter()
+        .filter(|(room, mem)| room.name() != from.name() && mem.rcl < 8)
+        .filter(|(room, _)| room.storage().is_some() && storage_energy(room) < low)
+        .map(|(room, _)| room.name())
+        .min_by_key(|t| coord::distance(from.name(), *t).unwrap_or(u32::MAX))
+}
+
+/// Picks (or keeps) the room `room` is supporting: a sender starts above the high-water mark
+/// and keeps going until the siege ends or it hits its reserve floor.
+fn pick_target(room: &Room, mem: &mut RoomMemory, besieged: &[RoomName]) -> Option<RoomName> {
+    let energy = storage_energy(room);
+    let current = mem
+        .supporting
+        .as_ref()
+        .and_then(|t| t.parse::<RoomName>().ok())
+        .filter(|t| besieged.contains(t));
+    let target = match current {
+        Some(t) if energy >= settings::u32_or("siege_reserve_floor", RESERVE_FLOOR) => Some(t),
+        Some(_) => None,
+        None if energy >= settings::u32_or("siege_high_water", HIGH_WATER) => besieged
+            .iter()
+            .filter(|t| **t != room.name())
+            .min_by_key(|t| coord::distance(room.name(), **t).unwrap_or(u32::MAX))
+            .cloned(),
+        None => None,
+    };
+    let label = target.map(|t| t.to_string());
+    if label != mem.supporting {
+        match &label {
+            Some(t) => info!("{} sending energy to besieged {}", room.name(), t),
+            None => info!("{} stopped siege support", room.name()),
+        }
+        mem.supporting = label;
+    }
+    target
+}
+
+fn send(room: &Room, target: RoomName, memo: &str) -> Result<(), BotError> {
+    let terminal = match room.terminal() {
+        Some(t) if t.cooldown() == 0 => t,
+        _ => return Ok(()),
+    };
+    let cost = screeps::game::market::calc_transaction_cost(SEND_AMOUNT, room.name(), target);
+    if terminal.store_of(ResourceType::Energy) < SEND_AMOUNT + cost {
+        return Ok(());
+    }
+    let r = intents::issue(
+        "terminal",
+        "send",
+        &target.to_string(),
+        Some(SEND_AMOUNT),
+        || terminal.send(ResourceType::Energy, SEND_AMOUNT, target, Some(memo)),
+    );
+    match r {
+        // another terminal already filled it up this tick
+        ReturnCode::Full => Ok(()),
+        r => error::check("terminal send", r),
+    }
+}
+
+/// Siege support: every tick a terminal is off cooldown, rooms with energy to spare send some
+/// to a besieged room. A `Mature` room supporting nobody sends its surplus over
+/// `MATURE_SURPLUS` to a developing room instead, and banks the rest.
+pub fn run_siege_support() -> Result<(), BotError> {
+    let mut rooms = Vec::new();
+    for room in screeps::game::rooms::values() {
+        if room.controller().map_or(false, |c| c.my()) && room.terminal().is_some() {
+            let mem = memory::get_room_memory(room.name())?;
+            rooms.push((room, mem));
+        }
+    }
+    let besieged = besieged_rooms(&rooms);
+    let surplus = settings::u32_or("mature_surplus", MATURE_SURPLUS);
+    for i in 0..rooms.len() {
+        let (room, mem) = &mut rooms[i];
+        let before = mem.supporting.clone();
+        let target = pick_target(room, mem, &besieged);
+        if mem.supporting != before {
+            memory::set_room_memory(room.name(), mem)?;
+        }
+        let mature = mem.mode == RoomMode::Mature && storage_energy(room) > surplus;
+        let room = room.clone();
+        let send_to = match target {
+            Some(t) => Some((t, "siege support")),
+            None if mature => developing_room(&room, &rooms).map(|t| (t, "surplus")),
+            None => None,
+        };
+        if let Some((target, memo)) = send_to {
+            if let Err(e) = send(&room, target, memo) {
+                error::report("terminal", &room.name().to_string(), &target.to_string(), &e);
+            }
+        }
+    }
+    Ok(())
+}
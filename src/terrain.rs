@@ -0,0 +1,137 @@
+use screeps::{Direction, RoomName, Terrain};
+
+pub const ROOM_SIZE: usize = 50;
+
+#[inline]
+pub fn index(x: usize, y: usize) -> usize {
+    y * ROOM_SIZE + x
+}
+
+/// `true` for every terrain wall tile, row-major.
+pub fn walls(room: RoomName) -> Vec<bool> {
+    let terrain = screeps::game::map::get_room_terrain(room);
+    let mut walls = vec![false; ROOM_SIZE * ROOM_SIZE];
+    for y in 0..ROOM_SIZE {
+        for x in 0..ROOM_SIZE {
+            walls[index(x, y)] = terrain.get(x as u32, y as u32) == Terrain::Wall;
+        }
+    }
+    walls
+}
+
+/// Whether the edge of a room on the `side` of it has any tile that isn't a wall, and so an
+/// exit to the room next door. Only the four straight sides have exits.
+pub fn exit_open(walls: &[bool], side: Direction) -> bool {
+    let last = ROOM_SIZE - 1;
+    (0..ROOM_SIZE).any(|i| {
+        let (x, y) = match side {
+            Direction::Top => (i, 0),
+            Direction::Right => (last, i),
+            Direction::Bottom => (i, last),
+            Direction::Left => (0, i),
+            _ => return false,
+        };
+        !walls[index(x, y)]
+    })
+}
+
+/// Rows `distance_rows` works through for a whole room: one forward and one backward pass.
+pub const DISTANCE_ROWS: usize = 2 * ROOM_SIZE;
+
+/// Chebyshev distance from each tile to the nearest blocked tile, with everything outside the
+/// room counted as blocked. Two passes, so it's linear in the room size.
+pub fn distance_transform(blocked: &[bool]) -> Vec<u8> {
+    let mut dist = vec![0u8; ROOM_SIZE * ROOM_SIZE];
+    distance_rows(blocked, &mut dist, 0, DISTANCE_ROWS);
+    dist
+}
+
+/// Works `distance_transform` into `dist` for up to `rows` more of its `DISTANCE_ROWS` rows,
+/// `done` of them having been worked already, and returns how many are done now. The forward
+/// pass is rows `0..ROOM_SIZE`, the backward pass the rest.
+pub fn distance_rows(blocked: &[bool], dist: &mut [u8], done: usize, rows: usize) -> usize {
+    let get = |dist: &[u8], x: isize, y: isize| -> u8 {
+        if x < 0 || y < 0 || x >= ROOM_SIZE as isize || y >= ROOM_SIZE as isize {
+            0
+        } else {
+            dist[index(x as usize, y as usize)]
+        }
+    };
+
+    let end = (done + rows).min(DISTANCE_ROWS);
+    for row in done..end {
+        if row < ROOM_SIZE {
+            let y = row as isize;
+            for x in 0..ROOM_SIZE as isize {
+                let i = index(x as usize, y as usize);
+                if blocked[i] {
+                    dist[i] = 0;
+                    continue;
+                }
+                let m = get(dist, x - 1, y)
+                    .min(get(dist, x, y - 1))
+                    .min(get(dist, x - 1, y - 1))
+                    .min(get(dist, x + 1, y - 1));
+                dist[i] = m.saturating_add(1);
+            }
+        } else {
+            let y = (DISTANCE_ROWS - 1 - row) as isize;
+            for x in (0..ROOM_SIZE as isize).rev() {
+                let i = index(x as usize, y as usize);
+                if blocked[i] {
+                    continue;
+                }
+                let m = get(dist, x + 1, y)
+                    .min(get(dist, x, y + 1))
+                    .min(get(dist, x + 1, y + 1))
+                    .min(get(dist, x - 1, y + 1));
+                dist[i] = dist[i].min(m.saturating_add(1));
+            }
+        }
+    }
+    end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_open_reads_each_edge() {
+        let mut walls = vec![false; ROOM_SIZE * ROOM_SIZE];
+        for i in 0..ROOM_SIZE {
+            walls[index(i, 0)] = true;
+            walls[index(0, i)] = true;
+        }
+        walls[index(0, 20)] = false;
+        assert!(!exit_open(&walls, Direction::Top));
+        assert!(exit_open(&walls, Direction::Left));
+        assert!(exit_open(&walls, Direction::Right));
+        assert!(exit_open(&walls, Direction::Bottom));
+        assert!(!exit_open(&walls, Direction::TopLeft));
+    }
+    #[test]
+    fn distance_rows_in_slices_matches_one_pass() {
+        let mut blocked = vec![false; ROOM_SIZE * ROOM_SIZE];
+        for i in 0..ROOM_SIZE {
+            blocked[index(i, 17)] = i % 7 != 0;
+            blocked[index(31, i)] = i % 5 == 0;
+        }
+        let whole = distance_transform(&blocked);
+        let mut sliced = vec![0u8; ROOM_SIZE * ROOM_SIZE];
+        let mut done = 0;
+        while done < DISTANCE_ROWS {
+            done = distance_rows(&blocked, &mut sliced, done, 7);
+        }
+        assert_eq!(sliced, whole);
+        assert_eq!(distance_rows(&blocked, &mut sliced, done, 7), DISTANCE_ROWS);
+    }
+
+    #[test]
+    fn distance_transform_counts_the_room_edge_as_blocked() {
+        let dist = distance_transform(&vec![false; ROOM_SIZE * ROOM_SIZE]);
+        assert_eq!(dist[index(0, 0)], 1);
+        assert_eq!(dist[index(1, 3)], 2);
+        assert_eq!(dist[index(24, 24)], 25);
+    }
+}
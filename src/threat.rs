@@ -0,0 +1,555 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+};
+
+use log::*;
+use screeps::{
+    prelude::*, Creep, ObjectId, Part, Position, ResourceType, ReturnCode, Room, RoomName,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    allies, diplomacy, intents, objects,
+    perimeter::{self, ExitSide},
+    room::RoomMemory,
+    settings, stats,
+    terrain::{self, ROOM_SIZE},
+};
+
+thread_local! {
+    static LOCKDOWN: RefCell<HashSet<RoomName>> = RefCell::new(HashSet::new());
+    static CRITICAL: RefCell<HashSet<RoomName>> = RefCell::new(HashSet::new());
+    /// Where each room's hostiles should be next tick, by creep id, and the tick it's for.
+    static PREDICTED: RefCell<HashMap<RoomName, (u32, HashMap<String, Position>)>> =
+        RefCell::new(HashMap::new());
+    /// Each room's border campers by creep id, as of its last room pass.
+    static CAMPERS: RefCell<HashMap<RoomName, HashSet<String>>> = RefCell::new(HashMap::new());
+    /// Tower energy held back from each camper since its room's last pass.
+    static HELD: RefCell<HashMap<String, u32>> = RefCell::new(HashMap::new());
+}
+
+/// Ticks of hostile positions kept for defenders to judge where they're headed.
+const TRAIL_TICKS: usize = 10;
+/// Ticks of each hostile's own positions kept to predict its next step.
+const TRACK_TICKS: usize = 3;
+/// A room attacked this many times within `HARASSMENT_TICKS` is being harassed...
+const HARASSED_ATTACKS: usize = 3;
+const HARASSMENT_TICKS: u32 = 20_000;
+/// ...and wants this much attack boost on hand for its defenders.
+const DEFENDER_BOOST: u32 = 1500;
+/// Hostiles within this many tiles of an exit count as at the border.
+const EXIT_MARGIN: u32 = 2;
+/// A hostile has to be seen this many ticks before it can be called a border camper...
+const CAMPER_MIN_TICKS: u32 = 20;
+/// ...and spend more than this percentage of them at the border; default for
+/// `Memory.settings.border_camper_percent`.
+const CAMPER_PERCENT: u32 = 75;
+/// Default for `Memory.settings.border_camper_fire_range`: towers only fire at a border camper
+/// this close, where a shot still does most of its damage.
+const CAMPER_FIRE_RANGE: u32 = 10;
+/// A hostile unseen this long is forgotten; no creep lives longer.
+const WATCH_TICKS: u32 = 1500;
+
+const EVENT_ATTACK: u8 = 1;
+const EVENT_OBJECT_DESTROYED: u8 = 2;
+const EVENT_HEAL: u8 = 6;
+
+/// Running totals for one hostile player over an attack.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AttackerTotals {
+    #[serde(default)]
+    pub damage: u32,
+    #[serde(default)]
+    pub healed: u32,
+    /// Destroyed object counts by type.
+    #[serde(default)]
+    pub destroyed: HashMap<String, u32>,
+}
+
+/// How a hostile has spent the ticks it's been seen in the room, to catch the ones that step
+/// in to draw tower fire at long range and step back out.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct BorderWatch {
+    pub owner: String,
+    #[serde(default)]
+    pub seen: u32,
+    /// Ticks of `seen` spent within `EXIT_MARGIN` of an exit.
+    #[serde(default)]
+    pub near_exit: u32,
+    #[serde(default)]
+    pub last_seen: u32,
+    #[serde(default)]
+    pub camper: bool,
+    /// Tower energy not spent on it since it was marked.
+    #[serde(default)]
+    pub held: u32,
+}
+
+/// One entry of `Room.getEventLog(true)`, with `data` left untyped so unknown event types
+/// don't break parsing.
+#[derive(Deserialize)]
+struct RawEvent {
+    event: u8,
+    #[serde(rename = "objectId", default)]
+    object_id: String,
+    #[serde(default)]
+    data: serde_json::Value,
+}
+
+/// Owner of the creep behind `id`, if it's still alive to ask.
+fn owner_of(id: &str) -> Option<String> {
+    id.parse::<ObjectId<Creep>>()
+        .ok()
+        .and_then(objects::get_cached)
+        .map(|c| c.owner_name())
+}
+
+fn data_u32(data: &serde_json::Value, key: &str) -> u32 {
+    data.get(key).and_then(|v| v.as_u64()).unwrap_or(0) as u32
+}
+
+/// Adds last tick's hostile attacks, heals and kills in `room` to `attackers`.
+fn record_events(room: &Room, attackers: &mut HashMap<String, AttackerTotals>) {
+    let events: Vec<RawEvent> = match serde_json::from_str(&room.get_event_log_raw()) {
+        Ok(e) => e,
+        Err(e) => {
+            warn!("{} couldn't parse the event log: {}", room.name(), e);
+            return;
+        }
+    };
+    // who hit each target last; kills are credited to them
+    let mut last_hit: HashMap<String, String> = HashMap::new();
+
+    for event in events {
+        match event.event {
+            EVENT_ATTACK | EVENT_HEAL => {
+                let owner = match owner_of(&event.object_id) {
+                    Some(o) if allies::is_hostile(&o) => o,
+                    Some(o) if allies::is_ally(&o) && event.event == EVENT_ATTACK => {
+                        let damage = data_u32(&event.data, "damage");
+                        info!("{} friendly fire: {} dealt {} damage", room.name(), o, damage);
+                        let room_name = Some(room.name());
+                        stats::increment_room("threat", room_name, "friendly_fire", damage as i32);
+                        continue;
+                    }
+                    _ => continue,
+                };
+                let totals = attackers.entry(owner.clone()).or_default();
+                if event.event == EVENT_ATTACK {
+                    totals.damage += data_u32(&event.data, "damage");
+                    if let Some(target) = event.data.get("targetId").and_then(|t| t.as_str()) {
+                        last_hit.insert(target.to_owned(), owner);
+                    }
+                } else {
+                    totals.healed += data_u32(&event.data, "amount");
+                }
+            }
+            EVENT_OBJECT_DESTROYED => {
+                let owner = match last_hit.get(&event.object_id) {
+                    Some(o) => o.clone(),
+                    None => continue,
+                };
+                let kind = event
+                    .data
+                    .get("type")
+                    .and_then(|t| t.as_str())
+                    .unwrap_or("object")
+                    .to_owned();
+                *attackers
+                    .entry(owner)
+                    .or_default()
+                    .destroyed
+                    .entry(kind)
+                    .or_insert(0) += 1;
+            }
+            _ => {}
+        }
+    }
+}
+
+fn with_commas(n: u32) -> String {
+    let digits = n.to_string();
+    let mut out = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            out.push(',');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// One line per attacker, most damage first, with our stance towards them, e.g. "PlayerX
+/// (hostile since tick 1234) dealt 4,200 damage, destroyed 3 extensions".
+pub fn summarize(attackers: &HashMap<String, AttackerTotals>) -> String {
+    let mut players: Vec<(&String, &AttackerTotals)> = attackers.iter().collect();
+    players.sort_by_key(|(_, t)| std::cmp::Reverse(t.damage));
+    let lines: Vec<String> = players
+        .into_iter()
+        .map(|(player, t)| {
+            let mut line = format!(
+                "{} ({}) dealt {} damage",
+                player,
+                diplomacy::describe(player),
+                with_commas(t.damage)
+            );
+            if t.healed > 0 {
+                line.push_str(&format!(", healed {}", with_commas(t.healed)));
+            }
+            let mut destroyed: Vec<(&String, &u32)> = t.destroyed.iter().collect();
+            destroyed.sort();
+            for (kind, count) in destroyed {
+                let plural = if *count == 1 { "" } else { "s" };
+                line.push_str(&format!(", destroyed {} {}{}", count, kind, plural));
+            }
+            line
+        })
+        .collect();
+    lines.join("\n")
+}
+
+/// Whether our pathing in `room` is kept inside the ramparts: on during a critical threat, or
+/// by hand with `Memory.settings.lockdown_<room>`.
+pub fn locked_down(room: RoomName) -> bool {
+    LOCKDOWN.with(|l| l.borrow().contains(&room))
+}
+
+/// Whether `room` has player creeps in it that can do damage, as of its room pass this tick.
+pub fn critical(room: RoomName) -> bool {
+    CRITICAL.with(|c| c.borrow().contains(&room))
+}
+
+/// Player creeps that can do damage; NPC invaders are left to the towers.
+fn dangerous(creep: &Creep) -> bool {
+    creep.owner_name() != "Invader"
+        && [Part::Attack, Part::RangedAttack, Part::Work, Part::Heal]
+            .iter()
+            .any(|p| creep.get_active_bodyparts(*p) > 0)
+}
+
+/// Adds where the hostiles are, on average, to the room's trail.
+fn record_trail(room: &Room, hostiles: &[Creep], mem: &mut RoomMemory) {
+    if hostiles.is_empty() {
+        mem.hostile_trail.clear();
+        return;
+    }
+    let n = hostiles.len() as u32;
+    let x = hostiles.iter().map(|h| h.pos().x()).sum::<u32>() / n;
+    let y = hostiles.iter().map(|h| h.pos().y()).sum::<u32>() / n;
+    mem.hostile_trail.push_back(Position::new(x, y, room.name()).packed_repr());
+    while mem.hostile_trail.len() > TRAIL_TICKS {
+        mem.hostile_trail.pop_front();
+    }
+}
+
+/// Boosts the room's defence wants in stock: attack boost for its defenders once it's being
+/// harassed.
+pub fn boost_demand(mem: &RoomMemory) -> Vec<(ResourceType, u32)> {
+    if mem.attack_starts.len() >= HARASSED_ATTACKS {
+        vec![(ResourceType::CatalyzedUtriumAcid, DEFENDER_BOOST)]
+    } else {
+        Vec::new()
+    }
+}
+
+/// Where a creep seen on the tiles of `track`, oldest first and a tick apart, should be next
+/// tick: another step the way it last moved, or back where it came from if that last step
+/// reversed the one before, as a creep strafing in and out of range does. A step into a wall
+/// or off the room leaves it where it is.
+pub fn predict(track: &[(u32, u32)], walls: &[bool]) -> Option<(u32, u32)> {
+    let n = track.len();
+    let last = *track.last()?;
+    if n < 2 {
+        return Some(last);
+    }
+    let step = |a: (u32, u32), b: (u32, u32)| {
+        ((b.0 as i32 - a.0 as i32).signum(), (b.1 as i32 - a.1 as i32).signum())
+    };
+    let v = step(track[n - 2], last);
+    if n >= 3 && v != (0, 0) && step(track[n - 3], track[n - 2]) == (-v.0, -v.1) {
+        return Some(track[n - 2]);
+    }
+    let (x, y) = (last.0 as i32 + v.0, last.1 as i32 + v.1);
+    let inside = |c: i32| c >= 0 && c < ROOM_SIZE as i32;
+    if !inside(x) || !inside(y) || walls[terrain::index(x as usize, y as usize)] {
+        return Some(last);
+    }
+    Some((x as u32, y as u32))
+}
+
+/// Adds where each hostile is to its track and predicts its next tile. Tracks only run over
+/// consecutive ticks; one broken by a skipped room pass starts again.
+fn record_tracks(room: &Room, hostiles: &[Creep], mem: &mut RoomMemory) {
+    let now = screeps::game::time();
+    if mem.hostile_tracks_at + 1 != now {
+        mem.hostile_tracks.clear();
+    }
+    mem.hostile_tracks_at = now;
+    let walls = terrain::walls(room.name());
+    let mut tracks = HashMap::new();
+    let mut predicted = HashMap::new();
+    for hostile in hostiles {
+        let id = hostile.id().to_string();
+        let mut track = mem.hostile_tracks.remove(&id).unwrap_or_default();
+        track.push(hostile.pos().packed_repr());
+        if track.len() > TRACK_TICKS {
+            track.remove(0);
+        }
+        let tiles: Vec<(u32, u32)> = track
+            .iter()
+            .map(|p| Position::from_packed(*p))
+            .map(|p| (p.x(), p.y()))
+            .collect();
+        if let Some((x, y)) = predict(&tiles, &walls) {
+            predicted.insert(id.clone(), Position::new(x, y, room.name()));
+        }
+        tracks.insert(id, track);
+    }
+    // hostiles that left or died drop out
+    mem.hostile_tracks = tracks;
+    PREDICTED.with(|p| p.borrow_mut().insert(room.name(), (now, predicted)));
+}
+
+fn near_exit(pos: Position) -> bool {
+    let far = ROOM_SIZE as u32 - 1 - EXIT_MARGIN;
+    pos.x() <= EXIT_MARGIN || pos.y() <= EXIT_MARGIN || pos.x() >= far || pos.y() >= far
+}
+
+/// Counts the ticks each hostile spends at the border and marks the ones that mostly stay
+/// there as border campers. Logs each new camper, and what the towers held back from it once
+/// it's gone, with the running total held back from its owner.
+fn watch_borders(room: &Room, hostiles: &[Creep], mem: &mut RoomMemory) {
+    let now = screeps::game::time();
+    let percent = settings::u32_or("border_camper_percent", CAMPER_PERCENT);
+    let fire_range = camper_fire_range();
+    let held = HELD.with(|h| std::mem::take(&mut *h.borrow_mut()));
+    for (id, energy) in held {
+        if let Some(watch) = mem.border_watch.get_mut(&id) {
+            watch.held += energy;
+            *mem.camper_held.entry(watch.owner.clone()).or_insert(0) += energy;
+        }
+    }
+    for hostile in hostiles {
+        let id = hostile.id().to_string();
+        let watch = mem.border_watch.entry(id.clone()).or_insert_with(|| BorderWatch {
+            owner: hostile.owner_name(),
+            ..BorderWatch::default()
+        });
+        watch.seen += 1;
+        if near_exit(hostile.pos()) {
+            watch.near_exit += 1;
+        }
+        watch.last_seen = now;
+        let camping =
+            watch.seen >= CAMPER_MIN_TICKS && watch.near_exit * 100 > watch.seen * percent;
+        if camping && !watch.camper {
+            info!(
+                "{} {} of {} is camping the border ({} of {} ticks within {} of an exit); \
+                 towers hold fire past range {}",
+                room.name(),
+                id,
+                watch.owner,
+                watch.near_exit,
+                watch.seen,
+                EXIT_MARGIN,
+                fire_range
+            );
+        }
+        watch.camper = camping;
+    }
+    let camper_held = &mem.camper_held;
+    mem.border_watch.retain(|id, watch| {
+        let keep = now.saturating_sub(watch.last_seen) < WATCH_TICKS;
+        if !keep && watch.camper {
+            let total = camper_held.get(&watch.owner).cloned().unwrap_or(0);
+            info!(
+                "{} border camper {} of {} is gone; towers held back {} energy ({} from {} so far)",
+                room.name(),
+                id,
+                watch.owner,
+                watch.held,
+                total,
+                watch.owner
+            );
+        }
+        keep
+    });
+    let campers = hostiles
+        .iter()
+        .map(|h| h.id().to_string())
+        .filter(|id| mem.border_watch.get(id).map_or(false, |w| w.camper))
+        .collect();
+    CAMPERS.with(|c| c.borrow_mut().insert(room.name(), campers));
+}
+
+/// Whether `hostile` was marked a border camper at its room's last pass.
+pub fn border_camper(hostile: &Creep) -> bool {
+    let id = hostile.id().to_string();
+    CAMPERS.with(|c| {
+        c.borrow()
+            .get(&hostile.pos().room_name())
+            .map_or(false, |set| set.contains(&id))
+    })
+}
+
+/// Range past which towers hold fire at border campers.
+pub fn camper_fire_range() -> u32 {
+    settings::u32_or("border_camper_fire_range", CAMPER_FIRE_RANGE)
+}
+
+/// Records `energy` a tower didn't spend on the border camper `hostile`.
+pub fn note_held(hostile: &Creep, energy: u32) {
+    let id = hostile.id().to_string();
+    HELD.with(|h| *h.borrow_mut().entry(id).or_insert(0) += energy);
+}
+
+/// Where `hostile` should be next tick, from this tick's tracks; where it is now when its room
+/// wasn't tracked this tick.
+pub fn predicted_pos(hostile: &Creep) -> Position {
+    let now = screeps::game::time();
+    let pos = hostile.pos();
+    PREDICTED.with(|p| {
+        p.borrow()
+            .get(&pos.room_name())
+            .filter(|(at, _)| *at == now)
+            .and_then(|(_, by_id)| by_id.get(&hostile.id().to_string()).cloned())
+            .unwrap_or(pos)
+    })
+}
+
+/// The side the hostile nearest an edge is closest to, taken as where the attack came in.
+fn entry_side(hostiles: &[Creep]) -> Option<ExitSide> {
+    let last = ROOM_SIZE as u32 - 1;
+    hostiles
+        .iter()
+        .map(|h| (h.pos().x(), h.pos().y()))
+        .min_by_key(|&(x, y)| x.min(y).min(last - x).min(last - y))
+        .map(|(x, y)| ExitSide::nearest(x, y))
+}
+
+/// Tracks an attack on `room` from the first hostile to the last, then sends a notification
+/// with what each attacker did. The event log is skipped while the bucket is low.
+pub fn run_threat(room: &Room, mem: &mut RoomMemory) {
+    let now = screeps::game::time();
+    // allies passing through are no threat
+    let hostile_creeps = allies::hostile_creeps(room);
+    let hostiles = !hostile_creeps.is_empty();
+    let critical = hostile_creeps.iter().any(dangerous);
+    record_trail(room, &hostile_creeps, mem);
+    record_tracks(room, &hostile_creeps, mem);
+    watch_borders(room, &hostile_creeps, mem);
+    if critical != mem.critical_threat {
+        info!("{} critical threat {}", room.name(), if critical { "started" } else { "over" });
+        mem.critical_threat = critical;
+    }
+    CRITICAL.with(|c| {
+        let mut c = c.borrow_mut();
+        if critical {
+            c.insert(room.name());
+        } else {
+            c.remove(&room.name());
+        }
+    });
+    let lockdown = critical || settings::flag(&format!("lockdown_{}", room.name()));
+    if lockdown != mem.lockdown {
+        info!("{} lockdown {}", room.name(), if lockdown { "on" } else { "off" });
+        mem.lockdown = lockdown;
+    }
+    LOCKDOWN.with(|l| {
+        let mut l = l.borrow_mut();
+        if lockdown {
+            l.insert(room.name());
+        } else {
+            l.remove(&room.name());
+        }
+    });
+    if hostiles && mem.threat_since.is_none() {
+        mem.threat_since = Some(now);
+        mem.hostile_entry = entry_side(&hostile_creeps);
+        mem.attack_starts.push_back(now);
+    }
+    while mem
+        .attack_starts
+        .front()
+        .map_or(false, |t| now.saturating_sub(*t) >= HARASSMENT_TICKS)
+    {
+        mem.attack_starts.pop_front();
+    }
+    if let Some(side) = mem.hostile_entry.filter(|_| hostiles) {
+        // keeps the cached chokes current as walls go up or come down mid-attack
+        perimeter::chokes(room, mem, side);
+    }
+    let since = match mem.threat_since {
+        Some(s) => s,
+        None => return,
+    };
+
+    // the log of the tick the last hostile left still has its final hits
+    if screeps::game::cpu::bucket() as u32 >= settings::u32_or("event_log_min_bucket", 2000) {
+        record_events(room, &mut mem.attackers);
+    }
+
+    if !hostiles {
+        mem.threat_since = None;
+        let summary = summarize(&mem.attackers);
+        let message = if summary.is_empty() {
+            format!("{} had hostiles for {} ticks", room.name(), now - since)
+        } else {
+            format!(
+                "{} was attacked for {} ticks:\n{}",
+                room.name(),
+                now - since,
+                summary
+            )
+        };
+        info!("{}", message);
+        intents::issue("threat", "notify", &room.name().to_string(), None, || {
+            screeps::game::notify(&message, None);
+            ReturnCode::Ok
+        });
+        mem.attackers.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open() -> Vec<bool> {
+        vec![false; ROOM_SIZE * ROOM_SIZE]
+    }
+
+    #[test]
+    fn straight_line_carries_on() {
+        let track = [(10, 20), (11, 21), (12, 22)];
+        assert_eq!(predict(&track, &open()), Some((13, 23)));
+    }
+
+    #[test]
+    fn reversal_goes_back() {
+        let track = [(10, 20), (11, 20), (10, 20)];
+        assert_eq!(predict(&track, &open()), Some((11, 20)));
+    }
+
+    #[test]
+    fn turn_follows_last_step() {
+        let track = [(10, 20), (11, 20), (11, 21)];
+        assert_eq!(predict(&track, &open()), Some((11, 22)));
+    }
+
+    #[test]
+    fn standing_still_stays() {
+        assert_eq!(predict(&[(5, 5), (5, 5)], &open()), Some((5, 5)));
+        assert_eq!(predict(&[(7, 8)], &open()), Some((7, 8)));
+        assert_eq!(predict(&[], &open()), None);
+    }
+
+    #[test]
+    fn walls_and_edges_stop_the_step() {
+        let mut walls = open();
+        walls[terrain::index(13, 20)] = true;
+        assert_eq!(predict(&[(11, 20), (12, 20)], &walls), Some((12, 20)));
+        assert_eq!(predict(&[(1, 30), (0, 30)], &open()), Some((0, 30)));
+    }
+}
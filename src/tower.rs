@@ -0,0 +1,126 @@
+use std::cmp::Reverse;
+
+use screeps::{
+    prelude::*, Creep, ResourceType, ReturnCode, Structure, StructureTower, StructureType,
+};
+
+use crate::{
+    accounts, allies,
+    error::{self, BotError},
+    intents, room, room_cache, settings, stats, threat, triage,
+};
+
+/// Energy every tower action costs.
+const TOWER_ACTION_COST: i32 = 10;
+/// Towers below this keep their energy for fighting instead of repairing.
+const REPAIR_RESERVE: u32 = 500;
+/// Ramparts are topped up by towers only until they're past this; the rest is for creeps.
+const FRESH_RAMPART_HITS: u32 = 10_000;
+/// A tower's attack does full damage out to `OPTIMAL_RANGE`, falling off to a quarter at
+/// `FALLOFF_RANGE` and beyond.
+const ATTACK_POWER: u32 = 600;
+const OPTIMAL_RANGE: u32 = 5;
+const FALLOFF_RANGE: u32 = 20;
+
+/// Counts the energy a tower spent on `action` under `Memory.stats.towers.<room>`, and in the
+/// room's books.
+fn account(tower: &StructureTower, action: &str) {
+    let room = tower.room().map(|r| r.name());
+    stats::increment_room("towers", room, action, TOWER_ACTION_COST);
+    if let Some(room) = room {
+        accounts::spent(room, TOWER_ACTION_COST as u32);
+    }
+}
+
+fn attack_damage(range: u32) -> u32 {
+    let falloff = range.max(OPTIMAL_RANGE).min(FALLOFF_RANGE) - OPTIMAL_RANGE;
+    ATTACK_POWER - ATTACK_POWER * 3 / 4 * falloff / (FALLOFF_RANGE - OPTIMAL_RANGE)
+}
+
+/// What towers may repair: ramparts below `Memory.settings.rampart_fresh_hits` (default
+/// 10,000) and spawns, storage and towers below half. Roads, containers and walls are left to
+/// creeps, which repair at a third of the cost.
+fn repair_target(tower: &StructureTower) -> Option<Structure> {
+    let room = tower.room()?;
+    let fresh = settings::u32_or("rampart_fresh_hits", FRESH_RAMPART_HITS);
+    room_cache::structures(&room)
+        .iter()
+        .filter(|s| {
+            let (hits, max) = match s.as_attackable() {
+                Some(a) => (a.hits(), a.hits_max()),
+                None => return false,
+            };
+            match s.structure_type() {
+                StructureType::Rampart => hits < fresh,
+                StructureType::Spawn | StructureType::Storage | StructureType::Tower => {
+                    hits * 2 < max
+                }
+                _ => false,
+            }
+        })
+        .min_by_key(|s| s.as_attackable().map_or(0, |a| a.hits()))
+        .cloned()
+}
+
+/// Attacks the hostile (never an ally) it will hit hardest where that hostile is headed, else
+/// heals the creep `triage` puts first, else repairs from the whitelist. Border campers out of
+/// `threat::camper_fire_range` are left to the defenders on the chokes. Repair is skipped
+/// while the tower is low and in rooms with `Memory.settings.tower_repair_off_<room>` set.
+pub fn run_tower(tower: &StructureTower) -> Result<(), BotError> {
+    let room = match tower.room() {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+    // past the controller level it can't act, and isn't sent energy either
+    if room::is_inactive(room.name(), tower.untyped_id()) {
+        return Ok(());
+    }
+    // border campers are only worth a shot up close; at range they step out before it tells
+    let fire_range = threat::camper_fire_range();
+    let (held, targets): (Vec<Creep>, Vec<Creep>) = allies::hostile_creeps(&room)
+        .into_iter()
+        .partition(|c| threat::border_camper(c) && tower.pos().get_range_to(c) > fire_range);
+    // the shot lands where the hostile moves to, so it's judged from there
+    let hostile = targets.into_iter().max_by_key(|c| {
+        let ahead = tower.pos().get_range_to(&threat::predicted_pos(c));
+        (attack_damage(ahead), Reverse(tower.pos().get_range_to(c)))
+    });
+    if hostile.is_none() {
+        if let Some(camper) = held.first() {
+            threat::note_held(camper, TOWER_ACTION_COST as u32);
+            stats::increment_room("towers", Some(room.name()), "camper_held", TOWER_ACTION_COST);
+        }
+    }
+    if let Some(hostile) = hostile {
+        let target = hostile.untyped_id().to_string();
+        let r = intents::issue("tower", "attack", &target, None, || tower.attack(&hostile));
+        error::check("tower attack", r)?;
+        account(tower, "attack");
+        return Ok(());
+    }
+    if let Some(creep) = triage::heal_target(&room, tower.pos()) {
+        let target = creep.untyped_id().to_string();
+        let r = intents::issue("tower", "heal", &target, None, || tower.heal(&creep));
+        error::check("tower heal", r)?;
+        account(tower, "heal");
+        return Ok(());
+    }
+
+    let room = room.name().to_string();
+    if settings::flag(&format!("tower_repair_off_{}", room))
+        || tower.store_of(ResourceType::Energy) < REPAIR_RESERVE
+    {
+        return Ok(());
+    }
+    if let Some(target) = repair_target(tower) {
+        let id = target.untyped_id().to_string();
+        match intents::issue("tower", "repair", &id, None, || tower.repair(&target)) {
+            ReturnCode::NotEnough => {}
+            r => {
+                error::check("tower repair", r)?;
+                account(tower, "repair");
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,148 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use screeps::{prelude::*, Creep, Position, Room, RoomName};
+
+use crate::{
+    construction, coord, intents, memory, route,
+    terrain::{self, ROOM_SIZE},
+};
+
+/// Creep visits are counted in windows this long; the last full window is kept alongside the
+/// current one so the map doesn't go blank at every rollover.
+const WINDOW_TICKS: u32 = 1500;
+/// Visits over the two windows that make a tile part of an active route.
+const LOAD_BEARING_VISITS: u16 = 20;
+
+struct RoomTraffic {
+    since: u32,
+    current: Vec<u16>,
+    previous: Vec<u16>,
+}
+
+thread_local! {
+    /// Heap only: a VM reset starts the map over, which only makes repairs more conservative
+    /// for a window.
+    static TRAFFIC: RefCell<HashMap<RoomName, RoomTraffic>> = RefCell::new(HashMap::new());
+    /// Where each creep stands and the tick it got there.
+    static STANDING: RefCell<HashMap<String, (Position, u32)>> = RefCell::new(HashMap::new());
+}
+
+/// Counts the tile `creep` stands on this tick.
+pub fn record(creep: &Creep) {
+    let pos = creep.pos();
+    let now = screeps::game::time();
+    TRAFFIC.with(|t| {
+        let mut t = t.borrow_mut();
+        let room = t.entry(pos.room_name()).or_insert_with(|| RoomTraffic {
+            since: now,
+            current: vec![0; ROOM_SIZE * ROOM_SIZE],
+            previous: vec![0; ROOM_SIZE * ROOM_SIZE],
+        });
+        if now - room.since >= WINDOW_TICKS {
+            room.previous = std::mem::replace(&mut room.current, vec![0; ROOM_SIZE * ROOM_SIZE]);
+            room.since = now;
+        }
+        let i = terrain::index(pos.x() as usize, pos.y() as usize);
+        room.current[i] = room.current[i].saturating_add(1);
+    });
+    STANDING.with(|s| {
+        let mut s = s.borrow_mut();
+        // creeps that died drop out with the rest of the entries a window old
+        if now % WINDOW_TICKS == 0 {
+            s.retain(|_, (_, since)| now - *since < WINDOW_TICKS);
+        }
+        let entry = s.entry(creep.name()).or_insert((pos, now));
+        if entry.0 != pos {
+            *entry = (pos, now);
+        }
+    })
+}
+
+/// Whether `creep` is standing where it stood last tick.
+fn stuck(creep: &Creep) -> bool {
+    let now = screeps::game::time();
+    STANDING.with(|s| {
+        s.borrow()
+            .get(&creep.name())
+            .map_or(false, |(pos, since)| *pos == creep.pos() && *since < now)
+    })
+}
+
+/// Whether a creep gives way to anyone who needs its tile: workers waiting on a staging tile.
+pub fn shovable(creep: &Creep) -> bool {
+    memory::creep_memory(creep).f64("stage").ok().flatten().is_some()
+}
+
+/// For a creep that didn't get any closer to `to` last tick: swaps places with a shovable
+/// creep next to it that's in the way, since the pathfinder routes around creeps that stood
+/// still.
+pub fn shove(creep: &Creep, to: Position) {
+    let pos = creep.pos();
+    if creep.fatigue() > 0 || !stuck(creep) {
+        return;
+    }
+    let range = pos.get_range_to(&to);
+    let blocker = pos
+        .find_in_range(screeps::find::MY_CREEPS, 1)
+        .into_iter()
+        .filter(|c| c.name() != creep.name() && shovable(c))
+        .filter(|c| c.pos().get_range_to(&to) < range)
+        .min_by_key(|c| c.pos().get_range_to(&to));
+    let blocker = match blocker {
+        Some(b) => b,
+        None => return,
+    };
+    let there = blocker.pos();
+    let back = coord::direction(there, pos);
+    if let (Some(back), Some(ahead)) = (back, coord::direction(pos, there)) {
+        intents::issue(
+            &intents::creep_actor(&blocker),
+            "move_direction",
+            &pos.to_string(),
+            None,
+            || blocker.move_direction(back),
+        );
+        intents::issue(
+            &intents::creep_actor(creep),
+            "move_direction",
+            &there.to_string(),
+            None,
+            || creep.move_direction(ahead),
+        );
+    }
+}
+
+/// Steps `creep` off a site it would keep from being built, once a builder is in range of it.
+pub fn clear_site(creep: &Creep, room: &Room) {
+    let pos = creep.pos();
+    if creep.fatigue() > 0
+        || !construction::blocking_sites(room).contains(&pos)
+        || !construction::being_built(pos)
+    {
+        return;
+    }
+    if let Some(dir) = route::flee_step(pos, &[pos], 2).and_then(|s| coord::direction(pos, s)) {
+        intents::issue(
+            &intents::creep_actor(creep),
+            "move_direction",
+            &pos.to_string(),
+            None,
+            || creep.move_direction(dir),
+        );
+    }
+}
+
+/// Creep visits to `pos` over the current and last window.
+pub fn visits(pos: Position) -> u16 {
+    let i = terrain::index(pos.x() as usize, pos.y() as usize);
+    TRAFFIC.with(|t| {
+        t.borrow()
+            .get(&pos.room_name())
+            .map_or(0, |r| r.current[i].saturating_add(r.previous[i]))
+    })
+}
+
+/// Whether creeps walk over `pos` often enough for it to be on an active route.
+pub fn on_route(pos: Position) -> bool {
+    visits(pos) >= LOAD_BEARING_VISITS
+}
@@ -0,0 +1,144 @@
+use screeps::{prelude::*, Creep, Part, Position, Room};
+
+use crate::{allies, labs, population, role::Role, room_cache, spawn};
+
+/// Creeps this close to dying of old age aren't worth the energy to heal.
+const MIN_TTL: u32 = 50;
+/// Defenders with a hostile this close are in the fight.
+const COMBAT_RANGE: u32 = 5;
+/// Bodies at least this dear are put ahead of ordinary creeps, like boosted ones.
+const DEAR_BODY_COST: u32 = 1200;
+
+/// What triage looks at in a damaged creep.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Patient {
+    pub role: Role,
+    pub hits: u32,
+    pub hits_max: u32,
+    pub ttl: u32,
+    pub body_cost: u32,
+    pub boosted: bool,
+    /// Range to the nearest hostile, if the room has any.
+    pub hostile_range: Option<u32>,
+}
+
+impl Patient {
+    /// `creep` as a patient, with `hostiles` the room's hostile creeps.
+    pub fn of(creep: &Creep, hostiles: &[Position]) -> Patient {
+        let parts: Vec<Part> = creep.body().iter().map(|p| p.part).collect();
+        Patient {
+            role: population::role_of(creep),
+            hits: creep.hits(),
+            hits_max: creep.hits_max(),
+            ttl: creep.ticks_to_live(),
+            body_cost: spawn::body_cost(&parts),
+            boosted: labs::boosted_parts(creep) > 0,
+            hostile_range: hostiles.iter().map(|h| creep.pos().get_range_to(h)).min(),
+        }
+    }
+}
+
+/// How urgently `patient` needs healing; higher goes first, and `None` is never healed.
+/// Defenders in a fight come first, then boosted or dear creeps, then everyone else, and
+/// within each the creep missing the largest share of its hits.
+pub fn score(patient: &Patient) -> Option<u32> {
+    let max = patient.hits_max;
+    if patient.hits >= max || patient.ttl < MIN_TTL {
+        return None;
+    }
+    let fighting = patient.role == Role::Defender
+        && patient.hostile_range.map_or(false, |r| r <= COMBAT_RANGE);
+    let tier = if fighting {
+        3
+    } else if patient.boosted || patient.body_cost >= DEAR_BODY_COST {
+        2
+    } else {
+        1
+    };
+    let missing = (max - patient.hits) * 100 / max.max(1);
+    Some(tier * 1000 + missing)
+}
+
+/// The damaged creep in `room` that most needs healing, the nearest to `from` among equals.
+/// Towers heal by this, so anything else healing agrees with them on who goes first.
+pub fn heal_target(room: &Room, from: Position) -> Option<Creep> {
+    let hostiles: Vec<Position> = allies::hostile_creeps(room).iter().map(|h| h.pos()).collect();
+    room_cache::damaged_creeps(room)
+        .iter()
+        .filter_map(|c| score(&Patient::of(c, &hostiles)).map(|s| (s, c)))
+        .max_by_key(|(s, c)| (*s, std::cmp::Reverse(from.get_range_to(*c))))
+        .map(|(_, c)| c.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn patient(role: Role, hits: u32, ttl: u32) -> Patient {
+        Patient {
+            role,
+            hits,
+            hits_max: 1000,
+            ttl,
+            body_cost: 600,
+            boosted: false,
+            hostile_range: None,
+        }
+    }
+
+    fn first(patients: &[Patient]) -> Option<Patient> {
+        patients
+            .iter()
+            .filter_map(|p| score(p).map(|s| (s, *p)))
+            .max_by_key(|(s, _)| *s)
+            .map(|(_, p)| p)
+    }
+
+    #[test]
+    fn fighting_defender_before_old_boosted_upgrader_and_dying_worker() {
+        let defender = Patient {
+            hostile_range: Some(3),
+            ..patient(Role::Defender, 900, 1200)
+        };
+        let worker = patient(Role::Worker, 100, 30);
+        let upgrader = Patient {
+            boosted: true,
+            ..patient(Role::Upgrader, 200, 400)
+        };
+        assert_eq!(first(&[worker, upgrader, defender]), Some(defender));
+        assert_eq!(score(&worker), None);
+        assert_eq!(first(&[worker, upgrader]), Some(upgrader));
+    }
+
+    #[test]
+    fn defender_out_of_the_fight_is_ordinary() {
+        let defender = Patient {
+            hostile_range: Some(COMBAT_RANGE + 1),
+            ..patient(Role::Defender, 900, 1200)
+        };
+        let worker = patient(Role::Worker, 500, 1200);
+        assert_eq!(first(&[defender, worker]), Some(worker));
+    }
+
+    #[test]
+    fn dear_body_ranks_with_boosted() {
+        let dear = Patient {
+            body_cost: DEAR_BODY_COST,
+            ..patient(Role::Worker, 900, 1200)
+        };
+        let cheap = patient(Role::Worker, 100, 1200);
+        assert_eq!(first(&[cheap, dear]), Some(dear));
+    }
+
+    #[test]
+    fn full_hits_are_never_healed() {
+        assert_eq!(score(&patient(Role::Defender, 1000, 1500)), None);
+    }
+
+    #[test]
+    fn most_hurt_first_within_a_tier() {
+        let light = patient(Role::Worker, 800, 1200);
+        let heavy = patient(Role::Worker, 300, 1200);
+        assert_eq!(first(&[light, heavy]), Some(heavy));
+    }
+}
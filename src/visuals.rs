@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+
+use screeps::{find, prelude::*, ResourceType, Room};
+use stdweb::js;
+
+use crate::{
+    growth::{self, Bound},
+    invaders, memory,
+    perimeter::PerimeterReport,
+    planner, population, remote,
+    role::Role,
+    settings,
+    terrain::ROOM_SIZE,
+};
+
+const LINE_HEIGHT: f64 = 0.8;
+const NORMAL: &str = "#dddddd";
+const WARNING: &str = "#ffcc00";
+const DANGER: &str = "#ff4444";
+
+/// Downgrade timers below this are drawn in red.
+const DOWNGRADE_DANGER_TICKS: u32 = 10_000;
+/// CPU buckets below this are drawn in yellow.
+const BUCKET_WARNING: u32 = 3000;
+
+struct Dashboard {
+    room: String,
+    line: f64,
+}
+
+impl Dashboard {
+    fn line(&mut self, text: String, color: &str) {
+        let room = &self.room;
+        let y = 1.0 + self.line * LINE_HEIGHT;
+        js! {
+            new RoomVisual(@{room}).text(@{text}, 1, @{y}, {
+                color: @{color},
+                align: "left",
+                font: 0.6,
+            });
+        }
+        self.line += 1.0;
+    }
+}
+
+fn thousands(n: u32) -> String {
+    if n >= 10_000 {
+        format!("{}k", n / 1000)
+    } else {
+        n.to_string()
+    }
+}
+
+/// Draws the colony health overlay in the top-left of `room`, when `Memory.settings.visuals`
+/// is set.
+pub fn draw_dashboard(room: &Room) {
+    if !settings::flag("visuals") {
+        return;
+    }
+    let mut d = Dashboard {
+        room: room.name().to_string(),
+        line: 0.0,
+    };
+
+    let storage = room
+        .storage()
+        .map(|s| thousands(s.store_of(ResourceType::Energy)))
+        .unwrap_or_else(|| "-".to_owned());
+    d.line(
+        format!(
+            "energy {}/{}  storage {}",
+            room.energy_available(),
+            room.energy_capacity_available(),
+            storage
+        ),
+        NORMAL,
+    );
+
+    if let Some(c) = room.controller() {
+        let progress = if c.progress_total() > 0 {
+            100.0 * c.progress() as f64 / c.progress_total() as f64
+        } else {
+            100.0
+        };
+        let downgrade = c.ticks_to_downgrade();
+        d.line(
+            format!(
+                "RCL {} {:.1}%  downgrade {}",
+                c.level(),
+                progress,
+                thousands(downgrade)
+            ),
+            if downgrade < DOWNGRADE_DANGER_TICKS {
+                DANGER
+            } else {
+                NORMAL
+            },
+        );
+    }
+
+    let mut counts: HashMap<Role, u32> = HashMap::new();
+    for creep in room.find(find::MY_CREEPS) {
+        *counts.entry(population::role_of(&creep)).or_insert(0) += 1;
+    }
+    let mem = memory::get_room_memory(room.name()).unwrap_or_default();
+    let roles: Vec<String> = population::targets(room, &mem)
+        .into_iter()
+        .map(|(role, target, _)| {
+            format!(
+                "{:?} {}/{}",
+                role,
+                counts.get(&role).cloned().unwrap_or(0),
+                target
+            )
+        })
+        .collect();
+    d.line(roles.join("  "), NORMAL);
+
+    let report = growth::report(room, &mem);
+    let color = if report.bound == Bound::Energy {
+        NORMAL
+    } else {
+        WARNING
+    };
+    d.line(format!("growth {}", growth::summary(&report)), color);
+
+    let hostiles = room.find(find::HOSTILE_CREEPS).len();
+    d.line(
+        format!("threat {} hostiles", hostiles),
+        if hostiles > 0 { DANGER } else { NORMAL },
+    );
+
+    // how close this room and its remotes are to their next invader
+    let mut any_due = false;
+    let clocks: Vec<String> = std::iter::once(room.name())
+        .chain(remote::remotes_of(room.name()))
+        .map(|name| {
+            let harvested = invaders::harvested(name);
+            let due = if harvested >= invaders::DUE_ENERGY {
+                any_due = true;
+                "!"
+            } else {
+                ""
+            };
+            format!(
+                "{} {}/{}{}",
+                name,
+                thousands(harvested),
+                thousands(invaders::INVADER_ENERGY),
+                due
+            )
+        })
+        .collect();
+    d.line(
+        format!("invaders {}", clocks.join("  ")),
+        if any_due { WARNING } else { NORMAL },
+    );
+
+    let planner = match (&mem.perimeter_scan, &mem.perimeter) {
+        (Some(scan), _) => format!(
+            "perimeter scanning {}/{} tiles",
+            scan.reached(),
+            ROOM_SIZE * ROOM_SIZE
+        ),
+        (None, Some(r)) if r.open => format!("perimeter open, {} ramparts", r.proposed.len()),
+        (None, Some(r)) => format!("perimeter closed at {}", r.checked),
+        (None, None) => "perimeter not checked".to_owned(),
+    };
+    let open = mem.perimeter.as_ref().map_or(false, |r| r.open);
+    d.line(planner, if open { WARNING } else { NORMAL });
+    let plan = match &mem.tile_plan {
+        Some(p) if p.finished_at.is_some() => format!("base plan for {} ready", p.origin),
+        Some(p) => format!(
+            "base plan for {} {}/{} rows",
+            p.origin,
+            p.rows,
+            planner::TILE_PLAN_ROWS
+        ),
+        None => "base plan idle".to_owned(),
+    };
+    d.line(plan, NORMAL);
+
+    let bucket = screeps::game::cpu::bucket() as u32;
+    d.line(
+        format!("bucket {}", bucket),
+        if bucket < BUCKET_WARNING {
+            WARNING
+        } else {
+            NORMAL
+        },
+    );
+}
+
+/// Draws the last perimeter check: the breach walk in red and the proposed ramparts as green
+/// dots.
+pub fn draw_perimeter(room: &Room, report: &PerimeterReport) {
+    if !settings::flag("visuals") || !report.open {
+        return;
+    }
+    let room = room.name().to_string();
+    let tile = |i: &u16| {
+        let i = *i as usize;
+        vec![(i % ROOM_SIZE) as u32, (i / ROOM_SIZE) as u32]
+    };
+    let breach: Vec<Vec<u32>> = report.breach.iter().map(tile).collect();
+    let proposed: Vec<Vec<u32>> = report.proposed.iter().map(tile).collect();
+    js! {
+        var v = new RoomVisual(@{room});
+        v.poly(@{breach}, { stroke: @{DANGER}, lineStyle: "dashed" });
+        for (var p of @{proposed}) {
+            v.circle(p[0], p[1], { radius: 0.2, fill: "#44ff44" });
+        }
+    }
+}